@@ -24,6 +24,14 @@ pub enum AuthError {
     #[error("token refresh failed: {0}")]
     TokenRefreshFailed(String),
 
+    /// Refresh token rejected by the provider (revoked or expired)
+    #[error("refresh token rejected: {0}")]
+    InvalidGrant(String),
+
+    /// Token reported inactive by the introspection endpoint
+    #[error("token is no longer active")]
+    TokenInactive,
+
     /// Invalid state parameter
     #[error("invalid state parameter")]
     InvalidState,
@@ -68,6 +76,34 @@ pub enum AuthError {
     #[error("device authorization expired")]
     DeviceAuthExpired,
 
+    /// SAML response or assertion rejected
+    #[error("SAML validation failed: {0}")]
+    SamlValidationFailed(String),
+
+    /// IdP reported an unsuccessful SAML login
+    #[error("SAML authentication failed: {0}")]
+    SamlStatus(String),
+
+    /// Unknown user or wrong password
+    #[error("invalid username or password")]
+    InvalidCredentials,
+
+    /// Wrong, expired or reused one-time code
+    #[error("invalid one-time code")]
+    InvalidOtp,
+
+    /// Account must enroll a second factor before logging in
+    #[error("second factor enrollment required")]
+    MfaRequired,
+
+    /// Password does not meet the password policy
+    #[error("password rejected: {0}")]
+    WeakPassword(String),
+
+    /// User store error
+    #[error("user store error: {0}")]
+    UserStore(#[from] corevpn_core::CoreError),
+
     /// HTTP error
     #[error("HTTP error: {0}")]
     HttpError(String),
@@ -139,8 +139,7 @@ impl AuthFlow {
             ("code_verifier", &state.code_verifier),
         ];
 
-        let client = reqwest::Client::new();
-        let response = client
+        let response = self.provider.http_client()
             .post(endpoint)
             .form(&params)
             .send()
@@ -168,41 +167,7 @@ impl AuthFlow {
 
     /// Refresh access token
     pub async fn refresh_token(&self, refresh_token: &str) -> Result<TokenSet> {
-        let endpoint = self.provider.token_endpoint()?;
-        let config = self.provider.config();
-
-        let params = [
-            ("grant_type", "refresh_token"),
-            ("client_id", &config.client_id),
-            ("client_secret", &config.client_secret),
-            ("refresh_token", refresh_token),
-        ];
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(endpoint)
-            .form(&params)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(AuthError::TokenRefreshFailed(error_text));
-        }
-
-        let token_response: TokenResponse = response.json().await?;
-
-        Ok(TokenSet {
-            access_token: token_response.access_token,
-            refresh_token: token_response.refresh_token,
-            id_token: token_response.id_token,
-            expires_at: chrono::Utc::now()
-                + chrono::Duration::seconds(token_response.expires_in.unwrap_or(3600) as i64),
-            token_type: token_response.token_type,
-            scopes: token_response.scope
-                .map(|s| s.split(' ').map(String::from).collect())
-                .unwrap_or_default(),
-        })
+        self.provider.refresh_token(refresh_token).await
     }
 }
 
@@ -218,6 +183,11 @@ impl DeviceAuthFlow {
         Self { provider }
     }
 
+    /// Get the provider
+    pub fn provider(&self) -> &OAuthProvider {
+        &self.provider
+    }
+
     /// Start device authorization
     pub async fn start(&self) -> Result<DeviceAuthResponse> {
         let endpoint = self.provider.device_authorization_endpoint()?;
@@ -229,8 +199,7 @@ impl DeviceAuthFlow {
             ("scope", &scopes),
         ];
 
-        let client = reqwest::Client::new();
-        let response = client
+        let response = self.provider.http_client()
             .post(endpoint)
             .form(&params)
             .send()
@@ -257,8 +226,7 @@ impl DeviceAuthFlow {
             ("device_code", device_code),
         ];
 
-        let client = reqwest::Client::new();
-        let response = client
+        let response = self.provider.http_client()
             .post(endpoint)
             .form(&params)
             .send()
@@ -295,7 +263,7 @@ impl DeviceAuthFlow {
 
 /// OAuth2 token response
 #[derive(Debug, Deserialize)]
-struct TokenResponse {
+pub(crate) struct TokenResponse {
     access_token: String,
     #[serde(default)]
     refresh_token: Option<String>,
@@ -328,31 +296,88 @@ pub struct DeviceAuthResponse {
     pub interval: u64,
 }
 
+impl TokenResponse {
+    /// Convert into a token set, computing the expiry from `expires_in`
+    pub(crate) fn into_token_set(self) -> TokenSet {
+        TokenSet {
+            access_token: self.access_token,
+            refresh_token: self.refresh_token,
+            id_token: self.id_token,
+            expires_at: chrono::Utc::now()
+                + chrono::Duration::seconds(self.expires_in.unwrap_or(3600) as i64),
+            token_type: self.token_type,
+            scopes: self.scope
+                .map(|s| s.split(' ').map(String::from).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
 fn default_interval() -> u64 {
     5
 }
 
 /// Error response
 #[derive(Debug, Deserialize)]
-struct ErrorResponse {
-    error: String,
+pub(crate) struct ErrorResponse {
+    pub(crate) error: String,
     #[serde(default)]
-    error_description: Option<String>,
+    pub(crate) error_description: Option<String>,
 }
 
-/// Generate authentication challenge for OpenVPN auth-user-pass
-pub fn generate_vpn_auth_challenge(device_response: &DeviceAuthResponse) -> String {
-    format!(
-        "CRV1:R,E:{}:Please visit {} and enter code: {}",
-        base64::Engine::encode(
-            &base64::engine::general_purpose::STANDARD,
-            device_response.device_code.as_bytes()
+/// Generate a CRV1 dynamic challenge for OpenVPN auth-user-pass
+///
+/// Format: `CRV1:R,E:<state_id>:<base64 username>:<text>`. The client
+/// answers on reconnect with the password `CRV1::<state_id>::<response>`.
+pub fn generate_vpn_auth_challenge(
+    state_id: &str,
+    username: &str,
+    device_response: &DeviceAuthResponse,
+) -> String {
+    generate_crv1_challenge(
+        state_id,
+        username,
+        &format!(
+            "Please visit {} and enter code: {}",
+            device_response.verification_uri, device_response.user_code
         ),
-        device_response.verification_uri,
-        device_response.user_code
     )
 }
 
+/// Generate a CRV1 dynamic challenge asking the user for `text`
+pub fn generate_crv1_challenge(state_id: &str, username: &str, text: &str) -> String {
+    format!(
+        "CRV1:R,E:{}:{}:{}",
+        state_id,
+        base64::Engine::encode(&base64::engine::general_purpose::STANDARD, username.as_bytes()),
+        text
+    )
+}
+
+/// Parse a CRV1 challenge response, returning the state ID and the response
+pub fn parse_vpn_auth_response(password: &str) -> Option<(&str, &str)> {
+    let rest = password.strip_prefix("CRV1::")?;
+    let (state_id, response) = rest.split_once("::")?;
+    (!state_id.is_empty()).then_some((state_id, response))
+}
+
+/// Parse a static challenge response, returning the password and the response
+///
+/// Clients configured with `static-challenge` send the password
+/// `SCRV1:<base64 password>:<base64 response>`.
+pub fn parse_static_challenge_response(password: &str) -> Option<(String, String)> {
+    use base64::Engine;
+
+    let (password, response) = password.strip_prefix("SCRV1:")?.split_once(':')?;
+    let decode = |value: &str| {
+        base64::engine::general_purpose::STANDARD
+            .decode(value)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+    };
+    Some((decode(password)?, decode(response)?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -377,4 +402,35 @@ mod tests {
         assert!(!challenge.contains('+'));
         assert!(!challenge.contains('/'));
     }
+
+    #[test]
+    fn test_vpn_auth_challenge() {
+        let device = DeviceAuthResponse {
+            device_code: "secret-device-code".to_string(),
+            user_code: "ABCD-EFGH".to_string(),
+            verification_uri: "https://example.com/device".to_string(),
+            verification_uri_complete: None,
+            expires_in: 600,
+            interval: 5,
+        };
+
+        let challenge = generate_vpn_auth_challenge("state-1", "alice", &device);
+        assert_eq!(
+            challenge,
+            "CRV1:R,E:state-1:YWxpY2U=:Please visit https://example.com/device and enter code: ABCD-EFGH"
+        );
+        assert!(!challenge.contains("secret-device-code"));
+
+        assert_eq!(parse_vpn_auth_response("CRV1::state-1::ok"), Some(("state-1", "ok")));
+        assert_eq!(parse_vpn_auth_response("CRV1::state-1::"), Some(("state-1", "")));
+        assert_eq!(parse_vpn_auth_response("hunter2"), None);
+        assert_eq!(parse_vpn_auth_response("CRV1::::ok"), None);
+
+        assert_eq!(
+            parse_static_challenge_response("SCRV1:aHVudGVyMg==:MTIzNDU2"),
+            Some(("hunter2".to_string(), "123456".to_string()))
+        );
+        assert_eq!(parse_static_challenge_response("SCRV1:aHVudGVyMg=="), None);
+        assert_eq!(parse_static_challenge_response("hunter2"), None);
+    }
 }
@@ -0,0 +1,494 @@
+//! JSON Web Key Sets
+//!
+//! Fetches the provider's signing keys from its `jwks_uri`, caches them and
+//! verifies JWT signatures (RS256, ES256 and EdDSA). Tokens signed with a key
+//! that is not in the cache trigger a refetch, so key rotation is picked up
+//! without waiting for the cache to expire.
+
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use parking_lot::RwLock;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+
+use crate::token::IdTokenClaims;
+use crate::{AuthError, Result};
+
+/// How long fetched keys are trusted before refetching
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Minimum time between refetches triggered by unknown key IDs
+pub const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// JWS signature algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JwsAlgorithm {
+    /// RSASSA-PKCS1-v1_5 with SHA-256
+    RS256,
+    /// ECDSA P-256 with SHA-256
+    ES256,
+    /// Ed25519
+    EdDSA,
+}
+
+impl JwsAlgorithm {
+    /// Parse the `alg` header value, rejecting `none` and unsupported algorithms
+    pub fn from_name(alg: &str) -> Result<Self> {
+        match alg {
+            "RS256" => Ok(JwsAlgorithm::RS256),
+            "ES256" => Ok(JwsAlgorithm::ES256),
+            "EdDSA" => Ok(JwsAlgorithm::EdDSA),
+            "none" | "" => Err(AuthError::TokenValidationFailed(
+                "unsigned tokens are not accepted".into(),
+            )),
+            other => Err(AuthError::TokenValidationFailed(format!(
+                "unsupported signature algorithm: {}",
+                other
+            ))),
+        }
+    }
+
+    /// JWA name
+    pub fn name(&self) -> &'static str {
+        match self {
+            JwsAlgorithm::RS256 => "RS256",
+            JwsAlgorithm::ES256 => "ES256",
+            JwsAlgorithm::EdDSA => "EdDSA",
+        }
+    }
+}
+
+/// JWT header
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// Public key from a JWK Set
+#[derive(Debug, Clone, Deserialize)]
+pub struct Jwk {
+    /// Key type (RSA, EC, OKP)
+    pub kty: String,
+    /// Key ID
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// Intended algorithm
+    #[serde(default)]
+    pub alg: Option<String>,
+    /// Intended use (`sig` or `enc`)
+    #[serde(default, rename = "use")]
+    pub key_use: Option<String>,
+    /// RSA modulus
+    #[serde(default)]
+    pub n: Option<String>,
+    /// RSA public exponent
+    #[serde(default)]
+    pub e: Option<String>,
+    /// Curve (P-256, Ed25519)
+    #[serde(default)]
+    pub crv: Option<String>,
+    /// EC x coordinate or OKP public key
+    #[serde(default)]
+    pub x: Option<String>,
+    /// EC y coordinate
+    #[serde(default)]
+    pub y: Option<String>,
+}
+
+impl Jwk {
+    /// Check if the key can verify signatures made with `alg`
+    pub fn supports(&self, alg: JwsAlgorithm) -> bool {
+        if self.key_use.as_deref().is_some_and(|u| u != "sig") {
+            return false;
+        }
+        if self.alg.as_deref().is_some_and(|a| a != alg.name()) {
+            return false;
+        }
+        match alg {
+            JwsAlgorithm::RS256 => self.kty == "RSA",
+            JwsAlgorithm::ES256 => self.kty == "EC" && self.crv.as_deref() == Some("P-256"),
+            JwsAlgorithm::EdDSA => self.kty == "OKP" && self.crv.as_deref() == Some("Ed25519"),
+        }
+    }
+
+    /// Verify a signature over `message`
+    pub fn verify(&self, alg: JwsAlgorithm, message: &[u8], sig: &[u8]) -> Result<()> {
+        if !self.supports(alg) {
+            return Err(AuthError::TokenValidationFailed("key does not match algorithm".into()));
+        }
+
+        let verified = match alg {
+            JwsAlgorithm::RS256 => {
+                let key = RsaPublicKeyComponents {
+                    n: decode_param(self.n.as_deref())?,
+                    e: decode_param(self.e.as_deref())?,
+                };
+                key.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+            }
+            JwsAlgorithm::ES256 => {
+                let mut point = vec![0x04];
+                point.extend(decode_param(self.x.as_deref())?);
+                point.extend(decode_param(self.y.as_deref())?);
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+            }
+            JwsAlgorithm::EdDSA => {
+                UnparsedPublicKey::new(&signature::ED25519, decode_param(self.x.as_deref())?)
+                    .verify(message, sig)
+            }
+        };
+
+        verified.map_err(|_| AuthError::TokenValidationFailed("invalid token signature".into()))
+    }
+}
+
+/// JWK Set document
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwkSet {
+    /// Keys
+    pub keys: Vec<Jwk>,
+}
+
+/// Keys fetched from the provider
+struct CachedKeys {
+    keys: Vec<Jwk>,
+    fetched_at: Instant,
+}
+
+/// Cached JWK Set used to verify ID tokens
+pub struct JwksCache {
+    /// JWKS endpoint
+    jwks_uri: String,
+    /// HTTP client
+    http_client: reqwest::Client,
+    /// Keys from the last fetch
+    cached: RwLock<Option<CachedKeys>>,
+    /// How long fetched keys are trusted
+    ttl: Duration,
+    /// Minimum time between refetches for unknown key IDs
+    min_refresh_interval: Duration,
+}
+
+impl JwksCache {
+    /// Create a cache for the given JWKS endpoint (keys are fetched on first use)
+    pub fn new(jwks_uri: &str) -> Self {
+        Self {
+            jwks_uri: jwks_uri.to_string(),
+            http_client: crate::provider::http_client(),
+            cached: RwLock::new(None),
+            ttl: DEFAULT_CACHE_TTL,
+            min_refresh_interval: DEFAULT_MIN_REFRESH_INTERVAL,
+        }
+    }
+
+    /// Set how long fetched keys are trusted
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set the minimum time between refetches for unknown key IDs
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    /// Get the JWKS endpoint
+    pub fn jwks_uri(&self) -> &str {
+        &self.jwks_uri
+    }
+
+    /// Fetch the key set, replacing the cached keys
+    pub async fn refresh(&self) -> Result<()> {
+        let response = self.http_client.get(&self.jwks_uri).send().await?;
+        if !response.status().is_success() {
+            return Err(AuthError::HttpError(format!(
+                "JWKS fetch failed: HTTP {}",
+                response.status()
+            )));
+        }
+
+        let set: JwkSet = response.json().await?;
+        *self.cached.write() = Some(CachedKeys {
+            keys: set.keys,
+            fetched_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Verify a JWT's signature and return its claims
+    pub async fn verify(&self, token: &str) -> Result<IdTokenClaims> {
+        let (header, payload, sig) = split_jwt(token)?;
+        let header: JwtHeader = serde_json::from_slice(&decode_segment(header)?)?;
+        let alg = JwsAlgorithm::from_name(&header.alg)?;
+        let message = &token.as_bytes()[..token.rfind('.').unwrap_or_default()];
+        let sig = decode_segment(sig)?;
+
+        if self.age().is_none_or(|age| age > self.ttl) {
+            self.refresh().await?;
+        }
+
+        let mut result = self.verify_cached(alg, header.kid.as_deref(), message, &sig);
+
+        // The provider may have rotated its keys since the last fetch
+        if matches!(result, Err(KeyLookup::UnknownKey))
+            && self.age().is_some_and(|age| age >= self.min_refresh_interval)
+        {
+            self.refresh().await?;
+            result = self.verify_cached(alg, header.kid.as_deref(), message, &sig);
+        }
+
+        match result {
+            Ok(()) => Ok(serde_json::from_slice(&decode_segment(payload)?)?),
+            Err(KeyLookup::UnknownKey) => Err(AuthError::TokenValidationFailed(format!(
+                "no {} signing key with ID {:?}",
+                alg.name(),
+                header.kid.unwrap_or_default()
+            ))),
+            Err(KeyLookup::Invalid(e)) => Err(e),
+        }
+    }
+
+    fn age(&self) -> Option<Duration> {
+        self.cached.read().as_ref().map(|cached| cached.fetched_at.elapsed())
+    }
+
+    fn verify_cached(
+        &self,
+        alg: JwsAlgorithm,
+        kid: Option<&str>,
+        message: &[u8],
+        sig: &[u8],
+    ) -> std::result::Result<(), KeyLookup> {
+        let cached = self.cached.read();
+        let candidates = cached
+            .iter()
+            .flat_map(|cached| cached.keys.iter())
+            .filter(|key| key.supports(alg))
+            .filter(|key| kid.is_none() || key.kid.as_deref() == kid);
+
+        // Without a key ID any matching key may have signed the token
+        let mut last_err = None;
+        for key in candidates {
+            match key.verify(alg, message, sig) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.map_or(KeyLookup::UnknownKey, KeyLookup::Invalid))
+    }
+}
+
+/// Key lookup failure
+enum KeyLookup {
+    /// No cached key matches the token's algorithm and key ID
+    UnknownKey,
+    /// A key matched but the signature did not verify
+    Invalid(AuthError),
+}
+
+/// Split a compact JWS into its header, payload and signature segments
+fn split_jwt(token: &str) -> Result<(&str, &str, &str)> {
+    let mut parts = token.split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(payload), Some(sig), None) => Ok((header, payload, sig)),
+        _ => Err(AuthError::TokenValidationFailed("invalid JWT format".into())),
+    }
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(segment)
+        .map_err(|e| AuthError::TokenValidationFailed(format!("base64 decode error: {}", e)))
+}
+
+fn decode_param(value: Option<&str>) -> Result<Vec<u8>> {
+    decode_segment(value.ok_or_else(|| {
+        AuthError::TokenValidationFailed("JWK is missing a key parameter".into())
+    })?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+
+    const RSA_PKCS8: &str = concat!(
+        "MIIEvgIBADANBgkqhkiG9w0BAQEFAASCBKgwggSkAgEAAoIBAQC0/k7sG9a/JWFO7LzckweMkpjM1QrMMwstBDer",
+        "JvRWGi0AmbeqIzc1NhCguVPuR4pHX9XDeKffK+i/V2AnzI8r1WEtxI006giibqBeDKD0QfCihkp+F0umRtRHAJDj",
+        "lDVYb+BfOjph5mdaU0uovEKcx5V0gEDxCCLgw7XExAHGZXYKaDS634EgFAK/fC7C+BbDnXTtDjxCmdoXPkENmTiK",
+        "gS5j8QZzafHftOTnbwc+lBDbylwxNuZnc/GBxj3UD7/0Pe6QOyXOjfHqkBUETHBcCKhB0sPQ+wOpXeeFDtUPVZnh",
+        "LXSIF4oNBoqfqHcgx81zBDy7f0iXOlD/NHEtMQjhAgMBAAECggEAAV9gV9gKM97jswaGlCGlUAKYmA0RjOTiM9Ax",
+        "X+HmvnRqXPPNecPUmlmznJRrN4ZhXRMz+syCcWXS2QifmQ2rFEkE+KTh8UHJGPo/yxbLba7GVWUxUW15zGQDHhq5",
+        "/o/UK7yFdhNp9+4YYI37J+dtleTJlF2YoHtQUGYLjddyr/93trE7Zz3lVKTsy/xoJdPIeUWgsPZxK0yjHX5fWkZM",
+        "e451XZmnPpNiLbhi5tQddrxLQ/eSQmoGBUCe/44oKf/YI3hzcJJbKlvAOOq1K/iWAXb5zIA1HlJw+HU8C9XDarvG",
+        "zYoseVOYkO71IiyFguGjlodGyb+ZcvY5b1q5CZYasQKBgQDcLZDtc8oQC20xJeVm7K27oSp5sJP3LJAW9CCRiQl7",
+        "v6TktvsCuJtdaC9e1K1XjpVw4KDGsoeeXvFYid3iOvQuBMqbdlpEkwQwqvebcWK8DjnfCjsUBT/2gd35/Qr2QNd7",
+        "52rikkSpzmPc45CA9hXcCai8TPcEnLR1mDBlyLIhJQKBgQDScLMsP7fqXSXRpVTHt2uujQVJOdVffSfwZdOXfYee",
+        "/eKCTiyxtlzfGf/Lf/J4Ingvjo/4ZlRmy+oAaSXMUok2migq6ZCpPBimooduaEkCDnYN/moKLdMcJNGRS1Uf95mn",
+        "22G54S8h51C+qj49jouVo6Hn8727tKOHJQDAi5HSDQKBgQCOgC88f6RXng2dsZk3dBc9SKHlAJPDdeQL9xTdHIQ2",
+        "w2JFZOgwaqqjWbrO9Xb7JB00YS2ta/66UViCpTgmI2HGJvO6im8B0uTzWrPGtyvf6pUp9cUmv/8V3Bd+pHdjybOt",
+        "Tamv2lr2S832OJJd5Odg8h9zP5zgL0vckBNCzRH8wQKBgCil8kYCzNCtS2fSaqzlJhyiSroZPLjkIPnTgWqKqc6x",
+        "NbZ7yTBOjGPfzsQNI9XGurZc8DxH79enXbzUkqOD8dMlNcsMMJAv9ocBb+2IoUmPZ4hOc0ciENwn1H8fMQMqvXhp",
+        "7NeNwp/m4XbixYD4bsfpwpVeRHDkdI3fPOT0UKdlAoGBAIVh79trRR4S5Z/7mL/XVShLYF0O+sZFc4/FkP5dw7XU",
+        "rd3G7iZBaxjA89IGpS7bqXjmL737gFTBOfRVgUVQoiCl/vfhcepaRJ84bfxL+PJiiqDXRhIX019ELU8oIZtRXgGo",
+        "buBoM0fXhDqdZG4u84MAheo6veFQOXzSx/uC4Khv",
+    );
+
+    fn b64(data: &[u8]) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+    }
+
+    /// Fixed RSA key in PKCS#8 DER, also used by the SAML tests
+    pub(crate) fn rsa_pkcs8() -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD.decode(RSA_PKCS8).unwrap()
+    }
+
+    /// Signing key of the stand-in issuer
+    pub(crate) enum TestKey {
+        Rsa(RsaKeyPair),
+        Ec(EcdsaKeyPair),
+        Ed(Ed25519KeyPair),
+    }
+
+    impl TestKey {
+        pub(crate) fn rsa() -> Self {
+            TestKey::Rsa(RsaKeyPair::from_pkcs8(&rsa_pkcs8()).unwrap())
+        }
+
+        pub(crate) fn ec() -> Self {
+            let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &SystemRandom::new()).unwrap();
+            TestKey::Ec(EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &SystemRandom::new()).unwrap())
+        }
+
+        pub(crate) fn ed() -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            TestKey::Ed(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap())
+        }
+
+        fn alg(&self) -> &'static str {
+            match self {
+                TestKey::Rsa(_) => "RS256",
+                TestKey::Ec(_) => "ES256",
+                TestKey::Ed(_) => "EdDSA",
+            }
+        }
+
+        pub(crate) fn jwk(&self, kid: &str) -> serde_json::Value {
+            match self {
+                TestKey::Rsa(key) => {
+                    let public = RsaPublicKeyComponents::<Vec<u8>>::from(key.public());
+                    serde_json::json!({"kty": "RSA", "kid": kid, "n": b64(&public.n), "e": b64(&public.e)})
+                }
+                TestKey::Ec(key) => {
+                    let point = key.public_key().as_ref();
+                    serde_json::json!({
+                        "kty": "EC", "kid": kid, "crv": "P-256",
+                        "x": b64(&point[1..33]), "y": b64(&point[33..]),
+                    })
+                }
+                TestKey::Ed(key) => serde_json::json!({
+                    "kty": "OKP", "kid": kid, "crv": "Ed25519", "x": b64(key.public_key().as_ref()),
+                }),
+            }
+        }
+
+        pub(crate) fn sign(&self, kid: &str, claims: &serde_json::Value) -> String {
+            let header = serde_json::json!({"alg": self.alg(), "kid": kid, "typ": "JWT"});
+            let input = format!("{}.{}", b64(header.to_string().as_bytes()), b64(claims.to_string().as_bytes()));
+            let rng = SystemRandom::new();
+            let sig = match self {
+                TestKey::Rsa(key) => {
+                    let mut sig = vec![0; key.public().modulus_len()];
+                    key.sign(&signature::RSA_PKCS1_SHA256, &rng, input.as_bytes(), &mut sig).unwrap();
+                    sig
+                }
+                TestKey::Ec(key) => key.sign(&rng, input.as_bytes()).unwrap().as_ref().to_vec(),
+                TestKey::Ed(key) => key.sign(input.as_bytes()).as_ref().to_vec(),
+            };
+            format!("{}.{}", input, b64(&sig))
+        }
+    }
+
+    /// Serve a JWK Set over HTTP, returning its URI; the set can be swapped to simulate rotation
+    pub(crate) async fn serve_jwks(jwks: Arc<RwLock<serde_json::Value>>) -> String {
+        let base = crate::testing::serve_http(move || ("200 OK", jwks.read().to_string())).await;
+        format!("{}/jwks", base)
+    }
+
+    pub(crate) fn claims() -> serde_json::Value {
+        let now = chrono::Utc::now().timestamp();
+        serde_json::json!({
+            "iss": "https://issuer.example.com", "sub": "user123", "aud": "client-id",
+            "exp": now + 3600, "iat": now, "email": "alice@example.com",
+        })
+    }
+
+    #[tokio::test]
+    async fn test_verify_algorithms() {
+        let keys = [TestKey::rsa(), TestKey::ec(), TestKey::ed()];
+        let set = serde_json::json!({
+            "keys": [keys[0].jwk("rsa"), keys[1].jwk("ec"), keys[2].jwk("ed")],
+        });
+        let cache = JwksCache::new(&serve_jwks(Arc::new(RwLock::new(set))).await);
+
+        for (key, kid) in keys.iter().zip(["rsa", "ec", "ed"]) {
+            let token = key.sign(kid, &claims());
+            let verified = cache.verify(&token).await.unwrap();
+            assert_eq!(verified.email.as_deref(), Some("alice@example.com"));
+
+            // Tampered payload
+            let mut parts: Vec<&str> = token.split('.').collect();
+            let forged = b64(br#"{"iss":"x","sub":"admin","aud":"client-id","exp":0,"iat":0}"#);
+            parts[1] = &forged;
+            assert!(cache.verify(&parts.join(".")).await.is_err());
+        }
+
+        // A key must not verify under another key's ID
+        assert!(cache.verify(&keys[1].sign("ed", &claims())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_unsigned() {
+        let set = serde_json::json!({"keys": [TestKey::ed().jwk("ed")]});
+        let cache = JwksCache::new(&serve_jwks(Arc::new(RwLock::new(set))).await);
+
+        let header = b64(br#"{"alg":"none","kid":"ed"}"#);
+        let token = format!("{}.{}.", header, b64(claims().to_string().as_bytes()));
+        match cache.verify(&token).await {
+            Err(AuthError::TokenValidationFailed(msg)) => assert!(msg.contains("unsigned")),
+            other => panic!("unexpected {:?}", other.map(|c| c.sub)),
+        }
+
+        let header = b64(br#"{"alg":"HS256","kid":"ed"}"#);
+        let token = format!("{}.{}.c2ln", header, b64(claims().to_string().as_bytes()));
+        assert!(cache.verify(&token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let (old, new) = (TestKey::ec(), TestKey::ec());
+        let jwks = Arc::new(RwLock::new(serde_json::json!({"keys": [old.jwk("k1")]})));
+        let cache = JwksCache::new(&serve_jwks(jwks.clone()).await)
+            .with_min_refresh_interval(Duration::ZERO);
+
+        assert!(cache.verify(&old.sign("k1", &claims())).await.is_ok());
+
+        // Provider rotates to a new key; the unknown key ID forces a refetch
+        *jwks.write() = serde_json::json!({"keys": [new.jwk("k2")]});
+        assert!(cache.verify(&new.sign("k2", &claims())).await.is_ok());
+        assert!(cache.verify(&old.sign("k1", &claims())).await.is_err());
+
+        // Refetches are rate limited
+        let cache = JwksCache::new(cache.jwks_uri());
+        assert!(cache.verify(&new.sign("k2", &claims())).await.is_ok());
+        *jwks.write() = serde_json::json!({"keys": [old.jwk("k1")]});
+        assert!(cache.verify(&old.sign("k1", &claims())).await.is_err());
+    }
+}
@@ -5,6 +5,9 @@
 //! - Microsoft Entra ID (Azure AD)
 //! - Okta
 //! - Generic OIDC providers
+//!
+//! and SAML 2.0 identity providers such as ADFS, as well as local password
+//! accounts hashed with Argon2id with an optional TOTP second factor.
 
 #![forbid(unsafe_code)]
 #![warn(missing_docs, rust_2018_idioms)]
@@ -14,12 +17,22 @@ pub mod provider;
 pub mod flow;
 pub mod token;
 pub mod session;
+pub mod jwks;
+pub mod saml;
+pub mod password;
+pub mod totp;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 
 pub use error::{AuthError, Result};
 pub use provider::{OAuthProvider, ProviderConfig, ProviderType};
 pub use flow::{AuthFlow, AuthState, DeviceAuthFlow};
-pub use token::{TokenSet, TokenValidator, UserInfo};
+pub use token::{TokenIntrospection, TokenSet, TokenValidator, UserInfo};
 pub use session::{AuthSession, AuthSessionManager};
+pub use jwks::{JwksCache, JwsAlgorithm};
+pub use saml::{AuthnRequest, SamlAssertion, SamlConfig, SamlServiceProvider};
+pub use password::{authenticate, hash_password, set_password, verify_password};
+pub use totp::{Login, TotpKey, TotpVerifier};
 
 /// Supported OAuth2 providers with pre-configured settings
 #[derive(Debug, Clone)]
@@ -39,7 +52,7 @@ pub enum KnownProvider {
         client_id: String,
         /// OAuth2 Client Secret
         client_secret: String,
-        /// Tenant ID (or "common" for multi-tenant)
+        /// Directory (tenant) ID; multi-tenant aliases like "common" are not supported
         tenant_id: String,
     },
     /// Okta
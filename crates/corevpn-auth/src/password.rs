@@ -0,0 +1,184 @@
+//! Local Password Authentication
+//!
+//! Verifies auth-user-pass credentials against local accounts in a
+//! [`UserStore`]. Passwords are stored as Argon2id hashes in PHC string format.
+
+use std::sync::OnceLock;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use corevpn_core::user::{User, UserId, UserStore};
+
+use crate::{AuthError, Result};
+
+/// Minimum accepted password length
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Maximum accepted password length (bounds hashing cost of hostile input)
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Hash a password with Argon2id and a random salt
+pub fn hash_password(password: &str) -> Result<String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AuthError::WeakPassword(format!(
+            "must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return Err(AuthError::WeakPassword(format!(
+            "must be at most {} bytes",
+            MAX_PASSWORD_LENGTH
+        )));
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AuthError::ConfigError(format!("password hashing failed: {}", e)))
+}
+
+/// Check a password against a stored hash
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return false;
+    }
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+/// Set or reset a user's password, creating the user if it does not exist
+pub async fn set_password(store: &dyn UserStore, username: &str, password: &str) -> Result<User> {
+    if username.is_empty() {
+        return Err(AuthError::ConfigError("username must not be empty".into()));
+    }
+
+    let mut user = match find_user(store, username).await {
+        Some(user) => user,
+        None if username.contains('@') => User::new(UserId::from_email(username)).with_email(username),
+        None => User::new(UserId::new(username)),
+    };
+
+    let password = password.to_string();
+    user.password_hash = Some(blocking(move || hash_password(&password)).await??);
+    store.upsert_user(&user).await?;
+    Ok(user)
+}
+
+/// Verify a user's credentials and record the login
+///
+/// Unknown users, users without a password and wrong passwords all fail with
+/// [`AuthError::InvalidCredentials`] after the same amount of hashing work.
+pub async fn authenticate(store: &dyn UserStore, username: &str, password: &str) -> Result<User> {
+    let mut user = check_password(store, username, password).await?;
+    user.record_login();
+    store.upsert_user(&user).await?;
+    Ok(user)
+}
+
+/// Verify a user's credentials without recording a login
+pub(crate) async fn check_password(store: &dyn UserStore, username: &str, password: &str) -> Result<User> {
+    let mut user = find_user(store, username).await;
+    let hash = user
+        .as_ref()
+        .and_then(|user| user.password_hash.clone())
+        .unwrap_or_else(|| dummy_hash().to_string());
+
+    let password = password.to_string();
+    let valid = blocking(move || verify_password(&password, &hash)).await?;
+
+    let user = match user.take() {
+        Some(user) if valid && user.password_hash.is_some() => user,
+        _ => return Err(AuthError::InvalidCredentials),
+    };
+    if !user.can_connect() {
+        return Err(AuthError::UserDisabled);
+    }
+    Ok(user)
+}
+
+/// Look up a user by ID, then by email
+pub(crate) async fn find_user(store: &dyn UserStore, username: &str) -> Option<User> {
+    match store.get_user(&UserId::new(username)).await {
+        Some(user) => Some(user),
+        None => store.get_user_by_email(username).await,
+    }
+}
+
+/// Hash verified for unknown users so they take as long as known ones
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(b"corevpn-unknown-user", &salt)
+            .map(|hash| hash.to_string())
+            .unwrap_or_default()
+    })
+}
+
+/// Run CPU-heavy hashing off the async runtime
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| AuthError::ConfigError(format!("password hashing task failed: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use corevpn_core::MemoryUserStore;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("correct horse battery").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse battery", &hash));
+        assert!(!verify_password("correct horse", &hash));
+        assert!(!verify_password("correct horse battery", "not a hash"));
+
+        // Salts are random
+        assert_ne!(hash, hash_password("correct horse battery").unwrap());
+
+        assert!(matches!(hash_password("short"), Err(AuthError::WeakPassword(_))));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let store = MemoryUserStore::new();
+        set_password(&store, "alice@example.com", "initial-password").await.unwrap();
+
+        let user = authenticate(&store, "alice@example.com", "initial-password").await.unwrap();
+        assert_eq!(user.email.as_deref(), Some("alice@example.com"));
+        assert!(user.last_login.is_some());
+
+        // Reset keeps the account and replaces the password
+        set_password(&store, "alice@example.com", "rotated-password").await.unwrap();
+        assert_eq!(store.list_users().await.len(), 1);
+        assert!(matches!(
+            authenticate(&store, "alice@example.com", "initial-password").await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(authenticate(&store, "alice@example.com", "rotated-password").await.is_ok());
+
+        assert!(matches!(
+            authenticate(&store, "mallory", "rotated-password").await,
+            Err(AuthError::InvalidCredentials)
+        ));
+
+        // Accounts without a password cannot log in with any password
+        store.upsert_user(&User::new(UserId::new("bob"))).await.unwrap();
+        assert!(matches!(authenticate(&store, "bob", "").await, Err(AuthError::InvalidCredentials)));
+
+        let mut alice = store.get_user_by_email("alice@example.com").await.unwrap();
+        alice.enabled = false;
+        store.upsert_user(&alice).await.unwrap();
+        assert!(matches!(
+            authenticate(&store, "alice@example.com", "rotated-password").await,
+            Err(AuthError::UserDisabled)
+        ));
+    }
+}
@@ -2,8 +2,27 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-
-use crate::{AuthError, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::flow::{ErrorResponse, TokenResponse};
+use crate::token::TokenIntrospection;
+use crate::{AuthError, JwksCache, Result, TokenSet, TokenValidator, UserInfo};
+
+/// Time allowed to connect to the provider
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time allowed for a whole request to the provider, body included
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP client for provider requests, so a stalled provider can't hang logins forever
+pub(crate) fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("failed to build HTTP client")
+}
 
 /// OAuth2 provider type
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -30,6 +49,12 @@ impl std::fmt::Display for ProviderType {
     }
 }
 
+/// Microsoft tenant aliases that accept users from many tenants
+///
+/// Their ID tokens are issued by each user's own tenant, so they never
+/// match a fixed issuer.
+const MICROSOFT_TENANT_ALIASES: [&str; 3] = ["common", "organizations", "consumers"];
+
 /// Provider configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProviderConfig {
@@ -52,6 +77,9 @@ pub struct ProviderConfig {
     pub device_authorization_endpoint: Option<String>,
     /// JWKS URI for token validation
     pub jwks_uri: Option<String>,
+    /// RFC 7662 token introspection endpoint (optional)
+    #[serde(default)]
+    pub introspection_endpoint: Option<String>,
     /// Scopes to request
     pub scopes: Vec<String>,
     /// Allowed domains (empty = all allowed)
@@ -81,6 +109,7 @@ impl ProviderConfig {
             userinfo_endpoint: Some("https://openidconnect.googleapis.com/v1/userinfo".to_string()),
             device_authorization_endpoint: Some("https://oauth2.googleapis.com/device/code".to_string()),
             jwks_uri: Some("https://www.googleapis.com/oauth2/v3/certs".to_string()),
+            introspection_endpoint: None,
             scopes: vec![
                 "openid".to_string(),
                 "email".to_string(),
@@ -104,6 +133,9 @@ impl ProviderConfig {
     }
 
     /// Create a Microsoft provider configuration
+    ///
+    /// `tenant_id` must name the directory (tenant) users sign in to;
+    /// multi-tenant aliases such as `common` fail [`ProviderConfig::validate`].
     pub fn microsoft(client_id: &str, client_secret: &str, tenant_id: &str) -> Self {
         let base_url = format!("https://login.microsoftonline.com/{}", tenant_id);
 
@@ -117,6 +149,7 @@ impl ProviderConfig {
             userinfo_endpoint: Some("https://graph.microsoft.com/oidc/userinfo".to_string()),
             device_authorization_endpoint: Some(format!("{}/oauth2/v2.0/devicecode", base_url)),
             jwks_uri: Some(format!("{}/discovery/v2.0/keys", base_url)),
+            introspection_endpoint: None,
             scopes: vec![
                 "openid".to_string(),
                 "email".to_string(),
@@ -147,6 +180,7 @@ impl ProviderConfig {
             userinfo_endpoint: Some(format!("{}/v1/userinfo", base_url)),
             device_authorization_endpoint: Some(format!("{}/v1/device/authorize", base_url)),
             jwks_uri: Some(format!("{}/v1/keys", base_url)),
+            introspection_endpoint: Some(format!("{}/v1/introspect", base_url)),
             scopes: vec![
                 "openid".to_string(),
                 "email".to_string(),
@@ -175,6 +209,7 @@ impl ProviderConfig {
             userinfo_endpoint: None,
             device_authorization_endpoint: None,
             jwks_uri: None,
+            introspection_endpoint: None,
             scopes: vec![
                 "openid".to_string(),
                 "email".to_string(),
@@ -200,6 +235,15 @@ impl ProviderConfig {
         if self.issuer_url.is_empty() {
             return Err(AuthError::ConfigError("issuer_url is required".into()));
         }
+        if self.provider_type == ProviderType::Microsoft {
+            let tenant = self.issuer_url.trim_end_matches("/v2.0").rsplit('/').next().unwrap_or_default();
+            if MICROSOFT_TENANT_ALIASES.contains(&tenant.to_ascii_lowercase().as_str()) {
+                return Err(AuthError::ConfigError(format!(
+                    "Microsoft tenant '{}' is not supported, use the directory (tenant) ID",
+                    tenant
+                )));
+            }
+        }
         Ok(())
     }
 }
@@ -225,6 +269,9 @@ pub struct OidcMetadata {
     #[serde(default)]
     pub device_authorization_endpoint: Option<String>,
     pub jwks_uri: String,
+    /// RFC 7662 token introspection endpoint
+    #[serde(default)]
+    pub introspection_endpoint: Option<String>,
     #[serde(default)]
     pub scopes_supported: Vec<String>,
     #[serde(default)]
@@ -238,7 +285,7 @@ impl OAuthProvider {
     pub fn new(config: ProviderConfig) -> Self {
         Self {
             config,
-            http_client: reqwest::Client::new(),
+            http_client: http_client(),
             metadata: None,
         }
     }
@@ -248,6 +295,11 @@ impl OAuthProvider {
         &self.config
     }
 
+    /// Get the HTTP client used for provider requests
+    pub(crate) fn http_client(&self) -> &reqwest::Client {
+        &self.http_client
+    }
+
     /// Perform OIDC discovery
     pub async fn discover(&mut self) -> Result<()> {
         let discovery_url = format!("{}/.well-known/openid-configuration", self.config.issuer_url);
@@ -273,6 +325,9 @@ impl OAuthProvider {
         self.config.userinfo_endpoint = metadata.userinfo_endpoint.clone();
         self.config.device_authorization_endpoint = metadata.device_authorization_endpoint.clone();
         self.config.jwks_uri = Some(metadata.jwks_uri.clone());
+        if metadata.introspection_endpoint.is_some() {
+            self.config.introspection_endpoint = metadata.introspection_endpoint.clone();
+        }
 
         self.metadata = Some(metadata);
 
@@ -300,23 +355,92 @@ impl OAuthProvider {
             .ok_or_else(|| AuthError::ConfigError("device_authorization_endpoint not configured".into()))
     }
 
-    /// Check if email domain is allowed
-    pub fn is_domain_allowed(&self, email: &str) -> bool {
-        if self.config.allowed_domains.is_empty() {
-            return true;
+    /// Get JWKS URI
+    pub fn jwks_uri(&self) -> Result<&str> {
+        self.config.jwks_uri
+            .as_deref()
+            .ok_or_else(|| AuthError::ConfigError("jwks_uri not configured".into()))
+    }
+
+    /// Get token introspection endpoint
+    pub fn introspection_endpoint(&self) -> Result<&str> {
+        self.config.introspection_endpoint
+            .as_deref()
+            .ok_or_else(|| AuthError::ConfigError("introspection_endpoint not configured".into()))
+    }
+
+    /// Exchange a refresh token for new tokens
+    ///
+    /// Returns [`AuthError::InvalidGrant`] when the provider has revoked the
+    /// refresh token, e.g. because the user was deactivated.
+    pub async fn refresh_token(&self, refresh_token: &str) -> Result<TokenSet> {
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("client_id", &self.config.client_id),
+            ("client_secret", &self.config.client_secret),
+            ("refresh_token", refresh_token),
+        ];
+
+        let response = self.http_client
+            .post(self.token_endpoint()?)
+            .form(&params)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(match serde_json::from_str::<ErrorResponse>(&error_text) {
+                Ok(error) if error.error == "invalid_grant" => {
+                    AuthError::InvalidGrant(error.error_description.unwrap_or(error.error))
+                }
+                _ => AuthError::TokenRefreshFailed(error_text),
+            });
+        }
+
+        let token_response: TokenResponse = response.json().await?;
+        Ok(token_response.into_token_set())
+    }
+
+    /// Ask the introspection endpoint whether a token is still active (RFC 7662)
+    pub async fn introspect(&self, token: &str, token_type_hint: &str) -> Result<TokenIntrospection> {
+        let params = [("token", token), ("token_type_hint", token_type_hint)];
+
+        let response = self.http_client
+            .post(self.introspection_endpoint()?)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&params)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(AuthError::OAuth2Error(format!(
+                "introspection failed: HTTP {}",
+                response.status()
+            )));
         }
 
-        let domain = email.split('@').nth(1).unwrap_or("");
-        self.config.allowed_domains.iter().any(|d| d == domain)
+        Ok(response.json().await?)
+    }
+
+    /// Create a validator for this provider's ID tokens, backed by its JWKS
+    pub fn token_validator(&self) -> Result<TokenValidator> {
+        let jwks = Arc::new(JwksCache::new(self.jwks_uri()?));
+        Ok(TokenValidator::new(&self.config.issuer_url, &self.config.client_id).with_jwks(jwks))
+    }
+
+    /// Check if email domain is allowed
+    pub fn is_domain_allowed(&self, email: &str) -> bool {
+        crate::token::is_domain_allowed(&self.config.allowed_domains, email)
     }
 
     /// Check if user is in required groups
     pub fn is_in_required_group(&self, groups: &[String]) -> bool {
-        if self.config.required_groups.is_empty() {
-            return true;
-        }
+        crate::token::is_in_required_group(&self.config.required_groups, groups)
+    }
 
-        self.config.required_groups.iter().any(|g| groups.contains(g))
+    /// Check that an authenticated user passes the domain and group restrictions
+    pub fn authorize_user(&self, user: &UserInfo) -> Result<()> {
+        user.check_access(&self.config.allowed_domains, &self.config.required_groups)
     }
 }
 
@@ -339,6 +463,12 @@ mod tests {
 
         assert_eq!(config.provider_type, ProviderType::Microsoft);
         assert!(config.issuer_url.contains("tenant-id"));
+        assert!(config.validate().is_ok());
+
+        // Tokens from multi-tenant endpoints never carry the configured issuer
+        for tenant in ["common", "organizations", "Consumers"] {
+            assert!(ProviderConfig::microsoft("client-id", "client-secret", tenant).validate().is_err());
+        }
     }
 
     #[test]
@@ -349,4 +479,81 @@ mod tests {
         assert!(provider.is_domain_allowed("user@example.com"));
         assert!(!provider.is_domain_allowed("user@other.com"));
     }
+
+    #[test]
+    fn test_authorize_user() {
+        let mut config = ProviderConfig::google("id", "secret", Some("example.com"));
+        config.required_groups = vec!["vpn-users".to_string()];
+        let provider = OAuthProvider::new(config);
+
+        let mut user = UserInfo {
+            sub: "user123".to_string(),
+            email: Some("user@example.com".to_string()),
+            email_verified: true,
+            name: None,
+            given_name: None,
+            family_name: None,
+            picture: None,
+            groups: vec!["vpn-users".to_string()],
+            provider: "google".to_string(),
+        };
+        assert!(provider.authorize_user(&user).is_ok());
+
+        user.groups.clear();
+        assert!(matches!(provider.authorize_user(&user), Err(AuthError::NotInRequiredGroup)));
+
+        user.email = Some("user@other.com".to_string());
+        assert!(matches!(provider.authorize_user(&user), Err(AuthError::UnauthorizedDomain(_))));
+
+        // An unverified address in an allowed domain is not enough
+        user.email = Some("user@example.com".to_string());
+        user.groups = vec!["vpn-users".to_string()];
+        user.email_verified = false;
+        assert!(matches!(provider.authorize_user(&user), Err(AuthError::UnauthorizedDomain(_))));
+        user.email_verified = true;
+
+        user.email = None;
+        assert!(matches!(provider.authorize_user(&user), Err(AuthError::UnauthorizedDomain(_))));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_introspect() {
+        use crate::testing::serve_response;
+
+        let revoked = serve_response(
+            "400 Bad Request",
+            r#"{"error":"invalid_grant","error_description":"token revoked"}"#,
+        ).await;
+        let mut config = ProviderConfig::generic("id", "secret", &revoked);
+        config.token_endpoint = Some(format!("{}/token", revoked));
+        config.introspection_endpoint = Some(format!("{}/introspect", revoked));
+        let provider = OAuthProvider::new(config);
+        match provider.refresh_token("refresh").await {
+            Err(AuthError::InvalidGrant(msg)) => assert_eq!(msg, "token revoked"),
+            other => panic!("unexpected {:?}", other.map(|t| t.access_token)),
+        }
+        assert!(provider.introspect("refresh", "refresh_token").await.is_err());
+
+        let unavailable = serve_response("503 Service Unavailable", "").await;
+        let mut config = ProviderConfig::generic("id", "secret", &unavailable);
+        config.token_endpoint = Some(format!("{}/token", unavailable));
+        let provider = OAuthProvider::new(config);
+        assert!(matches!(
+            provider.refresh_token("refresh").await,
+            Err(AuthError::TokenRefreshFailed(_))
+        ));
+        assert!(matches!(
+            provider.introspect("refresh", "refresh_token").await,
+            Err(AuthError::ConfigError(_))
+        ));
+
+        let inactive = serve_response("200 OK", r#"{"active":false}"#).await;
+        let mut config = ProviderConfig::generic("id", "secret", &inactive);
+        config.introspection_endpoint = Some(format!("{}/introspect", inactive));
+        let introspection = OAuthProvider::new(config)
+            .introspect("refresh", "refresh_token")
+            .await
+            .unwrap();
+        assert!(!introspection.active);
+    }
 }
@@ -0,0 +1,542 @@
+//! XML Signature Verification
+//!
+//! Verifies enveloped XML signatures (XML-DSig) over SAML messages, using
+//! Exclusive XML Canonicalization. Only keys from pinned IdP certificates
+//! are trusted; any `KeyInfo` in the message is ignored.
+
+use base64::Engine;
+use ring::signature::{self, UnparsedPublicKey};
+use roxmltree::{Node, NodeId};
+use sha2::{Digest, Sha256, Sha512};
+use x509_cert::der::oid::db::rfc5912;
+use x509_cert::der::oid::ObjectIdentifier;
+use x509_cert::der::{Decode, DecodePem};
+
+use crate::{AuthError, Result};
+
+/// XML-DSig namespace
+pub const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+
+/// Exclusive XML Canonicalization (without comments)
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+
+/// Enveloped signature transform
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+
+/// Signature algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// RSASSA-PKCS1-v1_5 with SHA-256
+    RsaSha256,
+    /// RSASSA-PKCS1-v1_5 with SHA-512
+    RsaSha512,
+    /// ECDSA P-256 with SHA-256
+    EcdsaSha256,
+}
+
+impl SignatureAlgorithm {
+    /// Parse a `SignatureMethod` algorithm URI, rejecting SHA-1 and unknown algorithms
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        match uri {
+            "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256" => Ok(SignatureAlgorithm::RsaSha256),
+            "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512" => Ok(SignatureAlgorithm::RsaSha512),
+            "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256" => {
+                Ok(SignatureAlgorithm::EcdsaSha256)
+            }
+            other => Err(invalid(format!("unsupported signature algorithm: {}", other))),
+        }
+    }
+
+    /// Algorithm URI
+    pub fn uri(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::RsaSha256 => "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256",
+            SignatureAlgorithm::RsaSha512 => "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512",
+            SignatureAlgorithm::EcdsaSha256 => "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256",
+        }
+    }
+}
+
+/// Digest algorithm of a signature reference
+#[derive(Debug, Clone, Copy)]
+enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    fn from_uri(uri: &str) -> Result<Self> {
+        match uri {
+            "http://www.w3.org/2001/04/xmlenc#sha256" => Ok(DigestAlgorithm::Sha256),
+            "http://www.w3.org/2001/04/xmlenc#sha512" => Ok(DigestAlgorithm::Sha512),
+            other => Err(invalid(format!("unsupported digest algorithm: {}", other))),
+        }
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            DigestAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            DigestAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+/// Public key type of a signing certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyType {
+    Rsa,
+    EcP256,
+}
+
+/// Public key from a pinned IdP signing certificate
+#[derive(Debug, Clone)]
+pub struct SigningKey {
+    /// Key type
+    key_type: KeyType,
+    /// PKCS#1 RSA public key or uncompressed EC point
+    public_key: Vec<u8>,
+    /// SHA-256 fingerprint of the certificate
+    fingerprint: [u8; 32],
+}
+
+impl SigningKey {
+    /// Load the key from a PEM certificate, or base64 DER as found in IdP metadata
+    pub fn from_certificate(certificate: &str) -> Result<Self> {
+        let der = if certificate.contains("-----BEGIN") {
+            use x509_cert::der::Encode;
+            x509_cert::Certificate::from_pem(certificate.as_bytes())
+                .and_then(|cert| cert.to_der())
+                .map_err(|e| AuthError::ConfigError(format!("invalid IdP certificate: {}", e)))?
+        } else {
+            decode_base64(certificate)
+                .map_err(|_| AuthError::ConfigError("invalid IdP certificate encoding".into()))?
+        };
+        let cert = x509_cert::Certificate::from_der(&der)
+            .map_err(|e| AuthError::ConfigError(format!("invalid IdP certificate: {}", e)))?;
+
+        let spki = &cert.tbs_certificate.subject_public_key_info;
+        let key_type = if spki.algorithm.oid == rfc5912::RSA_ENCRYPTION {
+            KeyType::Rsa
+        } else if spki.algorithm.oid == rfc5912::ID_EC_PUBLIC_KEY
+            && spki.algorithm.parameters.as_ref()
+                .and_then(|params| params.decode_as::<ObjectIdentifier>().ok())
+                == Some(rfc5912::SECP_256_R_1)
+        {
+            KeyType::EcP256
+        } else {
+            return Err(AuthError::ConfigError(format!(
+                "unsupported IdP certificate key type: {}",
+                spki.algorithm.oid
+            )));
+        };
+
+        Ok(Self {
+            key_type,
+            public_key: spki.subject_public_key.raw_bytes().to_vec(),
+            fingerprint: Sha256::digest(&der).into(),
+        })
+    }
+
+    /// SHA-256 fingerprint of the certificate
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    fn verify(&self, alg: SignatureAlgorithm, message: &[u8], sig: &[u8]) -> bool {
+        let alg: &dyn signature::VerificationAlgorithm = match (self.key_type, alg) {
+            (KeyType::Rsa, SignatureAlgorithm::RsaSha256) => &signature::RSA_PKCS1_2048_8192_SHA256,
+            (KeyType::Rsa, SignatureAlgorithm::RsaSha512) => &signature::RSA_PKCS1_2048_8192_SHA512,
+            (KeyType::EcP256, SignatureAlgorithm::EcdsaSha256) => {
+                &signature::ECDSA_P256_SHA256_FIXED
+            }
+            _ => return false,
+        };
+        UnparsedPublicKey::new(alg, &self.public_key).verify(message, sig).is_ok()
+    }
+}
+
+/// Verify the enveloped signature of `element` against the pinned keys
+///
+/// The signature must be a direct child of `element` and its only reference
+/// must point at `element`'s ID, so the verified content is exactly the
+/// element the caller goes on to read.
+pub fn verify_enveloped(element: Node<'_, '_>, keys: &[SigningKey]) -> Result<()> {
+    let id = element.attribute("ID")
+        .ok_or_else(|| invalid("signed element has no ID"))?;
+    let signature = dsig_child(element, "Signature")
+        .ok_or_else(|| invalid("element is not signed"))?;
+    let signed_info = dsig_child(signature, "SignedInfo")
+        .ok_or_else(|| invalid("signature has no SignedInfo"))?;
+
+    let c14n = dsig_child(signed_info, "CanonicalizationMethod")
+        .ok_or_else(|| invalid("signature has no CanonicalizationMethod"))?;
+    if c14n.attribute("Algorithm") != Some(EXC_C14N) {
+        return Err(invalid("unsupported canonicalization method"));
+    }
+    let alg = dsig_child(signed_info, "SignatureMethod")
+        .and_then(|method| method.attribute("Algorithm"))
+        .ok_or_else(|| invalid("signature has no SignatureMethod"))
+        .and_then(SignatureAlgorithm::from_uri)?;
+
+    let mut references = signed_info.children().filter(|n| is_dsig(*n, "Reference"));
+    let reference = match (references.next(), references.next()) {
+        (Some(reference), None) => reference,
+        _ => return Err(invalid("signature must have exactly one reference")),
+    };
+    if reference.attribute("URI").and_then(|uri| uri.strip_prefix('#')) != Some(id) {
+        return Err(invalid("signature does not reference the signed element"));
+    }
+
+    let mut prefixes = None;
+    for transform in dsig_child(reference, "Transforms")
+        .iter()
+        .flat_map(|transforms| transforms.children())
+        .filter(|n| is_dsig(*n, "Transform"))
+    {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED_SIGNATURE) => {}
+            Some(EXC_C14N) => prefixes = Some(inclusive_prefixes(transform)),
+            other => {
+                return Err(invalid(format!("unsupported transform: {}", other.unwrap_or_default())));
+            }
+        }
+    }
+    let prefixes = prefixes.ok_or_else(|| invalid("reference is not canonicalized"))?;
+
+    let digest_alg = dsig_child(reference, "DigestMethod")
+        .and_then(|method| method.attribute("Algorithm"))
+        .ok_or_else(|| invalid("reference has no DigestMethod"))
+        .and_then(DigestAlgorithm::from_uri)?;
+    let digest_value = dsig_child(reference, "DigestValue")
+        .map(|node| decode_base64(node.text().unwrap_or_default()))
+        .ok_or_else(|| invalid("reference has no DigestValue"))??;
+
+    let canonical = canonicalize(element, Some(signature.id()), &prefixes);
+    if digest_alg.digest(canonical.as_bytes()) != digest_value {
+        return Err(invalid("digest mismatch"));
+    }
+
+    let signature_value = dsig_child(signature, "SignatureValue")
+        .map(|node| decode_base64(node.text().unwrap_or_default()))
+        .ok_or_else(|| invalid("signature has no SignatureValue"))??;
+    let signed = canonicalize(signed_info, None, &inclusive_prefixes(c14n));
+    if keys.iter().any(|key| key.verify(alg, signed.as_bytes(), &signature_value)) {
+        Ok(())
+    } else {
+        Err(invalid("signature does not match a pinned IdP certificate"))
+    }
+}
+
+/// Exclusive XML Canonicalization (without comments) of `node`'s subtree
+///
+/// `exclude` is left out of the output (the enveloped signature), and
+/// `inclusive_prefixes` is the InclusiveNamespaces PrefixList.
+pub fn canonicalize(node: Node<'_, '_>, exclude: Option<NodeId>, inclusive_prefixes: &[String]) -> String {
+    let mut out = String::new();
+    write_element(&mut out, node, exclude, inclusive_prefixes, &mut Vec::new());
+    out
+}
+
+fn write_element(
+    out: &mut String,
+    node: Node<'_, '_>,
+    exclude: Option<NodeId>,
+    inclusive_prefixes: &[String],
+    rendered: &mut Vec<(String, String)>,
+) {
+    let input = node.document().input_text();
+    let qname = element_qname(input, node);
+
+    // Namespaces visibly used by the element and its attributes
+    let mut used: Vec<&str> = vec![qname.split_once(':').map_or("", |(prefix, _)| prefix)];
+    used.extend(node.attributes().filter_map(|a| input[a.range_qname()].split_once(':').map(|(p, _)| p)));
+    used.extend(inclusive_prefixes.iter().map(|p| if p == "#default" { "" } else { p.as_str() }));
+    used.sort_unstable();
+    used.dedup();
+
+    let depth = rendered.len();
+    out.push('<');
+    out.push_str(qname);
+    for prefix in used {
+        if prefix == "xml" {
+            continue;
+        }
+        let Some(uri) = node.lookup_namespace_uri((!prefix.is_empty()).then_some(prefix)).or(
+            // An unprefixed element outside any default namespace
+            prefix.is_empty().then_some(""),
+        ) else {
+            continue;
+        };
+        let current = rendered.iter().rev().find(|(p, _)| p == prefix).map_or("", |(_, u)| u.as_str());
+        if uri == current {
+            continue;
+        }
+
+        if prefix.is_empty() {
+            out.push_str(" xmlns=\"");
+        } else {
+            out.push_str(" xmlns:");
+            out.push_str(prefix);
+            out.push_str("=\"");
+        }
+        escape_attribute(out, uri);
+        out.push('"');
+        rendered.push((prefix.to_string(), uri.to_string()));
+    }
+
+    let mut attributes: Vec<_> = node.attributes().collect();
+    attributes.sort_by_key(|a| (a.namespace().unwrap_or_default(), a.name()));
+    for attribute in attributes {
+        out.push(' ');
+        out.push_str(&input[attribute.range_qname()]);
+        out.push_str("=\"");
+        escape_attribute(out, attribute.value());
+        out.push('"');
+    }
+    out.push('>');
+
+    for child in node.children() {
+        if Some(child.id()) == exclude {
+            continue;
+        }
+        if child.is_element() {
+            write_element(out, child, exclude, inclusive_prefixes, rendered);
+        } else if child.is_text() {
+            escape_text(out, child.text().unwrap_or_default());
+        } else if let Some(pi) = child.pi() {
+            out.push_str("<?");
+            out.push_str(pi.target);
+            if let Some(value) = pi.value {
+                out.push(' ');
+                out.push_str(value);
+            }
+            out.push_str("?>");
+        }
+    }
+
+    out.push_str("</");
+    out.push_str(qname);
+    out.push('>');
+    rendered.truncate(depth);
+}
+
+/// Qualified name of an element as written in the source
+fn element_qname<'a>(input: &'a str, node: Node<'_, '_>) -> &'a str {
+    let tag = &input[node.range().start + 1..];
+    let end = tag.find(|c: char| c.is_whitespace() || c == '/' || c == '>').unwrap_or(tag.len());
+    &tag[..end]
+}
+
+fn escape_attribute(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn escape_text(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// PrefixList of an exclusive canonicalization method or transform
+fn inclusive_prefixes(method: Node<'_, '_>) -> Vec<String> {
+    method.children()
+        .find(|n| n.tag_name().namespace() == Some(EXC_C14N) && n.tag_name().name() == "InclusiveNamespaces")
+        .and_then(|n| n.attribute("PrefixList"))
+        .map(|list| list.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn is_dsig(node: Node<'_, '_>, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(DSIG_NS) && node.tag_name().name() == name
+}
+
+fn dsig_child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| is_dsig(*n, name))
+}
+
+/// Decode base64 that may be wrapped over several lines
+pub(crate) fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    let compact: String = value.split_whitespace().collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(compact)
+        .map_err(|e| invalid(format!("base64 decode error: {}", e)))
+}
+
+fn invalid(msg: impl Into<String>) -> AuthError {
+    AuthError::SamlValidationFailed(msg.into())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, RsaKeyPair};
+
+    /// Signing identity of the stand-in IdP
+    pub(crate) struct TestIdp {
+        /// Certificate to pin, PEM
+        pub(crate) certificate: String,
+        key: TestIdpKey,
+    }
+
+    enum TestIdpKey {
+        Rsa(RsaKeyPair),
+        Ec(EcdsaKeyPair),
+    }
+
+    impl TestIdp {
+        pub(crate) fn ec() -> Self {
+            let key_pair = rcgen::KeyPair::generate().unwrap();
+            let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+            let key = EcdsaKeyPair::from_pkcs8(alg, &key_pair.serialize_der(), &SystemRandom::new()).unwrap();
+            Self { certificate: self_signed(&key_pair), key: TestIdpKey::Ec(key) }
+        }
+
+        pub(crate) fn rsa() -> Self {
+            let der = crate::jwks::tests::rsa_pkcs8();
+            let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(
+                &der.as_slice().into(),
+                &rcgen::PKCS_RSA_SHA256,
+            )
+            .unwrap();
+            let key = RsaKeyPair::from_pkcs8(&der).unwrap();
+            Self { certificate: self_signed(&key_pair), key: TestIdpKey::Rsa(key) }
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            match self.key {
+                TestIdpKey::Rsa(_) => SignatureAlgorithm::RsaSha256,
+                TestIdpKey::Ec(_) => SignatureAlgorithm::EcdsaSha256,
+            }
+        }
+
+        fn sign_bytes(&self, message: &[u8]) -> Vec<u8> {
+            let rng = SystemRandom::new();
+            match &self.key {
+                TestIdpKey::Rsa(key) => {
+                    let mut sig = vec![0; key.public().modulus_len()];
+                    key.sign(&signature::RSA_PKCS1_SHA256, &rng, message, &mut sig).unwrap();
+                    sig
+                }
+                TestIdpKey::Ec(key) => key.sign(&rng, message).unwrap().as_ref().to_vec(),
+            }
+        }
+
+        /// Replace each `<!--SIGNATURE-->` marker with an enveloped signature over
+        /// the element containing it, innermost element first
+        pub(crate) fn sign(&self, xml: &str) -> String {
+            let mut xml = xml.to_string();
+            while xml.contains("<!--SIGNATURE-->") {
+                xml = self.sign_innermost(&xml);
+            }
+            xml
+        }
+
+        fn sign_innermost(&self, xml: &str) -> String {
+            let b64 = |data: &[u8]| base64::engine::general_purpose::STANDARD.encode(data);
+
+            let doc = roxmltree::Document::parse(xml).unwrap();
+            let marker = doc.descendants()
+                .filter(|n| n.is_comment() && n.text() == Some("SIGNATURE"))
+                .max_by_key(|n| n.ancestors().count())
+                .unwrap();
+            let element = marker.parent_element().unwrap();
+            let digest = Sha256::digest(canonicalize(element, None, &[]).as_bytes());
+
+            let signature = format!(
+                concat!(
+                    r#"<ds:Signature xmlns:ds="{ns}"><ds:SignedInfo>"#,
+                    r#"<ds:CanonicalizationMethod Algorithm="{c14n}"/><ds:SignatureMethod Algorithm="{alg}"/>"#,
+                    r##"<ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="{enveloped}"/>"##,
+                    r#"<ds:Transform Algorithm="{c14n}"/></ds:Transforms>"#,
+                    r#"<ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"/>"#,
+                    r#"<ds:DigestValue>{digest}</ds:DigestValue></ds:Reference></ds:SignedInfo>"#,
+                    r#"<ds:SignatureValue>SIGNATURE_VALUE</ds:SignatureValue></ds:Signature>"#,
+                ),
+                ns = DSIG_NS,
+                c14n = EXC_C14N,
+                alg = self.algorithm().uri(),
+                id = element.attribute("ID").unwrap(),
+                enveloped = ENVELOPED_SIGNATURE,
+                digest = b64(&digest),
+            );
+            let range = marker.range();
+            let xml = format!("{}{}{}", &xml[..range.start], signature, &xml[range.end..]);
+
+            let doc = roxmltree::Document::parse(&xml).unwrap();
+            let signed_info = doc.descendants()
+                .find(|n| is_dsig(*n, "SignedInfo") && n.range().start > range.start)
+                .unwrap();
+            let value = b64(&self.sign_bytes(canonicalize(signed_info, None, &[]).as_bytes()));
+            xml.replacen("SIGNATURE_VALUE", &value, 1)
+        }
+    }
+
+    fn self_signed(key_pair: &rcgen::KeyPair) -> String {
+        let params = rcgen::CertificateParams::new(vec!["idp.example.com".to_string()]).unwrap();
+        params.self_signed(key_pair).unwrap().pem()
+    }
+
+    #[test]
+    fn test_exclusive_canonicalization() {
+        let xml = concat!(
+            r#"<a:Root xmlns:a="urn:a" xmlns:b="urn:b" z="1" a:y="2">"#,
+            r#"<a:Child xmlns:c="urn:c" b:attr="x&#10;y">x &amp; y<!-- comment --></a:Child>"#,
+            r#"<Plain/><d xmlns="urn:d"><e xmlns=""/></d></a:Root>"#,
+        );
+        let doc = roxmltree::Document::parse(xml).unwrap();
+        let root = doc.root_element();
+
+        assert_eq!(
+            canonicalize(root, None, &[]),
+            concat!(
+                r#"<a:Root xmlns:a="urn:a" z="1" a:y="2">"#,
+                r#"<a:Child xmlns:b="urn:b" b:attr="x&#xA;y">x &amp; y</a:Child>"#,
+                r#"<Plain></Plain><d xmlns="urn:d"><e xmlns=""></e></d></a:Root>"#,
+            )
+        );
+
+        let child = root.first_element_child().unwrap();
+        assert_eq!(
+            canonicalize(child, None, &["c".to_string()]),
+            r#"<a:Child xmlns:a="urn:a" xmlns:b="urn:b" xmlns:c="urn:c" b:attr="x&#xA;y">x &amp; y</a:Child>"#
+        );
+    }
+
+    #[test]
+    fn test_verify_enveloped() {
+        for idp in [TestIdp::ec(), TestIdp::rsa()] {
+            let keys = [SigningKey::from_certificate(&idp.certificate).unwrap()];
+            let signed = idp.sign(r#"<r:Doc xmlns:r="urn:r" ID="_1"><!--SIGNATURE--><r:Value>ok</r:Value></r:Doc>"#);
+
+            let doc = roxmltree::Document::parse(&signed).unwrap();
+            verify_enveloped(doc.root_element(), &keys).unwrap();
+
+            // Tampered content
+            let tampered = signed.replace(">ok<", ">no<");
+            let doc = roxmltree::Document::parse(&tampered).unwrap();
+            assert!(verify_enveloped(doc.root_element(), &keys).is_err());
+
+            // Unpinned key
+            let other = [SigningKey::from_certificate(&TestIdp::ec().certificate).unwrap()];
+            let doc = roxmltree::Document::parse(&signed).unwrap();
+            assert!(verify_enveloped(doc.root_element(), &other).is_err());
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Test Helpers
+//!
+//! Stand-in HTTP endpoints for exercising provider requests without a real
+//! identity provider. Enabled for this crate's tests and, for dependents,
+//! with the `test-util` feature.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Serve HTTP on a local port, answering every request with the status and
+/// JSON body returned by `respond`, and return the server's base URL
+pub async fn serve_http<F>(respond: F) -> String
+where
+    F: Fn() -> (&'static str, String) + Send + 'static,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            let (status, body) = respond();
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    format!("http://{}", addr)
+}
+
+/// Serve the same HTTP response to every request, returning the server's base URL
+pub async fn serve_response(status: &'static str, body: &'static str) -> String {
+    serve_http(move || (status, body.to_string())).await
+}
@@ -3,8 +3,9 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::{AuthError, Result};
+use crate::{AuthError, JwksCache, Result};
 
 /// OAuth2/OIDC Token Set
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -110,6 +111,8 @@ pub struct TokenValidator {
     audience: String,
     /// Clock skew tolerance (in seconds)
     clock_skew: i64,
+    /// Provider signing keys
+    jwks: Option<Arc<JwksCache>>,
 }
 
 impl TokenValidator {
@@ -119,6 +122,7 @@ impl TokenValidator {
             issuer: issuer.to_string(),
             audience: audience.to_string(),
             clock_skew: 60, // 1 minute tolerance
+            jwks: None,
         }
     }
 
@@ -128,10 +132,25 @@ impl TokenValidator {
         self
     }
 
+    /// Set the provider's signing keys, required by [`validate`](Self::validate)
+    pub fn with_jwks(mut self, jwks: Arc<JwksCache>) -> Self {
+        self.jwks = Some(jwks);
+        self
+    }
+
+    /// Verify an ID token's signature against the provider's JWKS and validate its claims
+    pub async fn validate(&self, token: &str, expected_nonce: Option<&str>) -> Result<IdTokenClaims> {
+        let jwks = self.jwks.as_ref()
+            .ok_or_else(|| AuthError::ConfigError("no JWKS configured for token validation".into()))?;
+        let claims = jwks.verify(token).await?;
+        self.validate_claims(&claims, expected_nonce)?;
+        Ok(claims)
+    }
+
     /// Validate ID token claims (without cryptographic verification)
     ///
-    /// Note: For production, you should also verify the JWT signature
-    /// using the provider's JWKS.
+    /// Use [`validate`](Self::validate) for tokens that have not had their
+    /// signature checked.
     pub fn validate_claims(&self, claims: &IdTokenClaims, expected_nonce: Option<&str>) -> Result<()> {
         // Check issuer
         if claims.iss != self.issuer {
@@ -173,8 +192,8 @@ impl TokenValidator {
 
     /// Decode JWT without verification (for extracting claims)
     ///
-    /// WARNING: This does not verify the signature. For production use,
-    /// you should use a proper JWT library that verifies signatures.
+    /// WARNING: This does not verify the signature. Use
+    /// [`validate`](Self::validate) for untrusted tokens.
     pub fn decode_jwt_claims(token: &str) -> Result<IdTokenClaims> {
         use base64::Engine;
 
@@ -193,6 +212,22 @@ impl TokenValidator {
     }
 }
 
+/// Token introspection response (RFC 7662)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenIntrospection {
+    /// Whether the token is currently active
+    pub active: bool,
+    /// Subject of the token
+    #[serde(default)]
+    pub sub: Option<String>,
+    /// Human-readable identifier of the resource owner
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Expiration time
+    #[serde(default)]
+    pub exp: Option<i64>,
+}
+
 /// User information extracted from tokens
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserInfo {
@@ -236,6 +271,49 @@ impl UserInfo {
     pub fn email_domain(&self) -> Option<&str> {
         self.email.as_ref().and_then(|e| e.split('@').nth(1))
     }
+
+    /// Check the user against domain and group restrictions (empty lists allow everyone)
+    ///
+    /// Only a verified email address counts towards an allowed domain.
+    pub fn check_access(&self, allowed_domains: &[String], required_groups: &[String]) -> Result<()> {
+        // Some providers let users set any address without proving they own it
+        if !allowed_domains.is_empty() && !self.email_verified {
+            return Err(AuthError::UnauthorizedDomain(format!(
+                "{} (email not verified)",
+                self.email_domain().unwrap_or("<none>")
+            )));
+        }
+        if !is_domain_allowed(allowed_domains, self.email.as_deref().unwrap_or_default()) {
+            return Err(AuthError::UnauthorizedDomain(
+                self.email_domain().unwrap_or("<none>").to_string(),
+            ));
+        }
+
+        if !is_in_required_group(required_groups, &self.groups) {
+            return Err(AuthError::NotInRequiredGroup);
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if an email's domain is allowed (an empty list allows every domain)
+pub(crate) fn is_domain_allowed(allowed_domains: &[String], email: &str) -> bool {
+    if allowed_domains.is_empty() {
+        return true;
+    }
+
+    let domain = email.split('@').nth(1).unwrap_or("");
+    allowed_domains.iter().any(|d| d == domain)
+}
+
+/// Check if any of the groups is required (an empty list requires none)
+pub(crate) fn is_in_required_group(required_groups: &[String], groups: &[String]) -> bool {
+    if required_groups.is_empty() {
+        return true;
+    }
+
+    required_groups.iter().any(|g| groups.contains(g))
 }
 
 #[cfg(test)]
@@ -294,4 +372,24 @@ mod tests {
         assert!(validator.validate_claims(&claims, Some("test-nonce")).is_ok());
         assert!(validator.validate_claims(&claims, Some("wrong-nonce")).is_err());
     }
+
+    #[tokio::test]
+    async fn test_validate_signed_token() {
+        use crate::jwks::tests::{claims, serve_jwks, TestKey};
+
+        let key = TestKey::rsa();
+        let set = serde_json::json!({"keys": [key.jwk("k1")]});
+        let jwks = Arc::new(JwksCache::new(&serve_jwks(Arc::new(parking_lot::RwLock::new(set))).await));
+        let token = key.sign("k1", &claims());
+
+        let validator = TokenValidator::new("https://issuer.example.com", "client-id");
+        assert!(matches!(validator.validate(&token, None).await, Err(AuthError::ConfigError(_))));
+
+        let validator = validator.with_jwks(jwks.clone());
+        assert_eq!(validator.validate(&token, None).await.unwrap().sub, "user123");
+
+        // A valid signature does not make up for the wrong issuer
+        let other = TokenValidator::new("https://other.example.com", "client-id").with_jwks(jwks);
+        assert!(other.validate(&token, None).await.is_err());
+    }
 }
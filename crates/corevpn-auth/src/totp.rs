@@ -0,0 +1,465 @@
+//! TOTP Second Factor
+//!
+//! RFC 6238 time-based one-time passwords for local password accounts.
+//! Secrets are encrypted at rest with a server key and codes are requested
+//! with OpenVPN's static (`SCRV1`) or dynamic (`CRV1`) challenge protocol.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use corevpn_core::user::{User, UserId, UserStore};
+use corevpn_core::CoreError;
+use corevpn_crypto::{Cipher, CipherSuite};
+use parking_lot::Mutex;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::flow::{generate_crv1_challenge, parse_static_challenge_response, parse_vpn_auth_response};
+use crate::password::{check_password, find_user};
+use crate::{AuthError, Result};
+
+/// Number of digits in a code
+pub const TOTP_DIGITS: u32 = 6;
+
+/// Length of a time step
+pub const TOTP_STEP: Duration = Duration::from_secs(30);
+
+/// Time steps accepted before and after the current one (clock skew)
+pub const TOTP_SKEW: u64 = 1;
+
+/// Size of newly generated secrets (160 bits, as recommended by RFC 4226)
+const SECRET_SIZE: usize = 20;
+
+/// How long a CRV1 challenge can be answered
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Key encrypting TOTP secrets at rest
+pub struct TotpKey {
+    cipher: Cipher,
+}
+
+impl TotpKey {
+    /// Create from raw key bytes
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self { cipher: Cipher::new(key, CipherSuite::ChaCha20Poly1305) }
+    }
+
+    /// Load the key file, generating it if it does not exist
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        if path.exists() {
+            let encoded = Zeroizing::new(std::fs::read_to_string(path).map_err(CoreError::from)?);
+            let bytes = Zeroizing::new(
+                engine.decode(encoded.trim())
+                    .map_err(|e| AuthError::ConfigError(format!("{}: {}", path.display(), e)))?,
+            );
+            let key: &[u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+                AuthError::ConfigError(format!("{}: expected a 256-bit key", path.display()))
+            })?;
+            return Ok(Self::from_bytes(key));
+        }
+
+        let key = Zeroizing::new(corevpn_crypto::random_bytes::<32>());
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(CoreError::from)?;
+        }
+        write_private(path, &format!("{}\n", engine.encode(key.as_slice())))?;
+        Ok(Self::from_bytes(&key))
+    }
+
+    /// Encrypt a secret for a user, binding it to the user ID
+    fn seal(&self, user_id: &UserId, secret: &[u8]) -> Result<String> {
+        let nonce = self.cipher.generate_nonce();
+        let ciphertext = self.cipher
+            .encrypt(&nonce, secret, user_id.as_str().as_bytes())
+            .map_err(|e| AuthError::ConfigError(format!("TOTP secret encryption failed: {}", e)))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(base64::engine::general_purpose::STANDARD_NO_PAD.encode(sealed))
+    }
+
+    /// Decrypt a user's secret
+    fn open(&self, user_id: &UserId, sealed: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let invalid = || AuthError::ConfigError(format!("invalid TOTP secret for {}", user_id));
+        let sealed = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(sealed)
+            .map_err(|_| invalid())?;
+        if sealed.len() < 12 {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let nonce: [u8; 12] = nonce.try_into().map_err(|_| invalid())?;
+        self.cipher
+            .decrypt(&nonce, ciphertext, user_id.as_str().as_bytes())
+            .map(Zeroizing::new)
+            .map_err(|_| invalid())
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(CoreError::from)?;
+    file.write_all(contents.as_bytes()).map_err(CoreError::from)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents).map_err(CoreError::from)?;
+    Ok(())
+}
+
+/// Generate a new secret for a user and return its `otpauth://` provisioning URI
+pub async fn enroll(store: &dyn UserStore, key: &TotpKey, username: &str, issuer: &str) -> Result<String> {
+    let mut user = find_user(store, username)
+        .await
+        .ok_or_else(|| CoreError::UserNotFound(username.to_string()))?;
+
+    let secret = Zeroizing::new(corevpn_crypto::random_bytes::<SECRET_SIZE>());
+    user.totp_secret = Some(key.seal(&user.id, secret.as_slice())?);
+    store.upsert_user(&user).await?;
+
+    let account = user.email.clone().unwrap_or_else(|| user.id.to_string());
+    Ok(provisioning_uri(secret.as_slice(), issuer, &account))
+}
+
+/// Remove a user's TOTP secret
+pub async fn disable(store: &dyn UserStore, username: &str) -> Result<User> {
+    let mut user = find_user(store, username)
+        .await
+        .ok_or_else(|| CoreError::UserNotFound(username.to_string()))?;
+    user.totp_secret = None;
+    store.upsert_user(&user).await?;
+    Ok(user)
+}
+
+/// Build an `otpauth://` URI for authenticator apps
+pub fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = urlencoding::encode(issuer),
+        account = urlencoding::encode(account),
+        secret = base32_encode(secret),
+        digits = TOTP_DIGITS,
+        period = TOTP_STEP.as_secs(),
+    )
+}
+
+/// Compute the HOTP value (RFC 4226) for a counter
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = ring::hmac::sign(&key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+
+    let offset = usize::from(digest[digest.len() - 1] & 0x0f);
+    let value = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    (value & 0x7fff_ffff) % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Time step for a Unix timestamp
+fn time_step(unix_time: u64) -> u64 {
+    unix_time / TOTP_STEP.as_secs()
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+    out
+}
+
+/// Outcome of a local login
+#[derive(Debug)]
+pub enum Login {
+    /// Credentials (and second factor, if enrolled) verified
+    Accepted(Box<User>),
+    /// Password verified; the client must answer this CRV1 challenge
+    Challenge(String),
+}
+
+/// Pending CRV1 challenge
+struct PendingChallenge {
+    user_id: UserId,
+    username: String,
+    expires_at: Instant,
+}
+
+/// Verifies TOTP codes and tracks pending challenges
+pub struct TotpVerifier {
+    key: TotpKey,
+    /// Reject logins from accounts without a second factor
+    required: bool,
+    /// Pending CRV1 challenges by state ID
+    challenges: Mutex<HashMap<String, PendingChallenge>>,
+    /// Last accepted time step per user, so a code cannot be replayed
+    last_steps: Mutex<HashMap<UserId, u64>>,
+}
+
+impl TotpVerifier {
+    /// Create a verifier using the given secret encryption key
+    pub fn new(key: TotpKey) -> Self {
+        Self {
+            key,
+            required: false,
+            challenges: Mutex::new(HashMap::new()),
+            last_steps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Require every account to have a second factor
+    pub fn with_required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    /// Verify a code for a user at the current time
+    pub fn verify(&self, user: &User, code: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        self.verify_at(user, code, now)
+    }
+
+    fn verify_at(&self, user: &User, code: &str, unix_time: u64) -> Result<()> {
+        let sealed = user.totp_secret.as_deref().ok_or(AuthError::InvalidOtp)?;
+        let secret = self.key.open(&user.id, sealed)?;
+
+        let code = code.trim();
+        if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(AuthError::InvalidOtp);
+        }
+
+        let current = time_step(unix_time);
+        let matched = (current.saturating_sub(TOTP_SKEW)..=current + TOTP_SKEW).find(|&step| {
+            let expected = format!("{:0width$}", hotp(&secret, step), width = TOTP_DIGITS as usize);
+            bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+        });
+        let step = matched.ok_or(AuthError::InvalidOtp)?;
+
+        let mut last_steps = self.last_steps.lock();
+        if last_steps.get(&user.id).is_some_and(|&last| step <= last) {
+            return Err(AuthError::InvalidOtp);
+        }
+        last_steps.insert(user.id.clone(), step);
+        Ok(())
+    }
+
+    /// Issue a CRV1 challenge asking a user for a code
+    pub fn challenge(&self, user: &User, username: &str) -> String {
+        let state_id = uuid::Uuid::new_v4().simple().to_string();
+        let now = Instant::now();
+
+        let mut challenges = self.challenges.lock();
+        challenges.retain(|_, pending| pending.expires_at > now);
+        challenges.insert(state_id.clone(), PendingChallenge {
+            user_id: user.id.clone(),
+            username: username.to_string(),
+            expires_at: now + CHALLENGE_TIMEOUT,
+        });
+
+        generate_crv1_challenge(&state_id, username, "Enter your authenticator code")
+    }
+
+    /// Consume a pending challenge, returning the user it was issued to
+    ///
+    /// A response naming another user leaves the challenge for its owner.
+    fn resume(&self, state_id: &str, username: &str) -> Option<UserId> {
+        let mut challenges = self.challenges.lock();
+        if challenges.get(state_id)?.username != username {
+            return None;
+        }
+        let pending = challenges.remove(state_id)?;
+        (pending.expires_at > Instant::now()).then_some(pending.user_id)
+    }
+}
+
+/// Log in with a local password and, if enrolled, a TOTP code
+///
+/// The code is taken from a static challenge response; without one, enrolled
+/// users get a CRV1 challenge which is answered on the next connection.
+pub async fn login(
+    store: &dyn UserStore,
+    totp: Option<&TotpVerifier>,
+    username: &str,
+    password: &str,
+) -> Result<Login> {
+    let Some(totp) = totp else {
+        let user = crate::password::authenticate(store, username, password).await?;
+        return Ok(Login::Accepted(Box::new(user)));
+    };
+
+    let mut user = if let Some((state_id, code)) = parse_vpn_auth_response(password) {
+        let user_id = totp.resume(state_id, username).ok_or(AuthError::InvalidState)?;
+        let user = store.get_user(&user_id).await.ok_or(AuthError::InvalidCredentials)?;
+        if !user.can_connect() {
+            return Err(AuthError::UserDisabled);
+        }
+        totp.verify(&user, code)?;
+        user
+    } else {
+        let (password, code) = match parse_static_challenge_response(password) {
+            Some((password, code)) => (Zeroizing::new(password), Some(code)),
+            None => (Zeroizing::new(password.to_string()), None),
+        };
+        let user = check_password(store, username, &password).await?;
+
+        match (&user.totp_secret, code) {
+            (Some(_), Some(code)) => totp.verify(&user, &code)?,
+            (Some(_), None) => return Ok(Login::Challenge(totp.challenge(&user, username))),
+            (None, _) if totp.required => return Err(AuthError::MfaRequired),
+            (None, _) => {}
+        }
+        user
+    };
+
+    user.record_login();
+    store.upsert_user(&user).await?;
+    Ok(Login::Accepted(Box::new(user)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use corevpn_core::MemoryUserStore;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn code(secret: &[u8], unix_time: u64) -> String {
+        format!("{:06}", hotp(secret, time_step(unix_time)))
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // RFC 6238 appendix B (SHA-1), truncated to 6 digits
+        assert_eq!(code(RFC_SECRET, 59), "287082");
+        assert_eq!(code(RFC_SECRET, 1111111109), "081804");
+        assert_eq!(code(RFC_SECRET, 1234567890), "005924");
+        assert_eq!(code(RFC_SECRET, 20000000000), "353130");
+
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(
+            provisioning_uri(b"foobar", "Core VPN", "alice@example.com"),
+            "otpauth://totp/Core%20VPN:alice%40example.com?secret=MZXW6YTBOI&issuer=Core%20VPN\
+             &algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_verify_window_and_replay() {
+        let key = TotpKey::from_bytes(&[7; 32]);
+        let mut user = User::new(UserId::new("alice"));
+        user.totp_secret = Some(key.seal(&user.id, RFC_SECRET).unwrap());
+        let verifier = TotpVerifier::new(key);
+
+        let now = 1111111109;
+        assert!(verifier.verify_at(&user, &code(RFC_SECRET, now - 30), now).is_ok());
+        assert!(verifier.verify_at(&user, &code(RFC_SECRET, now), now).is_ok());
+
+        // Replayed and out-of-window codes are rejected
+        assert!(matches!(verifier.verify_at(&user, &code(RFC_SECRET, now), now), Err(AuthError::InvalidOtp)));
+        assert!(verifier.verify_at(&user, &code(RFC_SECRET, now + 90), now).is_err());
+        assert!(verifier.verify_at(&user, "12345", now).is_err());
+
+        // Secrets are bound to the user they were sealed for
+        let mut mallory = User::new(UserId::new("mallory"));
+        mallory.totp_secret = user.totp_secret.clone();
+        assert!(verifier.verify_at(&mallory, &code(RFC_SECRET, now + 30), now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login_challenges() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("totp.key");
+        let store = MemoryUserStore::new();
+        crate::set_password(&store, "alice", "initial-password").await.unwrap();
+        crate::set_password(&store, "bob", "initial-password").await.unwrap();
+
+        let key = TotpKey::load_or_generate(&key_path).unwrap();
+        let uri = enroll(&store, &key, "alice", "CoreVPN").await.unwrap();
+        let secret = store.get_user(&UserId::new("alice")).await.unwrap().totp_secret.unwrap();
+        assert!(uri.starts_with("otpauth://totp/CoreVPN:alice?secret="));
+        assert!(!uri.contains(&secret));
+        assert!(TotpKey::load_or_generate(&key_path).is_ok());
+
+        // The key file is reused on the next start
+        let verifier = TotpVerifier::new(TotpKey::load_or_generate(&key_path).unwrap());
+
+        // Dynamic challenge: the password alone yields a CRV1 challenge
+        let Login::Challenge(challenge) = login(&store, Some(&verifier), "alice", "initial-password").await.unwrap()
+        else {
+            panic!("expected a challenge");
+        };
+        let state_id = challenge.split(':').nth(2).unwrap();
+        assert!(matches!(
+            login(&store, Some(&verifier), "alice", &format!("CRV1::{}::000000", state_id)).await,
+            Err(AuthError::InvalidOtp)
+        ));
+        // Challenges are single use
+        assert!(matches!(
+            login(&store, Some(&verifier), "alice", &format!("CRV1::{}::000000", state_id)).await,
+            Err(AuthError::InvalidState)
+        ));
+
+        let alice = store.get_user(&UserId::new("alice")).await.unwrap();
+        let secret = verifier.key.open(&alice.id, alice.totp_secret.as_deref().unwrap()).unwrap();
+        let now = chrono::Utc::now().timestamp() as u64;
+
+        let Login::Challenge(challenge) = login(&store, Some(&verifier), "alice", "initial-password").await.unwrap()
+        else {
+            panic!("expected a challenge");
+        };
+        let state_id = challenge.split(':').nth(2).unwrap();
+        let response = format!("CRV1::{}::{}", state_id, code(&secret, now));
+        // Answering someone else's challenge fails without using it up
+        assert!(matches!(login(&store, Some(&verifier), "bob", &response).await, Err(AuthError::InvalidState)));
+        let Login::Accepted(user) = login(&store, Some(&verifier), "alice", &response).await.unwrap() else {
+            panic!("expected the login to be accepted");
+        };
+        assert!(user.last_login.is_some());
+
+        // Static challenge: password and code in one SCRV1 response
+        let engine = base64::engine::general_purpose::STANDARD;
+        let scrv1 = |password: &str, code: &str| format!("SCRV1:{}:{}", engine.encode(password), engine.encode(code));
+        assert!(matches!(
+            login(&store, Some(&verifier), "alice", &scrv1("wrong-password", &code(&secret, now + 30))).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            login(&store, Some(&verifier), "alice", &scrv1("initial-password", &code(&secret, now + 30))).await,
+            Ok(Login::Accepted(_))
+        ));
+
+        // Accounts without a second factor pass unless one is required
+        assert!(matches!(
+            login(&store, Some(&verifier), "bob", "initial-password").await,
+            Ok(Login::Accepted(_))
+        ));
+        let verifier = verifier.with_required(true);
+        assert!(matches!(
+            login(&store, Some(&verifier), "bob", "initial-password").await,
+            Err(AuthError::MfaRequired)
+        ));
+    }
+}
@@ -23,6 +23,9 @@ pub struct ClientConfig {
     pub tls_auth_key: Option<String>,
     /// TLS crypt key (if enabled)
     pub tls_crypt_key: Option<String>,
+    /// Per-client tls-crypt-v2 key (if enabled)
+    #[serde(default)]
+    pub tls_crypt_v2_key: Option<String>,
     /// Cipher
     pub cipher: String,
     /// Auth digest
@@ -81,8 +84,12 @@ impl ClientConfig {
         lines.push("</key>".to_string());
         lines.push("".to_string());
 
-        // Add tls-auth or tls-crypt
-        if let Some(key) = &self.tls_crypt_key {
+        // Add tls-auth, tls-crypt or tls-crypt-v2
+        if let Some(key) = &self.tls_crypt_v2_key {
+            lines.push("<tls-crypt-v2>".to_string());
+            lines.push(key.trim().to_string());
+            lines.push("</tls-crypt-v2>".to_string());
+        } else if let Some(key) = &self.tls_crypt_key {
             lines.push("<tls-crypt>".to_string());
             lines.push(key.trim().to_string());
             lines.push("</tls-crypt>".to_string());
@@ -121,6 +128,7 @@ pub struct ClientConfigBuilder {
     client_key: String,
     tls_auth_key: Option<String>,
     tls_crypt_key: Option<String>,
+    tls_crypt_v2_key: Option<String>,
     cipher: String,
     auth: String,
     key_direction: Option<u8>,
@@ -140,6 +148,7 @@ impl ClientConfigBuilder {
             client_key: String::new(),
             tls_auth_key: None,
             tls_crypt_key: None,
+            tls_crypt_v2_key: None,
             cipher: "AES-256-GCM".to_string(),
             auth: "SHA256".to_string(),
             key_direction: Some(1),
@@ -191,6 +200,14 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Set per-client tls-crypt-v2 key
+    pub fn tls_crypt_v2(mut self, key: &str) -> Self {
+        self.tls_crypt_v2_key = Some(key.to_string());
+        self.tls_crypt_key = None;
+        self.tls_auth_key = None;
+        self
+    }
+
     /// Set cipher
     pub fn cipher(mut self, cipher: &str) -> Self {
         self.cipher = cipher.to_string();
@@ -215,6 +232,7 @@ impl ClientConfigBuilder {
             client_key: self.client_key,
             tls_auth_key: self.tls_auth_key,
             tls_crypt_key: self.tls_crypt_key,
+            tls_crypt_v2_key: self.tls_crypt_v2_key,
             cipher: self.cipher,
             auth: self.auth,
             key_direction: self.key_direction,
@@ -259,4 +277,17 @@ mod tests {
         assert!(ovpn.contains("<cert>"));
         assert!(ovpn.contains("<key>"));
     }
+
+    #[test]
+    fn test_tls_crypt_v2_block() {
+        let ovpn = ClientConfigBuilder::new("test", "vpn.example.com")
+            .tls_auth("TA KEY", 1)
+            .tls_crypt_v2("CLIENT V2 KEY")
+            .build()
+            .to_ovpn();
+
+        assert!(ovpn.contains("<tls-crypt-v2>\nCLIENT V2 KEY\n</tls-crypt-v2>"));
+        assert!(!ovpn.contains("<tls-auth>"));
+        assert!(!ovpn.contains("key-direction"));
+    }
 }
@@ -1,10 +1,20 @@
 //! Client Configuration Generator
 
 use std::path::Path;
+use std::sync::Mutex;
 
-use corevpn_crypto::{CertificateAuthority, Certificate};
+use corevpn_crypto::{
+    Certificate, CertificateAuthority, CertificateIndex, ClientKeyMetadata, IssuedCertificate,
+    RevocationReason, TlsCryptV2ServerKey,
+};
 
-use crate::{ClientConfigBuilder, ConfigError, Result, ServerConfig};
+use crate::{ClientConfigBuilder, ConfigError, Result, ServerConfig, TotpMode};
+
+/// Validity of generated CRLs in days
+pub const CRL_VALIDITY_DAYS: u32 = 365;
+
+/// Serializes read-modify-write cycles on the certificate index
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
 
 /// Client configuration generator
 pub struct ConfigGenerator {
@@ -47,6 +57,16 @@ impl ConfigGenerator {
             self.server_config.security.client_cert_lifetime_days,
         ).map_err(|e| ConfigError::ValidationError(e.to_string()))?;
 
+        self.update_index(|index| {
+            index.record(IssuedCertificate::new(
+                &cert.serial,
+                username,
+                email,
+                self.server_config.security.client_cert_lifetime_days,
+            ));
+            Ok(())
+        })?;
+
         // Build client config
         let mut builder = ClientConfigBuilder::new(
             username,
@@ -59,9 +79,15 @@ impl ConfigGenerator {
         .client_key(&cert.key_pem)
         .cipher(&self.map_cipher(&self.server_config.security.cipher));
 
-        // Add tls-auth if enabled
-        if let Some(ta_key) = &self.ta_key {
-            builder = builder.tls_auth(ta_key, 1);
+        builder = self.add_tls_wrap(builder, &cert)?;
+
+        if self.prompts_for_credentials() {
+            builder = builder
+                .extra_option("auth-user-pass")
+                .extra_option("auth-retry interact");
+        }
+        if let Some(challenge) = self.static_challenge() {
+            builder = builder.extra_option(challenge);
         }
 
         // Add compression stub (disabled for security)
@@ -101,8 +127,13 @@ impl ConfigGenerator {
         .extra_option("auth-retry interact")
         .extra_option("compress stub-v2");
 
-        if let Some(ta_key) = &self.ta_key {
-            builder = builder.tls_auth(ta_key, 1);
+        builder = self.add_tls_wrap(builder, &generated.certificate)?;
+
+        if self.prompts_for_credentials() {
+            builder = builder.extra_option("auth-user-pass");
+        }
+        if let Some(challenge) = self.static_challenge() {
+            builder = builder.extra_option(challenge);
         }
 
         let config = builder.build();
@@ -111,6 +142,120 @@ impl ConfigGenerator {
         Ok(generated)
     }
 
+    /// Revoke a certificate by serial number and publish a new CRL
+    pub fn revoke_certificate(&self, serial: &str, reason: RevocationReason) -> Result<IssuedCertificate> {
+        self.update_index(|index| {
+            let entry = index.revoke(serial, reason)
+                .map_err(|e| ConfigError::ValidationError(e.to_string()))?
+                .clone();
+            self.publish_crl(index)?;
+            Ok(entry)
+        })
+    }
+
+    /// Revoke every active certificate issued to a client and publish a new CRL
+    ///
+    /// Returns the revoked serial numbers.
+    pub fn revoke_client(&self, common_name: &str, reason: RevocationReason) -> Result<Vec<String>> {
+        self.update_index(|index| {
+            let serials: Vec<String> = index.active_for(common_name)
+                .map(|entry| entry.serial.clone())
+                .collect();
+            if serials.is_empty() {
+                return Err(ConfigError::ValidationError(format!(
+                    "No active certificates for client: {} (certificates issued before the index \
+                     must be imported first)",
+                    common_name
+                )));
+            }
+
+            for serial in &serials {
+                index.revoke(serial, reason)
+                    .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+            }
+            self.publish_crl(index)?;
+            Ok(serials)
+        })
+    }
+
+    /// Track a certificate issued before the index existed, so it can be revoked
+    ///
+    /// Returns `None` if the certificate is already in the index.
+    pub fn import_certificate(&self, cert_pem: &str) -> Result<Option<IssuedCertificate>> {
+        let entry = self.ca.verify_issued(cert_pem)
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+
+        self.update_index(|index| {
+            if index.find(&entry.serial).is_some() {
+                return Ok(None);
+            }
+            index.record(entry.clone());
+            Ok(Some(entry))
+        })
+    }
+
+    /// Sign a CRL from the index and write it to the data directory
+    fn publish_crl(&self, index: &mut CertificateIndex) -> Result<()> {
+        let crl = self.ca.generate_crl(index, CRL_VALIDITY_DAYS)
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+        std::fs::write(self.server_config.crl_path(), crl)?;
+        Ok(())
+    }
+
+    /// Load, modify and save the certificate index
+    fn update_index<T>(&self, f: impl FnOnce(&mut CertificateIndex) -> Result<T>) -> Result<T> {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = self.server_config.cert_index_path();
+
+        let created = !path.exists();
+        let mut index = CertificateIndex::load(&path)
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+        // The server requires a CRL once certificates are tracked
+        if created {
+            self.publish_crl(&mut index)?;
+        }
+        let result = f(&mut index)?;
+        index.save(&path)
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+        Ok(result)
+    }
+
+    /// Add tls-crypt-v2, tls-crypt or tls-auth matching the server's control channel mode
+    ///
+    /// tls-crypt-v2 keys carry the certificate serial as metadata, so revoking
+    /// the certificate also rejects the key before the TLS handshake.
+    fn add_tls_wrap(&self, builder: ClientConfigBuilder, cert: &Certificate) -> Result<ClientConfigBuilder> {
+        let security = &self.server_config.security;
+        if security.tls_crypt_v2 {
+            let server_key = load_tls_crypt_v2_key(&self.server_config.tls_crypt_v2_key_path())?;
+            let client_key = server_key
+                .generate_client_key(&ClientKeyMetadata::User(cert.serial.as_bytes().to_vec()))
+                .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+            return Ok(builder.tls_crypt_v2(&client_key));
+        }
+
+        Ok(match &self.ta_key {
+            Some(ta_key) if security.tls_crypt => builder.tls_crypt(ta_key),
+            Some(ta_key) if security.tls_auth => builder.tls_auth(ta_key, 1),
+            _ => builder,
+        })
+    }
+
+    /// Check if clients must send credentials via auth-user-pass
+    /// (an OAuth2 or SAML login, or a local password)
+    fn prompts_for_credentials(&self) -> bool {
+        self.server_config.security.password_auth
+            || self.server_config.oauth.as_ref().is_some_and(|oauth| oauth.enabled)
+            || self.server_config.saml.as_ref().is_some_and(|saml| saml.enabled)
+    }
+
+    /// Static challenge prompting for the TOTP code alongside the password
+    fn static_challenge(&self) -> Option<&'static str> {
+        let security = &self.server_config.security;
+        (security.password_auth && security.totp != TotpMode::Disabled && security.totp_static_challenge)
+            .then_some("static-challenge \"Enter your authenticator code\" 1")
+    }
+
     fn map_cipher(&self, cipher: &str) -> String {
         match cipher.to_lowercase().as_str() {
             "chacha20-poly1305" => "CHACHA20-POLY1305".to_string(),
@@ -145,7 +290,38 @@ impl GeneratedConfig {
     }
 }
 
-/// Initialize server PKI (CA, server cert, ta.key)
+/// PEM client certificates in a `.ovpn` profile's `<cert>` block, or in a PEM file
+pub fn client_certificates(content: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    // A profile's <ca> block holds the CA certificate, not the client's
+    let content = match (content.find("<cert>"), content.find("</cert>")) {
+        (Some(start), Some(end)) if start < end => &content[start..end],
+        _ => content,
+    };
+
+    let mut certs = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(BEGIN) {
+        let Some(len) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + len + END.len();
+        certs.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certs
+}
+
+/// Load the tls-crypt-v2 server key
+pub fn load_tls_crypt_v2_key(path: &Path) -> Result<TlsCryptV2ServerKey> {
+    let pem = std::fs::read_to_string(path)?;
+    TlsCryptV2ServerKey::from_pem(&pem)
+        .map_err(|e| ConfigError::ValidationError(format!("{}: {}", path.display(), e)))
+}
+
+/// Initialize server PKI (CA, server cert, ta.key, tls-crypt-v2 key)
 pub fn initialize_pki(
     data_dir: &Path,
     server_cn: &str,
@@ -179,19 +355,40 @@ pub fn initialize_pki(
     fs::write(data_dir.join("server.crt"), &server_cert.cert_pem)?;
     fs::write(data_dir.join("server.key"), &server_cert.key_pem)?;
 
+    // Start the issued-certificate index and publish an empty CRL
+    let mut index = CertificateIndex::new();
+    index.record(IssuedCertificate::new(&server_cert.serial, server_cn, None, 365));
+    let crl = ca.generate_crl(&mut index, CRL_VALIDITY_DAYS)
+        .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+    fs::write(data_dir.join("crl.pem"), crl)?;
+    index.save(&data_dir.join("index.json"))
+        .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+
     // Generate tls-auth key
     let ta_key_bytes = corevpn_crypto::cert::generate_static_key();
     let ta_key = corevpn_crypto::cert::format_static_key(&ta_key_bytes);
     fs::write(data_dir.join("ta.key"), &ta_key)?;
 
+    // Generate tls-crypt-v2 server key
+    fs::write(data_dir.join("tls-crypt-v2.key"), TlsCryptV2ServerKey::generate().to_pem())?;
+
     Ok((ca, ta_key))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::OAuthSettings;
     use tempfile::tempdir;
 
+    /// Reload the CA written by `initialize_pki`
+    fn load_ca(config: &ServerConfig) -> CertificateAuthority {
+        CertificateAuthority::from_pem(
+            &std::fs::read_to_string(config.ca_cert_path()).unwrap(),
+            &std::fs::read_to_string(config.ca_key_path()).unwrap(),
+        ).unwrap()
+    }
+
     #[test]
     fn test_initialize_pki() {
         let dir = tempdir().unwrap();
@@ -206,6 +403,148 @@ mod tests {
         assert!(dir.path().join("server.crt").exists());
         assert!(dir.path().join("server.key").exists());
         assert!(dir.path().join("ta.key").exists());
+        assert!(dir.path().join("tls-crypt-v2.key").exists());
+        assert!(dir.path().join("crl.pem").exists());
+        assert!(dir.path().join("index.json").exists());
         assert!(!ta_key.is_empty());
     }
+
+    #[test]
+    fn test_revoke_client() {
+        let dir = tempdir().unwrap();
+        let (ca, ta_key) = initialize_pki(dir.path(), "vpn.example.com", "Test Org").unwrap();
+
+        let mut config = ServerConfig::default_config("vpn.example.com");
+        config.server.data_dir = dir.path().to_path_buf();
+        let generator = ConfigGenerator::new(config.clone(), ca, Some(ta_key));
+
+        let alice = generator.generate_client_config("alice", None).unwrap();
+        generator.generate_client_config("bob", None).unwrap();
+
+        let revoked = generator.revoke_client("alice", RevocationReason::AffiliationChanged).unwrap();
+        assert_eq!(revoked, vec![alice.certificate.serial.clone()]);
+        assert!(generator.revoke_client("alice", RevocationReason::Unspecified).is_err());
+
+        let crl = std::fs::read_to_string(config.crl_path()).unwrap();
+        assert_eq!(corevpn_crypto::crl::crl_revoked_serials(&crl).unwrap(), revoked);
+
+        let index = CertificateIndex::load(&config.cert_index_path()).unwrap();
+        assert_eq!(index.active_for("bob").count(), 1);
+    }
+
+    #[test]
+    fn test_import_certificate() {
+        let dir = tempdir().unwrap();
+        let (ca, ta_key) = initialize_pki(dir.path(), "vpn.example.com", "Test Org").unwrap();
+
+        let mut config = ServerConfig::default_config("vpn.example.com");
+        config.server.data_dir = dir.path().to_path_buf();
+
+        // A profile issued before certificates were tracked
+        let alice = ConfigGenerator::new(config.clone(), load_ca(&config), Some(ta_key.clone()))
+            .generate_client_config("alice", None)
+            .unwrap();
+        std::fs::remove_file(config.cert_index_path()).unwrap();
+        std::fs::remove_file(config.crl_path()).unwrap();
+
+        let generator = ConfigGenerator::new(config.clone(), ca, Some(ta_key));
+        assert!(generator.revoke_client("alice", RevocationReason::Unspecified).is_err());
+        // Creating the index publishes a CRL alongside it
+        assert!(config.crl_path().exists());
+
+        let certs = client_certificates(&alice.ovpn_content);
+        assert_eq!(certs.len(), 1);
+        let entry = generator.import_certificate(certs[0]).unwrap().unwrap();
+        assert_eq!(entry.serial, alice.certificate.serial);
+        assert!(generator.import_certificate(certs[0]).unwrap().is_none());
+
+        let revoked = generator.revoke_client("alice", RevocationReason::KeyCompromise).unwrap();
+        assert_eq!(revoked, vec![alice.certificate.serial.clone()]);
+        let crl = std::fs::read_to_string(config.crl_path()).unwrap();
+        assert_eq!(corevpn_crypto::crl::crl_revoked_serials(&crl).unwrap(), revoked);
+
+        // Certificates from another CA are not tracked
+        let other = CertificateAuthority::new("Other CA", "Other Org", 365).unwrap();
+        let foreign = other.issue_client_certificate("mallory", None, 30).unwrap();
+        assert!(generator.import_certificate(&foreign.cert_pem).is_err());
+        assert_eq!(client_certificates(&foreign.cert_pem), vec![foreign.cert_pem.trim_end()]);
+    }
+
+    #[test]
+    fn test_tls_wrap_mode() {
+        let dir = tempdir().unwrap();
+        let (ca, ta_key) = initialize_pki(dir.path(), "vpn.example.com", "Test Org").unwrap();
+
+        let mut config = ServerConfig::default_config("vpn.example.com");
+        config.server.data_dir = dir.path().to_path_buf();
+        let generator = ConfigGenerator::new(config.clone(), ca, Some(ta_key.clone()));
+        let ovpn = generator.generate_client_config("alice", None).unwrap().ovpn_content;
+        assert!(ovpn.contains("<tls-auth>") && ovpn.contains("key-direction 1"));
+
+        config.security.tls_crypt = true;
+        let ca = load_ca(&config);
+        let generator = ConfigGenerator::new(config.clone(), ca, Some(ta_key));
+        let ovpn = generator.generate_mobile_config("bob", None).unwrap().ovpn_content;
+        assert!(ovpn.contains("<tls-crypt>") && !ovpn.contains("<tls-auth>"));
+
+        // tls-crypt-v2 keys are bound to the client certificate's serial
+        config.security.tls_crypt_v2 = true;
+        let ca = load_ca(&config);
+        let generator = ConfigGenerator::new(config.clone(), ca, None);
+        let carol = generator.generate_client_config("carol", None).unwrap();
+        let start = carol.ovpn_content.find("-----BEGIN OpenVPN tls-crypt-v2 client key").unwrap();
+        let end = carol.ovpn_content.find("</tls-crypt-v2>").unwrap();
+        let (_, wrapped) =
+            corevpn_crypto::tls_crypt_v2::parse_client_key(&carol.ovpn_content[start..end]).unwrap();
+
+        let server_key = load_tls_crypt_v2_key(&config.tls_crypt_v2_key_path()).unwrap();
+        let (_, metadata) = server_key.unwrap_client_key(&wrapped).unwrap();
+        assert_eq!(metadata, ClientKeyMetadata::User(carol.certificate.serial.into_bytes()));
+    }
+
+    #[test]
+    fn test_oauth_prompts_for_credentials() {
+        let dir = tempdir().unwrap();
+        let (ca, ta_key) = initialize_pki(dir.path(), "vpn.example.com", "Test Org").unwrap();
+
+        let mut config = ServerConfig::default_config("vpn.example.com");
+        config.server.data_dir = dir.path().to_path_buf();
+        config.oauth = Some(OAuthSettings {
+            enabled: true,
+            provider: "google".to_string(),
+            client_id: "client-id".to_string(),
+            client_secret: "client-secret".to_string(),
+            issuer_url: None,
+            tenant_id: None,
+            domain: None,
+            allowed_domains: vec![],
+            required_groups: vec![],
+            session_check_interval_secs: 300,
+            token_introspection: false,
+        });
+        let generator = ConfigGenerator::new(config.clone(), ca, Some(ta_key));
+
+        let ovpn = generator.generate_client_config("alice", None).unwrap().ovpn_content;
+        assert!(ovpn.contains("\nauth-user-pass\n"));
+        assert!(ovpn.contains("\nauth-retry interact\n"));
+
+        // Local passwords are sent the same way, but not alongside OAuth
+        config.security.password_auth = true;
+        assert!(config.validate().is_err());
+        config.oauth = None;
+        assert!(config.validate().is_ok());
+        let ca = load_ca(&config);
+        let generator = ConfigGenerator::new(config.clone(), ca, None);
+        let ovpn = generator.generate_client_config("bob", None).unwrap().ovpn_content;
+        assert!(ovpn.contains("\nauth-user-pass\n"));
+        assert!(!ovpn.contains("static-challenge"));
+
+        // TOTP codes can be asked for up front with a static challenge
+        config.security.totp = TotpMode::Required;
+        config.security.totp_static_challenge = true;
+        let ca = load_ca(&config);
+        let generator = ConfigGenerator::new(config, ca, None);
+        let ovpn = generator.generate_mobile_config("carol", None).unwrap().ovpn_content;
+        assert!(ovpn.contains("\nstatic-challenge \"Enter your authenticator code\" 1\n"));
+    }
 }
@@ -10,9 +10,10 @@ pub mod client;
 pub mod generator;
 
 pub use server::{
-    ServerConfig, LoggingSettings, ConnectionLogMode,
+    ServerConfig, SecuritySettings, LoggingSettings, ConnectionLogMode, TotpMode,
     ConnectionLogEvents, ConnectionLogAnonymization, ConnectionLogRetention,
     AuditSettings, AuditSinkConfig,
+    PolicySettings, PolicyAction, PolicyRoleAssignment, PolicyRule, PolicyGrant, PolicyProtocol,
 };
 pub use client::{ClientConfig, ClientConfigBuilder};
 pub use generator::ConfigGenerator;
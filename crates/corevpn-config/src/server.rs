@@ -1,9 +1,11 @@
 //! Server Configuration
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 
-use ipnet::Ipv4Net;
+use corevpn_core::UserRole;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use serde::{Deserialize, Serialize};
 
 use crate::{ConfigError, Result};
@@ -20,6 +22,9 @@ pub struct ServerConfig {
     /// OAuth2 settings
     #[serde(default)]
     pub oauth: Option<OAuthSettings>,
+    /// SAML 2.0 settings
+    #[serde(default)]
+    pub saml: Option<SamlSettings>,
     /// Logging settings
     #[serde(default)]
     pub logging: LoggingSettings,
@@ -29,6 +34,9 @@ pub struct ServerConfig {
     /// Audit logging settings (SIEM/cloud integration)
     #[serde(default)]
     pub audit: AuditSettings,
+    /// Access policy settings
+    #[serde(default)]
+    pub policy: PolicySettings,
 }
 
 /// Server network settings
@@ -48,6 +56,9 @@ pub struct ServerSettings {
     /// Maximum concurrent clients
     #[serde(default = "default_max_clients")]
     pub max_clients: u32,
+    /// UDP worker tasks, each with its own socket (0 = one per CPU)
+    #[serde(default)]
+    pub workers: usize,
     /// Data directory
     #[serde(default = "default_data_dir")]
     pub data_dir: PathBuf,
@@ -75,7 +86,7 @@ pub struct NetworkSettings {
     /// VPN subnet (e.g., "10.8.0.0/24")
     #[serde(default = "default_subnet")]
     pub subnet: String,
-    /// IPv6 subnet (optional)
+    /// IPv6 subnet (e.g., "fd00:8::/64"), enables IPv6 inside the tunnel
     #[serde(default)]
     pub subnet_v6: Option<String>,
     /// DNS servers to push to clients
@@ -84,7 +95,7 @@ pub struct NetworkSettings {
     /// Search domains
     #[serde(default)]
     pub dns_search: Vec<String>,
-    /// Routes to push to clients
+    /// Routes to push to clients (IPv4 or IPv6 CIDRs)
     #[serde(default)]
     pub push_routes: Vec<String>,
     /// Enable redirect-gateway (full tunnel)
@@ -93,6 +104,9 @@ pub struct NetworkSettings {
     /// MTU setting
     #[serde(default = "default_mtu")]
     pub mtu: u16,
+    /// Hours a disconnected user's address is kept for them
+    #[serde(default = "default_lease_hours")]
+    pub lease_hours: u32,
 }
 
 fn default_subnet() -> String {
@@ -111,6 +125,10 @@ fn default_mtu() -> u16 {
     1420
 }
 
+fn default_lease_hours() -> u32 {
+    168 // 1 week
+}
+
 /// Security settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecuritySettings {
@@ -126,18 +144,60 @@ pub struct SecuritySettings {
     /// Enable tls-crypt (stronger than tls-auth)
     #[serde(default)]
     pub tls_crypt: bool,
+    /// Enable tls-crypt-v2 with a key per client (overrides tls-auth and tls-crypt)
+    #[serde(default)]
+    pub tls_crypt_v2: bool,
     /// Certificate lifetime in days
     #[serde(default = "default_cert_lifetime")]
     pub cert_lifetime_days: u32,
     /// Client certificate lifetime in days
     #[serde(default = "default_client_cert_lifetime")]
     pub client_cert_lifetime_days: u32,
-    /// Renegotiation interval in seconds
+    /// Renegotiation interval in seconds (0 = disabled)
     #[serde(default = "default_reneg_sec")]
     pub reneg_sec: u32,
+    /// Renegotiate after this many bytes on one key (0 = disabled)
+    #[serde(default)]
+    pub reneg_bytes: u64,
+    /// Renegotiate after this many packets on one key (0 = disabled)
+    #[serde(default)]
+    pub reneg_pkts: u64,
+    /// Seconds a renegotiation may take before the client is disconnected
+    #[serde(default = "default_hand_window")]
+    pub hand_window: u32,
+    /// Seconds the previous key keeps decrypting after a renegotiation
+    #[serde(default = "default_transition_window")]
+    pub transition_window: u32,
+    /// Seconds a pushed auth token lets a client without a certificate
+    /// reconnect without logging in again (0 = renegotiation only)
+    #[serde(default = "default_auth_token_lifetime")]
+    pub auth_token_lifetime: u32,
     /// Enable perfect forward secrecy
     #[serde(default = "default_true")]
     pub pfs: bool,
+    /// Require a local username/password (in addition to the client certificate)
+    #[serde(default)]
+    pub password_auth: bool,
+    /// TOTP second factor for local password logins
+    #[serde(default)]
+    pub totp: TotpMode,
+    /// Prompt for the TOTP code with a static challenge in the client config
+    /// instead of a CRV1 dynamic challenge after the password
+    #[serde(default)]
+    pub totp_static_challenge: bool,
+}
+
+/// TOTP second factor enforcement
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TotpMode {
+    /// No second factor
+    #[default]
+    Disabled,
+    /// Enrolled users must enter a code
+    Optional,
+    /// Every user must be enrolled and enter a code
+    Required,
 }
 
 fn default_cipher() -> String {
@@ -164,6 +224,18 @@ fn default_reneg_sec() -> u32 {
     3600 // 1 hour
 }
 
+fn default_hand_window() -> u32 {
+    60
+}
+
+fn default_transition_window() -> u32 {
+    60
+}
+
+fn default_auth_token_lifetime() -> u32 {
+    43200 // 12 hours
+}
+
 /// OAuth2 settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OAuthSettings {
@@ -192,6 +264,65 @@ pub struct OAuthSettings {
     /// Required groups (user must be in at least one)
     #[serde(default)]
     pub required_groups: Vec<String>,
+    /// How often connected users' tokens are refreshed and re-checked, in seconds
+    #[serde(default = "default_session_check_interval")]
+    pub session_check_interval_secs: u64,
+    /// Also check tokens against the provider's RFC 7662 introspection endpoint
+    #[serde(default)]
+    pub token_introspection: bool,
+}
+
+fn default_session_check_interval() -> u64 {
+    300 // 5 minutes
+}
+
+/// SAML 2.0 settings
+///
+/// The server answers the service provider endpoints (`/saml/metadata`,
+/// `/saml/login` and `/saml/acs`) itself on `listen_addr`; put a reverse
+/// proxy terminating TLS at `public_url` in front of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamlSettings {
+    /// Enable SAML authentication
+    #[serde(default)]
+    pub enabled: bool,
+    /// Listen address for the service provider endpoints
+    #[serde(default = "default_saml_listen_addr")]
+    pub listen_addr: SocketAddr,
+    /// Public URL the endpoints are reached at (e.g. "https://vpn.example.com")
+    pub public_url: String,
+    /// Entity ID of this service provider (defaults to the metadata URL)
+    #[serde(default)]
+    pub entity_id: Option<String>,
+    /// Entity ID of the identity provider
+    pub idp_entity_id: String,
+    /// IdP single sign-on service URL
+    pub idp_sso_url: String,
+    /// IdP signing certificates (PEM, or base64 DER as in the IdP's metadata)
+    pub idp_certificates: Vec<String>,
+    /// Attribute holding the email address, tried before the standard names
+    #[serde(default)]
+    pub email_attribute: Option<String>,
+    /// Attribute holding group memberships, tried before the standard names
+    #[serde(default)]
+    pub groups_attribute: Option<String>,
+    /// Allowed email domains
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// Required groups (user must be in at least one)
+    #[serde(default)]
+    pub required_groups: Vec<String>,
+    /// How long a user has to finish logging in with the IdP, in seconds
+    #[serde(default = "default_saml_login_timeout")]
+    pub login_timeout_secs: u64,
+}
+
+fn default_saml_listen_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081)
+}
+
+fn default_saml_login_timeout() -> u64 {
+    300 // 5 minutes
 }
 
 /// Connection logging mode
@@ -618,6 +749,138 @@ fn default_audit_format() -> String { "json".to_string() }
 fn default_max_size() -> u64 { 100 }
 fn default_max_files() -> u32 { 10 }
 
+/// Group-based access policy
+///
+/// When enabled, each client's identity is matched against the rules to decide
+/// which destinations it may reach through the tunnel. Clients with a
+/// restricted policy are pushed routes for their granted networks only.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicySettings {
+    /// Enforce the policy
+    #[serde(default)]
+    pub enabled: bool,
+    /// Access for clients that match no rule (never applies to `Limited` clients)
+    #[serde(default)]
+    pub default_action: PolicyAction,
+    /// Role assignments, first match wins (clients default to `User`)
+    #[serde(default)]
+    pub roles: Vec<PolicyRoleAssignment>,
+    /// Access rules
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+/// Access for clients that match no policy rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// Unrestricted access, as without a policy
+    Allow,
+    /// Reject the client
+    #[default]
+    Deny,
+}
+
+/// Assigns a role to members of groups or to named users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRoleAssignment {
+    /// Role to assign
+    pub role: UserRole,
+    /// Groups whose members get the role
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Usernames or email addresses that get the role
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Access rule granting destinations to groups, users or roles
+///
+/// A rule without groups, users or roles applies to every client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRule {
+    /// Rule name (used in audit events)
+    pub name: String,
+    /// Groups the rule applies to
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Usernames or email addresses the rule applies to
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Roles the rule applies to
+    #[serde(default)]
+    pub roles: Vec<UserRole>,
+    /// Allowed destinations
+    #[serde(default)]
+    pub allow: Vec<PolicyGrant>,
+}
+
+/// Allowed destination network, protocol and ports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyGrant {
+    /// Destination network (IPv4 or IPv6)
+    pub cidr: IpNet,
+    /// IP protocol
+    #[serde(default)]
+    pub protocol: PolicyProtocol,
+    /// Destination ports or ranges (e.g. `"443"`, `"8000-8100"`), empty for all
+    #[serde(default)]
+    pub ports: Vec<String>,
+}
+
+impl PolicyGrant {
+    /// Parse the destination port ranges
+    pub fn port_ranges(&self) -> Result<Vec<RangeInclusive<u16>>> {
+        if !self.ports.is_empty() && !matches!(self.protocol, PolicyProtocol::Tcp | PolicyProtocol::Udp) {
+            return Err(ConfigError::ValidationError(format!(
+                "policy grant for {}: ports require protocol tcp or udp",
+                self.cidr
+            )));
+        }
+
+        self.ports
+            .iter()
+            .map(|spec| {
+                let (start, end) = spec.split_once('-').unwrap_or((spec, spec));
+                match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                    (Ok(start), Ok(end)) if start <= end => Ok(start..=end),
+                    _ => Err(ConfigError::ValidationError(format!(
+                        "policy grant for {}: invalid port range {:?}",
+                        self.cidr, spec
+                    ))),
+                }
+            })
+            .collect()
+    }
+}
+
+/// IP protocol matched by a policy grant
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyProtocol {
+    /// Any protocol
+    #[default]
+    Any,
+    /// TCP
+    Tcp,
+    /// UDP
+    Udp,
+    /// ICMP (and ICMPv6)
+    Icmp,
+}
+
+impl PolicyProtocol {
+    /// Check if the grant covers an IP protocol number
+    pub fn matches(&self, protocol: u8) -> bool {
+        match self {
+            PolicyProtocol::Any => true,
+            PolicyProtocol::Tcp => protocol == 6,
+            PolicyProtocol::Udp => protocol == 17,
+            PolicyProtocol::Icmp => protocol == 1 || protocol == 58,
+        }
+    }
+}
+
 impl ServerConfig {
     /// Create a default configuration
     pub fn default_config(public_host: &str) -> Self {
@@ -628,6 +891,7 @@ impl ServerConfig {
                 public_host: public_host.to_string(),
                 protocol: default_protocol(),
                 max_clients: default_max_clients(),
+                workers: 0,
                 data_dir: default_data_dir(),
             },
             network: NetworkSettings {
@@ -638,21 +902,33 @@ impl ServerConfig {
                 push_routes: vec![],
                 redirect_gateway: default_redirect_gateway(),
                 mtu: default_mtu(),
+                lease_hours: default_lease_hours(),
             },
             security: SecuritySettings {
                 cipher: default_cipher(),
                 tls_min_version: default_tls_version(),
                 tls_auth: true,
                 tls_crypt: false,
+                tls_crypt_v2: false,
                 cert_lifetime_days: default_cert_lifetime(),
                 client_cert_lifetime_days: default_client_cert_lifetime(),
                 reneg_sec: default_reneg_sec(),
+                reneg_bytes: 0,
+                reneg_pkts: 0,
+                hand_window: default_hand_window(),
+                transition_window: default_transition_window(),
+                auth_token_lifetime: default_auth_token_lifetime(),
                 pfs: true,
+                password_auth: false,
+                totp: TotpMode::Disabled,
+                totp_static_challenge: false,
             },
             oauth: None,
+            saml: None,
             logging: LoggingSettings::default(),
             admin: AdminSettings::default(),
             audit: AuditSettings::default(),
+            policy: PolicySettings::default(),
         }
     }
 
@@ -680,6 +956,10 @@ impl ServerConfig {
         // Validate subnet
         self.network.subnet.parse::<Ipv4Net>()
             .map_err(|e| ConfigError::ValidationError(format!("invalid subnet: {}", e)))?;
+        if let Some(subnet_v6) = &self.network.subnet_v6 {
+            subnet_v6.parse::<Ipv6Net>()
+                .map_err(|e| ConfigError::ValidationError(format!("invalid subnet_v6: {}", e)))?;
+        }
 
         // Validate OAuth if enabled
         if let Some(oauth) = &self.oauth {
@@ -693,6 +973,55 @@ impl ServerConfig {
             }
         }
 
+        // Validate SAML if enabled
+        if let Some(saml) = &self.saml {
+            if saml.enabled {
+                for (value, name) in [
+                    (&saml.public_url, "saml.public_url"),
+                    (&saml.idp_entity_id, "saml.idp_entity_id"),
+                    (&saml.idp_sso_url, "saml.idp_sso_url"),
+                ] {
+                    if value.is_empty() {
+                        return Err(ConfigError::MissingField(name.into()));
+                    }
+                }
+                if saml.idp_certificates.is_empty() {
+                    return Err(ConfigError::MissingField("saml.idp_certificates".into()));
+                }
+                if self.oauth.as_ref().is_some_and(|oauth| oauth.enabled) {
+                    return Err(ConfigError::ValidationError(
+                        "SAML cannot be combined with OAuth".into(),
+                    ));
+                }
+                // Both use the auth-user-pass fields
+                if self.security.password_auth {
+                    return Err(ConfigError::ValidationError(
+                        "security.password_auth cannot be combined with SAML".into(),
+                    ));
+                }
+            }
+        }
+
+        // Local passwords and OAuth logins both use the auth-user-pass fields
+        if self.security.password_auth && self.oauth.as_ref().is_some_and(|oauth| oauth.enabled) {
+            return Err(ConfigError::ValidationError(
+                "security.password_auth cannot be combined with OAuth".into(),
+            ));
+        }
+
+        if self.security.totp != TotpMode::Disabled && !self.security.password_auth {
+            return Err(ConfigError::ValidationError(
+                "security.totp requires security.password_auth".into(),
+            ));
+        }
+
+        // Validate policy grants
+        for rule in &self.policy.rules {
+            for grant in &rule.allow {
+                grant.port_ranges()?;
+            }
+        }
+
         Ok(())
     }
 
@@ -726,6 +1055,31 @@ impl ServerConfig {
         self.server.data_dir.join("ta.key")
     }
 
+    /// Get tls-crypt-v2 server key path
+    pub fn tls_crypt_v2_key_path(&self) -> PathBuf {
+        self.server.data_dir.join("tls-crypt-v2.key")
+    }
+
+    /// Get VPN address lease database path
+    pub fn leases_path(&self) -> PathBuf {
+        self.server.data_dir.join(corevpn_core::lease::LEASE_FILE)
+    }
+
+    /// Get path of the key encrypting TOTP secrets
+    pub fn totp_key_path(&self) -> PathBuf {
+        self.server.data_dir.join("totp.key")
+    }
+
+    /// Get issued-certificate index path
+    pub fn cert_index_path(&self) -> PathBuf {
+        self.server.data_dir.join("index.json")
+    }
+
+    /// Get certificate revocation list path
+    pub fn crl_path(&self) -> PathBuf {
+        self.server.data_dir.join("crl.pem")
+    }
+
     /// Get DH parameters path (for compatibility)
     pub fn dh_path(&self) -> PathBuf {
         self.server.data_dir.join("dh.pem")
@@ -750,7 +1104,80 @@ mod tests {
         let mut config = ServerConfig::default_config("vpn.example.com");
         assert!(config.validate().is_ok());
 
+        config.network.subnet_v6 = Some("fd00:8::/64".into());
+        assert!(config.validate().is_ok());
+        config.network.subnet_v6 = Some("fd00:vpn::/64".into());
+        assert!(config.validate().is_err());
+        config.network.subnet_v6 = None;
+
         config.server.public_host = String::new();
         assert!(config.validate().is_err());
     }
+
+    #[test]
+    fn test_saml_settings() {
+        let toml = r#"
+            enabled = true
+            public_url = "https://vpn.example.com"
+            idp_entity_id = "http://adfs.example.com/adfs/services/trust"
+            idp_sso_url = "https://adfs.example.com/adfs/ls/"
+            idp_certificates = ["MIIC..."]
+        "#;
+        let saml: SamlSettings = toml::from_str(toml).unwrap();
+        assert_eq!(saml.listen_addr, default_saml_listen_addr());
+        assert_eq!(saml.login_timeout_secs, 300);
+
+        let mut config = ServerConfig::default_config("vpn.example.com");
+        config.saml = Some(saml);
+        assert!(config.validate().is_ok());
+
+        config.security.password_auth = true;
+        assert!(config.validate().is_err());
+        config.security.password_auth = false;
+
+        if let Some(saml) = config.saml.as_mut() {
+            saml.idp_certificates.clear();
+        }
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_policy_settings() {
+        let toml = r#"
+            enabled = true
+
+            [[roles]]
+            role = "Limited"
+            groups = ["contractors"]
+
+            [[rules]]
+            name = "contractors-web"
+            groups = ["contractors"]
+            allow = [
+                { cidr = "10.20.0.0/16", protocol = "tcp", ports = ["443", "8000-8100"] },
+                { cidr = "10.30.1.5/32" },
+                { cidr = "fd00:20::/48", protocol = "icmp" },
+            ]
+        "#;
+        let policy: PolicySettings = toml::from_str(toml).unwrap();
+        assert_eq!(policy.default_action, PolicyAction::Deny);
+        assert_eq!(policy.roles[0].role, UserRole::Limited);
+
+        let grants = &policy.rules[0].allow;
+        assert_eq!(grants[0].port_ranges().unwrap(), vec![443..=443, 8000..=8100]);
+        assert_eq!(grants[1].protocol, PolicyProtocol::Any);
+        assert!(grants[1].port_ranges().unwrap().is_empty());
+        assert_eq!(grants[2].cidr, "fd00:20::/48".parse::<IpNet>().unwrap());
+        assert!(grants[2].protocol.matches(58));
+
+        let mut config = ServerConfig::default_config("vpn.example.com");
+        config.policy = policy;
+        assert!(config.validate().is_ok());
+
+        config.policy.rules[0].allow[0].ports.push("9000-8000".into());
+        assert!(config.validate().is_err());
+        config.policy.rules[0].allow[0].ports.pop();
+        config.policy.rules[0].allow[1].ports.push("53".into());
+        assert!(config.validate().is_err());
+    }
 }
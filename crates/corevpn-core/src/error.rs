@@ -40,6 +40,10 @@ pub enum CoreError {
     #[error("crypto error: {0}")]
     CryptoError(#[from] corevpn_crypto::CryptoError),
 
+    /// Storage backend error
+    #[error("storage error: {0}")]
+    StorageError(String),
+
     /// IO error
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
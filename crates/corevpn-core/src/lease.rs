@@ -0,0 +1,443 @@
+//! VPN Address Leases
+//!
+//! Durable address leases keyed by username or certificate common name, so a
+//! client keeps its VPN address across reconnects (like OpenVPN's
+//! `ifconfig-pool-persist`). Static reservations from the user store always
+//! win over dynamic leases.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::network::{AddressPool, VpnAddress};
+use crate::{CoreError, Result};
+
+/// File name of the lease database in the data directory
+pub const LEASE_FILE: &str = "leases.json";
+
+/// Address leased to a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    /// Username or certificate common name
+    pub key: String,
+    /// Leased address
+    pub address: VpnAddress,
+    /// Static reservation from the user store
+    #[serde(default)]
+    pub reserved: bool,
+    /// Held by a live connection
+    #[serde(skip)]
+    pub bound: bool,
+    /// When the address may go to someone else (`None` while bound or reserved)
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Lease {
+    /// Check if the lease can no longer be renewed
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        !self.bound && !self.reserved && self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Hands out addresses from a pool, remembering who held which address
+pub struct LeaseManager {
+    pool: AddressPool,
+    lease_time: Duration,
+    /// Lease database (if persisted)
+    path: Option<PathBuf>,
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+impl LeaseManager {
+    /// Create an in-memory lease manager
+    pub fn new(pool: AddressPool, lease_time: Duration) -> Self {
+        Self {
+            pool,
+            lease_time,
+            path: None,
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load the lease database at `path`, creating it on the first change
+    ///
+    /// Leases outside the pool (e.g. after a subnet change) are dropped.
+    pub fn load(path: &Path, pool: AddressPool, lease_time: Duration) -> Result<Self> {
+        let saved: Vec<Lease> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| CoreError::StorageError(format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = Utc::now();
+        let mut leases = HashMap::new();
+        for mut lease in saved {
+            match pool.allocate_fitted(lease.address) {
+                Ok(address) => lease.address = address,
+                Err(e) => {
+                    warn!("Dropping lease of {:?} for {}: {}", lease.address, lease.key, e);
+                    continue;
+                }
+            }
+            // Leases held when the server stopped start expiring now
+            if !lease.reserved && lease.expires_at.is_none() {
+                lease.expires_at = Some(now + lease_time);
+            }
+            leases.insert(lease.key.clone(), lease);
+        }
+
+        let manager = Self {
+            pool,
+            lease_time,
+            path: Some(path.to_path_buf()),
+            leases: Mutex::new(leases),
+        };
+        manager.expire();
+        Ok(manager)
+    }
+
+    /// Address pool the leases are taken from
+    pub fn pool(&self) -> &AddressPool {
+        &self.pool
+    }
+
+    /// Bind an address to a live connection
+    ///
+    /// Connections with a key renew their lease or reservation. A lease that
+    /// is already bound is never handed out twice: further connections with
+    /// the same key, and connections without a key, get a temporary address.
+    pub fn acquire(&self, key: Option<&str>) -> Result<VpnAddress> {
+        let mut leases = self.leases.lock();
+
+        let Some(key) = key else {
+            return self.allocate(&mut leases);
+        };
+        if let Some(lease) = leases.get_mut(key) {
+            if lease.bound {
+                debug!("Lease for {} is in use, assigning a temporary address", key);
+                return self.allocate(&mut leases);
+            }
+            lease.bound = true;
+            lease.expires_at = None;
+            let address = lease.address;
+            self.save(&leases);
+            return Ok(address);
+        }
+
+        let address = self.allocate(&mut leases)?;
+        leases.insert(key.to_string(), Lease {
+            key: key.to_string(),
+            address,
+            reserved: false,
+            bound: true,
+            expires_at: None,
+        });
+        self.save(&leases);
+        Ok(address)
+    }
+
+    /// Unbind an address when its connection goes away
+    pub fn release(&self, address: &VpnAddress) {
+        let mut leases = self.leases.lock();
+        match leases.values_mut().find(|lease| lease.bound && lease.address == *address) {
+            Some(lease) => {
+                lease.bound = false;
+                if !lease.reserved {
+                    lease.expires_at = Some(Utc::now() + self.lease_time);
+                }
+                self.save(&leases);
+            }
+            None => self.pool.release(address),
+        }
+    }
+
+    /// Drop expired leases, returning their addresses to the pool
+    pub fn expire(&self) -> usize {
+        let now = Utc::now();
+        let mut leases = self.leases.lock();
+        let before = leases.len();
+        leases.retain(|_, lease| {
+            if lease.is_expired(now) {
+                self.pool.release(&lease.address);
+                false
+            } else {
+                true
+            }
+        });
+
+        let expired = before - leases.len();
+        if expired > 0 {
+            self.save(&leases);
+        }
+        expired
+    }
+
+    /// Replace the static reservations with the given addresses by key
+    ///
+    /// Reservations that conflict with a live connection are applied once it
+    /// disconnects, on the next call. A family left out of a reservation is
+    /// allocated from the pool.
+    pub fn set_reservations(&self, reservations: &HashMap<String, VpnAddress>) {
+        let mut leases = self.leases.lock();
+        let mut changed = false;
+
+        // Withdrawn or moved reservations become ordinary leases
+        for lease in leases.values_mut() {
+            let kept = reservations.get(&lease.key).is_some_and(|reserved| satisfies(&lease.address, reserved));
+            if lease.reserved && !kept {
+                changed = true;
+                lease.reserved = false;
+                if !lease.bound {
+                    lease.expires_at = Some(Utc::now());
+                }
+            }
+        }
+
+        for (key, &address) in reservations {
+            if let Some(lease) = leases.get_mut(key).filter(|lease| satisfies(&lease.address, &address)) {
+                changed |= !lease.reserved || lease.expires_at.is_some();
+                lease.reserved = true;
+                lease.expires_at = None;
+                continue;
+            }
+
+            // Evict whoever holds the address or the key, unless connected
+            let conflicts: Vec<String> = leases
+                .values()
+                .filter(|lease| lease.key == *key || overlaps(&lease.address, &address))
+                .map(|lease| lease.key.clone())
+                .collect();
+            if conflicts.iter().any(|conflict| leases[conflict].bound) {
+                debug!("Reservation of {:?} for {} deferred: lease in use", address, key);
+                continue;
+            }
+            for conflict in conflicts {
+                if let Some(lease) = leases.remove(&conflict) {
+                    changed = true;
+                    self.pool.release(&lease.address);
+                }
+            }
+
+            let address = match self.pool.allocate_fitted(address) {
+                Ok(address) => address,
+                Err(e) => {
+                    warn!("Cannot reserve {:?} for {}: {}", address, key, e);
+                    continue;
+                }
+            };
+            leases.insert(key.clone(), Lease {
+                key: key.clone(),
+                address,
+                reserved: true,
+                bound: false,
+                expires_at: None,
+            });
+            changed = true;
+        }
+
+        // Called on every user store sync, which mostly changes nothing
+        if changed {
+            self.save(&leases);
+        }
+    }
+
+    /// Snapshot of all leases, ordered by key
+    pub fn leases(&self) -> Vec<Lease> {
+        let mut leases: Vec<Lease> = self.leases.lock().values().cloned().collect();
+        leases.sort_by(|a, b| a.key.cmp(&b.key));
+        leases
+    }
+
+    /// Allocate a fresh address, reclaiming the least recently used lease if the pool is full
+    fn allocate(&self, leases: &mut HashMap<String, Lease>) -> Result<VpnAddress> {
+        match self.pool.allocate() {
+            Err(CoreError::AddressPoolExhausted) => {}
+            result => return result,
+        }
+
+        let oldest = leases
+            .values()
+            .filter(|lease| !lease.bound && !lease.reserved)
+            .min_by_key(|lease| lease.expires_at)
+            .map(|lease| lease.key.clone())
+            .ok_or(CoreError::AddressPoolExhausted)?;
+        if let Some(lease) = leases.remove(&oldest) {
+            debug!("Reclaiming {:?} from {}", lease.address, lease.key);
+            self.pool.release(&lease.address);
+        }
+        self.pool.allocate()
+    }
+
+    /// Write the lease database, if persisted
+    fn save(&self, leases: &HashMap<String, Lease>) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+
+        let mut sorted: Vec<&Lease> = leases.values().collect();
+        sorted.sort_by(|a, b| a.key.cmp(&b.key));
+        let result = serde_json::to_vec_pretty(&sorted)
+            .map_err(std::io::Error::other)
+            .and_then(|data| {
+                let tmp = path.with_extension("json.tmp");
+                std::fs::write(&tmp, data)?;
+                std::fs::rename(&tmp, path)
+            });
+        if let Err(e) = result {
+            warn!("Failed to save leases to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Check if a leased address has every family a reservation asks for
+fn satisfies(address: &VpnAddress, reserved: &VpnAddress) -> bool {
+    reserved.ipv4.is_none_or(|v4| address.ipv4 == Some(v4))
+        && reserved.ipv6.is_none_or(|v6| address.ipv6 == Some(v6))
+}
+
+/// Check if two addresses share an IPv4 or IPv6 address
+fn overlaps(a: &VpnAddress, b: &VpnAddress) -> bool {
+    a.addresses().any(|addr| b.contains(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn pool() -> AddressPool {
+        // .2 to .6 are assignable
+        AddressPool::new(Some("10.8.0.0/29".parse().unwrap()), None)
+    }
+
+    fn v4(last: u8) -> VpnAddress {
+        VpnAddress::v4(Ipv4Addr::new(10, 8, 0, last))
+    }
+
+    #[test]
+    fn test_sticky_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LEASE_FILE);
+
+        let leases = LeaseManager::load(&path, pool(), Duration::hours(1)).unwrap();
+        let alice = leases.acquire(Some("alice")).unwrap();
+        let bob = leases.acquire(Some("bob")).unwrap();
+        assert_ne!(alice, bob);
+
+        // A second live connection for alice gets a temporary address
+        let temporary = leases.acquire(Some("alice")).unwrap();
+        assert_ne!(temporary, alice);
+        leases.release(&temporary);
+        assert_eq!(leases.leases().len(), 2);
+
+        leases.release(&alice);
+        leases.release(&bob);
+        assert_eq!(leases.acquire(Some("alice")).unwrap(), alice);
+
+        // Leases survive a restart
+        drop(leases);
+        let leases = LeaseManager::load(&path, pool(), Duration::hours(1)).unwrap();
+        assert_eq!(leases.acquire(Some("bob")).unwrap(), bob);
+        assert_ne!(leases.acquire(None).unwrap(), alice);
+    }
+
+    #[test]
+    fn test_expiry_and_reuse() {
+        let leases = LeaseManager::new(pool(), Duration::zero());
+        let alice = leases.acquire(Some("alice")).unwrap();
+        leases.release(&alice);
+        assert_eq!(leases.expire(), 1);
+        assert!(leases.leases().is_empty());
+
+        // Released but unexpired leases are reclaimed when the pool runs out
+        let leases = LeaseManager::new(pool(), Duration::hours(1));
+        let addresses: Vec<_> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|key| leases.acquire(Some(key)).unwrap())
+            .collect();
+        assert!(leases.acquire(Some("f")).is_err());
+        leases.release(&addresses[1]);
+        assert_eq!(leases.acquire(Some("f")).unwrap(), addresses[1]);
+        assert!(leases.leases().iter().all(|lease| lease.key != "b"));
+    }
+
+    #[test]
+    fn test_reservations() {
+        let leases = LeaseManager::new(pool(), Duration::hours(1));
+        let bob = leases.acquire(Some("bob")).unwrap();
+        assert_eq!(bob, v4(2));
+
+        // A reservation held by a live connection waits until it disconnects
+        let reservations = HashMap::from([("alice".to_string(), v4(2))]);
+        leases.set_reservations(&reservations);
+        assert!(leases.leases().iter().all(|lease| !lease.reserved));
+
+        leases.release(&bob);
+        leases.set_reservations(&reservations);
+        assert_eq!(leases.acquire(Some("alice")).unwrap(), v4(2));
+        let bob = leases.acquire(Some("bob")).unwrap();
+        assert_ne!(bob, v4(2));
+
+        // Reserved addresses are not handed to anyone else
+        leases.release(&v4(2));
+        for _ in 0..3 {
+            assert_ne!(leases.acquire(None).unwrap(), v4(2));
+        }
+        assert!(leases.acquire(None).is_err());
+
+        // Out-of-pool reservations are ignored
+        leases.set_reservations(&HashMap::from([("carol".to_string(), v4(9))]));
+        assert!(leases.leases().iter().all(|lease| lease.key != "carol" && !lease.reserved));
+    }
+
+    #[test]
+    fn test_reservations_saved_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LEASE_FILE);
+        let leases = LeaseManager::load(&path, pool(), Duration::hours(1)).unwrap();
+
+        let reservations = HashMap::from([("alice".to_string(), v4(3))]);
+        leases.set_reservations(&reservations);
+        assert!(path.exists());
+
+        // Unchanged reservations leave the database alone
+        std::fs::remove_file(&path).unwrap();
+        leases.set_reservations(&reservations);
+        assert!(!path.exists());
+
+        leases.set_reservations(&HashMap::new());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_dual_stack_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LEASE_FILE);
+        let alice = LeaseManager::load(&path, pool(), Duration::hours(1))
+            .unwrap()
+            .acquire(Some("alice"))
+            .unwrap();
+        assert_eq!(alice, v4(2));
+
+        // Adding an IPv6 range keeps the IPv4 lease and extends it
+        let dual = AddressPool::new(Some("10.8.0.0/29".parse().unwrap()), Some("fd00:8::/64".parse().unwrap()));
+        let leases = LeaseManager::load(&path, dual, Duration::hours(1)).unwrap();
+        let alice = leases.acquire(Some("alice")).unwrap();
+        assert_eq!(alice.ipv4, Some(Ipv4Addr::new(10, 8, 0, 2)));
+        assert_eq!(alice.ipv6, Some("fd00:8::2".parse().unwrap()));
+
+        // IPv4-only reservations get an IPv6 address and stay stable
+        let reservations = HashMap::from([("bob".to_string(), v4(5))]);
+        leases.set_reservations(&reservations);
+        leases.set_reservations(&reservations);
+        let bob = leases.acquire(Some("bob")).unwrap();
+        assert_eq!(bob.ipv4, Some(Ipv4Addr::new(10, 8, 0, 5)));
+        assert!(bob.ipv6.is_some_and(|v6| v6 != alice.ipv6.unwrap()));
+        assert!(leases.leases().iter().any(|lease| lease.key == "bob" && lease.reserved));
+    }
+}
@@ -8,9 +8,15 @@
 pub mod error;
 pub mod session;
 pub mod network;
+pub mod lease;
 pub mod user;
+#[cfg(feature = "sqlite")]
+pub mod user_store;
 
 pub use error::{CoreError, Result};
 pub use session::{Session, SessionId, SessionState, SessionManager};
 pub use network::{VpnAddress, AddressPool, Route};
-pub use user::{User, UserId, UserRole};
+pub use lease::{Lease, LeaseManager};
+pub use user::{User, UserId, UserRole, UserStore, MemoryUserStore};
+#[cfg(feature = "sqlite")]
+pub use user_store::SqliteUserStore;
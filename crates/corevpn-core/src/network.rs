@@ -47,6 +47,19 @@ impl VpnAddress {
             .map(IpAddr::V4)
             .or_else(|| self.ipv6.map(IpAddr::V6))
     }
+
+    /// Iterate over the assigned addresses, IPv4 first
+    pub fn addresses(&self) -> impl Iterator<Item = IpAddr> {
+        self.ipv4
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.ipv6.map(IpAddr::V6))
+    }
+
+    /// Check if an address is one of the assigned addresses
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.addresses().any(|assigned| assigned == addr)
+    }
 }
 
 /// Route to be pushed to VPN clients
@@ -152,6 +165,16 @@ impl AddressPool {
         }
     }
 
+    /// IPv4 network range
+    pub fn ipv4_net(&self) -> Option<Ipv4Net> {
+        self.ipv4_net
+    }
+
+    /// IPv6 network range
+    pub fn ipv6_net(&self) -> Option<Ipv6Net> {
+        self.ipv6_net
+    }
+
     /// Get the gateway IPv4 address
     pub fn gateway_v4(&self) -> Option<Ipv4Addr> {
         self.ipv4_net.map(|net| {
@@ -174,10 +197,15 @@ impl AddressPool {
             None
         };
 
-        let ipv6 = if let Some(net) = &self.ipv6_net {
-            Some(self.allocate_v6(net)?)
-        } else {
-            None
+        let ipv6 = match &self.ipv6_net {
+            Some(net) => match self.allocate_v6(net) {
+                Ok(addr) => Some(addr),
+                Err(e) => {
+                    self.release(&VpnAddress { ipv4, ipv6: None });
+                    return Err(e);
+                }
+            },
+            None => None,
         };
 
         if ipv4.is_none() && ipv6.is_none() {
@@ -211,7 +239,7 @@ impl AddressPool {
         // Start from ::2 (after gateway)
         let start = u128::from(net.network()) + 2;
         // Limit search to reasonable range
-        let end = start + 65534;
+        let end = (start + 65534).min(u128::from(net.broadcast()) + 1);
 
         for addr_u128 in start..end {
             let addr = Ipv6Addr::from(addr_u128);
@@ -267,6 +295,38 @@ impl AddressPool {
         Ok(addr)
     }
 
+    /// Allocate a known address for the families this pool serves
+    ///
+    /// Families the pool does not serve are dropped and missing ones are
+    /// allocated, so an address kept from an IPv4-only pool gains an IPv6
+    /// address once an IPv6 range is configured.
+    pub fn allocate_fitted(&self, addr: VpnAddress) -> Result<VpnAddress> {
+        let mut addr = self.allocate_specific(VpnAddress {
+            ipv4: addr.ipv4.filter(|_| self.ipv4_net.is_some()),
+            ipv6: addr.ipv6.filter(|_| self.ipv6_net.is_some()),
+        })?;
+
+        if let (None, Some(net)) = (addr.ipv4, &self.ipv4_net) {
+            match self.allocate_v4(net) {
+                Ok(v4) => addr.ipv4 = Some(v4),
+                Err(e) => {
+                    self.release(&addr);
+                    return Err(e);
+                }
+            }
+        }
+        if let (None, Some(net)) = (addr.ipv6, &self.ipv6_net) {
+            match self.allocate_v6(net) {
+                Ok(v6) => addr.ipv6 = Some(v6),
+                Err(e) => {
+                    self.release(&addr);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(addr)
+    }
+
     /// Release an address back to the pool
     pub fn release(&self, addr: &VpnAddress) {
         if let Some(v4) = addr.ipv4 {
@@ -386,6 +446,31 @@ mod tests {
         assert_eq!(pool.gateway_v6(), Some("fd00::1".parse().unwrap()));
     }
 
+    #[test]
+    fn test_dual_stack_pool() {
+        let pool = AddressPool::new(
+            Some("10.8.0.0/24".parse().unwrap()),
+            Some("fd00:8::/122".parse().unwrap()),
+        );
+
+        let addr = pool.allocate().unwrap();
+        assert_eq!(addr, VpnAddress::dual("10.8.0.2".parse().unwrap(), "fd00:8::2".parse().unwrap()));
+        assert!(addr.contains("fd00:8::2".parse().unwrap()));
+        assert_eq!(addr.addresses().count(), 2);
+
+        // An IPv4-only address gains an IPv6 address from the pool
+        let fitted = pool.allocate_fitted(VpnAddress::v4("10.8.0.9".parse().unwrap())).unwrap();
+        assert_eq!(fitted.ipv4, Some("10.8.0.9".parse().unwrap()));
+        assert_eq!(fitted.ipv6, Some("fd00:8::3".parse().unwrap()));
+
+        // IPv6 allocation stays inside a small range and does not leak IPv4
+        for _ in 0..60 {
+            pool.allocate().unwrap();
+        }
+        assert!(matches!(pool.allocate(), Err(CoreError::AddressPoolExhausted)));
+        assert_eq!(pool.stats().ipv4_allocated, 62);
+    }
+
     #[test]
     fn test_route() {
         let route = Route::new("192.168.1.0/24".parse().unwrap())
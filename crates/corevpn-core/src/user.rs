@@ -83,6 +83,12 @@ pub struct User {
     pub oauth_subject: Option<String>,
     /// Groups the user belongs to
     pub groups: Vec<String>,
+    /// Argon2id hash of the local password (PHC string format, never serialized)
+    #[serde(default, skip_serializing)]
+    pub password_hash: Option<String>,
+    /// TOTP secret encrypted with the server's TOTP key (never serialized)
+    #[serde(default, skip_serializing)]
+    pub totp_secret: Option<String>,
     /// Static VPN IP (if configured)
     pub static_ip: Option<crate::VpnAddress>,
     /// Custom routes for this user
@@ -111,6 +117,8 @@ impl User {
             oauth_provider: None,
             oauth_subject: None,
             groups: vec![],
+            password_hash: None,
+            totp_secret: None,
             static_ip: None,
             custom_routes: vec![],
             max_sessions: 3,
@@ -142,6 +150,8 @@ impl User {
             oauth_provider: Some(provider.to_string()),
             oauth_subject: Some(subject.to_string()),
             groups,
+            password_hash: None,
+            totp_secret: None,
             static_ip: None,
             custom_routes: vec![],
             max_sessions: 3,
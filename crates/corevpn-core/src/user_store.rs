@@ -0,0 +1,410 @@
+//! SQLite User Store
+//!
+//! Persists users in a SQLite database under the server's data directory.
+//! The schema is versioned with `PRAGMA user_version` and upgraded on open.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow};
+use sqlx::{Executor, Row};
+use tracing::warn;
+
+use crate::user::{User, UserId, UserRole, UserStore};
+use crate::{CoreError, Result};
+
+/// File name of the user database in the data directory
+pub const USER_DB_FILE: &str = "users.db";
+
+/// Schema migrations, applied in order; the index + 1 is the schema version
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE users (
+        id TEXT PRIMARY KEY NOT NULL,
+        email TEXT COLLATE NOCASE,
+        name TEXT,
+        role TEXT NOT NULL,
+        enabled INTEGER NOT NULL,
+        oauth_provider TEXT,
+        oauth_subject TEXT,
+        static_ip TEXT,
+        custom_routes TEXT NOT NULL,
+        max_sessions INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        last_login TEXT,
+        expires_at TEXT,
+        metadata TEXT NOT NULL
+    );
+
+    CREATE INDEX idx_users_email ON users(email);
+    CREATE INDEX idx_users_oauth ON users(oauth_provider, oauth_subject);
+
+    CREATE TABLE user_groups (
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        group_name TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (user_id, group_name)
+    );
+
+    CREATE INDEX idx_user_groups_group ON user_groups(group_name);
+    "#,
+    "ALTER TABLE users ADD COLUMN password_hash TEXT;",
+    "ALTER TABLE users ADD COLUMN totp_secret TEXT;",
+];
+
+/// Columns selected for a user row
+const USER_COLUMNS: &str = "id, email, name, role, enabled, oauth_provider, oauth_subject, \
+    static_ip, custom_routes, max_sessions, created_at, last_login, expires_at, metadata, password_hash, \
+    totp_secret";
+
+/// SQLite-backed user store
+pub struct SqliteUserStore {
+    pool: SqlitePool,
+}
+
+impl SqliteUserStore {
+    /// Open the user database in a data directory, creating it if needed
+    pub async fn open_in(data_dir: &Path) -> Result<Self> {
+        Self::open(&data_dir.join(USER_DB_FILE)).await
+    }
+
+    /// Open the user database at `path`, creating it if needed
+    pub async fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+            .synchronous(sqlx::sqlite::SqliteSynchronous::Normal);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .map_err(storage_error)?;
+
+        let store = Self { pool };
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    /// Current schema version
+    pub async fn schema_version(&self) -> Result<usize> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(storage_error)?;
+        Ok(version as usize)
+    }
+
+    /// Apply pending schema migrations
+    async fn migrate(&self) -> Result<()> {
+        let current = self.schema_version().await?;
+        if current > MIGRATIONS.len() {
+            return Err(CoreError::StorageError(format!(
+                "user database schema version {} is newer than supported ({})",
+                current,
+                MIGRATIONS.len()
+            )));
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+            let version = format!("PRAGMA user_version = {}", index + 1);
+            let mut tx = self.pool.begin().await.map_err(storage_error)?;
+            tx.execute(sqlx::raw_sql(migration)).await.map_err(storage_error)?;
+            tx.execute(sqlx::raw_sql(&version)).await.map_err(storage_error)?;
+            tx.commit().await.map_err(storage_error)?;
+        }
+        Ok(())
+    }
+
+    /// Load users matching a `WHERE` clause and its bound parameters
+    async fn query_users(&self, filter: &str, params: &[&str]) -> Result<Vec<User>> {
+        let sql = format!("SELECT {} FROM users {} ORDER BY created_at, id", USER_COLUMNS, filter);
+        let mut query = sqlx::query(&sql);
+        for param in params {
+            query = query.bind(*param);
+        }
+        let rows = query.fetch_all(&self.pool).await.map_err(storage_error)?;
+        let mut users = rows.iter().map(user_from_row).collect::<Result<Vec<_>>>()?;
+        if users.is_empty() {
+            return Ok(users);
+        }
+
+        // Attach group memberships
+        let sql = format!(
+            "SELECT user_id, group_name FROM user_groups \
+             WHERE user_id IN (SELECT id FROM users {}) ORDER BY user_id, position",
+            filter
+        );
+        let mut query = sqlx::query(&sql);
+        for param in params {
+            query = query.bind(*param);
+        }
+        let group_rows = query.fetch_all(&self.pool).await.map_err(storage_error)?;
+
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        for row in group_rows {
+            groups.entry(row.get("user_id")).or_default().push(row.get("group_name"));
+        }
+        for user in &mut users {
+            user.groups = groups.remove(user.id.as_str()).unwrap_or_default();
+        }
+        Ok(users)
+    }
+
+    async fn query_user(&self, filter: &str, params: &[&str]) -> Option<User> {
+        match self.query_users(filter, params).await {
+            Ok(users) => users.into_iter().next(),
+            Err(e) => {
+                warn!("User lookup failed: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn query_list(&self, filter: &str, params: &[&str]) -> Vec<User> {
+        self.query_users(filter, params).await.unwrap_or_else(|e| {
+            warn!("User query failed: {}", e);
+            Vec::new()
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStore for SqliteUserStore {
+    async fn get_user(&self, id: &UserId) -> Option<User> {
+        self.query_user("WHERE id = ?", &[id.as_str()]).await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Option<User> {
+        self.query_user("WHERE email = ?", &[email]).await
+    }
+
+    async fn get_user_by_oauth(&self, provider: &str, subject: &str) -> Option<User> {
+        self.query_user("WHERE oauth_provider = ? AND oauth_subject = ?", &[provider, subject])
+            .await
+    }
+
+    async fn upsert_user(&self, user: &User) -> Result<()> {
+        let mut tx = self.pool.begin().await.map_err(storage_error)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO users
+            (id, email, name, role, enabled, oauth_provider, oauth_subject, static_ip,
+             custom_routes, max_sessions, created_at, last_login, expires_at, metadata, password_hash,
+             totp_secret)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+                email = excluded.email,
+                name = excluded.name,
+                role = excluded.role,
+                enabled = excluded.enabled,
+                oauth_provider = excluded.oauth_provider,
+                oauth_subject = excluded.oauth_subject,
+                static_ip = excluded.static_ip,
+                custom_routes = excluded.custom_routes,
+                max_sessions = excluded.max_sessions,
+                created_at = excluded.created_at,
+                last_login = excluded.last_login,
+                expires_at = excluded.expires_at,
+                metadata = excluded.metadata,
+                password_hash = excluded.password_hash,
+                totp_secret = excluded.totp_secret
+            "#,
+        )
+        .bind(user.id.as_str())
+        .bind(user.email.as_deref())
+        .bind(user.name.as_deref())
+        .bind(to_json(&user.role)?)
+        .bind(user.enabled)
+        .bind(user.oauth_provider.as_deref())
+        .bind(user.oauth_subject.as_deref())
+        .bind(user.static_ip.as_ref().map(to_json).transpose()?)
+        .bind(to_json(&user.custom_routes)?)
+        .bind(i64::from(user.max_sessions))
+        .bind(user.created_at.to_rfc3339())
+        .bind(user.last_login.map(|t| t.to_rfc3339()))
+        .bind(user.expires_at.map(|t| t.to_rfc3339()))
+        .bind(to_json(&user.metadata)?)
+        .bind(user.password_hash.as_deref())
+        .bind(user.totp_secret.as_deref())
+        .execute(&mut *tx)
+        .await
+        .map_err(storage_error)?;
+
+        sqlx::query("DELETE FROM user_groups WHERE user_id = ?")
+            .bind(user.id.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(storage_error)?;
+
+        for (position, group) in user.groups.iter().enumerate() {
+            sqlx::query(
+                "INSERT OR IGNORE INTO user_groups (user_id, group_name, position) VALUES (?, ?, ?)",
+            )
+            .bind(user.id.as_str())
+            .bind(group)
+            .bind(position as i64)
+            .execute(&mut *tx)
+            .await
+            .map_err(storage_error)?;
+        }
+
+        tx.commit().await.map_err(storage_error)
+    }
+
+    async fn delete_user(&self, id: &UserId) -> Result<()> {
+        sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(id.as_str())
+            .execute(&self.pool)
+            .await
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn list_users(&self) -> Vec<User> {
+        self.query_list("", &[]).await
+    }
+
+    async fn get_users_in_group(&self, group: &str) -> Vec<User> {
+        self.query_list(
+            "WHERE id IN (SELECT user_id FROM user_groups WHERE group_name = ?)",
+            &[group],
+        )
+        .await
+    }
+}
+
+fn user_from_row(row: &SqliteRow) -> Result<User> {
+    let static_ip: Option<String> = row.get("static_ip");
+    let max_sessions: i64 = row.get("max_sessions");
+
+    Ok(User {
+        id: UserId::new(row.get::<String, _>("id")),
+        email: row.get("email"),
+        name: row.get("name"),
+        role: from_json::<UserRole>(row.get("role"))?,
+        enabled: row.get("enabled"),
+        oauth_provider: row.get("oauth_provider"),
+        oauth_subject: row.get("oauth_subject"),
+        groups: Vec::new(),
+        password_hash: row.get("password_hash"),
+        totp_secret: row.get("totp_secret"),
+        static_ip: static_ip.as_deref().map(from_json).transpose()?,
+        custom_routes: from_json(row.get("custom_routes"))?,
+        max_sessions: u32::try_from(max_sessions).unwrap_or(u32::MAX),
+        created_at: parse_time(row.get("created_at"))?,
+        last_login: row.get::<Option<&str>, _>("last_login").map(parse_time).transpose()?,
+        expires_at: row.get::<Option<&str>, _>("expires_at").map(parse_time).transpose()?,
+        metadata: from_json(row.get("metadata"))?,
+    })
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String> {
+    serde_json::to_string(value).map_err(|e| CoreError::StorageError(e.to_string()))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(value: &str) -> Result<T> {
+    serde_json::from_str(value).map_err(|e| CoreError::StorageError(e.to_string()))
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| CoreError::StorageError(format!("invalid timestamp {:?}: {}", value, e)))
+}
+
+fn storage_error(e: sqlx::Error) -> CoreError {
+    CoreError::StorageError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Route, VpnAddress};
+
+    fn contractor() -> User {
+        let mut user = User::new(UserId::from_email("bob@example.com"))
+            .with_email("Bob@Example.com")
+            .with_name("Bob")
+            .with_role(UserRole::Custom("contractor".into()));
+        user.oauth_provider = Some("okta".into());
+        user.oauth_subject = Some("00u1".into());
+        user.add_group("contractors");
+        user.add_group("vpn-users");
+        user.static_ip = Some(VpnAddress::v4("10.8.0.50".parse().unwrap()));
+        user.custom_routes = vec![Route::new("10.20.0.0/16".parse().unwrap())];
+        user.expires_at = Some(Utc::now() + chrono::Duration::days(30));
+        user.metadata.insert("ticket".into(), "OPS-42".into());
+        user.password_hash = Some("$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".into());
+        user.totp_secret = Some("bm9uY2UtYW5kLWNpcGhlcnRleHQ".into());
+        user.record_login();
+        user
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let user = contractor();
+
+        {
+            let store = SqliteUserStore::open_in(dir.path()).await.unwrap();
+            store.upsert_user(&user).await.unwrap();
+        }
+
+        // Reopening keeps the data and does not re-run migrations
+        let store = SqliteUserStore::open_in(dir.path()).await.unwrap();
+        assert_eq!(store.schema_version().await.unwrap(), MIGRATIONS.len());
+
+        let loaded = store.get_user(&user.id).await.unwrap();
+        assert_eq!(loaded.email, user.email);
+        assert_eq!(loaded.role, user.role);
+        assert_eq!(loaded.groups, user.groups);
+        assert_eq!(loaded.static_ip, user.static_ip);
+        assert_eq!(loaded.custom_routes, user.custom_routes);
+        assert_eq!(loaded.metadata, user.metadata);
+        assert_eq!(loaded.password_hash, user.password_hash);
+        assert_eq!(loaded.totp_secret, user.totp_secret);
+        assert_eq!(loaded.expires_at.map(|t| t.timestamp()), user.expires_at.map(|t| t.timestamp()));
+        assert_eq!(loaded.last_login.map(|t| t.timestamp()), user.last_login.map(|t| t.timestamp()));
+    }
+
+    #[tokio::test]
+    async fn test_lookups() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteUserStore::open_in(dir.path()).await.unwrap();
+        let bob = contractor();
+        let alice = User::new(UserId::new("alice")).with_role(UserRole::Admin);
+        store.upsert_user(&bob).await.unwrap();
+        store.upsert_user(&alice).await.unwrap();
+
+        assert_eq!(store.get_user_by_email("bob@example.com").await.unwrap().id, bob.id);
+        assert_eq!(store.get_user_by_oauth("okta", "00u1").await.unwrap().id, bob.id);
+        assert!(store.get_user_by_oauth("azure", "00u1").await.is_none());
+        assert_eq!(store.list_users().await.len(), 2);
+
+        let contractors = store.get_users_in_group("contractors").await;
+        assert_eq!(contractors.len(), 1);
+        assert_eq!(contractors[0].id, bob.id);
+
+        // Updating replaces group memberships
+        let mut bob = bob;
+        bob.remove_group("contractors");
+        bob.enabled = false;
+        store.upsert_user(&bob).await.unwrap();
+        assert!(store.get_users_in_group("contractors").await.is_empty());
+        assert!(!store.get_user(&bob.id).await.unwrap().enabled);
+
+        store.delete_user(&bob.id).await.unwrap();
+        assert!(store.get_user(&bob.id).await.is_none());
+        assert!(store.get_users_in_group("vpn-users").await.is_empty());
+        assert_eq!(store.list_users().await.len(), 1);
+    }
+}
@@ -5,28 +5,26 @@
 use std::time::{Duration, SystemTime};
 
 use rcgen::{
-    BasicConstraints, Certificate as RcgenCertificate, CertificateParams, DistinguishedName,
-    DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
-    SanType,
+    BasicConstraints, Certificate as RcgenCertificate, CertificateParams,
+    CertificateRevocationListParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, RevokedCertParams, SanType, SerialNumber,
 };
 use serde::{Deserialize, Serialize};
+use x509_cert::der::oid::db::{rfc4519, rfc5280, rfc5912, rfc8410};
+use x509_cert::der::oid::ObjectIdentifier;
+use x509_cert::der::{Decode, Encode, Tag, Tagged};
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::SubjectAltName;
 
+use crate::crl::{serial_to_hex, CertificateIndex, IssuedCertificate};
 use crate::{CryptoError, Result};
 
-/// Extract Common Name from a PEM certificate (simple parser)
-fn extract_cn_from_pem(pem: &str) -> Option<String> {
-    // Simple extraction - look for CN= in the subject line
-    // This is a basic parser for display purposes
-    for line in pem.lines() {
-        if line.contains("Subject:") && line.contains("CN=") {
-            if let Some(start) = line.find("CN=") {
-                let rest = &line[start + 3..];
-                let end = rest.find(',').unwrap_or(rest.len());
-                return Some(rest[..end].trim().to_string());
-            }
-        }
-    }
-    None
+/// Generate a random positive 128-bit serial number
+fn random_serial() -> SerialNumber {
+    let mut bytes: [u8; 16] = crate::random_bytes();
+    // Keep the DER integer positive and free of leading zero octets
+    bytes[0] = (bytes[0] & 0x7f).max(1);
+    SerialNumber::from_slice(&bytes)
 }
 
 /// Certificate Authority for issuing client/server certificates
@@ -84,33 +82,15 @@ impl CertificateAuthority {
     /// Load CA from PEM-encoded certificate and private key
     ///
     /// Note: This creates a new CA certificate with the same key pair.
-    /// The original certificate PEM is preserved for distribution.
+    /// The subject and key identifier are taken from the original certificate
+    /// so that issued certificates and CRLs chain to it; the original PEM is
+    /// preserved for distribution.
     pub fn from_pem(cert_pem: &str, key_pem: &str) -> Result<Self> {
         let key_pair = KeyPair::from_pem(key_pem)
             .map_err(|e| CryptoError::InvalidPem(e.to_string()))?;
 
-        // Extract CN from existing cert if possible, otherwise use a default
-        let common_name = extract_cn_from_pem(cert_pem).unwrap_or_else(|| "CoreVPN CA".to_string());
-
-        // Create new CA params with the existing key pair
-        let mut params = CertificateParams::new(vec![])
-            .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
-
-        let mut dn = DistinguishedName::new();
-        dn.push(DnType::CommonName, &common_name);
-        params.distinguished_name = dn;
-
-        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-        params.key_usages = vec![
-            KeyUsagePurpose::KeyCertSign,
-            KeyUsagePurpose::CrlSign,
-            KeyUsagePurpose::DigitalSignature,
-        ];
-
-        // Long validity for CA
-        let now = SystemTime::now();
-        params.not_before = now.into();
-        params.not_after = (now + Duration::from_secs(365 * 10 * 24 * 60 * 60)).into();
+        let params = CertificateParams::from_ca_cert_pem(cert_pem)
+            .map_err(|e| CryptoError::InvalidPem(e.to_string()))?;
 
         let ca_cert = params.self_signed(&key_pair)
             .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
@@ -172,6 +152,9 @@ impl CertificateAuthority {
         ];
         params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
         params.subject_alt_names = sans;
+        let serial_number = random_serial();
+        let serial = serial_to_hex(serial_number.as_ref());
+        params.serial_number = Some(serial_number);
 
         // Validity
         let now = SystemTime::now();
@@ -182,6 +165,7 @@ impl CertificateAuthority {
             .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
 
         Ok(Certificate {
+            serial,
             cert_pem: cert.pem(),
             key_pem: server_key.serialize_pem(),
             ca_pem: self.cert_pem.clone(),
@@ -223,6 +207,9 @@ impl CertificateAuthority {
         params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
         params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
         params.subject_alt_names = sans;
+        let serial_number = random_serial();
+        let serial = serial_to_hex(serial_number.as_ref());
+        params.serial_number = Some(serial_number);
 
         // Validity - short lifetime for clients (security best practice)
         let now = SystemTime::now();
@@ -233,16 +220,129 @@ impl CertificateAuthority {
             .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
 
         Ok(Certificate {
+            serial,
             cert_pem: cert.pem(),
             key_pem: client_key.serialize_pem(),
             ca_pem: self.cert_pem.clone(),
         })
     }
+
+    /// Check that a certificate was issued by this CA and describe it for the index
+    ///
+    /// Brings certificates issued before the index existed under revocation.
+    pub fn verify_issued(&self, cert_pem: &str) -> Result<IssuedCertificate> {
+        let der = |pem_str: &str| {
+            pem::parse(pem_str)
+                .map(pem::Pem::into_contents)
+                .map_err(|e| CryptoError::InvalidPem(e.to_string()))
+        };
+        let parse = |der: &[u8]| {
+            x509_cert::Certificate::from_der(der).map_err(|e| CryptoError::CertificateError(e.to_string()))
+        };
+        let ca = parse(&der(&self.cert_pem)?)?;
+        let cert_der = der(cert_pem)?;
+        let cert = parse(&cert_der)?;
+        let tbs = &cert.tbs_certificate;
+
+        if tbs.issuer != ca.tbs_certificate.subject {
+            return Err(CryptoError::CertificateError("certificate was not issued by this CA".into()));
+        }
+        if tbs.subject == tbs.issuer {
+            return Err(CryptoError::CertificateError("CA certificates are not tracked".into()));
+        }
+
+        let algorithm = signature_algorithm(&cert.signature_algorithm.oid).ok_or_else(|| {
+            CryptoError::CertificateError(format!("unsupported signature algorithm {}", cert.signature_algorithm.oid))
+        })?;
+        let ca_key = ca.tbs_certificate.subject_public_key_info.subject_public_key.raw_bytes();
+        let signed = tbs.to_der().map_err(|e| CryptoError::CertificateError(e.to_string()))?;
+        let signature = cert.signature.as_bytes()
+            .ok_or_else(|| CryptoError::CertificateError("malformed signature".into()))?;
+        ring::signature::UnparsedPublicKey::new(algorithm, ca_key)
+            .verify(&signed, signature)
+            .map_err(|_| CryptoError::CertificateError("certificate was not signed by this CA".into()))?;
+
+        let identity = CertificateIdentity::from_der(&cert_der)?;
+        Ok(IssuedCertificate {
+            serial: identity.serial,
+            common_name: identity.common_name.unwrap_or_default(),
+            email: identity.email,
+            issued_at: tbs.validity.not_before.to_system_time().into(),
+            expires_at: tbs.validity.not_after.to_system_time().into(),
+            revoked_at: None,
+            revocation_reason: None,
+        })
+    }
+
+    /// Generate a CRL listing every revoked, unexpired certificate in the index
+    ///
+    /// Each call advances the index's CRL number, so the index should be
+    /// saved afterwards.
+    pub fn generate_crl(&self, index: &mut CertificateIndex, validity_days: u32) -> Result<String> {
+        let now = SystemTime::now();
+        let revoked_certs = index
+            .revoked()
+            .filter(|entry| SystemTime::from(entry.expires_at) > now)
+            .map(|entry| {
+                let serial = hex_to_bytes(&entry.serial)?;
+                Ok(RevokedCertParams {
+                    serial_number: SerialNumber::from_slice(&serial),
+                    revocation_time: entry.revoked_at.map(SystemTime::from).unwrap_or(now).into(),
+                    reason_code: entry.revocation_reason.map(Into::into),
+                    invalidity_date: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let params = CertificateRevocationListParams {
+            this_update: now.into(),
+            next_update: (now + Duration::from_secs(validity_days.max(1) as u64 * 24 * 60 * 60)).into(),
+            crl_number: SerialNumber::from(index.next_crl_number()),
+            issuing_distribution_point: None,
+            revoked_certs,
+            key_identifier_method: self.ca_cert.params().key_identifier_method.clone(),
+        };
+
+        let crl = params.signed_by(&self.ca_cert, &self.key_pair)
+            .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
+
+        crl.pem().map_err(|e| CryptoError::CertificateError(e.to_string()))
+    }
+}
+
+/// Signature algorithm a CA could have signed a certificate with
+fn signature_algorithm(oid: &ObjectIdentifier) -> Option<&'static dyn ring::signature::VerificationAlgorithm> {
+    use ring::signature;
+
+    match *oid {
+        rfc8410::ID_ED_25519 => Some(&signature::ED25519),
+        rfc5912::ECDSA_WITH_SHA_256 => Some(&signature::ECDSA_P256_SHA256_ASN1),
+        rfc5912::ECDSA_WITH_SHA_384 => Some(&signature::ECDSA_P384_SHA384_ASN1),
+        rfc5912::SHA_256_WITH_RSA_ENCRYPTION => Some(&signature::RSA_PKCS1_2048_8192_SHA256),
+        _ => None,
+    }
+}
+
+/// Decode a hex serial number
+fn hex_to_bytes(hex: &str) -> Result<Vec<u8>> {
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return Err(CryptoError::CertificateError(format!("Invalid serial number: {}", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| CryptoError::CertificateError(format!("Invalid serial number: {}", hex)))
+        })
+        .collect()
 }
 
 /// Issued certificate with private key
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Certificate {
+    /// Serial number (lowercase hex)
+    #[serde(default)]
+    pub serial: String,
     /// Certificate in PEM format
     pub cert_pem: String,
     /// Private key in PEM format
@@ -263,6 +363,56 @@ impl Certificate {
     }
 }
 
+/// Identity carried by a client certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateIdentity {
+    /// Serial number (lowercase hex)
+    pub serial: String,
+    /// Subject common name
+    pub common_name: Option<String>,
+    /// Email address from the subject alternative names
+    pub email: Option<String>,
+}
+
+impl CertificateIdentity {
+    /// Parse the identity from a DER-encoded certificate
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let cert = x509_cert::Certificate::from_der(der)
+            .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
+        let tbs = &cert.tbs_certificate;
+
+        let common_name = tbs.subject.0
+            .iter()
+            .flat_map(|rdn| rdn.0.iter())
+            .find(|atv| atv.oid == rfc4519::CN)
+            .filter(|atv| matches!(atv.value.tag(), Tag::Utf8String | Tag::PrintableString | Tag::Ia5String))
+            .and_then(|atv| std::str::from_utf8(atv.value.value()).ok())
+            .map(String::from);
+
+        let email = tbs.extensions
+            .iter()
+            .flatten()
+            .filter(|ext| ext.extn_id == rfc5280::ID_CE_SUBJECT_ALT_NAME)
+            .filter_map(|ext| SubjectAltName::from_der(ext.extn_value.as_bytes()).ok())
+            .flat_map(|san| san.0)
+            .find_map(|name| match name {
+                GeneralName::Rfc822Name(email) => Some(email.to_string()),
+                _ => None,
+            });
+
+        Ok(Self {
+            serial: serial_to_hex(tbs.serial_number.as_bytes()),
+            common_name,
+            email,
+        })
+    }
+
+    /// VPN username: the common name, falling back to the email address
+    pub fn username(&self) -> Option<&str> {
+        self.common_name.as_deref().or(self.email.as_deref())
+    }
+}
+
 /// Certificate signing request (for external CAs)
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CertificateRequest {
@@ -415,6 +565,62 @@ mod tests {
         assert!(ovpn.contains("<key>"));
     }
 
+    #[test]
+    fn test_reloaded_ca_keeps_issuer() {
+        use x509_cert::der::{Decode, DecodePem};
+
+        let ca = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+        let reloaded = CertificateAuthority::from_pem(ca.certificate_pem(), &ca.private_key_pem()).unwrap();
+        let cert = reloaded.issue_client_certificate("alice", None, 30).unwrap();
+
+        let ca_cert = x509_cert::Certificate::from_pem(ca.certificate_pem()).unwrap();
+        let client_cert = x509_cert::Certificate::from_der(pem::parse(&cert.cert_pem).unwrap().contents()).unwrap();
+        assert_eq!(client_cert.tbs_certificate.issuer, ca_cert.tbs_certificate.subject);
+        assert_eq!(crate::crl::serial_to_hex(client_cert.tbs_certificate.serial_number.as_bytes()), cert.serial);
+    }
+
+    #[test]
+    fn test_certificate_identity() {
+        let ca = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+
+        let cert = ca.issue_client_certificate("alice", Some("alice@example.com"), 30).unwrap();
+        let der = pem::parse(&cert.cert_pem).unwrap();
+        let identity = CertificateIdentity::from_der(der.contents()).unwrap();
+        assert_eq!(identity.serial, cert.serial);
+        assert_eq!(identity.common_name.as_deref(), Some("alice"));
+        assert_eq!(identity.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(identity.username(), Some("alice"));
+
+        let cert = ca.issue_client_certificate("bob", None, 30).unwrap();
+        let der = pem::parse(&cert.cert_pem).unwrap();
+        let identity = CertificateIdentity::from_der(der.contents()).unwrap();
+        assert_eq!(identity.email, None);
+        assert_eq!(identity.username(), Some("bob"));
+    }
+
+    #[test]
+    fn test_verify_issued() {
+        let ca = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+        let cert = ca.issue_client_certificate("alice", Some("alice@example.com"), 30).unwrap();
+
+        let entry = ca.verify_issued(&cert.cert_pem).unwrap();
+        assert_eq!(entry.serial, cert.serial);
+        assert_eq!(entry.common_name, "alice");
+        assert_eq!(entry.email.as_deref(), Some("alice@example.com"));
+        assert!(entry.is_active());
+        assert_eq!((entry.expires_at - entry.issued_at).num_days(), 30);
+
+        // A reloaded CA still recognizes what it issued
+        let reloaded = CertificateAuthority::from_pem(ca.certificate_pem(), &ca.private_key_pem()).unwrap();
+        assert!(reloaded.verify_issued(&cert.cert_pem).is_ok());
+
+        // Other CAs' certificates, even with the same name, and the CA itself are refused
+        let other = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+        let forged = other.issue_client_certificate("alice", None, 30).unwrap();
+        assert!(ca.verify_issued(&forged.cert_pem).is_err());
+        assert!(ca.verify_issued(ca.certificate_pem()).is_err());
+    }
+
     #[test]
     fn test_static_key_roundtrip() {
         let key = generate_static_key();
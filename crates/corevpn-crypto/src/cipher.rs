@@ -7,10 +7,20 @@
 //! - Cipher instances are cached in PacketCipher
 //! - Counter-based nonces avoid RNG syscalls
 //! - Pre-allocated output buffers reduce allocations
+//! - In-place sealing into reserved headroom and tailroom avoids copies
 //! - Inlined hot paths for better performance
+//!
+//! # Usage Limits
+//! Every data channel key tracks how much it has encrypted and how many
+//! forgeries it has rejected in a [`KeyUsage`]. Its [`KeyStatus`] asks for
+//! a renegotiation well before a [`UsageLimits`] bound, and a key that
+//! reaches one is refused.
+
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use aes_gcm::{Aes256Gcm, KeyInit};
-use chacha20poly1305::{ChaCha20Poly1305, aead::AeadCore};
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::{ChaCha20Poly1305, aead::{AeadCore, AeadInPlace}};
 use zeroize::ZeroizeOnDrop;
 use serde::{Serialize, Deserialize};
 
@@ -51,6 +61,153 @@ impl CipherSuite {
     pub const fn tag_size(&self) -> usize {
         Self::TAG_SIZE
     }
+
+    /// OpenVPN cipher name (as used in `cipher` and `data-ciphers`)
+    pub const fn openvpn_name(&self) -> &'static str {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => "CHACHA20-POLY1305",
+            CipherSuite::Aes256Gcm => "AES-256-GCM",
+        }
+    }
+
+    /// Usage limits for one key of this cipher suite
+    pub const fn usage_limits(&self) -> UsageLimits {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => UsageLimits {
+                encrypted_blocks: None,
+                failed_decryptions: 1 << 38,
+                packets: u64::MAX,
+            },
+            CipherSuite::Aes256Gcm => UsageLimits {
+                encrypted_blocks: Some(1 << 36),
+                failed_decryptions: 1 << 62,
+                packets: u64::MAX,
+            },
+        }
+    }
+}
+
+/// How much one AEAD key may be used
+///
+/// Follows draft-irtf-cfrg-aead-limits for an attacker advantage of 2^-57,
+/// like OpenVPN: AES-GCM seals at most 2^36 plaintext blocks plus packets,
+/// while ChaCha20-Poly1305 has no practical confidentiality limit. With
+/// packets of up to 2^8 blocks, a key may reject 2^62 (AES-GCM) or 2^38
+/// (ChaCha20-Poly1305) forgeries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageLimits {
+    /// Plaintext blocks plus packets encrypted (confidentiality limit)
+    pub encrypted_blocks: Option<u64>,
+    /// Failed decryptions (integrity limit)
+    pub failed_decryptions: u64,
+    /// Packets encrypted before the packet ID runs out
+    pub packets: u64,
+}
+
+impl UsageLimits {
+    /// Limit the packets encrypted to a packet ID space
+    pub const fn with_packet_ids(mut self, packets: u64) -> Self {
+        if packets < self.packets {
+            self.packets = packets;
+        }
+        self
+    }
+}
+
+/// How close a key is to its usage limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyStatus {
+    /// Within all limits
+    Usable,
+    /// Past 7/8 of a limit, so a new key should be negotiated
+    Renegotiate,
+    /// A limit was reached and the key is refused
+    Exhausted,
+}
+
+impl KeyStatus {
+    fn of(used: u64, limit: u64) -> Self {
+        if used >= limit {
+            KeyStatus::Exhausted
+        } else if used >= limit - limit / 8 {
+            KeyStatus::Renegotiate
+        } else {
+            KeyStatus::Usable
+        }
+    }
+}
+
+/// AEAD block size used to count encrypted blocks
+const AEAD_BLOCK_SIZE: usize = 16;
+
+/// Usage of one data channel key, shared between threads
+#[derive(Debug)]
+pub struct KeyUsage {
+    limits: UsageLimits,
+    /// Packets encrypted
+    packets: AtomicU64,
+    /// Plaintext blocks encrypted
+    blocks: AtomicU64,
+    /// Packets that failed authentication
+    failed_decryptions: AtomicU64,
+}
+
+impl KeyUsage {
+    /// Start counting usage against the given limits
+    pub fn new(limits: UsageLimits) -> Self {
+        Self {
+            limits,
+            packets: AtomicU64::new(0),
+            blocks: AtomicU64::new(0),
+            failed_decryptions: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a packet of `len` plaintext bytes about to be encrypted
+    ///
+    /// Fails with [`CryptoError::KeyExpired`] instead of going past a limit.
+    #[inline]
+    pub fn record_encrypt(&self, len: usize) -> Result<()> {
+        let blocks = len.div_ceil(AEAD_BLOCK_SIZE) as u64;
+        let packets = self.packets.fetch_add(1, Ordering::Relaxed) + 1;
+        let blocks = self.blocks.fetch_add(blocks, Ordering::Relaxed) + blocks;
+
+        let over_blocks = self.limits.encrypted_blocks.is_some_and(|limit| blocks + packets > limit);
+        if packets > self.limits.packets || over_blocks {
+            return Err(CryptoError::KeyExpired);
+        }
+        Ok(())
+    }
+
+    /// Check that the key may still decrypt
+    #[inline]
+    pub fn check_decrypt(&self) -> Result<()> {
+        if self.failed_decryptions.load(Ordering::Relaxed) >= self.limits.failed_decryptions {
+            return Err(CryptoError::KeyExpired);
+        }
+        Ok(())
+    }
+
+    /// Count a packet that failed authentication
+    #[inline]
+    pub fn record_decrypt_failure(&self) {
+        self.failed_decryptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How close the key is to its limits
+    pub fn status(&self) -> KeyStatus {
+        let packets = self.packets.load(Ordering::Relaxed);
+        let blocks = self.blocks.load(Ordering::Relaxed);
+        let failed = self.failed_decryptions.load(Ordering::Relaxed);
+
+        let confidentiality = match self.limits.encrypted_blocks {
+            Some(limit) => KeyStatus::of(blocks + packets, limit),
+            None => KeyStatus::Usable,
+        };
+        confidentiality
+            .max(KeyStatus::of(packets, self.limits.packets))
+            .max(KeyStatus::of(failed, self.limits.failed_decryptions))
+    }
 }
 
 /// Data channel encryption key with secure memory handling
@@ -85,6 +242,15 @@ impl Drop for DataChannelKey {
 
 impl ZeroizeOnDrop for DataChannelKey {}
 
+/// Bytes reserved in front of a plaintext packet for data channel headers
+///
+/// Enough for the largest header (P_DATA_V2 opcode and peer ID, packet ID
+/// and a leading authentication tag), so sealing never moves the payload.
+pub const PACKET_HEADROOM: usize = 32;
+
+/// Spare capacity kept after a plaintext packet for a trailing tag
+pub const PACKET_TAILROOM: usize = CipherSuite::TAG_SIZE;
+
 /// AEAD cipher for encrypting/decrypting data channel packets
 pub struct Cipher {
     inner: CipherInner,
@@ -139,25 +305,65 @@ impl Cipher {
     /// Buffer must have capacity for plaintext + TAG_SIZE bytes.
     #[inline]
     pub fn encrypt_into(&self, nonce: &[u8; 12], plaintext: &[u8], aad: &[u8], out: &mut Vec<u8>) -> Result<usize> {
-        use chacha20poly1305::aead::Aead;
-        use aes_gcm::aead::Payload;
-
-        let payload = Payload { msg: plaintext, aad };
         let start_len = out.len();
+        out.extend_from_slice(plaintext);
+
+        let tag = self.encrypt_in_place_detached(nonce, aad, &mut out[start_len..]);
+        match tag {
+            Ok(tag) => out.extend_from_slice(&tag),
+            Err(e) => {
+                out.truncate(start_len);
+                return Err(e);
+            }
+        }
+        Ok(out.len() - start_len)
+    }
 
-        let ciphertext = match &self.inner {
+    /// Encrypt a buffer in place, returning the authentication tag
+    ///
+    /// The tag is kept apart from the ciphertext so callers can place it
+    /// wherever their packet format wants it.
+    #[inline]
+    pub fn encrypt_in_place_detached(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<[u8; CipherSuite::TAG_SIZE]> {
+        let tag = match &self.inner {
             CipherInner::ChaCha(cipher) => {
-                cipher.encrypt(nonce.into(), payload)
+                cipher.encrypt_in_place_detached(nonce.into(), aad, buffer)
                     .map_err(|_| CryptoError::EncryptionFailed("ChaCha20-Poly1305 encryption failed"))?
             }
             CipherInner::Aes(cipher) => {
-                cipher.encrypt(nonce.into(), payload)
+                cipher.encrypt_in_place_detached(nonce.into(), aad, buffer)
                     .map_err(|_| CryptoError::EncryptionFailed("AES-256-GCM encryption failed"))?
             }
         };
+        Ok(tag.into())
+    }
 
-        out.extend_from_slice(&ciphertext);
-        Ok(out.len() - start_len)
+    /// Decrypt a buffer in place after verifying its detached tag
+    ///
+    /// The buffer is left untouched if authentication fails.
+    #[inline]
+    pub fn decrypt_in_place_detached(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8; CipherSuite::TAG_SIZE],
+    ) -> Result<()> {
+        match &self.inner {
+            CipherInner::ChaCha(cipher) => {
+                cipher.decrypt_in_place_detached(nonce.into(), aad, buffer, tag.into())
+                    .map_err(|_| CryptoError::DecryptionFailed)
+            }
+            CipherInner::Aes(cipher) => {
+                cipher.decrypt_in_place_detached(nonce.into(), aad, buffer, tag.into())
+                    .map_err(|_| CryptoError::DecryptionFailed)
+            }
+        }
     }
 
     /// Decrypt ciphertext with associated data
@@ -213,6 +419,8 @@ impl Cipher {
 /// - Pre-allocates output buffers with known capacity
 pub struct PacketCipher {
     cipher: Cipher,
+    /// Usage against the key's limits
+    usage: KeyUsage,
     /// Outgoing packet counter (used as nonce)
     tx_counter: u64,
     /// Replay protection window
@@ -228,6 +436,7 @@ impl PacketCipher {
     pub fn new(key: DataChannelKey) -> Self {
         Self {
             cipher: key.cipher(),
+            usage: KeyUsage::new(key.cipher_suite().usage_limits()),
             tx_counter: 0,
             rx_window: ReplayWindow::new(),
         }
@@ -238,6 +447,8 @@ impl PacketCipher {
     /// Returns: [8-byte packet_id | ciphertext | 16-byte tag]
     #[inline]
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.usage.record_encrypt(plaintext.len())?;
+
         // Increment counter (fail if overflow - extremely unlikely)
         self.tx_counter = self.tx_counter.checked_add(1)
             .ok_or(CryptoError::EncryptionFailed("packet counter overflow"))?;
@@ -268,6 +479,7 @@ impl PacketCipher {
     /// Buffer should be cleared before calling.
     #[inline]
     pub fn encrypt_into(&mut self, plaintext: &[u8], output: &mut Vec<u8>) -> Result<usize> {
+        self.usage.record_encrypt(plaintext.len())?;
         self.tx_counter = self.tx_counter.checked_add(1)
             .ok_or(CryptoError::EncryptionFailed("packet counter overflow"))?;
 
@@ -294,8 +506,10 @@ impl PacketCipher {
         let packet_id: [u8; 8] = packet[..8].try_into().unwrap();
         let counter = u64::from_be_bytes(packet_id);
 
+        self.usage.check_decrypt()?;
+
         // Check replay (inline for performance)
-        if !self.rx_window.check_and_update(counter) {
+        if !self.rx_window.check(counter) {
             return Err(CryptoError::ReplayDetected);
         }
 
@@ -304,7 +518,74 @@ impl PacketCipher {
         nonce[4..].copy_from_slice(&packet_id);
 
         // Decrypt
-        self.cipher.decrypt(&nonce, &packet[8..], &packet_id)
+        let plaintext = self.cipher.decrypt(&nonce, &packet[8..], &packet_id)
+            .inspect_err(|_| self.usage.record_decrypt_failure())?;
+
+        // Only authenticated packets may advance the replay window
+        self.rx_window.commit(counter);
+        Ok(plaintext)
+    }
+
+    /// Encrypt a packet in place
+    ///
+    /// `buf` holds [`PACKET_HEADROOM`] reserved bytes followed by the
+    /// plaintext. The packet ID is written into the headroom and the tag
+    /// appended, which does not allocate when [`PACKET_TAILROOM`] bytes of
+    /// spare capacity are reserved. Returns the offset where the packet
+    /// starts; the headroom before it is left for outer headers.
+    #[inline]
+    pub fn encrypt_in_place(&mut self, buf: &mut BytesMut) -> Result<usize> {
+        if buf.len() < PACKET_HEADROOM {
+            return Err(CryptoError::EncryptionFailed("missing packet headroom"));
+        }
+        self.usage.record_encrypt(buf.len() - PACKET_HEADROOM)?;
+
+        self.tx_counter = self.tx_counter.checked_add(1)
+            .ok_or(CryptoError::EncryptionFailed("packet counter overflow"))?;
+
+        let mut nonce = [0u8; 12];
+        let packet_id = self.tx_counter.to_be_bytes();
+        nonce[4..].copy_from_slice(&packet_id);
+
+        let tag = self.cipher.encrypt_in_place_detached(&nonce, &packet_id, &mut buf[PACKET_HEADROOM..])?;
+        buf.extend_from_slice(&tag);
+
+        let start = PACKET_HEADROOM - PACKET_HEADER_SIZE;
+        buf[start..PACKET_HEADROOM].copy_from_slice(&packet_id);
+        Ok(start)
+    }
+
+    /// Decrypt a packet in place with replay protection
+    ///
+    /// On success `buf` holds just the plaintext.
+    #[inline]
+    pub fn decrypt_in_place(&mut self, buf: &mut BytesMut) -> Result<()> {
+        const MIN_PACKET_SIZE: usize = PACKET_HEADER_SIZE + CipherSuite::TAG_SIZE;
+
+        if buf.len() < MIN_PACKET_SIZE {
+            return Err(CryptoError::DecryptionFailed);
+        }
+
+        let packet_id: [u8; 8] = buf[..8].try_into().unwrap();
+        let counter = u64::from_be_bytes(packet_id);
+
+        self.usage.check_decrypt()?;
+        if !self.rx_window.check(counter) {
+            return Err(CryptoError::ReplayDetected);
+        }
+
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&packet_id);
+
+        let tag_start = buf.len() - CipherSuite::TAG_SIZE;
+        let tag: [u8; CipherSuite::TAG_SIZE] = buf[tag_start..].try_into().unwrap();
+        self.cipher.decrypt_in_place_detached(&nonce, &packet_id, &mut buf[PACKET_HEADER_SIZE..tag_start], &tag)
+            .inspect_err(|_| self.usage.record_decrypt_failure())?;
+        self.rx_window.commit(counter);
+
+        buf.truncate(tag_start);
+        buf.advance(PACKET_HEADER_SIZE);
+        Ok(())
     }
 
     /// Get current TX counter (for debugging/stats)
@@ -312,13 +593,18 @@ impl PacketCipher {
     pub fn tx_counter(&self) -> u64 {
         self.tx_counter
     }
+
+    /// Usage of this key against its limits
+    pub fn usage(&self) -> &KeyUsage {
+        &self.usage
+    }
 }
 
 /// Sliding window for replay protection
 ///
 /// Uses a 128-bit bitmap for efficient replay detection with O(1) operations.
 /// The window tracks the last 128 packet IDs relative to the highest seen.
-struct ReplayWindow {
+pub struct ReplayWindow {
     /// Highest seen packet ID
     highest: u64,
     /// Bitmap of recently seen packets (relative to highest)
@@ -330,25 +616,38 @@ impl ReplayWindow {
     /// Window size in packets (128 bits = 128 packet tracking)
     const WINDOW_SIZE: u64 = 128;
 
+    /// Create an empty replay window
     #[inline]
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             highest: 0,
             bitmap: 0,
         }
     }
 
-    /// Check if packet ID is valid (not replayed) and update window
+    /// Check if packet ID is valid (not replayed) without updating the window
     ///
-    /// Returns true if the packet should be processed, false if it's a replay
-    /// or too old.
+    /// Returns true if the packet may be processed, false if it's a replay
+    /// or too old. Call [`commit`](Self::commit) once the packet is
+    /// authenticated, so forged packets cannot move the window.
     #[inline]
-    fn check_and_update(&mut self, packet_id: u64) -> bool {
+    pub fn check(&self, packet_id: u64) -> bool {
         // Packet ID 0 is invalid (counter starts at 1)
         if packet_id == 0 {
             return false;
         }
+        if packet_id > self.highest {
+            return true;
+        }
 
+        // Packet is at or before highest: it must be within the window and unseen
+        let diff = self.highest - packet_id;
+        diff < Self::WINDOW_SIZE && self.bitmap & (1u128 << diff) == 0
+    }
+
+    /// Mark an authenticated packet ID as seen, advancing the window past it
+    #[inline]
+    pub fn commit(&mut self, packet_id: u64) {
         if packet_id > self.highest {
             // New highest packet - advance window
             let shift = packet_id - self.highest;
@@ -358,34 +657,31 @@ impl ReplayWindow {
                 self.bitmap = 1; // Only mark current packet
             } else {
                 // Shift window and mark current packet
-                // Use saturating shift to handle edge cases
                 self.bitmap = (self.bitmap << shift) | 1;
             }
             self.highest = packet_id;
-            true
         } else {
-            // Packet is at or before highest
             let diff = self.highest - packet_id;
-
-            // Check if packet is within window
-            if diff >= Self::WINDOW_SIZE {
-                return false; // Too old
-            }
-
-            // Check if already seen using bit test
-            let mask = 1u128 << diff;
-            if self.bitmap & mask != 0 {
-                return false; // Replay detected
+            if diff < Self::WINDOW_SIZE {
+                self.bitmap |= 1u128 << diff;
             }
+        }
+    }
 
-            // Mark as seen
-            self.bitmap |= mask;
-            true
+    /// Check if packet ID is valid (not replayed) and update window
+    ///
+    /// Returns true if the packet should be processed, false if it's a replay
+    /// or too old. Only for packets that are already authenticated.
+    #[inline]
+    pub fn check_and_update(&mut self, packet_id: u64) -> bool {
+        if !self.check(packet_id) {
+            return false;
         }
+        self.commit(packet_id);
+        true
     }
 
     /// Reset the replay window (e.g., for key renegotiation)
-    #[allow(dead_code)]
     #[inline]
     pub fn reset(&mut self) {
         self.highest = 0;
@@ -393,6 +689,87 @@ impl ReplayWindow {
     }
 }
 
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Replay window that can be checked from several threads without locking
+///
+/// Packet IDs are tracked in 32-packet blocks spread over a ring of slots.
+/// Each slot holds a block number next to that block's bitmap, so a single
+/// compare-and-swap updates both. Slots only ever move on to newer blocks,
+/// which means a packet ID accepted once is never accepted again.
+pub struct AtomicReplayWindow {
+    /// Highest accepted packet ID
+    highest: AtomicU64,
+    /// Block number (high 32 bits) and bitmap (low 32 bits) per slot
+    slots: [AtomicU64; Self::SLOTS],
+}
+
+impl AtomicReplayWindow {
+    const SLOTS: usize = 8;
+    const BLOCK_BITS: u64 = 32;
+
+    /// Window size in packets
+    pub const WINDOW_SIZE: u64 = (Self::SLOTS as u64 - 1) * Self::BLOCK_BITS;
+
+    /// Create an empty replay window
+    pub fn new() -> Self {
+        Self {
+            highest: AtomicU64::new(0),
+            slots: Default::default(),
+        }
+    }
+
+    /// Check if packet ID is valid (not replayed) and update window
+    ///
+    /// Returns true if the packet should be processed, false if it's a replay
+    /// or too old.
+    #[inline]
+    pub fn check_and_update(&self, packet_id: u32) -> bool {
+        // Packet ID 0 is invalid (counter starts at 1)
+        if packet_id == 0 {
+            return false;
+        }
+
+        let packet_id = u64::from(packet_id);
+        if packet_id + Self::WINDOW_SIZE <= self.highest.load(Ordering::Acquire) {
+            return false; // Too old
+        }
+
+        let block = packet_id / Self::BLOCK_BITS;
+        let bit = 1u64 << (packet_id % Self::BLOCK_BITS);
+        let slot = &self.slots[(block % Self::SLOTS as u64) as usize];
+
+        let mut current = slot.load(Ordering::Acquire);
+        loop {
+            let updated = match (current >> 32).cmp(&block) {
+                std::cmp::Ordering::Equal if current & bit != 0 => return false, // Replay
+                std::cmp::Ordering::Equal => current | bit,
+                // Slot still holds an older block, start this one
+                std::cmp::Ordering::Less => (block << 32) | bit,
+                // Slot already moved past this block
+                std::cmp::Ordering::Greater => return false,
+            };
+            match slot.compare_exchange_weak(current, updated, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+
+        self.highest.fetch_max(packet_id, Ordering::AcqRel);
+        true
+    }
+}
+
+impl Default for AtomicReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -456,6 +833,86 @@ mod tests {
         assert!(decryptor.decrypt(&p3).is_err());
     }
 
+    #[test]
+    fn test_packet_cipher_in_place() {
+        for suite in [CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm] {
+            let mut encryptor = PacketCipher::new(DataChannelKey::new([0x42u8; 32], suite));
+            let mut decryptor = PacketCipher::new(DataChannelKey::new([0x42u8; 32], suite));
+
+            let mut buf = BytesMut::with_capacity(PACKET_HEADROOM + 64 + PACKET_TAILROOM);
+            buf.extend_from_slice(&[0u8; PACKET_HEADROOM]);
+            buf.extend_from_slice(b"in-place packet");
+            let capacity = buf.capacity();
+
+            let start = encryptor.encrypt_in_place(&mut buf).unwrap();
+            assert_eq!(buf.capacity(), capacity, "tag must fit in the tailroom");
+            buf.advance(start);
+
+            // Interoperates with the copying API
+            let copied = decryptor.decrypt(&buf).unwrap();
+            assert_eq!(copied, b"in-place packet");
+
+            let mut packet = BytesMut::from(&encryptor.encrypt(b"second").unwrap()[..]);
+            decryptor.decrypt_in_place(&mut packet).unwrap();
+            assert_eq!(&packet[..], b"second");
+
+            // Authentication failures leave the buffer as received
+            let mut packet = BytesMut::from(&encryptor.encrypt(b"third").unwrap()[..]);
+            packet[10] ^= 0xFF;
+            let tampered = packet.clone();
+            assert!(decryptor.decrypt_in_place(&mut packet).is_err());
+            assert_eq!(packet, tampered);
+        }
+
+        // Encrypting without headroom is refused
+        let mut encryptor = PacketCipher::new(DataChannelKey::new([0x42u8; 32], CipherSuite::ChaCha20Poly1305));
+        let mut buf = BytesMut::from(&b"short"[..]);
+        assert!(encryptor.encrypt_in_place(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_key_usage_limits() {
+        let usage = KeyUsage::new(UsageLimits {
+            encrypted_blocks: Some(80),
+            failed_decryptions: 8,
+            packets: u64::MAX,
+        });
+
+        // A 128-byte packet counts as 8 blocks plus the packet itself
+        for _ in 0..7 {
+            usage.record_encrypt(128).unwrap();
+        }
+        assert_eq!(usage.status(), KeyStatus::Usable);
+        usage.record_encrypt(128).unwrap();
+        assert_eq!(usage.status(), KeyStatus::Renegotiate);
+        usage.record_encrypt(64).unwrap();
+        assert!(matches!(usage.record_encrypt(64), Err(CryptoError::KeyExpired)));
+        assert_eq!(usage.status(), KeyStatus::Exhausted);
+
+        // Forgeries count against the integrity limit
+        let usage = KeyUsage::new(CipherSuite::ChaCha20Poly1305.usage_limits().with_packet_ids(8));
+        for _ in 0..7 {
+            usage.record_encrypt(1400).unwrap();
+            usage.record_decrypt_failure();
+        }
+        assert_eq!(usage.status(), KeyStatus::Renegotiate);
+        usage.record_encrypt(1400).unwrap();
+        assert!(usage.record_encrypt(1400).is_err());
+        assert!(usage.check_decrypt().is_ok());
+
+        let mut decryptor = PacketCipher::new(DataChannelKey::new([0x42u8; 32], CipherSuite::Aes256Gcm));
+        decryptor.usage = KeyUsage::new(UsageLimits { failed_decryptions: 2, ..decryptor.usage.limits });
+        let mut encryptor = PacketCipher::new(DataChannelKey::new([0x42u8; 32], CipherSuite::Aes256Gcm));
+        for _ in 0..2 {
+            let mut packet = encryptor.encrypt(b"forged").unwrap();
+            packet[10] ^= 0xFF;
+            assert!(matches!(decryptor.decrypt(&packet), Err(CryptoError::DecryptionFailed)));
+        }
+        let packet = encryptor.encrypt(b"genuine").unwrap();
+        assert!(matches!(decryptor.decrypt(&packet), Err(CryptoError::KeyExpired)));
+        assert_eq!(decryptor.usage().status(), KeyStatus::Exhausted);
+    }
+
     #[test]
     fn test_replay_window() {
         let mut window = ReplayWindow::new();
@@ -467,5 +924,86 @@ mod tests {
         assert!(!window.check_and_update(1)); // Too old
         assert!(window.check_and_update(99)); // In window
         assert!(!window.check_and_update(99)); // Replay
+
+        // Checking alone leaves the window where it was
+        assert!(window.check(1000));
+        assert!(window.check(98));
+        window.commit(98);
+        assert!(!window.check(98));
+        assert!(window.check_and_update(97));
+    }
+
+    #[test]
+    fn test_forged_packet_does_not_advance_window() {
+        let key = || DataChannelKey::new([0x42u8; 32], CipherSuite::ChaCha20Poly1305);
+        let mut encryptor = PacketCipher::new(key());
+        let mut decryptor = PacketCipher::new(key());
+
+        let genuine: Vec<_> = (0..3).map(|i| encryptor.encrypt(&[i; 64]).unwrap()).collect();
+        assert_eq!(decryptor.decrypt(&genuine[0]).unwrap(), vec![0; 64]);
+
+        // Forged packet far ahead of the window, with a bad tag
+        let mut forged = genuine[1].clone();
+        forged[..8].copy_from_slice(&1_000_000u64.to_be_bytes());
+        assert!(matches!(decryptor.decrypt(&forged), Err(CryptoError::DecryptionFailed)));
+        let mut buf = BytesMut::from(&forged[..]);
+        assert!(matches!(decryptor.decrypt_in_place(&mut buf), Err(CryptoError::DecryptionFailed)));
+
+        // Genuine packets after it are still accepted, once
+        assert_eq!(decryptor.decrypt(&genuine[1]).unwrap(), vec![1; 64]);
+        let mut buf = BytesMut::from(&genuine[2][..]);
+        decryptor.decrypt_in_place(&mut buf).unwrap();
+        assert_eq!(&buf[..], &[2; 64][..]);
+        assert!(matches!(decryptor.decrypt(&genuine[2]), Err(CryptoError::ReplayDetected)));
+    }
+
+    #[test]
+    fn test_atomic_replay_window() {
+        let window = AtomicReplayWindow::new();
+
+        assert!(!window.check_and_update(0));
+        assert!(window.check_and_update(1));
+        assert!(window.check_and_update(2));
+        assert!(!window.check_and_update(1)); // Replay
+        assert!(window.check_and_update(100));
+        assert!(window.check_and_update(40)); // In window
+        assert!(!window.check_and_update(40)); // Replay
+        assert!(window.check_and_update(1000));
+        assert!(!window.check_and_update(100)); // Too old
+        assert!(window.check_and_update(1000 - AtomicReplayWindow::WINDOW_SIZE as u32 + 1));
+
+        // Concurrent receivers with interleaved packet IDs (t, t+4, ...) accept
+        // each of them; rounds of 128 IDs keep them all inside the window
+        const THREADS: u32 = 4;
+        const ROUND: u32 = 128;
+        let window = std::sync::Arc::new(AtomicReplayWindow::new());
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(THREADS as usize));
+        let run = || {
+            (1..=THREADS)
+                .map(|t| {
+                    let (window, barrier) = (window.clone(), barrier.clone());
+                    std::thread::spawn(move || {
+                        let mut accepted = Vec::new();
+                        for round in 0..16 {
+                            let start = round * ROUND + t;
+                            accepted.extend((start..start + ROUND).step_by(THREADS as usize)
+                                .filter(|&id| window.check_and_update(id)));
+                            barrier.wait();
+                        }
+                        accepted
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<u32>>()
+        };
+        let mut accepted = run();
+        accepted.sort_unstable();
+        assert_eq!(accepted, (1..=16 * ROUND).collect::<Vec<_>>());
+
+        // Replaying any of them is rejected
+        assert!(run().is_empty());
+        assert!((1..=16 * ROUND).all(|id| !window.check_and_update(id)));
     }
 }
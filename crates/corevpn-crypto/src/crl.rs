@@ -0,0 +1,270 @@
+//! Certificate Revocation
+//!
+//! Tracks every certificate issued by the CA so that individual serials can
+//! be revoked and published in a CRL signed by the CA key.
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use x509_cert::der::Decode;
+
+use crate::{CryptoError, Result};
+
+/// Reason a certificate was revoked (RFC 5280 §5.3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationReason {
+    /// No reason given
+    Unspecified,
+    /// Private key was compromised
+    KeyCompromise,
+    /// CA key was compromised
+    CaCompromise,
+    /// Holder's affiliation changed (e.g. left the organization)
+    AffiliationChanged,
+    /// Replaced by a newer certificate
+    Superseded,
+    /// Certificate is no longer needed
+    CessationOfOperation,
+    /// Access was withdrawn
+    PrivilegeWithdrawn,
+}
+
+impl From<RevocationReason> for rcgen::RevocationReason {
+    fn from(reason: RevocationReason) -> Self {
+        match reason {
+            RevocationReason::Unspecified => rcgen::RevocationReason::Unspecified,
+            RevocationReason::KeyCompromise => rcgen::RevocationReason::KeyCompromise,
+            RevocationReason::CaCompromise => rcgen::RevocationReason::CaCompromise,
+            RevocationReason::AffiliationChanged => rcgen::RevocationReason::AffiliationChanged,
+            RevocationReason::Superseded => rcgen::RevocationReason::Superseded,
+            RevocationReason::CessationOfOperation => rcgen::RevocationReason::CessationOfOperation,
+            RevocationReason::PrivilegeWithdrawn => rcgen::RevocationReason::PrivilegeWithdrawn,
+        }
+    }
+}
+
+/// Entry in the issued-certificate index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedCertificate {
+    /// Serial number (lowercase hex)
+    pub serial: String,
+    /// Subject common name
+    pub common_name: String,
+    /// Email SAN, if any
+    pub email: Option<String>,
+    /// Issue time
+    pub issued_at: DateTime<Utc>,
+    /// Expiry time
+    pub expires_at: DateTime<Utc>,
+    /// Revocation time, if revoked
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Revocation reason, if revoked
+    pub revocation_reason: Option<RevocationReason>,
+}
+
+impl IssuedCertificate {
+    /// Create an index entry for a newly issued certificate
+    pub fn new(serial: &str, common_name: &str, email: Option<&str>, validity_days: u32) -> Self {
+        let issued_at = Utc::now();
+        Self {
+            serial: serial.to_string(),
+            common_name: common_name.to_string(),
+            email: email.map(String::from),
+            issued_at,
+            expires_at: issued_at + chrono::Duration::days(validity_days as i64),
+            revoked_at: None,
+            revocation_reason: None,
+        }
+    }
+
+    /// Check if the certificate has been revoked
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    /// Check if the certificate is neither revoked nor expired
+    pub fn is_active(&self) -> bool {
+        !self.is_revoked() && self.expires_at > Utc::now()
+    }
+}
+
+/// Persisted index of issued certificates
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CertificateIndex {
+    /// Number of the most recently generated CRL
+    crl_number: u64,
+    /// Issued certificates, oldest first
+    certificates: Vec<IssuedCertificate>,
+}
+
+impl CertificateIndex {
+    /// Create an empty index
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the index from disk, returning an empty index if the file does not exist
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| CryptoError::IndexError(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(CryptoError::IndexError(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    /// Atomically write the index to disk
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| CryptoError::IndexError(e.to_string()))?;
+
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|e| CryptoError::IndexError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Record a newly issued certificate
+    pub fn record(&mut self, entry: IssuedCertificate) {
+        self.certificates.push(entry);
+    }
+
+    /// Revoke a certificate by serial number
+    pub fn revoke(&mut self, serial: &str, reason: RevocationReason) -> Result<&IssuedCertificate> {
+        let serial = serial.to_lowercase();
+        let entry = self.certificates
+            .iter_mut()
+            .find(|entry| entry.serial == serial)
+            .ok_or_else(|| CryptoError::IndexError(format!("Unknown certificate serial: {}", serial)))?;
+
+        if entry.is_revoked() {
+            return Err(CryptoError::IndexError(format!("Certificate {} is already revoked", serial)));
+        }
+
+        entry.revoked_at = Some(Utc::now());
+        entry.revocation_reason = Some(reason);
+        Ok(entry)
+    }
+
+    /// Look up a certificate by serial number
+    pub fn find(&self, serial: &str) -> Option<&IssuedCertificate> {
+        let serial = serial.to_lowercase();
+        self.certificates.iter().find(|entry| entry.serial == serial)
+    }
+
+    /// All issued certificates
+    pub fn certificates(&self) -> &[IssuedCertificate] {
+        &self.certificates
+    }
+
+    /// Active (unrevoked, unexpired) certificates issued to a common name
+    pub fn active_for(&self, common_name: &str) -> impl Iterator<Item = &IssuedCertificate> {
+        self.certificates
+            .iter()
+            .filter(move |entry| entry.common_name == common_name && entry.is_active())
+    }
+
+    /// Revoked certificates
+    pub fn revoked(&self) -> impl Iterator<Item = &IssuedCertificate> {
+        self.certificates.iter().filter(|entry| entry.is_revoked())
+    }
+
+    /// Advance and return the CRL number
+    pub fn next_crl_number(&mut self) -> u64 {
+        self.crl_number += 1;
+        self.crl_number
+    }
+}
+
+/// Format a serial number as lowercase hex without leading zero octets
+pub fn serial_to_hex(serial: &[u8]) -> String {
+    let start = serial.iter().position(|&b| b != 0).unwrap_or(serial.len().saturating_sub(1));
+    serial[start..].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Get the serial number of a DER-encoded certificate
+pub fn certificate_serial(der: &[u8]) -> Result<String> {
+    crate::cert::CertificateIdentity::from_der(der).map(|identity| identity.serial)
+}
+
+/// Get the revoked serial numbers listed in a PEM-encoded CRL
+pub fn crl_revoked_serials(crl_pem: &str) -> Result<Vec<String>> {
+    let mut serials = Vec::new();
+    for block in pem::parse_many(crl_pem).map_err(|e| CryptoError::InvalidPem(e.to_string()))? {
+        if block.tag() != "X509 CRL" {
+            continue;
+        }
+        let crl = x509_cert::crl::CertificateList::from_der(block.contents())
+            .map_err(|e| CryptoError::CertificateError(e.to_string()))?;
+        for revoked in crl.tbs_cert_list.revoked_certificates.unwrap_or_default() {
+            serials.push(serial_to_hex(revoked.serial_number.as_bytes()));
+        }
+    }
+    Ok(serials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CertificateAuthority;
+
+    #[test]
+    fn test_revoke() {
+        let mut index = CertificateIndex::new();
+        index.record(IssuedCertificate::new("0a1b", "alice", None, 30));
+        index.record(IssuedCertificate::new("0c2d", "bob", None, 30));
+
+        index.revoke("0A1B", RevocationReason::AffiliationChanged).unwrap();
+        assert!(index.find("0a1b").unwrap().is_revoked());
+        assert_eq!(index.active_for("alice").count(), 0);
+        assert_eq!(index.active_for("bob").count(), 1);
+
+        assert!(index.revoke("0a1b", RevocationReason::Unspecified).is_err());
+        assert!(index.revoke("ffff", RevocationReason::Unspecified).is_err());
+    }
+
+    #[test]
+    fn test_index_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+
+        assert!(CertificateIndex::load(&path).unwrap().certificates().is_empty());
+
+        let mut index = CertificateIndex::new();
+        index.record(IssuedCertificate::new("0a1b", "alice", Some("alice@example.com"), 30));
+        index.revoke("0a1b", RevocationReason::KeyCompromise).unwrap();
+        index.save(&path).unwrap();
+
+        let loaded = CertificateIndex::load(&path).unwrap();
+        let entry = loaded.find("0a1b").unwrap();
+        assert_eq!(entry.revocation_reason, Some(RevocationReason::KeyCompromise));
+        assert_eq!(entry.email.as_deref(), Some("alice@example.com"));
+    }
+
+    #[test]
+    fn test_crl_lists_revoked_serials() {
+        let ca = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+        let alice = ca.issue_client_certificate("alice", None, 30).unwrap();
+        let bob = ca.issue_client_certificate("bob", None, 30).unwrap();
+
+        let mut index = CertificateIndex::new();
+        index.record(IssuedCertificate::new(&alice.serial, "alice", None, 30));
+        index.record(IssuedCertificate::new(&bob.serial, "bob", None, 30));
+        index.revoke(&alice.serial, RevocationReason::AffiliationChanged).unwrap();
+
+        let crl = ca.generate_crl(&mut index, 30).unwrap();
+        assert!(crl.contains("BEGIN X509 CRL"));
+        assert_eq!(crl_revoked_serials(&crl).unwrap(), vec![alice.serial.clone()]);
+
+        let der = pem::parse(&alice.cert_pem).unwrap();
+        assert_eq!(certificate_serial(der.contents()).unwrap(), alice.serial);
+    }
+
+    #[test]
+    fn test_serial_to_hex() {
+        assert_eq!(serial_to_hex(&[0x00, 0x8f, 0x01]), "8f01");
+        assert_eq!(serial_to_hex(&[0x00]), "00");
+    }
+}
@@ -55,11 +55,15 @@ pub enum CryptoError {
     #[error("replay attack detected: packet ID already seen")]
     ReplayDetected,
 
-    /// Key expired
+    /// Key expired (e.g. it reached an AEAD usage limit)
     #[error("key has expired")]
     KeyExpired,
 
     /// Random number generation failed
     #[error("random number generation failed")]
     RngFailed,
+
+    /// Issued-certificate index error
+    #[error("certificate index error: {0}")]
+    IndexError(String),
 }
@@ -3,6 +3,7 @@
 //! Provides pre-shared key authentication for the control channel,
 //! protecting against DoS attacks and providing an additional layer of security.
 
+use ctr::cipher::{KeyIvInit, StreamCipher};
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
 use subtle::ConstantTimeEq;
@@ -11,6 +12,22 @@ use zeroize::ZeroizeOnDrop;
 use crate::{CryptoError, Result};
 
 type HmacSha256 = Hmac<Sha256>;
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// Size of one direction's key in an OpenVPN static key file
+const TA_KEY_SLOT_SIZE: usize = 128;
+
+/// Cipher key of a static key slot (first 32 bytes of the 64-byte cipher half)
+fn ta_cipher_key(ta_key: &[u8; 256], slot: usize) -> [u8; 32] {
+    let start = slot * TA_KEY_SLOT_SIZE;
+    ta_key[start..start + 32].try_into().unwrap()
+}
+
+/// HMAC key of a static key slot (first 32 bytes of the 64-byte HMAC half)
+fn ta_hmac_key(ta_key: &[u8; 256], slot: usize) -> [u8; 32] {
+    let start = slot * TA_KEY_SLOT_SIZE + 64;
+    ta_key[start..start + 32].try_into().unwrap()
+}
 
 /// HMAC authentication key for tls-auth
 #[derive(ZeroizeOnDrop)]
@@ -42,49 +59,23 @@ impl HmacAuth {
 
     /// Create from OpenVPN ta.key format (2048-bit / 256 bytes)
     ///
-    /// OpenVPN ta.key contains 4 keys:
-    /// - Bytes 0-63: Client HMAC key (encrypt direction)
-    /// - Bytes 64-127: Server HMAC key (encrypt direction)
-    /// - Bytes 128-191: Client HMAC key (decrypt direction)
-    /// - Bytes 192-255: Server HMAC key (decrypt direction)
-    pub fn from_ta_key(ta_key: &[u8; 256], is_server: bool, key_direction: Option<u8>) -> Self {
-        let (tx_key, rx_key) = match (is_server, key_direction) {
-            // Server with key-direction 0 (normal)
-            (true, Some(0)) | (true, None) => {
-                let mut tx = [0u8; 32];
-                let mut rx = [0u8; 32];
-                tx.copy_from_slice(&ta_key[64..96]);
-                rx.copy_from_slice(&ta_key[0..32]);
-                (tx, rx)
-            }
-            // Server with key-direction 1 (reversed)
-            (true, Some(1)) => {
-                let mut tx = [0u8; 32];
-                let mut rx = [0u8; 32];
-                tx.copy_from_slice(&ta_key[0..32]);
-                rx.copy_from_slice(&ta_key[64..96]);
-                (tx, rx)
-            }
-            // Client with key-direction 1 (normal for client)
-            (false, Some(1)) | (false, None) => {
-                let mut tx = [0u8; 32];
-                let mut rx = [0u8; 32];
-                tx.copy_from_slice(&ta_key[0..32]);
-                rx.copy_from_slice(&ta_key[64..96]);
-                (tx, rx)
-            }
-            // Client with key-direction 0 (reversed for client)
-            (false, Some(0)) => {
-                let mut tx = [0u8; 32];
-                let mut rx = [0u8; 32];
-                tx.copy_from_slice(&ta_key[64..96]);
-                rx.copy_from_slice(&ta_key[0..32]);
-                (tx, rx)
-            }
+    /// OpenVPN ta.key contains 2 keys of 128 bytes, each a 64-byte cipher
+    /// key followed by a 64-byte HMAC key:
+    /// - key-direction 0 (server): send with key 0, receive with key 1
+    /// - key-direction 1 (client): send with key 1, receive with key 0
+    /// - no key-direction: key 0 in both directions
+    pub fn from_ta_key(ta_key: &[u8; 256], key_direction: Option<u8>) -> Self {
+        let (tx_slot, rx_slot) = match key_direction {
+            None => (0, 0),
+            Some(0) => (0, 1),
+            Some(1) => (1, 0),
             _ => panic!("Invalid key direction"),
         };
 
-        Self { tx_key, rx_key }
+        Self {
+            tx_key: ta_hmac_key(ta_key, tx_slot),
+            rx_key: ta_hmac_key(ta_key, rx_slot),
+        }
     }
 
     /// Compute HMAC for an outgoing packet
@@ -133,18 +124,33 @@ impl HmacAuth {
 }
 
 /// tls-crypt key for both HMAC and encryption
+///
+/// Uses OpenVPN's construction: an HMAC-SHA256 tag over the packet header
+/// and plaintext, whose first 128 bits are the IV for AES-256-CTR.
 #[derive(ZeroizeOnDrop)]
 pub struct TlsCryptKey {
-    /// Encryption key
-    cipher_key: [u8; 32],
-    /// HMAC authentication key
-    hmac_key: [u8; 32],
+    /// Encryption key for outgoing packets
+    tx_cipher_key: [u8; 32],
+    /// HMAC key for outgoing packets
+    tx_hmac_key: [u8; 32],
+    /// Encryption key for incoming packets
+    rx_cipher_key: [u8; 32],
+    /// HMAC key for incoming packets
+    rx_hmac_key: [u8; 32],
 }
 
 impl TlsCryptKey {
-    /// Create from raw keys
+    /// Authentication tag size in bytes
+    pub const TAG_SIZE: usize = 32;
+
+    /// Create from raw keys (same keys in both directions)
     pub fn new(cipher_key: [u8; 32], hmac_key: [u8; 32]) -> Self {
-        Self { cipher_key, hmac_key }
+        Self {
+            tx_cipher_key: cipher_key,
+            tx_hmac_key: hmac_key,
+            rx_cipher_key: cipher_key,
+            rx_hmac_key: hmac_key,
+        }
     }
 
     /// Create from a 512-bit (64-byte) combined key
@@ -153,79 +159,78 @@ impl TlsCryptKey {
         let mut hmac_key = [0u8; 32];
         cipher_key.copy_from_slice(&key[0..32]);
         hmac_key.copy_from_slice(&key[32..64]);
-        Self { cipher_key, hmac_key }
+        Self::new(cipher_key, hmac_key)
     }
 
-    /// Get the cipher key
+    /// Create from OpenVPN static key format (2048-bit / 256 bytes)
+    ///
+    /// The server sends with key 0 and receives with key 1; the client
+    /// does the reverse.
+    pub fn from_ta_key(ta_key: &[u8; 256], is_server: bool) -> Self {
+        let (tx_slot, rx_slot) = if is_server { (0, 1) } else { (1, 0) };
+        Self {
+            tx_cipher_key: ta_cipher_key(ta_key, tx_slot),
+            tx_hmac_key: ta_hmac_key(ta_key, tx_slot),
+            rx_cipher_key: ta_cipher_key(ta_key, rx_slot),
+            rx_hmac_key: ta_hmac_key(ta_key, rx_slot),
+        }
+    }
+
+    /// Get the cipher key for outgoing packets
     pub fn cipher_key(&self) -> &[u8; 32] {
-        &self.cipher_key
+        &self.tx_cipher_key
     }
 
-    /// Get the HMAC key
+    /// Get the HMAC key for outgoing packets
     pub fn hmac_key(&self) -> &[u8; 32] {
-        &self.hmac_key
+        &self.tx_hmac_key
     }
 
-    /// Wrap control channel packet with tls-crypt (encrypt-then-MAC)
+    /// Wrap control channel packet with tls-crypt
     ///
-    /// Format: [HMAC-SHA256(ciphertext) | IV | ciphertext]
-    pub fn wrap(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
-        use crate::cipher::Cipher;
-        use crate::CipherSuite;
-
-        // Generate random IV
-        let cipher = Cipher::new(&self.cipher_key, CipherSuite::ChaCha20Poly1305);
-        let nonce = cipher.generate_nonce();
+    /// The header (opcode, session ID, packet ID) is authenticated but
+    /// not encrypted. Format: [HMAC-SHA256(header | plaintext) | ciphertext]
+    pub fn wrap(&self, header: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let tag = Self::tag(&self.tx_hmac_key, header, plaintext);
 
-        // Encrypt
-        let ciphertext = cipher.encrypt(&nonce, plaintext, &[])?;
-
-        // Compute HMAC over IV + ciphertext
-        let mut hmac_input = Vec::with_capacity(12 + ciphertext.len());
-        hmac_input.extend_from_slice(&nonce);
-        hmac_input.extend_from_slice(&ciphertext);
-
-        let mut mac = HmacSha256::new_from_slice(&self.hmac_key)
-            .expect("HMAC key size is always valid");
-        mac.update(&hmac_input);
-        let hmac = mac.finalize().into_bytes();
-
-        // Build output: HMAC | IV | ciphertext
-        let mut output = Vec::with_capacity(32 + 12 + ciphertext.len());
-        output.extend_from_slice(&hmac);
-        output.extend_from_slice(&nonce);
-        output.extend_from_slice(&ciphertext);
+        let mut output = Vec::with_capacity(Self::TAG_SIZE + plaintext.len());
+        output.extend_from_slice(&tag);
+        output.extend_from_slice(plaintext);
+        Self::apply_keystream(&self.tx_cipher_key, &tag, &mut output[Self::TAG_SIZE..]);
 
         Ok(output)
     }
 
     /// Unwrap tls-crypt protected packet
-    pub fn unwrap(&self, packet: &[u8]) -> Result<Vec<u8>> {
-        use crate::cipher::Cipher;
-        use crate::CipherSuite;
-
-        if packet.len() < 32 + 12 + 16 {
+    pub fn unwrap(&self, header: &[u8], packet: &[u8]) -> Result<Vec<u8>> {
+        if packet.len() < Self::TAG_SIZE {
             return Err(CryptoError::DecryptionFailed);
         }
 
-        let (hmac, rest) = packet.split_at(32);
-        let (nonce, ciphertext) = rest.split_at(12);
-
-        // Verify HMAC first (constant-time)
-        let mut mac = HmacSha256::new_from_slice(&self.hmac_key)
-            .expect("HMAC key size is always valid");
-        mac.update(nonce);
-        mac.update(ciphertext);
-        let computed = mac.finalize().into_bytes();
+        let (tag, ciphertext) = packet.split_at(Self::TAG_SIZE);
+        let mut plaintext = ciphertext.to_vec();
+        Self::apply_keystream(&self.rx_cipher_key, tag, &mut plaintext);
 
-        if !bool::from(computed.ct_eq(hmac)) {
+        // Verify the tag over the recovered plaintext (constant-time)
+        let computed = Self::tag(&self.rx_hmac_key, header, &plaintext);
+        if !bool::from(computed.ct_eq(tag)) {
             return Err(CryptoError::HmacVerificationFailed);
         }
 
-        // Decrypt
-        let nonce: [u8; 12] = nonce.try_into().unwrap();
-        let cipher = Cipher::new(&self.cipher_key, CipherSuite::ChaCha20Poly1305);
-        cipher.decrypt(&nonce, ciphertext, &[])
+        Ok(plaintext)
+    }
+
+    fn tag(hmac_key: &[u8; 32], header: &[u8], plaintext: &[u8]) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(hmac_key)
+            .expect("HMAC key size is always valid");
+        mac.update(header);
+        mac.update(plaintext);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn apply_keystream(cipher_key: &[u8; 32], tag: &[u8], data: &mut [u8]) {
+        let mut cipher = Aes256Ctr::new(cipher_key.into(), tag[..16].into());
+        cipher.apply_keystream(data);
     }
 }
 
@@ -260,9 +265,10 @@ mod tests {
     fn test_tls_crypt_roundtrip() {
         let key = TlsCryptKey::new([0x42u8; 32], [0x43u8; 32]);
 
+        let header = b"header";
         let plaintext = b"secret control channel data";
-        let wrapped = key.wrap(plaintext).unwrap();
-        let unwrapped = key.unwrap(&wrapped).unwrap();
+        let wrapped = key.wrap(header, plaintext).unwrap();
+        let unwrapped = key.unwrap(header, &wrapped).unwrap();
 
         assert_eq!(plaintext.as_slice(), unwrapped.as_slice());
     }
@@ -271,9 +277,50 @@ mod tests {
     fn test_tls_crypt_tamper_detection() {
         let key = TlsCryptKey::new([0x42u8; 32], [0x43u8; 32]);
 
-        let mut wrapped = key.wrap(b"secret data").unwrap();
+        let mut wrapped = key.wrap(b"header", b"secret data").unwrap();
+        assert!(key.unwrap(b"HEADER", &wrapped).is_err()); // Tamper with header
+
         wrapped[40] ^= 0xFF; // Tamper with ciphertext
+        assert!(key.unwrap(b"header", &wrapped).is_err());
+    }
+
+    fn test_ta_key() -> [u8; 256] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
 
-        assert!(key.unwrap(&wrapped).is_err());
+    #[test]
+    fn test_tls_auth_key_direction() {
+        let ta_key = test_ta_key();
+        let server = HmacAuth::from_ta_key(&ta_key, Some(0));
+        let client = HmacAuth::from_ta_key(&ta_key, Some(1));
+
+        // Server sends with the HMAC half of key 0
+        assert_eq!(
+            to_hex(&server.authenticate(b"abc")),
+            "910f4315f170bdf2f5a197d760828322c22cf67c043b7df72b6920db6e4caf97"
+        );
+        assert!(client.unwrap(&server.wrap(b"abc")).is_ok());
+        assert!(server.unwrap(&client.wrap(b"abc")).is_ok());
+        assert!(server.unwrap(&server.wrap(b"abc")).is_err());
+    }
+
+    #[test]
+    fn test_tls_crypt_openvpn_vector() {
+        let ta_key = test_ta_key();
+        let server = TlsCryptKey::from_ta_key(&ta_key, true);
+        let client = TlsCryptKey::from_ta_key(&ta_key, false);
+
+        let header = [0x38, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 1, 0, 0, 0, 2];
+        let wrapped = server.wrap(&header, b"hello").unwrap();
+        assert_eq!(
+            to_hex(&wrapped),
+            "b899e17d38f0bb398988b9e465499864f6a6ef468bf3e774dab4dc7109a788d6da38859c6e"
+        );
+        assert_eq!(client.unwrap(&header, &wrapped).unwrap(), b"hello");
+        assert!(server.unwrap(&header, &wrapped).is_err());
     }
 }
@@ -1,8 +1,13 @@
 //! Key Derivation Functions
 //!
-//! Uses HKDF-SHA256 for deriving encryption keys from shared secrets.
+//! Uses HKDF-SHA256 for deriving encryption keys from shared secrets, and
+//! OpenVPN's TLS 1.0 PRF for key-method-2 key expansion.
 
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use hmac::digest::KeyInit;
+use md5::Md5;
+use sha1::Sha1;
 use sha2::Sha256;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
@@ -66,7 +71,85 @@ pub fn derive_keys(
     Ok(material)
 }
 
+/// Size of an OpenVPN key block (two directions of cipher + HMAC keys)
+pub const KEY_BLOCK_SIZE: usize = 256;
+
+/// TLS keying material exporter label used by OpenVPN (`key-derivation tls-ekm`)
+pub const EKM_LABEL: &[u8] = b"EXPORTER-OpenVPN-datakeys";
+
+/// Derive key material from a key-method-2 exchange
+///
+/// Follows OpenVPN's two-step expansion: a master secret is derived from the
+/// client's pre-master secret and first randoms, then expanded into a key
+/// block bound to both session IDs.
+pub fn openvpn_key_expansion(
+    pre_master: &[u8; 48],
+    client_random: (&[u8; 32], &[u8; 32]),
+    server_random: (&[u8; 32], &[u8; 32]),
+    client_session_id: &[u8; 8],
+    server_session_id: &[u8; 8],
+) -> Result<KeyMaterial> {
+    let mut seed = Vec::with_capacity(80);
+    seed.extend_from_slice(client_random.0);
+    seed.extend_from_slice(server_random.0);
+    let mut master = openvpn_prf(pre_master, b"OpenVPN master secret", &seed, 48)?;
+
+    seed.clear();
+    seed.extend_from_slice(client_random.1);
+    seed.extend_from_slice(server_random.1);
+    seed.extend_from_slice(client_session_id);
+    seed.extend_from_slice(server_session_id);
+    let mut block = openvpn_prf(&master, b"OpenVPN key expansion", &seed, KEY_BLOCK_SIZE)?;
+
+    let material = KeyMaterial::from_key_block(&block);
+
+    master.zeroize();
+    block.zeroize();
+    seed.zeroize();
+
+    material
+}
+
 impl KeyMaterial {
+    /// Build key material from an OpenVPN key block
+    ///
+    /// The block holds two 128-byte keys (client->server, then
+    /// server->client), each a 64-byte cipher key followed by a 64-byte
+    /// HMAC key. Only the first 32 bytes of each part are used.
+    pub fn from_key_block(block: &[u8]) -> Result<Self> {
+        if block.len() < KEY_BLOCK_SIZE {
+            return Err(CryptoError::KeyDerivationFailed("key block too short"));
+        }
+
+        let mut material = KeyMaterial {
+            client_write_key: [0u8; 32],
+            server_write_key: [0u8; 32],
+            client_hmac_key: [0u8; 32],
+            server_hmac_key: [0u8; 32],
+        };
+
+        material.client_write_key.copy_from_slice(&block[0..32]);
+        material.client_hmac_key.copy_from_slice(&block[64..96]);
+        material.server_write_key.copy_from_slice(&block[128..160]);
+        material.server_hmac_key.copy_from_slice(&block[192..224]);
+
+        Ok(material)
+    }
+
+    /// Implicit AEAD IV for client -> server packets (OpenVPN takes it from the HMAC key)
+    pub fn client_implicit_iv(&self) -> [u8; 8] {
+        let mut iv = [0u8; 8];
+        iv.copy_from_slice(&self.client_hmac_key[..8]);
+        iv
+    }
+
+    /// Implicit AEAD IV for server -> client packets
+    pub fn server_implicit_iv(&self) -> [u8; 8] {
+        let mut iv = [0u8; 8];
+        iv.copy_from_slice(&self.server_hmac_key[..8]);
+        iv
+    }
+
     /// Create data channel keys for the client side
     pub fn client_data_key(&self, suite: CipherSuite) -> DataChannelKey {
         DataChannelKey::new(self.client_write_key, suite)
@@ -91,38 +174,43 @@ pub fn derive_single_key(
     Ok(okm)
 }
 
-/// PRF for OpenVPN TLS key expansion
+/// PRF for OpenVPN key-method-2 key expansion
 ///
-/// Compatible with OpenVPN's PRF which uses:
-/// P_SHA256(secret, seed) = HMAC_SHA256(secret, A(1) + seed) +
-///                          HMAC_SHA256(secret, A(2) + seed) + ...
-/// where A(0) = seed, A(i) = HMAC_SHA256(secret, A(i-1))
+/// OpenVPN expands keys with the TLS 1.0 PRF (RFC 2246 §5) whatever TLS
+/// version the control channel negotiated: the secret is split in two
+/// halves (sharing the middle byte if its length is odd), and
+/// P_MD5(first half, label + seed) is XORed with P_SHA1(second half,
+/// label + seed).
 pub fn openvpn_prf(secret: &[u8], label: &[u8], seed: &[u8], output_len: usize) -> Result<Vec<u8>> {
-    use hmac::{Hmac, Mac};
-
-    type HmacSha256 = Hmac<Sha256>;
-
-    // Combine label and seed
     let mut combined_seed = Vec::with_capacity(label.len() + seed.len());
     combined_seed.extend_from_slice(label);
     combined_seed.extend_from_slice(seed);
 
-    let mut output = Vec::with_capacity(output_len);
-    let mut a = combined_seed.clone();
+    let half = secret.len().div_ceil(2);
+    let mut output = p_hash::<Hmac<Md5>>(&secret[..half], &combined_seed, output_len)?;
+    let mut sha1 = p_hash::<Hmac<Sha1>>(&secret[secret.len() - half..], &combined_seed, output_len)?;
+    for (out, byte) in output.iter_mut().zip(&sha1) {
+        *out ^= byte;
+    }
+
+    sha1.zeroize();
+    combined_seed.zeroize();
+    Ok(output)
+}
+
+/// TLS P_hash expansion
+///
+/// P_hash(secret, seed) = HMAC(secret, A(1) + seed) + HMAC(secret, A(2) + seed) + ...
+/// where A(0) = seed, A(i) = HMAC(secret, A(i-1))
+fn p_hash<M: Mac + KeyInit + Clone>(secret: &[u8], seed: &[u8], output_len: usize) -> Result<Vec<u8>> {
+    let hmac = <M as KeyInit>::new_from_slice(secret)
+        .map_err(|_| CryptoError::KeyDerivationFailed("Invalid HMAC key"))?;
 
+    let mut output = Vec::with_capacity(output_len);
+    let mut a = seed.to_vec();
     while output.len() < output_len {
-        // A(i) = HMAC(secret, A(i-1))
-        let mut mac = HmacSha256::new_from_slice(secret)
-            .map_err(|_| CryptoError::KeyDerivationFailed("Invalid HMAC key"))?;
-        mac.update(&a);
-        a = mac.finalize().into_bytes().to_vec();
-
-        // P_hash = HMAC(secret, A(i) + seed)
-        let mut mac = HmacSha256::new_from_slice(secret)
-            .map_err(|_| CryptoError::KeyDerivationFailed("Invalid HMAC key"))?;
-        mac.update(&a);
-        mac.update(&combined_seed);
-        output.extend_from_slice(&mac.finalize().into_bytes());
+        a = hmac.clone().chain_update(&a).finalize().into_bytes().to_vec();
+        output.extend_from_slice(&hmac.clone().chain_update(&a).chain_update(seed).finalize().into_bytes());
     }
 
     output.truncate(output_len);
@@ -159,17 +247,70 @@ mod tests {
         assert_eq!(keys1.client_write_key, keys2.client_write_key);
     }
 
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
     #[test]
     fn test_openvpn_prf() {
-        let secret = b"test secret";
-        let label = b"test label";
-        let seed = b"test seed";
+        // crypto_test_tls_prf from OpenVPN's tests/unit_tests/openvpn/test_crypto.c
+        let secret = b"Lorem ipsum dolor sit amet, consectetur adipisici elit, sed eiusmod tempor incidunt \
+            ut labore et dolore magna aliqua.";
+        let seed = b"Quis aute iure reprehenderit in voluptate velit esse cillum dolore";
+
+        let output = openvpn_prf(secret, b"", seed, 32).unwrap();
+        assert_eq!(output, hex("d98c8518c85e946927916acfc2d592fbb1567e4b4b1459e6a904ac2ddab72d67"));
+
+        // The label is part of the seed
+        let (label, rest) = seed.split_at(10);
+        assert_eq!(openvpn_prf(secret, label, rest, 32).unwrap(), output);
+    }
+
+    #[test]
+    fn test_openvpn_key_expansion_vector() {
+        // Expected keys computed with OpenSSL's TLS1-PRF (MD5-SHA1), which OpenVPN calls
+        let keys = openvpn_key_expansion(
+            &[0x11; 48], (&[0x01; 32], &[0x02; 32]), (&[0x03; 32], &[0x04; 32]), &[1; 8], &[2; 8],
+        ).unwrap();
+
+        assert_eq!(
+            keys.client_write_key.to_vec(),
+            hex("4aee113ec2af24c991336ace4d22c50470756476166364d0180ff5c362f9be27"),
+        );
+        assert_eq!(keys.client_implicit_iv().to_vec(), hex("159dc99c2c0ebab7"));
+        assert_eq!(
+            keys.server_write_key.to_vec(),
+            hex("fddf4126d93205031c71ebf05723bc79065dde30e63560c2cf9915cffcf699f9"),
+        );
+        assert_eq!(keys.server_implicit_iv().to_vec(), hex("75a392883bc59079"));
+    }
+
+    #[test]
+    fn test_key_block_layout() {
+        let block: Vec<u8> = (0..KEY_BLOCK_SIZE).map(|i| i as u8).collect();
+        let keys = KeyMaterial::from_key_block(&block).unwrap();
+
+        assert_eq!(keys.client_write_key[0], 0);
+        assert_eq!(keys.client_hmac_key[0], 64);
+        assert_eq!(keys.server_write_key[0], 128);
+        assert_eq!(keys.server_hmac_key[0], 192);
+        assert!(KeyMaterial::from_key_block(&block[..128]).is_err());
+    }
+
+    #[test]
+    fn test_openvpn_key_expansion_binds_session_ids() {
+        let pre_master = [0x11u8; 48];
+        let client = ([0x01u8; 32], [0x02u8; 32]);
+        let server = ([0x03u8; 32], [0x04u8; 32]);
 
-        let output = openvpn_prf(secret, label, seed, 64).unwrap();
-        assert_eq!(output.len(), 64);
+        let keys1 = openvpn_key_expansion(
+            &pre_master, (&client.0, &client.1), (&server.0, &server.1), &[1; 8], &[2; 8],
+        ).unwrap();
+        let keys2 = openvpn_key_expansion(
+            &pre_master, (&client.0, &client.1), (&server.0, &server.1), &[1; 8], &[3; 8],
+        ).unwrap();
 
-        // Should be deterministic
-        let output2 = openvpn_prf(secret, label, seed, 64).unwrap();
-        assert_eq!(output, output2);
+        assert_ne!(keys1.client_write_key, keys1.server_write_key);
+        assert_ne!(keys1.client_write_key, keys2.client_write_key);
     }
 }
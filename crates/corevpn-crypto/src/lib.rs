@@ -17,7 +17,9 @@ pub mod keys;
 pub mod cipher;
 pub mod kdf;
 pub mod cert;
+pub mod crl;
 pub mod hmac_auth;
+pub mod tls_crypt_v2;
 
 pub use error::{CryptoError, Result};
 pub use keys::{
@@ -25,10 +27,15 @@ pub use keys::{
     SigningKey, VerifyingKey, Signature,
     KeyPair,
 };
-pub use cipher::{Cipher, CipherSuite, DataChannelKey, PacketCipher};
-pub use kdf::{derive_keys, KeyMaterial};
-pub use cert::{CertificateAuthority, Certificate, CertificateRequest};
-pub use hmac_auth::HmacAuth;
+pub use cipher::{
+    AtomicReplayWindow, Cipher, CipherSuite, DataChannelKey, KeyStatus, KeyUsage, PacketCipher, ReplayWindow,
+    UsageLimits, PACKET_HEADROOM, PACKET_TAILROOM,
+};
+pub use kdf::{derive_keys, openvpn_key_expansion, KeyMaterial};
+pub use cert::{CertificateAuthority, Certificate, CertificateIdentity, CertificateRequest};
+pub use crl::{CertificateIndex, IssuedCertificate, RevocationReason};
+pub use hmac_auth::{HmacAuth, TlsCryptKey};
+pub use tls_crypt_v2::{ClientKeyMetadata, TlsCryptV2ServerKey};
 
 /// Securely generate random bytes
 pub fn random_bytes<const N: usize>() -> [u8; N] {
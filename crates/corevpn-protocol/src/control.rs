@@ -18,10 +18,66 @@ pub enum ControlMessage {
     Auth(AuthMessage),
     /// Info message (version, etc.)
     Info(String),
+    /// Authentication rejected, with optional reason
+    AuthFailed(Option<String>),
+    /// Authentication still in progress, with optional arguments (e.g. `timeout 300`)
+    AuthPending(Option<String>),
+    /// Ask the client to reconnect, with optional reason
+    Restart(Option<String>),
     /// Exit/shutdown
     Exit,
 }
 
+impl ControlMessage {
+    /// Parse a text control message (trailing NUL bytes are ignored)
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let data = data.split(|&b| b == 0).next().unwrap_or_default();
+        let s = std::str::from_utf8(data)
+            .map_err(|_| ProtocolError::InvalidPacket("invalid UTF-8 in control message".into()))?;
+
+        let (command, args) = match s.split_once(',') {
+            Some((command, args)) => (command, Some(args.to_string())),
+            None => (s, None),
+        };
+
+        match command {
+            "PUSH_REQUEST" => Ok(ControlMessage::PushRequest),
+            "PUSH_REPLY" => Ok(ControlMessage::PushReply(PushReply::parse(s)?)),
+            "AUTH_FAILED" => Ok(ControlMessage::AuthFailed(args)),
+            "AUTH_PENDING" => Ok(ControlMessage::AuthPending(args)),
+            "RESTART" => Ok(ControlMessage::Restart(args)),
+            "EXIT" => Ok(ControlMessage::Exit),
+            _ if command.starts_with("INFO") => Ok(ControlMessage::Info(s.to_string())),
+            _ => Err(ProtocolError::InvalidPacket(format!("unknown control message: {}", command))),
+        }
+    }
+
+    /// Encode as a NUL-terminated control channel message
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = match self {
+            ControlMessage::TlsData(data) => return data.to_vec(),
+            ControlMessage::PushRequest => "PUSH_REQUEST".to_string(),
+            ControlMessage::PushReply(reply) => reply.encode(),
+            ControlMessage::Auth(auth) => return auth.encode(),
+            ControlMessage::Info(info) => info.clone(),
+            ControlMessage::AuthFailed(reason) => with_reason("AUTH_FAILED", reason),
+            ControlMessage::AuthPending(args) => with_reason("AUTH_PENDING", args),
+            ControlMessage::Restart(reason) => with_reason("RESTART", reason),
+            ControlMessage::Exit => "EXIT".to_string(),
+        }
+        .into_bytes();
+        buf.push(0);
+        buf
+    }
+}
+
+fn with_reason(command: &str, reason: &Option<String>) -> String {
+    match reason {
+        Some(reason) => format!("{},{}", command, reason),
+        None => command.to_string(),
+    }
+}
+
 /// Control packet for the reliable transport layer
 #[derive(Debug, Clone)]
 pub struct ControlPacket {
@@ -45,20 +101,28 @@ pub struct PushReply {
     pub routes: Vec<PushRoute>,
     /// IPv4 address and netmask
     pub ifconfig: Option<(String, String)>,
-    /// IPv6 address
+    /// IPv6 address with prefix length, and the remote endpoint
     pub ifconfig_ipv6: Option<String>,
+    /// IPv6 routes (`network/prefix`)
+    pub routes_ipv6: Vec<String>,
+    /// Gateway for pushed routes (required with subnet topology)
+    pub route_gateway: Option<String>,
     /// DNS servers
     pub dns: Vec<String>,
     /// Search domains
     pub dns_search: Vec<String>,
     /// Redirect gateway (full tunnel)
     pub redirect_gateway: bool,
+    /// Redirect the IPv6 default route as well
+    pub redirect_gateway_ipv6: bool,
     /// Topology type
     pub topology: Topology,
     /// Ping interval
     pub ping: u32,
     /// Ping restart timeout
     pub ping_restart: u32,
+    /// Server-assigned peer ID for P_DATA_V2
+    pub peer_id: Option<u32>,
     /// Additional options
     pub options: Vec<String>,
 }
@@ -69,12 +133,16 @@ impl Default for PushReply {
             routes: vec![],
             ifconfig: None,
             ifconfig_ipv6: None,
+            routes_ipv6: vec![],
+            route_gateway: None,
             dns: vec![],
             dns_search: vec![],
             redirect_gateway: false,
+            redirect_gateway_ipv6: false,
             topology: Topology::Subnet,
             ping: 10,
             ping_restart: 60,
+            peer_id: None,
             options: vec![],
         }
     }
@@ -98,14 +166,25 @@ impl PushReply {
             parts.push(format!("ifconfig-ipv6 {}", ipv6));
         }
 
+        // Route gateway
+        if let Some(gateway) = &self.route_gateway {
+            parts.push(format!("route-gateway {}", gateway));
+        }
+
         // Routes
         for route in &self.routes {
             parts.push(route.encode());
         }
+        for route in &self.routes_ipv6 {
+            parts.push(format!("route-ipv6 {}", route));
+        }
 
         // Redirect gateway
-        if self.redirect_gateway {
-            parts.push("redirect-gateway def1".to_string());
+        match (self.redirect_gateway, self.redirect_gateway_ipv6) {
+            (true, false) => parts.push("redirect-gateway def1".to_string()),
+            (true, true) => parts.push("redirect-gateway def1 ipv6".to_string()),
+            (false, true) => parts.push("redirect-gateway ipv6 !ipv4".to_string()),
+            (false, false) => {}
         }
 
         // DNS
@@ -122,6 +201,11 @@ impl PushReply {
         parts.push(format!("ping {}", self.ping));
         parts.push(format!("ping-restart {}", self.ping_restart));
 
+        // Peer ID
+        if let Some(peer_id) = self.peer_id {
+            parts.push(format!("peer-id {}", peer_id));
+        }
+
         // Additional options
         for opt in &self.options {
             parts.push(opt.clone());
@@ -156,8 +240,14 @@ impl PushReply {
                     reply.ifconfig = Some((ip, mask));
                 }
                 Some("ifconfig-ipv6") => {
-                    if let Some(ipv6) = tokens.next() {
-                        reply.ifconfig_ipv6 = Some(ipv6.to_string());
+                    let ipv6 = tokens.collect::<Vec<_>>().join(" ");
+                    if !ipv6.is_empty() {
+                        reply.ifconfig_ipv6 = Some(ipv6);
+                    }
+                }
+                Some("route-gateway") => {
+                    if let Some(gateway) = tokens.next() {
+                        reply.route_gateway = Some(gateway.to_string());
                     }
                 }
                 Some("route") => {
@@ -165,8 +255,15 @@ impl PushReply {
                         reply.routes.push(route);
                     }
                 }
+                Some("route-ipv6") => {
+                    if let Some(route) = tokens.next() {
+                        reply.routes_ipv6.push(route.to_string());
+                    }
+                }
                 Some("redirect-gateway") => {
-                    reply.redirect_gateway = true;
+                    let flags: Vec<&str> = tokens.collect();
+                    reply.redirect_gateway = !flags.contains(&"!ipv4");
+                    reply.redirect_gateway_ipv6 = flags.contains(&"ipv6");
                 }
                 Some("dhcp-option") => {
                     match tokens.next() {
@@ -193,6 +290,9 @@ impl PushReply {
                         reply.ping_restart = p;
                     }
                 }
+                Some("peer-id") => {
+                    reply.peer_id = tokens.next().and_then(|s| s.parse().ok());
+                }
                 _ => {
                     reply.options.push(part.to_string());
                 }
@@ -346,13 +446,21 @@ impl AuthMessage {
     }
 }
 
+/// IV_PROTO flag: client supports P_DATA_V2 with a server-assigned peer ID
+pub const IV_PROTO_DATA_V2: u32 = 1 << 1;
+
+/// IV_PROTO flag: client supports TLS keying material export
+pub const IV_PROTO_TLS_KEY_EXPORT: u32 = 1 << 3;
+
 /// Key method v2 data (exchanged during TLS handshake)
 #[derive(Debug, Clone)]
 pub struct KeyMethodV2 {
-    /// Pre-master secret (48 bytes)
-    pub pre_master: [u8; 48],
-    /// Random data (32 bytes)
-    pub random: [u8; 32],
+    /// Pre-master secret (48 bytes, sent by the client only)
+    pub pre_master: Option<[u8; 48]>,
+    /// Random seeding the master secret (32 bytes)
+    pub random1: [u8; 32],
+    /// Random seeding the key expansion (32 bytes)
+    pub random2: [u8; 32],
     /// Options string
     pub options: String,
     /// Username (if using auth)
@@ -364,6 +472,22 @@ pub struct KeyMethodV2 {
 }
 
 impl KeyMethodV2 {
+    /// Key method identifier
+    pub const KEY_METHOD: u8 = 2;
+
+    /// Create the server's key method v2 message with fresh randoms
+    pub fn new_server(options: String) -> Self {
+        Self {
+            pre_master: None,
+            random1: corevpn_crypto::random_bytes(),
+            random2: corevpn_crypto::random_bytes(),
+            options,
+            username: None,
+            password: None,
+            peer_info: None,
+        }
+    }
+
     /// Encode to bytes
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
@@ -372,48 +496,165 @@ impl KeyMethodV2 {
         buf.extend_from_slice(&[0u8; 4]);
 
         // Key method (2)
-        buf.push(2);
+        buf.push(Self::KEY_METHOD);
 
-        // Pre-master secret
-        buf.extend_from_slice(&self.pre_master);
+        // Pre-master secret (client only)
+        if let Some(pre_master) = &self.pre_master {
+            buf.extend_from_slice(pre_master);
+        }
 
-        // Random
-        buf.extend_from_slice(&self.random);
+        // Randoms
+        buf.extend_from_slice(&self.random1);
+        buf.extend_from_slice(&self.random2);
 
-        // Options string length + string
-        let opts_bytes = self.options.as_bytes();
-        buf.extend_from_slice(&(opts_bytes.len() as u16).to_be_bytes());
-        buf.extend_from_slice(opts_bytes);
+        // Options, username and password are always present
+        write_string(&mut buf, Some(&self.options));
+        write_string(&mut buf, self.username.as_deref());
+        write_string(&mut buf, self.password.as_deref());
 
-        // Username (optional)
-        if let Some(username) = &self.username {
-            let username_bytes = username.as_bytes();
-            buf.extend_from_slice(&(username_bytes.len() as u16).to_be_bytes());
-            buf.extend_from_slice(username_bytes);
-        } else {
-            buf.extend_from_slice(&0u16.to_be_bytes());
+        // Peer info (optional)
+        if self.peer_info.is_some() {
+            write_string(&mut buf, self.peer_info.as_deref());
         }
 
-        // Password (optional)
-        if let Some(password) = &self.password {
-            let password_bytes = password.as_bytes();
-            buf.extend_from_slice(&(password_bytes.len() as u16).to_be_bytes());
-            buf.extend_from_slice(password_bytes);
-        } else {
-            buf.extend_from_slice(&0u16.to_be_bytes());
+        buf
+    }
+
+    /// Parse from bytes
+    ///
+    /// `from_client` selects the client layout, which carries the
+    /// pre-master secret ahead of the randoms. Also returns the number of
+    /// bytes the message took up, as the next control message may follow
+    /// it in the same TLS record.
+    pub fn parse(data: &[u8], from_client: bool) -> Result<(Self, usize)> {
+        let fixed_len = 5 + if from_client { 48 } else { 0 } + 64;
+        if data.len() < fixed_len {
+            return Err(ProtocolError::PacketTooShort {
+                expected: fixed_len,
+                got: data.len(),
+            });
         }
 
-        // Peer info (optional)
-        if let Some(peer_info) = &self.peer_info {
-            let peer_info_bytes = peer_info.as_bytes();
-            buf.extend_from_slice(&(peer_info_bytes.len() as u16).to_be_bytes());
-            buf.extend_from_slice(peer_info_bytes);
+        if data[..4] != [0u8; 4] {
+            return Err(ProtocolError::InvalidPacket("key method: missing literal zero".into()));
+        }
+        if data[4] & 0x0F != Self::KEY_METHOD {
+            return Err(ProtocolError::InvalidPacket(format!(
+                "unsupported key method {}",
+                data[4] & 0x0F
+            )));
         }
 
-        buf
+        let mut pos = 5;
+        let pre_master = if from_client {
+            let mut pre_master = [0u8; 48];
+            pre_master.copy_from_slice(&data[pos..pos + 48]);
+            pos += 48;
+            Some(pre_master)
+        } else {
+            None
+        };
+
+        let mut random1 = [0u8; 32];
+        random1.copy_from_slice(&data[pos..pos + 32]);
+        pos += 32;
+        let mut random2 = [0u8; 32];
+        random2.copy_from_slice(&data[pos..pos + 32]);
+        pos += 32;
+
+        let options = read_string(data, &mut pos)?.unwrap_or_default();
+
+        // Username, password and peer info may be absent on older peers
+        let username = if pos < data.len() { read_string(data, &mut pos)? } else { None };
+        let password = if pos < data.len() { read_string(data, &mut pos)? } else { None };
+        let peer_info = if pos < data.len() { read_string(data, &mut pos)? } else { None };
+
+        let km = Self {
+            pre_master,
+            random1,
+            random2,
+            options,
+            username,
+            password,
+            peer_info,
+        };
+        Ok((km, pos))
+    }
+
+    /// Look up a `KEY=value` entry in the peer info
+    pub fn peer_info_value(&self, key: &str) -> Option<&str> {
+        self.peer_info.as_deref()?.lines().find_map(|line| {
+            let (k, v) = line.split_once('=')?;
+            (k == key).then_some(v)
+        })
+    }
+
+    /// Get the auth-user-pass credentials, if the client sent any
+    pub fn auth_message(&self) -> Option<AuthMessage> {
+        Some(AuthMessage {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Check if the peer advertises an IV_SSO method (e.g. `webauth`, `openurl`, `crtext`)
+    pub fn supports_sso(&self, method: &str) -> bool {
+        self.peer_info_value("IV_SSO")
+            .is_some_and(|methods| methods.split(',').any(|m| m == method))
+    }
+
+    /// Get the IV_PROTO capability flags advertised by the peer
+    pub fn iv_proto(&self) -> u32 {
+        self.peer_info_value("IV_PROTO")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// Write an OpenVPN length-prefixed, NUL-terminated string
+///
+/// `None` and empty strings are written as a zero length.
+fn write_string(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) if !s.is_empty() => {
+            buf.extend_from_slice(&((s.len() + 1) as u16).to_be_bytes());
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+        }
+        _ => buf.extend_from_slice(&0u16.to_be_bytes()),
     }
 }
 
+/// Read an OpenVPN length-prefixed, NUL-terminated string
+fn read_string(data: &[u8], pos: &mut usize) -> Result<Option<String>> {
+    if data.len() < *pos + 2 {
+        return Err(ProtocolError::PacketTooShort {
+            expected: *pos + 2,
+            got: data.len(),
+        });
+    }
+    let len = u16::from_be_bytes([data[*pos], data[*pos + 1]]) as usize;
+    *pos += 2;
+
+    if len == 0 {
+        return Ok(None);
+    }
+    if data.len() < *pos + len {
+        return Err(ProtocolError::PacketTooShort {
+            expected: *pos + len,
+            got: data.len(),
+        });
+    }
+
+    let raw = &data[*pos..*pos + len];
+    *pos += len;
+
+    let raw = raw.split(|&b| b == 0).next().unwrap_or_default();
+    let s = std::str::from_utf8(raw)
+        .map_err(|_| ProtocolError::InvalidPacket("invalid UTF-8 in key method string".into()))?;
+    Ok(Some(s.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -425,13 +666,67 @@ mod tests {
         reply.dns.push("1.1.1.1".to_string());
         reply.routes.push(PushRoute::new("192.168.1.0", "255.255.255.0"));
         reply.redirect_gateway = true;
+        reply.route_gateway = Some("10.8.0.1".to_string());
+        reply.peer_id = Some(42);
 
         let encoded = reply.encode();
+        assert!(encoded.contains(",peer-id 42"));
         let parsed = PushReply::parse(&encoded).unwrap();
 
         assert_eq!(parsed.ifconfig, reply.ifconfig);
+        assert_eq!(parsed.route_gateway, reply.route_gateway);
+        assert_eq!(parsed.peer_id, Some(42));
         assert_eq!(parsed.dns, reply.dns);
         assert!(parsed.redirect_gateway);
+        assert!(!parsed.redirect_gateway_ipv6);
+    }
+
+    #[test]
+    fn test_push_reply_ipv6() {
+        let mut reply = PushReply {
+            ifconfig_ipv6: Some("fd00:8::2/64 fd00:8::1".to_string()),
+            routes_ipv6: vec!["2001:db8::/32".to_string()],
+            redirect_gateway: true,
+            redirect_gateway_ipv6: true,
+            ..Default::default()
+        };
+
+        let encoded = reply.encode();
+        assert!(encoded.contains(",ifconfig-ipv6 fd00:8::2/64 fd00:8::1,"));
+        assert!(encoded.contains(",route-ipv6 2001:db8::/32,"));
+        assert!(encoded.contains(",redirect-gateway def1 ipv6,"));
+
+        let parsed = PushReply::parse(&encoded).unwrap();
+        assert_eq!(parsed.ifconfig_ipv6, reply.ifconfig_ipv6);
+        assert_eq!(parsed.routes_ipv6, reply.routes_ipv6);
+        assert!(parsed.redirect_gateway && parsed.redirect_gateway_ipv6);
+
+        // IPv6-only redirect leaves the IPv4 default route alone
+        reply.redirect_gateway = false;
+        let parsed = PushReply::parse(&reply.encode()).unwrap();
+        assert!(!parsed.redirect_gateway && parsed.redirect_gateway_ipv6);
+    }
+
+    #[test]
+    fn test_control_message_text() {
+        assert!(matches!(
+            ControlMessage::parse(b"PUSH_REQUEST\0").unwrap(),
+            ControlMessage::PushRequest
+        ));
+
+        let encoded = ControlMessage::AuthFailed(Some("bad token".into())).encode();
+        assert_eq!(encoded, b"AUTH_FAILED,bad token\0");
+        match ControlMessage::parse(&encoded).unwrap() {
+            ControlMessage::AuthFailed(reason) => assert_eq!(reason.as_deref(), Some("bad token")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_eq!(ControlMessage::Restart(None).encode(), b"RESTART\0");
+        assert_eq!(
+            ControlMessage::AuthPending(Some("timeout 300".into())).encode(),
+            b"AUTH_PENDING,timeout 300\0"
+        );
+        assert!(ControlMessage::parse(b"BOGUS").is_err());
     }
 
     #[test]
@@ -447,4 +742,80 @@ mod tests {
         assert_eq!(parsed.username, "user");
         assert_eq!(parsed.password, "pass");
     }
+
+    #[test]
+    fn test_key_method_v2_client_roundtrip() {
+        let km = KeyMethodV2 {
+            pre_master: Some([0x11; 48]),
+            random1: [0x22; 32],
+            random2: [0x33; 32],
+            options: "V4,dev-type tun".to_string(),
+            username: Some("alice".to_string()),
+            password: Some("secret".to_string()),
+            peer_info: Some("IV_VER=2.6.8\nIV_PROTO=990\nIV_SSO=openurl,webauth\n".to_string()),
+        };
+
+        let encoded = km.encode();
+        let (parsed, consumed) = KeyMethodV2::parse(&encoded, true).unwrap();
+        assert_eq!(consumed, encoded.len());
+
+        assert_eq!(parsed.pre_master, km.pre_master);
+        assert_eq!(parsed.random1, km.random1);
+        assert_eq!(parsed.random2, km.random2);
+        assert_eq!(parsed.options, km.options);
+        assert_eq!(parsed.username.as_deref(), Some("alice"));
+        assert_eq!(parsed.password.as_deref(), Some("secret"));
+        assert_eq!(parsed.peer_info_value("IV_VER"), Some("2.6.8"));
+        assert_ne!(parsed.iv_proto() & IV_PROTO_TLS_KEY_EXPORT, 0);
+        assert!(parsed.supports_sso("webauth"));
+        assert!(!parsed.supports_sso("crtext"));
+
+        let auth = parsed.auth_message().unwrap();
+        assert_eq!((auth.username.as_str(), auth.password.as_str()), ("alice", "secret"));
+    }
+
+    #[test]
+    fn test_key_method_v2_server_layout() {
+        let km = KeyMethodV2::new_server("V4,tls-server".to_string());
+        let encoded = km.encode();
+
+        // zero + method + randoms + options + empty username/password
+        assert_eq!(encoded.len(), 5 + 64 + 2 + 14 + 2 + 2);
+
+        let (parsed, _) = KeyMethodV2::parse(&encoded, false).unwrap();
+        assert!(parsed.pre_master.is_none());
+        assert_eq!(parsed.random1, km.random1);
+        assert_eq!(parsed.options, "V4,tls-server");
+        assert!(parsed.username.is_none());
+    }
+
+    #[test]
+    fn test_key_method_v2_followed_by_message() {
+        let km = KeyMethodV2 {
+            pre_master: Some([0x11; 48]),
+            username: Some("alice".to_string()),
+            password: Some("secret".to_string()),
+            peer_info: Some("IV_VER=2.6.8\n".to_string()),
+            ..KeyMethodV2::new_server("V4".to_string())
+        };
+        let mut data = km.encode();
+        let len = data.len();
+        data.extend_from_slice(b"PUSH_REQUEST\0");
+
+        // A PUSH_REQUEST coalesced into the same record is left for the caller
+        let (parsed, consumed) = KeyMethodV2::parse(&data, true).unwrap();
+        assert_eq!(consumed, len);
+        assert_eq!(parsed.peer_info_value("IV_VER"), Some("2.6.8"));
+        assert!(matches!(ControlMessage::parse(&data[consumed..]).unwrap(), ControlMessage::PushRequest));
+    }
+
+    #[test]
+    fn test_key_method_v2_truncated() {
+        let km = KeyMethodV2::new_server("V4".to_string());
+        let encoded = km.encode();
+        assert!(matches!(
+            KeyMethodV2::parse(&encoded[..40], false),
+            Err(ProtocolError::PacketTooShort { .. })
+        ));
+    }
 }
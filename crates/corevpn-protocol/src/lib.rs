@@ -14,12 +14,17 @@ pub mod data;
 pub mod reliable;
 pub mod session;
 pub mod tls;
+pub mod tls_wrap;
 
 pub use error::{ProtocolError, Result};
 pub use opcode::{OpCode, KeyId};
 pub use packet::{Packet, PacketHeader};
-pub use control::{ControlPacket, ControlMessage};
-pub use data::{DataPacket, DataChannel};
+pub use control::{ControlPacket, ControlMessage, KeyMethodV2};
+pub use data::{DataPacket, DataChannel, DataChannels, UNDEFINED_PEER_ID};
 pub use reliable::{ReliableTransport, ReliableConfig, TlsRecordReassembler};
 pub use session::{ProtocolSession, ProtocolState, ProcessedPacket};
-pub use tls::{TlsHandler, create_server_config, load_certs_from_pem, load_key_from_pem};
+pub use tls::{
+    TlsHandler, RevocableClientVerifier, create_server_config, load_certs_from_pem,
+    load_crls_from_pem, load_key_from_pem,
+};
+pub use tls_wrap::{TlsWrap, TlsWrapKey};
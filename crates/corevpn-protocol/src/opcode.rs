@@ -121,9 +121,14 @@ impl KeyId {
         Self(byte & 0x07)
     }
 
-    /// Get the next key ID (wraps around)
+    /// Get the next key ID for a renegotiation
+    ///
+    /// Wraps from 7 to 1, since key ID 0 is only used by the initial handshake.
     pub fn next(&self) -> Self {
-        Self((self.0 + 1) & 0x07)
+        match (self.0 + 1) & 0x07 {
+            0 => Self(1),
+            id => Self(id),
+        }
     }
 }
 
@@ -155,6 +160,7 @@ mod tests {
     #[test]
     fn test_key_id_wrap() {
         let key_id = KeyId::new(7);
-        assert_eq!(key_id.next(), KeyId::new(0));
+        assert_eq!(key_id.next(), KeyId::new(1));
+        assert_eq!(KeyId::new(0).next(), KeyId::new(1));
     }
 }
@@ -4,13 +4,14 @@ use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 
-use corevpn_crypto::{CipherSuite, KeyMaterial};
+use corevpn_crypto::{CipherSuite, KeyMaterial, KeyStatus};
 
 use crate::{
-    KeyId, OpCode, Packet, DataPacket, DataChannel,
-    ReliableTransport, ReliableConfig, TlsRecordReassembler,
+    KeyId, KeyMethodV2, OpCode, Packet, DataChannel, DataChannels,
+    ReliableTransport, ReliableConfig, TlsRecordReassembler, TlsWrap, TlsWrapKey,
     ProtocolError, Result,
 };
+use crate::data::AeadKey;
 use crate::packet::ControlPacketData;
 
 /// Protocol session state
@@ -32,6 +33,9 @@ pub enum ProtocolState {
     Terminated,
 }
 
+/// Largest TLS record buffered from the control channel
+const MAX_TLS_BUFFER: usize = 65536;
+
 /// Session ID type (8 bytes)
 pub type SessionIdBytes = [u8; 8];
 
@@ -50,13 +54,19 @@ pub struct ProtocolSession {
     /// TLS record reassembler
     tls_reassembler: TlsRecordReassembler,
     /// Data channels (one per key ID)
-    data_channels: [Option<DataChannel>; 8],
+    data_channels: DataChannels,
+    /// Replaced key still accepted for data, and when it was replaced
+    lame_duck: Option<(KeyId, Instant)>,
+    /// When the renegotiation in progress started
+    rekey_started: Option<Instant>,
+    /// Message packet ID of our P_CONTROL_SOFT_RESET_V1 for the current key
+    soft_reset_id: Option<u32>,
     /// Peer ID (for P_DATA_V2)
     peer_id: Option<u32>,
-    /// Use tls-auth
-    use_tls_auth: bool,
-    /// tls-auth key
-    tls_auth_key: Option<corevpn_crypto::HmacAuth>,
+    /// tls-auth / tls-crypt control channel wrapping
+    tls_wrap: Option<TlsWrap>,
+    /// Running over a stream transport (TCP), so no retransmits
+    stream_transport: bool,
     /// Session creation time
     created_at: Instant,
     /// Last activity time
@@ -74,11 +84,14 @@ impl ProtocolSession {
             state: ProtocolState::Initial,
             current_key_id: KeyId::default(),
             reliable: ReliableTransport::new(ReliableConfig::default()),
-            tls_reassembler: TlsRecordReassembler::new(65536),
-            data_channels: Default::default(),
+            tls_reassembler: TlsRecordReassembler::new(MAX_TLS_BUFFER),
+            data_channels: DataChannels::default(),
+            lame_duck: None,
+            rekey_started: None,
+            soft_reset_id: None,
             peer_id: None,
-            use_tls_auth: false,
-            tls_auth_key: None,
+            tls_wrap: None,
+            stream_transport: false,
             created_at: Instant::now(),
             last_activity: Instant::now(),
             cipher_suite,
@@ -120,39 +133,87 @@ impl ProtocolSession {
 
     /// Enable tls-auth
     pub fn set_tls_auth(&mut self, key: corevpn_crypto::HmacAuth) {
-        self.use_tls_auth = true;
-        self.tls_auth_key = Some(key);
+        self.set_tls_wrap(TlsWrapKey::Auth(std::sync::Arc::new(key)));
+    }
+
+    /// Enable tls-auth or tls-crypt wrapping of control packets
+    pub fn set_tls_wrap(&mut self, key: TlsWrapKey) {
+        self.tls_wrap = Some(TlsWrap::new(key));
+    }
+
+    /// Set the peer ID sent in front of P_DATA_V2 packets
+    ///
+    /// Takes effect for data channel keys installed afterwards.
+    pub fn set_peer_id(&mut self, peer_id: u32) {
+        self.peer_id = Some(peer_id);
+    }
+
+    /// Peer ID used for P_DATA_V2, if one was assigned
+    pub fn peer_id(&self) -> Option<u32> {
+        self.peer_id
+    }
+
+    /// Mark the session as running over a stream transport (TCP)
+    ///
+    /// Control packets are still numbered and acknowledged, but never
+    /// retransmitted since the transport already guarantees delivery.
+    pub fn set_stream_transport(&mut self, stream: bool) {
+        self.stream_transport = stream;
     }
 
-    /// Process incoming packet
+    /// Process an incoming control packet
+    ///
+    /// Data packets are not accepted here: the data path decrypts them in
+    /// place with [`DataChannels::decrypt_in_place`] on its shared
+    /// [`data_channels`](Self::data_channels).
     pub fn process_packet(&mut self, data: &[u8]) -> Result<ProcessedPacket> {
+        let opcode = data.first().map(|&byte| OpCode::from_byte(byte)).transpose()?;
+        if opcode.is_some_and(|opcode| opcode.is_data()) {
+            return Err(ProtocolError::InvalidPacket("data packet on the control path".into()));
+        }
         self.last_activity = Instant::now();
 
-        // Verify HMAC if tls-auth enabled
-        let data = if self.use_tls_auth {
-            if let Some(key) = &self.tls_auth_key {
-                // First byte is opcode, check if control
-                if !data.is_empty() && OpCode::from_byte(data[0])?.is_control() {
-                    key.unwrap(data)?
-                } else {
-                    data.to_vec()
-                }
-            } else {
-                data.to_vec()
+        // Verify and unwrap control packets if tls-auth/tls-crypt enabled
+        let unwrapped;
+        let data = match &mut self.tls_wrap {
+            Some(wrap) if opcode.is_some_and(|opcode| opcode.is_control()) => {
+                unwrapped = wrap.unwrap(data)?;
+                &unwrapped[..]
             }
-        } else {
-            data.to_vec()
+            _ => data,
         };
 
-        let packet = Packet::parse(&data, false)?;
+        let packet = Packet::parse(data, false)?;
 
         match packet {
             Packet::Control(ctrl) => self.process_control_packet(ctrl),
-            Packet::Data(data_pkt) => self.process_data_packet(data_pkt),
+            Packet::Data(_) => Err(ProtocolError::InvalidPacket("data packet on the control path".into())),
         }
     }
 
     fn process_control_packet(&mut self, ctrl: ControlPacketData) -> Result<ProcessedPacket> {
+        // Each key ID has its own control channel sequence
+        let key_id = ctrl.header.key_id;
+        let new_key = ctrl.header.opcode == OpCode::SoftResetV1 && key_id != self.current_key_id;
+        if new_key && key_id != self.current_key_id.next() {
+            // Renegotiation moves to the next key ID only: anything else would
+            // reuse key ID 0 or overwrite the lame duck key
+            return Ok(ProcessedPacket::None);
+        }
+        if new_key {
+            if self.data_channels.is_empty() {
+                return Err(ProtocolError::HandshakeFailed("soft reset before keys were installed".into()));
+            }
+            // Only the established peer may renegotiate its keys
+            if ctrl.header.session_id.is_none() || ctrl.header.session_id != self.remote_session_id {
+                return Err(ProtocolError::InvalidSessionId);
+            }
+            self.begin_key(key_id);
+        } else if key_id != self.current_key_id {
+            // Left over from the TLS session of a replaced key
+            return Ok(ProcessedPacket::None);
+        }
+
         // Process ACKs
         if !ctrl.acks.is_empty() {
             self.reliable.process_acks(&ctrl.acks);
@@ -196,30 +257,20 @@ impl ProtocolSession {
                 Ok(ProcessedPacket::None)
             }
             OpCode::SoftResetV1 => {
-                // Key renegotiation
-                self.state = ProtocolState::Rekeying;
-                Ok(ProcessedPacket::SoftReset)
+                // Key renegotiation, or the peer answering ours
+                if let Some(packet_id) = ctrl.message_packet_id {
+                    self.reliable.receive(packet_id, ctrl.payload);
+                }
+                if new_key {
+                    Ok(ProcessedPacket::SoftReset { key_id })
+                } else {
+                    Ok(ProcessedPacket::None)
+                }
             }
             _ => Err(ProtocolError::UnknownOpcode(ctrl.header.opcode as u8)),
         }
     }
 
-    fn process_data_packet(&mut self, data_pkt: crate::packet::DataPacketData) -> Result<ProcessedPacket> {
-        let packet = DataPacket {
-            key_id: data_pkt.header.key_id,
-            peer_id: data_pkt.peer_id,
-            payload: data_pkt.payload,
-        };
-
-        let key_id = packet.key_id.0 as usize;
-        if let Some(channel) = &mut self.data_channels[key_id] {
-            let decrypted = channel.decrypt(&packet)?;
-            Ok(ProcessedPacket::Data(decrypted))
-        } else {
-            Err(ProtocolError::KeyNotAvailable(packet.key_id.0))
-        }
-    }
-
     /// Create a hard reset response packet
     pub fn create_hard_reset_response(&mut self) -> Result<Bytes> {
         let packet = crate::packet::ControlPacketData {
@@ -238,7 +289,7 @@ impl ProtocolSession {
         };
 
         let serialized = Packet::Control(packet).serialize();
-        Ok(self.maybe_wrap_tls_auth(serialized.freeze()))
+        self.wrap_control(serialized.freeze())
     }
 
     /// Create a control packet with TLS data
@@ -261,7 +312,33 @@ impl ProtocolSession {
         };
 
         let serialized = Packet::Control(packet).serialize();
-        Ok(self.maybe_wrap_tls_auth(serialized.freeze()))
+        self.wrap_control(serialized.freeze())
+    }
+
+    /// Create a P_CONTROL_SOFT_RESET_V1 for the current key ID
+    ///
+    /// Sent to start a renegotiation and to answer the peer's soft reset.
+    pub fn create_soft_reset(&mut self) -> Result<Bytes> {
+        let (packet_id, _) = self.reliable.send(Bytes::new())?;
+        self.soft_reset_id = Some(packet_id);
+
+        let packet = crate::packet::ControlPacketData {
+            header: crate::PacketHeader {
+                opcode: OpCode::SoftResetV1,
+                key_id: self.current_key_id,
+                session_id: Some(self.local_session_id),
+                hmac: None,
+                packet_id: None,
+                timestamp: None,
+            },
+            remote_session_id: self.remote_session_id,
+            acks: self.reliable.get_acks(),
+            message_packet_id: Some(packet_id),
+            payload: Bytes::new(),
+        };
+
+        let serialized = Packet::Control(packet).serialize();
+        self.wrap_control(serialized.freeze())
     }
 
     /// Create an ACK packet
@@ -288,27 +365,37 @@ impl ProtocolSession {
 
         self.reliable.ack_sent();
         let serialized = Packet::Control(packet).serialize();
-        Some(self.maybe_wrap_tls_auth(serialized.freeze()))
+        self.wrap_control(serialized.freeze()).ok()
     }
 
-    /// Install data channel keys
+    /// Install data channel keys for the current key ID
+    ///
+    /// Keys being replaced keep decrypting as a lame duck until
+    /// [`ProtocolSession::expire_lame_duck`] retires them.
     pub fn install_keys(&mut self, key_material: &KeyMaterial, is_server: bool) {
         let key_id = self.current_key_id;
-        let idx = key_id.0 as usize;
+        let replaced = self.data_channels.current();
+        if !self.data_channels.is_empty() && replaced != key_id {
+            self.lame_duck = Some((replaced, Instant::now()));
+        }
+        self.rekey_started = None;
+
+        let client_key = AeadKey::new(
+            key_material.client_data_key(self.cipher_suite),
+            key_material.client_implicit_iv(),
+        );
+        let server_key = AeadKey::new(
+            key_material.server_data_key(self.cipher_suite),
+            key_material.server_implicit_iv(),
+        );
 
         let (encrypt_key, decrypt_key) = if is_server {
-            (
-                key_material.server_data_key(self.cipher_suite),
-                key_material.client_data_key(self.cipher_suite),
-            )
+            (server_key, client_key)
         } else {
-            (
-                key_material.client_data_key(self.cipher_suite),
-                key_material.server_data_key(self.cipher_suite),
-            )
+            (client_key, server_key)
         };
 
-        self.data_channels[idx] = Some(DataChannel::new(
+        self.data_channels.install(DataChannel::new_openvpn(
             key_id,
             encrypt_key,
             decrypt_key,
@@ -317,27 +404,65 @@ impl ProtocolSession {
         ));
     }
 
-    /// Encrypt data for transmission
-    pub fn encrypt_data(&mut self, data: &[u8]) -> Result<Bytes> {
-        let idx = self.current_key_id.0 as usize;
-        if let Some(channel) = &mut self.data_channels[idx] {
-            let packet = channel.encrypt(data)?;
-            Ok(packet.serialize().freeze())
+    /// Derive keys from a key-method-2 exchange and install them
+    pub fn install_exchanged_keys(
+        &mut self,
+        client: &KeyMethodV2,
+        server: &KeyMethodV2,
+        is_server: bool,
+    ) -> Result<()> {
+        let pre_master = client.pre_master.as_ref()
+            .ok_or_else(|| ProtocolError::HandshakeFailed("missing pre-master secret".into()))?;
+        let remote_session_id = self.remote_session_id
+            .ok_or(ProtocolError::InvalidSessionId)?;
+
+        let (client_sid, server_sid) = if is_server {
+            (remote_session_id, self.local_session_id)
         } else {
-            Err(ProtocolError::KeyNotAvailable(self.current_key_id.0))
-        }
+            (self.local_session_id, remote_session_id)
+        };
+
+        let key_material = corevpn_crypto::openvpn_key_expansion(
+            pre_master,
+            (&client.random1, &client.random2),
+            (&server.random1, &server.random2),
+            &client_sid,
+            &server_sid,
+        )?;
+
+        self.install_keys(&key_material, is_server);
+        Ok(())
+    }
+
+    /// Encrypt data for transmission
+    pub fn encrypt_data(&self, data: &[u8]) -> Result<Bytes> {
+        self.data_channels.encrypt(data)
+    }
+
+    /// Installed data channels, shareable with a separate data path
+    pub fn data_channels(&self) -> &DataChannels {
+        &self.data_channels
     }
 
     /// Get packets needing retransmission
     pub fn get_retransmits(&mut self) -> Vec<Bytes> {
-        self.reliable
+        if self.stream_transport {
+            return Vec::new();
+        }
+
+        let packets: Vec<Bytes> = self.reliable
             .get_retransmits()
             .into_iter()
             .map(|(id, data)| {
                 // Rebuild packet with same ID
+                let opcode = if self.soft_reset_id == Some(id) {
+                    OpCode::SoftResetV1
+                } else {
+                    OpCode::ControlV1
+                };
                 let packet = crate::packet::ControlPacketData {
                     header: crate::PacketHeader {
-                        opcode: OpCode::ControlV1,
+                        opcode,
                         key_id: self.current_key_id,
                         session_id: Some(self.local_session_id),
                         hmac: None,
@@ -349,9 +474,13 @@ impl ProtocolSession {
                     message_packet_id: Some(id),
                     payload: data,
                 };
-                let serialized = Packet::Control(packet).serialize();
-                self.maybe_wrap_tls_auth(serialized.freeze())
+                Packet::Control(packet).serialize().freeze()
             })
+            .collect();
+
+        packets
+            .into_iter()
+            .filter_map(|packet| self.wrap_control(packet).ok())
             .collect()
     }
 
@@ -380,18 +509,77 @@ impl ProtocolSession {
         self.last_activity.elapsed()
     }
 
-    fn maybe_wrap_tls_auth(&self, data: Bytes) -> Bytes {
-        if self.use_tls_auth {
-            if let Some(key) = &self.tls_auth_key {
-                return Bytes::from(key.wrap(&data));
+    fn wrap_control(&mut self, data: Bytes) -> Result<Bytes> {
+        match &mut self.tls_wrap {
+            Some(wrap) => wrap.wrap(&data),
+            None => Ok(data),
+        }
+    }
+
+    /// Key ID of the control channel and newest data channel keys
+    pub fn key_id(&self) -> KeyId {
+        self.current_key_id
+    }
+
+    /// Start renegotiating the data channel keys on the next key ID
+    ///
+    /// Returns the P_CONTROL_SOFT_RESET_V1 to send. The peer answers with its
+    /// own soft reset and a new TLS session on the new key ID, while data
+    /// keeps flowing on the current keys.
+    pub fn start_renegotiation(&mut self) -> Result<Bytes> {
+        if self.state != ProtocolState::Established {
+            return Err(ProtocolError::HandshakeFailed("renegotiation before the session is established".into()));
+        }
+
+        self.begin_key(self.current_key_id.next());
+        self.create_soft_reset()
+    }
+
+    /// How close the current data channel key is to its usage limits
+    ///
+    /// Once this reaches [`KeyStatus::Renegotiate`] a new key should be
+    /// negotiated before the current one is refused.
+    pub fn key_status(&self) -> KeyStatus {
+        self.data_channels.key_status()
+    }
+
+    /// Check if a renegotiation is in progress
+    pub fn is_renegotiating(&self) -> bool {
+        self.rekey_started.is_some()
+    }
+
+    /// Time spent on the renegotiation in progress
+    pub fn renegotiation_time(&self) -> Option<Duration> {
+        self.rekey_started.map(|started| started.elapsed())
+    }
+
+    /// Replaced key still accepted for data, if any
+    pub fn lame_duck(&self) -> Option<KeyId> {
+        self.lame_duck.map(|(key_id, _)| key_id)
+    }
+
+    /// Retire the lame duck key once it has outlived the transition window
+    ///
+    /// Returns true if a key was retired.
+    pub fn expire_lame_duck(&mut self, transition_window: Duration) -> bool {
+        match self.lame_duck {
+            Some((key_id, replaced_at)) if replaced_at.elapsed() >= transition_window => {
+                self.data_channels.retire(key_id);
+                self.lame_duck = None;
+                true
             }
+            _ => false,
         }
-        data
     }
 
-    /// Rotate to next key ID (for rekeying)
-    pub fn rotate_key(&mut self) {
-        self.current_key_id = self.current_key_id.next();
+    /// Switch the control channel to a new key ID with fresh reliable and TLS record state
+    fn begin_key(&mut self, key_id: KeyId) {
+        self.current_key_id = key_id;
+        self.reliable = ReliableTransport::new(ReliableConfig::default());
+        self.tls_reassembler = TlsRecordReassembler::new(MAX_TLS_BUFFER);
+        self.soft_reset_id = None;
+        self.rekey_started = Some(Instant::now());
+        self.state = ProtocolState::Rekeying;
     }
 }
 
@@ -409,10 +597,13 @@ pub enum ProcessedPacket {
     HardResetAck,
     /// TLS records to process
     TlsData(Vec<Bytes>),
-    /// Decrypted data packet
-    Data(Bytes),
-    /// Soft reset (rekey)
-    SoftReset,
+    /// Soft reset: the peer started renegotiating keys on a new key ID
+    ///
+    /// Answer with [`ProtocolSession::create_soft_reset`] and a new TLS session.
+    SoftReset {
+        /// Key ID being negotiated
+        key_id: KeyId,
+    },
 }
 
 #[cfg(test)]
@@ -441,4 +632,187 @@ mod tests {
         matches!(result, ProcessedPacket::HardReset { .. });
         assert_eq!(session.state(), ProtocolState::TlsHandshake);
     }
+
+    #[test]
+    fn test_stream_transport_skips_retransmits() {
+        let mut session = ProtocolSession::new_server(CipherSuite::ChaCha20Poly1305);
+        session.set_stream_transport(true);
+        session.reliable = ReliableTransport::new(ReliableConfig {
+            initial_rto: Duration::ZERO,
+            ..ReliableConfig::default()
+        });
+
+        session.create_control_packet(Bytes::from_static(b"hello")).unwrap();
+        assert!(session.get_retransmits().is_empty());
+
+        session.set_stream_transport(false);
+        assert_eq!(session.get_retransmits().len(), 1);
+    }
+
+    #[test]
+    fn test_exchanged_keys_interoperate() {
+        let mut server = ProtocolSession::new_server(CipherSuite::ChaCha20Poly1305);
+        let mut client = ProtocolSession::new_client(CipherSuite::ChaCha20Poly1305);
+        server.set_remote_session_id(*client.local_session_id());
+        client.set_remote_session_id(*server.local_session_id());
+
+        let client_km = KeyMethodV2 {
+            pre_master: Some(corevpn_crypto::random_bytes()),
+            ..KeyMethodV2::new_server(String::new())
+        };
+        let server_km = KeyMethodV2::new_server(String::new());
+
+        server.install_exchanged_keys(&client_km, &server_km, true).unwrap();
+        client.install_exchanged_keys(&client_km, &server_km, false).unwrap();
+
+        let packet = client.encrypt_data(b"ping").unwrap();
+        assert_eq!(&server.data_channels().decrypt(&packet).unwrap()[..], b"ping");
+
+        // Data packets never go through the control path
+        assert!(matches!(server.process_packet(&packet), Err(ProtocolError::InvalidPacket(_))));
+    }
+
+    #[test]
+    fn test_renegotiation() {
+        let mut server = ProtocolSession::new_server(CipherSuite::ChaCha20Poly1305);
+        let mut client = ProtocolSession::new_client(CipherSuite::ChaCha20Poly1305);
+        server.set_remote_session_id(*client.local_session_id());
+        client.set_remote_session_id(*server.local_session_id());
+
+        let exchange = |server: &mut ProtocolSession, client: &mut ProtocolSession| {
+            let client_km = KeyMethodV2 {
+                pre_master: Some(corevpn_crypto::random_bytes()),
+                ..KeyMethodV2::new_server(String::new())
+            };
+            let server_km = KeyMethodV2::new_server(String::new());
+            server.install_exchanged_keys(&client_km, &server_km, true).unwrap();
+            client.install_exchanged_keys(&client_km, &server_km, false).unwrap();
+            server.set_state(ProtocolState::Established);
+            client.set_state(ProtocolState::Established);
+        };
+        exchange(&mut server, &mut client);
+        let old_packet = client.encrypt_data(b"old").unwrap();
+
+        // Client starts a renegotiation, server answers on the new key ID
+        let soft_reset = client.start_renegotiation().unwrap();
+        match server.process_packet(&soft_reset).unwrap() {
+            ProcessedPacket::SoftReset { key_id } => assert_eq!(key_id, KeyId::new(1)),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(server.is_renegotiating());
+        let answer = server.create_soft_reset().unwrap();
+        assert!(matches!(client.process_packet(&answer).unwrap(), ProcessedPacket::None));
+
+        // Control packets of the replaced key are ignored
+        let stale = [0x28, 1, 2, 3, 4, 5, 6, 7, 8, 0];
+        assert!(matches!(server.process_packet(&stale).unwrap(), ProcessedPacket::None));
+
+        exchange(&mut server, &mut client);
+        assert!(!server.is_renegotiating());
+        assert_eq!(server.data_channels().current(), KeyId::new(1));
+        let packet = client.encrypt_data(b"new").unwrap();
+        assert_eq!(&server.data_channels().decrypt(&packet).unwrap()[..], b"new");
+
+        // The old key keeps decrypting until the transition window ends
+        assert!(!server.expire_lame_duck(Duration::from_secs(60)));
+        assert!(server.data_channels().decrypt(&old_packet).is_ok());
+        assert!(server.expire_lame_duck(Duration::ZERO));
+        let late = client.data_channels().get(KeyId::new(0)).unwrap().encrypt(b"late").unwrap().serialize();
+        assert!(matches!(server.data_channels().decrypt(&late), Err(ProtocolError::KeyNotAvailable(0))));
+    }
+
+    #[test]
+    fn test_soft_reset_session_mismatch() {
+        let mut server = ProtocolSession::new_server(CipherSuite::ChaCha20Poly1305);
+        let mut client = ProtocolSession::new_client(CipherSuite::ChaCha20Poly1305);
+        server.set_remote_session_id(*client.local_session_id());
+        let client_km = KeyMethodV2 {
+            pre_master: Some(corevpn_crypto::random_bytes()),
+            ..KeyMethodV2::new_server(String::new())
+        };
+        let server_km = KeyMethodV2::new_server(String::new());
+        server.install_exchanged_keys(&client_km, &server_km, true).unwrap();
+        server.set_state(ProtocolState::Established);
+
+        // A soft reset from another session doesn't start a renegotiation
+        let mut other = ProtocolSession::new_client(CipherSuite::ChaCha20Poly1305);
+        other.set_remote_session_id(*server.local_session_id());
+        other.set_state(ProtocolState::Established);
+        let soft_reset = other.start_renegotiation().unwrap();
+        assert!(matches!(server.process_packet(&soft_reset), Err(ProtocolError::InvalidSessionId)));
+        assert!(!server.is_renegotiating());
+        assert_eq!(server.key_id(), KeyId::new(0));
+
+        client.set_remote_session_id(*server.local_session_id());
+        client.set_state(ProtocolState::Established);
+        let soft_reset = client.start_renegotiation().unwrap();
+        assert!(matches!(server.process_packet(&soft_reset).unwrap(), ProcessedPacket::SoftReset { .. }));
+    }
+
+    #[test]
+    fn test_soft_reset_key_id() {
+        let mut server = ProtocolSession::new_server(CipherSuite::ChaCha20Poly1305);
+        let mut client = ProtocolSession::new_client(CipherSuite::ChaCha20Poly1305);
+        server.set_remote_session_id(*client.local_session_id());
+        client.set_remote_session_id(*server.local_session_id());
+        let exchange = |server: &mut ProtocolSession, client: &mut ProtocolSession| {
+            let client_km = KeyMethodV2 {
+                pre_master: Some(corevpn_crypto::random_bytes()),
+                ..KeyMethodV2::new_server(String::new())
+            };
+            let server_km = KeyMethodV2::new_server(String::new());
+            server.install_exchanged_keys(&client_km, &server_km, true).unwrap();
+            client.install_exchanged_keys(&client_km, &server_km, false).unwrap();
+            server.set_state(ProtocolState::Established);
+            client.set_state(ProtocolState::Established);
+        };
+        exchange(&mut server, &mut client);
+
+        // The same soft reset on other key IDs
+        let soft_reset = client.start_renegotiation().unwrap();
+        let with_key_id = |key_id: u8| {
+            let mut packet = soft_reset.to_vec();
+            packet[0] = (packet[0] & !0x07) | key_id;
+            packet
+        };
+
+        // Skipping ahead is dropped
+        for key_id in [2, 7] {
+            assert!(matches!(server.process_packet(&with_key_id(key_id)).unwrap(), ProcessedPacket::None));
+            assert!(!server.is_renegotiating());
+            assert_eq!(server.key_id(), KeyId::new(0));
+        }
+        assert!(matches!(server.process_packet(&soft_reset).unwrap(), ProcessedPacket::SoftReset { .. }));
+        exchange(&mut server, &mut client);
+        assert_eq!(server.key_id(), KeyId::new(1));
+
+        // Key ID 0, also the lame duck's, is never reused
+        assert!(matches!(server.process_packet(&with_key_id(0)).unwrap(), ProcessedPacket::None));
+        assert!(!server.is_renegotiating());
+        assert_eq!(server.key_id(), KeyId::new(1));
+        assert!(matches!(server.process_packet(&with_key_id(2)).unwrap(), ProcessedPacket::SoftReset { .. }));
+    }
+
+    #[test]
+    fn test_tls_wrap_required() {
+        let ta_key = corevpn_crypto::cert::generate_static_key();
+        let mut session = ProtocolSession::new_server(CipherSuite::ChaCha20Poly1305);
+        session.set_tls_wrap(TlsWrapKey::tls_crypt(&ta_key, true));
+
+        let hard_reset = [
+            0x38, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert!(session.process_packet(&hard_reset).is_err());
+
+        let mut client = TlsWrap::new(TlsWrapKey::tls_crypt(&ta_key, false));
+        let wrapped = client.wrap(&hard_reset).unwrap();
+        assert!(matches!(
+            session.process_packet(&wrapped).unwrap(),
+            ProcessedPacket::HardReset { .. }
+        ));
+
+        let response = session.create_hard_reset_response().unwrap();
+        let plain = client.unwrap(&response).unwrap();
+        assert_eq!(plain[0] >> 3, OpCode::HardResetServerV2 as u8);
+    }
 }
@@ -3,11 +3,15 @@
 //! Bridges rustls with the OpenVPN control channel transport.
 
 use std::io::{Read, Write, ErrorKind};
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError, RwLock};
 
 use bytes::{Bytes, BytesMut};
-use rustls::{ServerConfig, ServerConnection};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig, ServerConnection, SignatureScheme};
 
 use crate::{ProtocolError, Result};
 
@@ -140,6 +144,13 @@ impl TlsHandler {
         })
     }
 
+    /// Export keying material from the TLS session (RFC 5705)
+    pub fn export_keying_material(&self, label: &[u8], len: usize) -> Result<Vec<u8>> {
+        self.conn
+            .export_keying_material(vec![0u8; len], label, None)
+            .map_err(|e| ProtocolError::TlsError(e.to_string()))
+    }
+
     /// Get negotiated cipher suite name
     pub fn cipher_suite(&self) -> Option<&'static str> {
         self.conn.negotiated_cipher_suite().map(|cs| cs.suite().as_str().unwrap_or("unknown"))
@@ -160,22 +171,125 @@ impl<'a> Write for VecWriter<'a> {
     }
 }
 
+/// Client certificate verifier whose CRLs can be replaced at runtime
+///
+/// Wraps a webpki verifier for the CA so that a newly published CRL takes
+/// effect for subsequent handshakes without rebuilding the server config.
+#[derive(Debug)]
+pub struct RevocableClientVerifier {
+    /// Trusted client CA certificates
+    roots: Arc<RootCertStore>,
+    /// CA subjects sent in the CertificateRequest
+    root_hints: Vec<DistinguishedName>,
+    /// Verifier for the current CRL set
+    inner: RwLock<Arc<dyn ClientCertVerifier>>,
+}
+
+impl RevocableClientVerifier {
+    /// Create a verifier trusting the given CA certificates
+    pub fn new(
+        ca_certs: Vec<CertificateDer<'static>>,
+        crls: Vec<CertificateRevocationListDer<'static>>,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in ca_certs {
+            roots.add(cert).map_err(|e| ProtocolError::TlsError(e.to_string()))?;
+        }
+        let roots = Arc::new(roots);
+        let root_hints = roots.subjects();
+        let inner = Self::build(&roots, crls)?;
+
+        Ok(Self {
+            roots,
+            root_hints,
+            inner: RwLock::new(inner),
+        })
+    }
+
+    /// Replace the CRLs checked for new handshakes
+    pub fn reload_crls(&self, crls: Vec<CertificateRevocationListDer<'static>>) -> Result<()> {
+        let verifier = Self::build(&self.roots, crls)?;
+        *self.inner.write().unwrap_or_else(PoisonError::into_inner) = verifier;
+        Ok(())
+    }
+
+    fn build(
+        roots: &Arc<RootCertStore>,
+        crls: Vec<CertificateRevocationListDer<'static>>,
+    ) -> Result<Arc<dyn ClientCertVerifier>> {
+        WebPkiClientVerifier::builder_with_provider(roots.clone(), crypto_provider())
+            .with_crls(crls)
+            .build()
+            .map_err(|e| ProtocolError::TlsError(e.to_string()))
+    }
+
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl ClientCertVerifier for RevocableClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &self.root_hints
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        self.current().verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.current().supported_verify_schemes()
+    }
+}
+
+/// Crypto provider for all TLS configuration
+///
+/// Chosen explicitly because other dependencies may enable a second rustls
+/// provider, in which case no process default can be inferred.
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
 /// Create TLS server config from certificates and key
 pub fn create_server_config(
     cert_chain: Vec<CertificateDer<'static>>,
     key: PrivateKeyDer<'static>,
     client_cert_verifier: Option<Arc<dyn rustls::server::danger::ClientCertVerifier>>,
 ) -> Result<Arc<ServerConfig>> {
-
-
+    let builder = ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ProtocolError::TlsError(e.to_string()))?;
 
     let config = if let Some(verifier) = client_cert_verifier {
-        ServerConfig::builder()
+        builder
             .with_client_cert_verifier(verifier)
             .with_single_cert(cert_chain, key)
             .map_err(|e| ProtocolError::TlsError(e.to_string()))?
     } else {
-        ServerConfig::builder()
+        builder
             .with_no_client_auth()
             .with_single_cert(cert_chain, key)
             .map_err(|e| ProtocolError::TlsError(e.to_string()))?
@@ -213,6 +327,13 @@ pub fn load_key_from_pem(pem: &str) -> Result<PrivateKeyDer<'static>> {
     Err(ProtocolError::TlsError("No private key found in PEM".into()))
 }
 
+/// Load certificate revocation lists from PEM
+pub fn load_crls_from_pem(pem: &str) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+    rustls_pemfile::crls(&mut pem.as_bytes())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| ProtocolError::TlsError(format!("Failed to parse CRL: {}", e)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,4 +343,58 @@ mod tests {
     fn test_tls_handler_creation() {
         // Would need valid certs to create a real handler
     }
+
+    #[test]
+    fn test_revoked_client_rejected() {
+        use corevpn_crypto::{CertificateAuthority, CertificateIndex, IssuedCertificate, RevocationReason};
+
+        let ca = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+        let client = ca.issue_client_certificate("alice", None, 30).unwrap();
+        let client_der = load_certs_from_pem(&client.cert_pem).unwrap().remove(0);
+
+        let mut index = CertificateIndex::new();
+        index.record(IssuedCertificate::new(&client.serial, "alice", None, 30));
+        let crl = ca.generate_crl(&mut index, 30).unwrap();
+
+        let verifier = RevocableClientVerifier::new(
+            load_certs_from_pem(ca.certificate_pem()).unwrap(),
+            load_crls_from_pem(&crl).unwrap(),
+        ).unwrap();
+        assert!(verifier.verify_client_cert(&client_der, &[], UnixTime::now()).is_ok());
+
+        index.revoke(&client.serial, RevocationReason::AffiliationChanged).unwrap();
+        let crl = ca.generate_crl(&mut index, 30).unwrap();
+        verifier.reload_crls(load_crls_from_pem(&crl).unwrap()).unwrap();
+        assert!(verifier.verify_client_cert(&client_der, &[], UnixTime::now()).is_err());
+    }
+
+    #[test]
+    fn test_client_cert_requirements() {
+        use corevpn_crypto::CertificateAuthority;
+
+        let ca = CertificateAuthority::new("CoreVPN CA", "CoreVPN", 365).unwrap();
+        let verifier = RevocableClientVerifier::new(
+            load_certs_from_pem(ca.certificate_pem()).unwrap(),
+            Vec::new(),
+        ).unwrap();
+        assert!(verifier.client_auth_mandatory());
+
+        // Server certificates lack the ClientAuth EKU
+        let server = ca.issue_server_certificate("vpn.example.com", &[], &[], 30).unwrap();
+        let server_der = load_certs_from_pem(&server.cert_pem).unwrap().remove(0);
+        assert!(verifier.verify_client_cert(&server_der, &[], UnixTime::now()).is_err());
+
+        // Client certificates are only valid within their validity period
+        let client = ca.issue_client_certificate("alice", None, 30).unwrap();
+        let client_der = load_certs_from_pem(&client.cert_pem).unwrap().remove(0);
+        let expired = std::time::Duration::from_secs(UnixTime::now().as_secs() + 31 * 24 * 60 * 60);
+        let later = UnixTime::since_unix_epoch(expired);
+        assert!(verifier.verify_client_cert(&client_der, &[], later).is_err());
+
+        // Certificates from another CA are rejected
+        let other = CertificateAuthority::new("Other CA", "Other", 365).unwrap();
+        let foreign = other.issue_client_certificate("mallory", None, 30).unwrap();
+        let foreign_der = load_certs_from_pem(&foreign.cert_pem).unwrap().remove(0);
+        assert!(verifier.verify_client_cert(&foreign_der, &[], UnixTime::now()).is_err());
+    }
 }
@@ -0,0 +1,336 @@
+//! Control Channel Wrapping
+//!
+//! tls-auth and tls-crypt protection of control channel packets, keyed
+//! from the OpenVPN static key (ta.key).
+//!
+//! On the wire both modes carry a replay-protected packet ID and timestamp
+//! after the opcode and session ID:
+//! - tls-auth: `op | sid | HMAC | packet_id | time | rest`
+//! - tls-crypt: `op | sid | packet_id | time | tag | encrypted rest`
+//!
+//! With tls-crypt-v2 each client has its own tls-crypt key, which it sends
+//! wrapped by the server key (WKc) at the end of its first hard reset.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use corevpn_crypto::tls_crypt_v2::wrapped_key_len;
+use corevpn_crypto::{
+    ClientKeyMetadata, CryptoError, HmacAuth, ReplayWindow, TlsCryptKey, TlsCryptV2ServerKey,
+};
+
+use crate::{ProtocolError, Result};
+
+/// Size of the opcode and session ID prefix
+const PREFIX_SIZE: usize = 1 + 8;
+
+/// Size of the replay packet ID and timestamp
+const REPLAY_SIZE: usize = 4 + 4;
+
+/// Key used to wrap control channel packets
+#[derive(Clone)]
+pub enum TlsWrapKey {
+    /// HMAC authentication (tls-auth)
+    Auth(Arc<HmacAuth>),
+    /// Authenticated encryption (tls-crypt)
+    Crypt(Arc<TlsCryptKey>),
+}
+
+impl TlsWrapKey {
+    /// tls-auth key from ta.key with the given key direction
+    pub fn tls_auth(ta_key: &[u8; 256], key_direction: Option<u8>) -> Self {
+        Self::Auth(Arc::new(HmacAuth::from_ta_key(ta_key, key_direction)))
+    }
+
+    /// tls-crypt key from ta.key
+    pub fn tls_crypt(ta_key: &[u8; 256], is_server: bool) -> Self {
+        Self::Crypt(Arc::new(TlsCryptKey::from_ta_key(ta_key, is_server)))
+    }
+
+    /// Recover a tls-crypt-v2 client's key from its hard reset
+    ///
+    /// Returns the client's tls-crypt key, the server-side metadata it was
+    /// issued with, and the packet with the WKc removed.
+    pub fn from_wrapped_client_key<'a>(
+        server_key: &TlsCryptV2ServerKey,
+        packet: &'a [u8],
+    ) -> Result<(Self, ClientKeyMetadata, &'a [u8])> {
+        let wrapped_len = wrapped_key_len(packet)?;
+        let split = packet.len()
+            .checked_sub(wrapped_len)
+            .filter(|&len| len >= PREFIX_SIZE)
+            .ok_or(ProtocolError::PacketTooShort {
+                expected: PREFIX_SIZE + wrapped_len,
+                got: packet.len(),
+            })?;
+
+        let (packet, wrapped) = packet.split_at(split);
+        let (client_key, metadata) = server_key.unwrap_client_key(wrapped)?;
+        Ok((Self::tls_crypt(&client_key, true), metadata, packet))
+    }
+
+    /// Mode name as used in OpenVPN configs
+    pub fn mode(&self) -> &'static str {
+        match self {
+            TlsWrapKey::Auth(_) => "tls-auth",
+            TlsWrapKey::Crypt(_) => "tls-crypt",
+        }
+    }
+
+    /// Verify and strip the wrapping from a control packet
+    ///
+    /// Returns the plain packet and its replay ID. This does not check
+    /// for replays, so it can be used to vet packets before any session
+    /// state exists.
+    pub fn unwrap(&self, packet: &[u8]) -> Result<(Vec<u8>, u64)> {
+        match self {
+            TlsWrapKey::Auth(key) => {
+                let min = PREFIX_SIZE + HmacAuth::HMAC_SIZE + REPLAY_SIZE;
+                check_len(packet, min)?;
+
+                let hmac: [u8; 32] = packet[PREFIX_SIZE..PREFIX_SIZE + HmacAuth::HMAC_SIZE]
+                    .try_into()
+                    .unwrap();
+                let replay = &packet[PREFIX_SIZE + HmacAuth::HMAC_SIZE..min];
+
+                // HMAC covers packet_id | time | op | sid | rest
+                let mut authenticated = Vec::with_capacity(packet.len() - HmacAuth::HMAC_SIZE);
+                authenticated.extend_from_slice(replay);
+                authenticated.extend_from_slice(&packet[..PREFIX_SIZE]);
+                authenticated.extend_from_slice(&packet[min..]);
+                key.verify(&authenticated, &hmac)?;
+
+                let plain = authenticated.split_off(REPLAY_SIZE);
+                Ok((plain, replay_id(replay)))
+            }
+            TlsWrapKey::Crypt(key) => {
+                let header_len = PREFIX_SIZE + REPLAY_SIZE;
+                check_len(packet, header_len + TlsCryptKey::TAG_SIZE)?;
+
+                let (header, wrapped) = packet.split_at(header_len);
+                let decrypted = key.unwrap(header, wrapped)?;
+
+                let mut plain = Vec::with_capacity(PREFIX_SIZE + decrypted.len());
+                plain.extend_from_slice(&header[..PREFIX_SIZE]);
+                plain.extend_from_slice(&decrypted);
+                Ok((plain, replay_id(&header[PREFIX_SIZE..])))
+            }
+        }
+    }
+
+    fn wrap(&self, packet: &[u8], replay: &[u8; REPLAY_SIZE]) -> Result<Vec<u8>> {
+        check_len(packet, PREFIX_SIZE)?;
+        let (prefix, rest) = packet.split_at(PREFIX_SIZE);
+
+        match self {
+            TlsWrapKey::Auth(key) => {
+                let mut authenticated = Vec::with_capacity(REPLAY_SIZE + packet.len());
+                authenticated.extend_from_slice(replay);
+                authenticated.extend_from_slice(packet);
+                let hmac = key.authenticate(&authenticated);
+
+                let mut output = Vec::with_capacity(HmacAuth::HMAC_SIZE + REPLAY_SIZE + packet.len());
+                output.extend_from_slice(prefix);
+                output.extend_from_slice(&hmac);
+                output.extend_from_slice(replay);
+                output.extend_from_slice(rest);
+                Ok(output)
+            }
+            TlsWrapKey::Crypt(key) => {
+                let mut output = Vec::with_capacity(
+                    PREFIX_SIZE + REPLAY_SIZE + TlsCryptKey::TAG_SIZE + rest.len(),
+                );
+                output.extend_from_slice(prefix);
+                output.extend_from_slice(replay);
+                let wrapped = key.wrap(&output, rest)?;
+                output.extend_from_slice(&wrapped);
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// Per-session control channel wrapping with replay protection
+pub struct TlsWrap {
+    /// Wrapping key
+    key: TlsWrapKey,
+    /// Next outgoing packet ID
+    tx_packet_id: u32,
+    /// Timestamp sent with outgoing packets
+    tx_time: u32,
+    /// Replay window for incoming packets
+    rx_window: ReplayWindow,
+}
+
+impl TlsWrap {
+    /// Create session state for a wrapping key
+    pub fn new(key: TlsWrapKey) -> Self {
+        let tx_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+
+        Self {
+            key,
+            tx_packet_id: 1,
+            tx_time,
+            rx_window: ReplayWindow::new(),
+        }
+    }
+
+    /// Get the wrapping key
+    pub fn key(&self) -> &TlsWrapKey {
+        &self.key
+    }
+
+    /// Wrap an outgoing control packet
+    pub fn wrap(&mut self, packet: &[u8]) -> Result<Bytes> {
+        if self.tx_packet_id == 0 {
+            // Packet ID space exhausted; OpenVPN renegotiates long before this
+            return Err(CryptoError::EncryptionFailed("control channel packet ID exhausted").into());
+        }
+
+        let mut replay = [0u8; REPLAY_SIZE];
+        replay[..4].copy_from_slice(&self.tx_packet_id.to_be_bytes());
+        replay[4..].copy_from_slice(&self.tx_time.to_be_bytes());
+        self.tx_packet_id = self.tx_packet_id.wrapping_add(1);
+
+        self.key.wrap(packet, &replay).map(Bytes::from)
+    }
+
+    /// Verify, unwrap and replay-check an incoming control packet
+    pub fn unwrap(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        let (plain, id) = self.key.unwrap(packet)?;
+        if id & 0xFFFF_FFFF == 0 || !self.rx_window.check_and_update(id) {
+            return Err(ProtocolError::ReplayDetected);
+        }
+        Ok(plain)
+    }
+}
+
+fn check_len(packet: &[u8], expected: usize) -> Result<()> {
+    if packet.len() < expected {
+        return Err(ProtocolError::PacketTooShort {
+            expected,
+            got: packet.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Combine the wire packet ID and timestamp into a monotonic replay ID
+fn replay_id(replay: &[u8]) -> u64 {
+    let packet_id = u32::from_be_bytes(replay[..4].try_into().unwrap());
+    let time = u32::from_be_bytes(replay[4..8].try_into().unwrap());
+    ((time as u64) << 32) | packet_id as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HARD_RESET: [u8; 19] = [
+        0x38, 1, 2, 3, 4, 5, 6, 7, 8, // opcode + session_id
+        0x00, // ack_count
+        0, 0, 0, 0, // message packet_id
+        0xAA, 0xBB, 0xCC, 0xDD, 0xEE, // payload
+    ];
+
+    fn peers(server_key: TlsWrapKey, client_key: TlsWrapKey) -> (TlsWrap, TlsWrap) {
+        (TlsWrap::new(server_key), TlsWrap::new(client_key))
+    }
+
+    #[test]
+    fn test_tls_auth_roundtrip() {
+        let ta_key = corevpn_crypto::cert::generate_static_key();
+        let (mut server, mut client) = peers(
+            TlsWrapKey::tls_auth(&ta_key, Some(0)),
+            TlsWrapKey::tls_auth(&ta_key, Some(1)),
+        );
+
+        let wrapped = client.wrap(&HARD_RESET).unwrap();
+        assert_eq!(wrapped.len(), HARD_RESET.len() + 32 + 8);
+        assert_eq!(&wrapped[..9], &HARD_RESET[..9]);
+        assert_eq!(server.unwrap(&wrapped).unwrap(), HARD_RESET);
+
+        let reply = server.wrap(&HARD_RESET).unwrap();
+        assert_eq!(client.unwrap(&reply).unwrap(), HARD_RESET);
+    }
+
+    #[test]
+    fn test_tls_crypt_roundtrip() {
+        let ta_key = corevpn_crypto::cert::generate_static_key();
+        let (mut server, mut client) = peers(
+            TlsWrapKey::tls_crypt(&ta_key, true),
+            TlsWrapKey::tls_crypt(&ta_key, false),
+        );
+
+        let wrapped = client.wrap(&HARD_RESET).unwrap();
+        assert_eq!(&wrapped[..9], &HARD_RESET[..9]);
+        assert!(!wrapped.windows(5).any(|w| w == [0xAA, 0xBB, 0xCC, 0xDD, 0xEE]));
+        assert_eq!(server.unwrap(&wrapped).unwrap(), HARD_RESET);
+
+        let reply = server.wrap(&HARD_RESET).unwrap();
+        assert_eq!(client.unwrap(&reply).unwrap(), HARD_RESET);
+    }
+
+    #[test]
+    fn test_replay_and_tamper_rejected() {
+        let ta_key = corevpn_crypto::cert::generate_static_key();
+        for (server_key, client_key) in [
+            (TlsWrapKey::tls_auth(&ta_key, Some(0)), TlsWrapKey::tls_auth(&ta_key, Some(1))),
+            (TlsWrapKey::tls_crypt(&ta_key, true), TlsWrapKey::tls_crypt(&ta_key, false)),
+        ] {
+            let (mut server, mut client) = peers(server_key, client_key);
+
+            let wrapped = client.wrap(&HARD_RESET).unwrap();
+            server.unwrap(&wrapped).unwrap();
+            assert!(matches!(server.unwrap(&wrapped), Err(ProtocolError::ReplayDetected)));
+
+            let mut tampered = client.wrap(&HARD_RESET).unwrap().to_vec();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 0x01;
+            assert!(server.unwrap(&tampered).is_err());
+
+            // Unwrapped packets never pass
+            assert!(server.key().unwrap(&HARD_RESET).is_err());
+        }
+    }
+
+    #[test]
+    fn test_wrong_key_rejected() {
+        let (mut server, mut client) = peers(
+            TlsWrapKey::tls_auth(&corevpn_crypto::cert::generate_static_key(), Some(0)),
+            TlsWrapKey::tls_auth(&corevpn_crypto::cert::generate_static_key(), Some(1)),
+        );
+        let wrapped = client.wrap(&HARD_RESET).unwrap();
+        assert!(server.unwrap(&wrapped).is_err());
+    }
+
+    #[test]
+    fn test_tls_crypt_v2_client_key() {
+        let server_key = TlsCryptV2ServerKey::generate();
+        let metadata = ClientKeyMetadata::User(b"0a1b".to_vec());
+        let pem = server_key.generate_client_key(&metadata).unwrap();
+        let (client_key, wrapped) = corevpn_crypto::tls_crypt_v2::parse_client_key(&pem).unwrap();
+
+        let mut client = TlsWrap::new(TlsWrapKey::tls_crypt(&client_key, false));
+        let mut hard_reset = HARD_RESET;
+        hard_reset[0] = 0x50; // HardResetClientV3
+        let mut packet = client.wrap(&hard_reset).unwrap().to_vec();
+        packet.extend_from_slice(&wrapped);
+
+        let (key, unwrapped_metadata, packet) =
+            TlsWrapKey::from_wrapped_client_key(&server_key, &packet).unwrap();
+        assert_eq!(unwrapped_metadata, metadata);
+        let mut server = TlsWrap::new(key);
+        assert_eq!(server.unwrap(packet).unwrap(), hard_reset);
+
+        let other = TlsCryptV2ServerKey::generate();
+        let mut packet = client.wrap(&hard_reset).unwrap().to_vec();
+        packet.extend_from_slice(&wrapped);
+        assert!(TlsWrapKey::from_wrapped_client_key(&other, &packet).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+//! Data Path Benchmarks
+//!
+//! End-to-end benchmarks for the tunnel data path, built from the server's
+//! own modules: clients encrypt and send P_DATA_V2 packets over loopback UDP
+//! to the server's UDP workers sharing one port via `SO_REUSEPORT`, which
+//! look each packet up in the sharded peer tables, decrypt it in place and
+//! queue it for the TUN writer. In the other direction, packets from the
+//! TUN device are routed to the egress workers, one per socket, which
+//! encrypt them in place and send them to the clients in batches. Scaling
+//! across worker counts shows how throughput follows cores.
+
+use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use corevpn_config::ServerConfig;
+use corevpn_core::VpnAddress;
+use corevpn_crypto::{CipherSuite, DataChannelKey, PACKET_HEADROOM, PACKET_TAILROOM};
+use corevpn_protocol::{DataChannel, DataChannels, KeyId};
+use corevpn_protocol::data::{AeadKey, IMPLICIT_IV_SIZE};
+use corevpn_server::datapath::{DataPath, TrafficCounters};
+use corevpn_server::policy::DeniedPackets;
+use corevpn_server::pool::PacketPool;
+use corevpn_server::server::{self, EgressQueues, VpnServer};
+use corevpn_server::transport::{self, Transport};
+
+/// Client sessions sending concurrently
+const FLOWS: usize = 8;
+
+/// Packets sent per flow in each iteration, few enough for the socket and TUN queues to absorb a burst
+const PACKETS_PER_FLOW: u64 = 64;
+
+/// Typical tunnel packet size
+const PACKET_SIZE: usize = 1400;
+
+/// How long receivers may stay idle before an iteration counts lost packets as done
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+// =============================================================================
+// Setup
+// =============================================================================
+
+/// Client and server ends of one session
+fn session_pair(peer_id: u32) -> (DataChannels, DataChannels) {
+    let key: [u8; 32] = std::array::from_fn(|i| i as u8 ^ peer_id as u8);
+    let iv: [u8; IMPLICIT_IV_SIZE] = [0xA0; IMPLICIT_IV_SIZE];
+    let channel = || {
+        let mut channels = DataChannels::default();
+        channels.install(DataChannel::new_openvpn(
+            KeyId::new(0),
+            AeadKey::new(DataChannelKey::new(key, CipherSuite::Aes256Gcm), iv),
+            AeadKey::new(DataChannelKey::new(key, CipherSuite::Aes256Gcm), iv),
+            true,
+            Some(peer_id),
+        ));
+        channels
+    };
+    (channel(), channel())
+}
+
+/// IPv4 packet from a client's VPN address, so the workers forward it
+fn ip_packet(source: Ipv4Addr, len: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; len];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    packet[12..16].copy_from_slice(&source.octets());
+    packet[16..20].copy_from_slice(&[10, 8, 0, 1]);
+    packet
+}
+
+/// IPv4 packet to a client's VPN address, as read from the TUN device
+fn tun_packet(destination: Ipv4Addr, len: usize) -> Vec<u8> {
+    let mut packet = ip_packet(Ipv4Addr::new(10, 8, 0, 1), len);
+    packet[16..20].copy_from_slice(&destination.octets());
+    packet
+}
+
+/// Server configuration for the data path alone
+fn server_config(data_dir: &tempfile::TempDir) -> ServerConfig {
+    let mut config = ServerConfig::default_config("127.0.0.1");
+    config.server.data_dir = data_dir.path().to_path_buf();
+    // Only data packets are sent, so the control channel needs no keys
+    config.security.tls_auth = false;
+    config.security.tls_crypt = false;
+    // Without a CA the server only starts with logins required
+    config.security.password_auth = true;
+    config
+}
+
+/// Client's published data path, reached through the first worker socket
+fn data_path(peer_id: u32, client: &UdpSocket, socket: &Arc<tokio::net::UdpSocket>, channels: DataChannels) -> DataPath {
+    DataPath {
+        peer_addr: client.local_addr().unwrap(),
+        peer_id,
+        transport: Transport::Udp(socket.clone()),
+        channels,
+        vpn_address: VpnAddress::v4(client_address(peer_id)),
+        access: None,
+        username: None,
+        counters: Arc::new(TrafficCounters::new()),
+        denied_packets: Mutex::new(DeniedPackets::default()),
+    }
+}
+
+/// VPN address of the client with a given peer ID
+fn client_address(peer_id: u32) -> Ipv4Addr {
+    Ipv4Addr::new(10, 8, 0, 2 + peer_id as u8)
+}
+
+/// The server's UDP workers on sockets sharing one port
+///
+/// Each client gets a published data path, and packets the workers hand
+/// to the TUN writer queue are counted.
+struct Workers {
+    _runtime: Runtime,
+    addr: SocketAddr,
+    received: Arc<AtomicU64>,
+    _data_dir: tempfile::TempDir,
+}
+
+impl Workers {
+    fn start(count: usize, clients: &[UdpSocket], sessions: Vec<DataChannels>) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(count)
+            .enable_all()
+            .build()
+            .unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let config = server_config(&data_dir);
+
+        // A free port for every worker socket to join
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let received = Arc::new(AtomicU64::new(0));
+
+        runtime.block_on(async {
+            let (tun_tx, mut tun_rx) = mpsc::channel(server::TUN_QUEUE_DEPTH);
+            let server = Arc::new(VpnServer::new(config).await.unwrap().with_tunnel(tun_tx));
+            let sockets = transport::bind_udp(addr, count).unwrap();
+            for socket in &sockets {
+                socket2::SockRef::from(&**socket).set_recv_buffer_size(4 << 20).unwrap();
+            }
+
+            for (peer_id, (client, channels)) in clients.iter().zip(sessions).enumerate() {
+                server.install_data_path(data_path(peer_id as u32, client, &sockets[0], channels));
+            }
+
+            // Stands in for the TUN writer, off the workers' runtime
+            let counter = received.clone();
+            thread::spawn(move || {
+                while tun_rx.blocking_recv().is_some() {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+
+            // No control packets are sent, so nothing reads the control queue
+            let (control_tx, _) = mpsc::channel(1);
+            for socket in sockets {
+                tokio::spawn(server::run_udp_worker(server.clone(), socket, control_tx.clone()));
+            }
+        });
+
+        Self { _runtime: runtime, addr, received, _data_dir: data_dir }
+    }
+
+    /// Wait until `target` packets were decrypted or the workers go idle
+    ///
+    /// Returns when the last packet arrived, so time spent waiting for
+    /// packets lost on a saturated host is not counted.
+    fn wait_for(&self, target: u64) -> Instant {
+        let mut last = self.received.load(Ordering::Relaxed);
+        let mut last_progress = Instant::now();
+        while last < target && last_progress.elapsed() < IDLE_TIMEOUT {
+            thread::yield_now();
+            let current = self.received.load(Ordering::Relaxed);
+            if current != last {
+                last = current;
+                last_progress = Instant::now();
+            }
+        }
+        last_progress
+    }
+}
+
+/// The server's egress workers, one per socket on a shared port
+///
+/// Each client gets a published data path, and a thread per client counts
+/// the encrypted packets it receives.
+struct Egress {
+    _runtime: Runtime,
+    server: Arc<VpnServer>,
+    queues: EgressQueues,
+    received: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    _data_dir: tempfile::TempDir,
+}
+
+impl Egress {
+    fn start(count: usize, clients: Vec<UdpSocket>, sessions: Vec<DataChannels>) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(count)
+            .enable_all()
+            .build()
+            .unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let config = server_config(&data_dir);
+        let received = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (server, queues) = runtime.block_on(async {
+            let server = Arc::new(VpnServer::new(config).await.unwrap());
+            let sockets = transport::bind_udp("127.0.0.1:0".parse().unwrap(), count).unwrap();
+            for (peer_id, (client, channels)) in clients.iter().zip(sessions).enumerate() {
+                server.install_data_path(data_path(peer_id as u32, client, &sockets[0], channels));
+            }
+            (server, EgressQueues::spawn(&sockets))
+        });
+
+        // Stand in for the clients, off the workers' runtime
+        for client in clients {
+            socket2::SockRef::from(&client).set_recv_buffer_size(4 << 20).unwrap();
+            client.set_read_timeout(Some(IDLE_TIMEOUT)).unwrap();
+            let (counter, stop) = (received.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut buf = [0u8; 2048];
+                while !stop.load(Ordering::Relaxed) {
+                    if client.recv(&mut buf).is_ok() {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+
+        Self { _runtime: runtime, server, queues, received, stop, _data_dir: data_dir }
+    }
+
+    /// Wait until `target` packets reached the clients or they go idle
+    fn wait_for(&self, target: u64) -> Instant {
+        let mut last = self.received.load(Ordering::Relaxed);
+        let mut last_progress = Instant::now();
+        while last < target && last_progress.elapsed() < IDLE_TIMEOUT {
+            thread::yield_now();
+            let current = self.received.load(Ordering::Relaxed);
+            if current != last {
+                last = current;
+                last_progress = Instant::now();
+            }
+        }
+        last_progress
+    }
+}
+
+impl Drop for Egress {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// =============================================================================
+// UDP Data Path Benchmarks
+// =============================================================================
+
+fn bench_udp_datapath(c: &mut Criterion) {
+    let mut group = c.benchmark_group("udp_datapath");
+    group.throughput(Throughput::Elements(FLOWS as u64 * PACKETS_PER_FLOW));
+
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let payloads: Vec<Vec<u8>> = (0..FLOWS as u32).map(|peer_id| ip_packet(client_address(peer_id), PACKET_SIZE)).collect();
+
+    for workers in [1, 2, 4, 8].into_iter().filter(|&workers| workers <= cores.max(4)) {
+        let clients: Vec<UdpSocket> = (0..FLOWS)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..FLOWS as u32).map(session_pair).unzip();
+        let pool = Workers::start(workers, &clients, receivers);
+        for client in &clients {
+            client.connect(pool.addr).unwrap();
+        }
+
+        group.bench_with_input(BenchmarkId::new("workers", workers), &workers, |b, _| {
+            b.iter_custom(|iters| {
+                let target = pool.received.load(Ordering::Relaxed) + iters * FLOWS as u64 * PACKETS_PER_FLOW;
+                let start = Instant::now();
+
+                thread::scope(|scope| {
+                    for ((client, channels), payload) in clients.iter().zip(&senders).zip(&payloads) {
+                        scope.spawn(move || {
+                            for _ in 0..iters * PACKETS_PER_FLOW {
+                                let packet = channels.encrypt(payload).unwrap();
+                                let _ = client.send(&packet);
+                            }
+                        });
+                    }
+                });
+                pool.wait_for(target) - start
+            });
+        });
+    }
+
+    group.finish();
+}
+
+// =============================================================================
+// TUN Egress Benchmarks
+// =============================================================================
+
+fn bench_tun_egress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tun_egress");
+    let burst = FLOWS as u64 * PACKETS_PER_FLOW;
+    group.throughput(Throughput::Elements(burst));
+
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let payloads: Vec<Vec<u8>> = (0..FLOWS as u32).map(|peer_id| tun_packet(client_address(peer_id), PACKET_SIZE)).collect();
+
+    for workers in [1, 2, 4, 8].into_iter().filter(|&workers| workers <= cores.max(4)) {
+        let clients: Vec<UdpSocket> = (0..FLOWS)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let (_, senders): (Vec<_>, Vec<_>) = (0..FLOWS as u32).map(session_pair).unzip();
+        let egress = Egress::start(workers, clients, senders);
+        let mut pool = PacketPool::new(
+            egress.queues.pool_slots(),
+            PACKET_HEADROOM + PACKET_SIZE + PACKET_TAILROOM,
+        );
+
+        // Bursts small enough for the egress queues, so no packet is dropped before encryption
+        group.bench_with_input(BenchmarkId::new("workers", workers), &workers, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let target = egress.received.load(Ordering::Relaxed) + burst;
+                    let start = Instant::now();
+                    for _ in 0..PACKETS_PER_FLOW {
+                        for payload in &payloads {
+                            let mut packet = pool.plaintext();
+                            packet.extend_from_slice(payload);
+                            egress.queues.route(&egress.server, packet);
+                        }
+                    }
+                    elapsed += egress.wait_for(target) - start;
+                }
+                assert!(egress.received.load(Ordering::Relaxed) > 0, "no packets reached the clients");
+                elapsed
+            });
+        });
+    }
+
+    group.finish();
+}
+
+// =============================================================================
+// Shared Key Benchmarks
+// =============================================================================
+
+fn bench_shared_channels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("shared_channels");
+    group.throughput(Throughput::Bytes(PACKET_SIZE as u64));
+
+    let payload = vec![0x45u8; PACKET_SIZE];
+    let (sender, receiver) = session_pair(1);
+    let packet = sender.encrypt(&payload).unwrap();
+
+    // Encryption from one thread through the shared packet ID counter
+    group.bench_function("encrypt_1400", |b| {
+        b.iter(|| sender.encrypt(std::hint::black_box(&payload)).unwrap());
+    });
+
+    // Authentication failures stop before the replay window
+    let mut tampered = packet.to_vec();
+    tampered[PACKET_SIZE / 2] ^= 0xFF;
+    group.bench_function("reject_tampered_1400", |b| {
+        b.iter(|| receiver.decrypt(std::hint::black_box(&tampered)).is_err());
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_udp_datapath,
+    bench_tun_egress,
+    bench_shared_channels,
+);
+
+criterion_main!(benches);
@@ -0,0 +1,176 @@
+//! Packet Path Benchmarks
+//!
+//! Follows a batch of packets from TUN read to TUN write through the
+//! server's packet pools and batched sockets over loopback, and checks that
+//! the path runs without touching the allocator. Allocations are counted by
+//! a global allocator, so these benchmarks get a binary of their own.
+
+use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BufMut;
+use tokio::runtime::Runtime;
+
+use corevpn_crypto::{CipherSuite, DataChannelKey, PACKET_HEADROOM, PACKET_TAILROOM};
+use corevpn_protocol::{DataChannel, DataChannels, KeyId};
+use corevpn_protocol::data::{AeadKey, IMPLICIT_IV_SIZE};
+use corevpn_server::pool::PacketPool;
+use corevpn_server::udp::{BatchSocket, RecvBatch, SendBatch, BATCH_SIZE};
+
+/// How long the receiver may stay idle before a batch counts lost packets as done
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Buffers in each packet path pool
+const POOL_SLOTS: usize = 4 * BATCH_SIZE;
+
+/// System allocator that counts allocations
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// =============================================================================
+// Setup
+// =============================================================================
+
+/// Client and server ends of one session
+fn session_pair(peer_id: u32) -> (DataChannels, DataChannels) {
+    let key: [u8; 32] = std::array::from_fn(|i| i as u8 ^ peer_id as u8);
+    let iv: [u8; IMPLICIT_IV_SIZE] = [0xA0; IMPLICIT_IV_SIZE];
+    let channel = || {
+        let mut channels = DataChannels::default();
+        channels.install(DataChannel::new_openvpn(
+            KeyId::new(0),
+            AeadKey::new(DataChannelKey::new(key, CipherSuite::Aes256Gcm), iv),
+            AeadKey::new(DataChannelKey::new(key, CipherSuite::Aes256Gcm), iv),
+            true,
+            Some(peer_id),
+        ));
+        channels
+    };
+    (channel(), channel())
+}
+
+// =============================================================================
+// Packet Path Benchmarks
+// =============================================================================
+
+/// The server's packet path for one batch over loopback
+///
+/// Packets are read behind headroom into buffers from the TUN reader's
+/// pool, encrypted in place and sent with one batched send, then received
+/// straight into buffers from a UDP worker's pool and decrypted in place.
+struct PacketPath {
+    runtime: Runtime,
+    sender: BatchSocket,
+    receiver: BatchSocket,
+    to: SocketAddr,
+    tun_pool: PacketPool,
+    udp_pool: PacketPool,
+    send: SendBatch,
+    recv: RecvBatch,
+}
+
+impl PacketPath {
+    fn new() -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (sender, receiver) = runtime.block_on(async {
+            let bind = || async { BatchSocket::new(Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap())) };
+            (bind().await, bind().await)
+        });
+        let to = receiver.socket().local_addr().unwrap();
+        let packet_size = PACKET_HEADROOM + 1500 + PACKET_TAILROOM;
+
+        Self {
+            runtime,
+            sender,
+            receiver,
+            to,
+            tun_pool: PacketPool::new(POOL_SLOTS, packet_size),
+            udp_pool: PacketPool::new(POOL_SLOTS, packet_size),
+            send: SendBatch::new(),
+            recv: RecvBatch::new(),
+        }
+    }
+
+    /// Move a batch of packets from TUN read to TUN write, returning how many arrived
+    fn forward(&mut self, sender: &DataChannels, receiver: &DataChannels, ip_packet: &[u8]) -> usize {
+        let Self { runtime, sender: tx, receiver: rx, to, tun_pool, udp_pool, send, recv } = self;
+        runtime.block_on(async {
+            for _ in 0..BATCH_SIZE {
+                let mut packet = tun_pool.plaintext();
+                packet.put_slice(ip_packet);
+                sender.encrypt_in_place(&mut packet).unwrap();
+                send.push(*to, packet.freeze());
+            }
+            tx.send(send).await.unwrap();
+
+            let mut received = 0;
+            while received < BATCH_SIZE {
+                match tokio::time::timeout(IDLE_TIMEOUT, rx.recv(recv, udp_pool)).await {
+                    Ok(result) => result.unwrap(),
+                    Err(_) => break,
+                };
+                for (_, mut packet) in recv.datagrams() {
+                    receiver.decrypt_in_place(&mut packet).unwrap();
+                    drop(std::hint::black_box(packet.freeze()));
+                    received += 1;
+                }
+            }
+            received
+        })
+    }
+}
+
+fn bench_packet_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_path");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+
+    for size in [64usize, 1400] {
+        let payload = vec![0x45u8; size];
+        let (sender, receiver) = session_pair(1);
+        let mut path = PacketPath::new();
+
+        // Once every pool slot has been handed out, forwarding reuses their buffers
+        for _ in 0..2 * POOL_SLOTS / BATCH_SIZE {
+            path.forward(&sender, &receiver, &payload);
+        }
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for _ in 0..100 {
+            assert_eq!(path.forward(&sender, &receiver, &payload), BATCH_SIZE, "packets lost on loopback");
+        }
+        assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), before, "pooled packet path allocated");
+
+        group.bench_with_input(BenchmarkId::new("pooled", size), &payload, |b, payload| {
+            b.iter(|| path.forward(&sender, &receiver, std::hint::black_box(payload)));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_packet_path);
+
+criterion_main!(benches);
@@ -0,0 +1,178 @@
+//! UDP Socket Benchmarks
+//!
+//! Packets-per-second over loopback for the server's UDP socket layer:
+//! one `send_to`/`recv_from` per datagram (the old path) against batched
+//! `sendmmsg`/`recvmmsg` with UDP GSO and GRO.
+
+use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+
+use corevpn_server::pool::PacketPool;
+use corevpn_server::udp::{BatchSocket, RecvBatch, SendBatch, BATCH_SIZE};
+
+/// Datagrams sent per iteration
+const PACKETS: u64 = 4096;
+
+/// How long the receiver may stay idle before an iteration counts lost packets as done
+const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How packets are moved through the sockets
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// One system call per datagram
+    PerPacket,
+    /// `sendmmsg`/`recvmmsg` with GSO and GRO
+    Batched,
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+/// Receiver counting datagrams on its own thread
+struct Receiver {
+    addr: SocketAddr,
+    received: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Receiver {
+    fn start(mode: Mode) -> Self {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let received = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let received = received.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                runtime().block_on(async move {
+                    let socket = Arc::new(UdpSocket::from_std(socket).unwrap());
+                    let batched = BatchSocket::new(socket.clone());
+                    let mut batch = RecvBatch::new();
+                    let mut pool = PacketPool::new(2 * BATCH_SIZE, 2048);
+                    let mut buf = vec![0u8; 65536];
+
+                    while !stop.load(Ordering::Relaxed) {
+                        let count = match mode {
+                            Mode::PerPacket => {
+                                let recv = socket.recv_from(&mut buf);
+                                match tokio::time::timeout(Duration::from_millis(50), recv).await {
+                                    Ok(Ok(_)) => 1,
+                                    _ => 0,
+                                }
+                            }
+                            Mode::Batched => {
+                                let recv = batched.recv(&mut batch, &mut pool);
+                                match tokio::time::timeout(Duration::from_millis(50), recv).await {
+                                    Ok(Ok(_)) => batch.datagrams().count() as u64,
+                                    _ => 0,
+                                }
+                            }
+                        };
+                        received.fetch_add(count, Ordering::Relaxed);
+                    }
+                });
+            })
+        };
+
+        Self { addr, received, stop, thread: Some(thread) }
+    }
+
+    /// Wait until `target` datagrams arrived or the receiver goes idle
+    fn wait_for(&self, target: u64) {
+        let mut last = self.received.load(Ordering::Relaxed);
+        let mut last_progress = Instant::now();
+        while last < target && last_progress.elapsed() < IDLE_TIMEOUT {
+            thread::yield_now();
+            let current = self.received.load(Ordering::Relaxed);
+            if current != last {
+                last = current;
+                last_progress = Instant::now();
+            }
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// =============================================================================
+// Loopback Packets-per-Second Benchmarks
+// =============================================================================
+
+fn bench_udp_pps(c: &mut Criterion) {
+    let mut group = c.benchmark_group("udp_pps");
+    group.throughput(Throughput::Elements(PACKETS));
+
+    let rt = runtime();
+    for size in [64usize, 1400] {
+        let packet = Bytes::from(vec![0x48u8; size]);
+
+        for mode in [Mode::PerPacket, Mode::Batched] {
+            let receiver = Receiver::start(mode);
+            let sender = rt.block_on(async {
+                BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()))
+            });
+
+            let id = format!("{:?}/{}", mode, size);
+            group.bench_function(BenchmarkId::from_parameter(id), |b| {
+                b.iter_custom(|iters| {
+                    let target = receiver.received.load(Ordering::Relaxed) + iters * PACKETS;
+                    let start = Instant::now();
+
+                    rt.block_on(async {
+                        let base = receiver.received.load(Ordering::Relaxed);
+                        let mut batch = SendBatch::new();
+                        for n in 0..iters * PACKETS / BATCH_SIZE as u64 {
+                            // At most two batches in flight, so the receive buffer never overflows
+                            receiver.wait_for(base + n.saturating_sub(1) * BATCH_SIZE as u64);
+                            match mode {
+                                Mode::PerPacket => {
+                                    for _ in 0..BATCH_SIZE {
+                                        let _ = sender.socket().send_to(&packet, receiver.addr).await;
+                                    }
+                                }
+                                Mode::Batched => {
+                                    for _ in 0..BATCH_SIZE {
+                                        batch.push(receiver.addr, packet.clone());
+                                    }
+                                    let _ = sender.send(&mut batch).await;
+                                }
+                            }
+                        }
+                    });
+                    receiver.wait_for(target);
+
+                    start.elapsed()
+                });
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_udp_pps,
+);
+
+criterion_main!(benches);
@@ -463,6 +463,41 @@ impl AuditEventBuilder {
             .data("session_duration_secs", duration_secs)
     }
 
+    /// Access policy granted to a client
+    pub fn policy_granted(username: &str, source_ip: &str, role: &str, rules: &[String], routes: &[String]) -> Self {
+        Self::new(AuditCategory::Authorization)
+            .action("policy.granted")
+            .actor_user(username, Some(source_ip.to_string()))
+            .message(format!("Access policy granted to {}", username))
+            .data("role", role)
+            .data("rules", rules)
+            .data("routes", routes)
+    }
+
+    /// Client rejected by the access policy
+    pub fn policy_denied(username: &str, source_ip: &str, role: &str) -> Self {
+        Self::new(AuditCategory::Authorization)
+            .action("policy.denied")
+            .severity(AuditSeverity::Medium)
+            .actor_user(username, Some(source_ip.to_string()))
+            .failure("POLICY_DENIED", "no policy rule grants access")
+            .message(format!("Access policy denied VPN access to {}", username))
+            .data("role", role)
+    }
+
+    /// Tunnel packets dropped by the access policy
+    pub fn policy_packet_denied(username: &str, source_ip: &str, destination: &str, protocol: u8, dropped: u64) -> Self {
+        Self::new(AuditCategory::Authorization)
+            .action("policy.packet_denied")
+            .severity(AuditSeverity::Low)
+            .actor_user(username, Some(source_ip.to_string()))
+            .target_resource("network", destination)
+            .failure("POLICY_DENIED", "destination not granted by policy")
+            .message(format!("Access policy dropped traffic from {} to {}", username, destination))
+            .data("protocol", protocol)
+            .data("dropped_packets", dropped)
+    }
+
     /// Configuration change
     pub fn config_change(admin: &str, setting: &str, old_value: &str, new_value: &str) -> Self {
         Self::new(AuditCategory::Configuration)
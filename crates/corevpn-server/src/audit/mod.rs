@@ -15,6 +15,7 @@ pub mod formats;
 pub mod sinks;
 
 use std::sync::Arc;
+use corevpn_config::AuditSettings;
 use tokio::sync::mpsc;
 
 pub use events::{AuditEvent, AuditEventBuilder, AuditSeverity, AuditCategory};
@@ -69,6 +70,17 @@ impl AuditLogger {
         }
     }
 
+    /// Log an audit event from synchronous code, dropping it if the queue is full
+    pub fn try_log(&self, event: AuditEvent) {
+        if !self.enabled {
+            return;
+        }
+
+        if let Err(e) = self.tx.try_send(event) {
+            log::warn!("Dropping audit event: {}", e);
+        }
+    }
+
     /// Create a builder for a new audit event
     pub fn event(&self, category: AuditCategory) -> AuditEventBuilder {
         AuditEventBuilder::new(category)
@@ -164,6 +176,26 @@ fn default_categories() -> Vec<AuditCategory> {
     ]
 }
 
+impl AuditConfig {
+    /// Build the logger configuration from the server's `[audit]` settings
+    pub fn from_settings(settings: &AuditSettings) -> Result<Self, AuditError> {
+        let sinks = settings.sinks
+            .iter()
+            .map(SinkConfig::from_settings)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            enabled: settings.enabled,
+            buffer_size: settings.buffer_size,
+            sinks,
+            include_source_ip: settings.include_source_ip,
+            include_user_identity: settings.include_user_identity,
+            hash_sensitive_fields: settings.hash_sensitive_fields,
+            ..Default::default()
+        })
+    }
+}
+
 impl Default for AuditConfig {
     fn default() -> Self {
         Self {
@@ -14,6 +14,7 @@ mod file;
 
 use std::sync::Arc;
 use async_trait::async_trait;
+use corevpn_config::AuditSinkConfig;
 use serde::{Deserialize, Serialize};
 
 use super::{AuditError, AuditEvent};
@@ -94,6 +95,39 @@ pub enum SinkConfig {
     File(FileConfig),
 }
 
+impl SinkConfig {
+    /// Convert a sink from the server configuration file
+    ///
+    /// The file and webhook sinks have a simplified layout there; the other
+    /// sinks share their field names and unset fields take their defaults.
+    pub fn from_settings(settings: &AuditSinkConfig) -> Result<Self, AuditError> {
+        let value = match settings {
+            AuditSinkConfig::File { path, format, max_size_mb, max_files } => serde_json::json!({
+                "type": "file",
+                "path": path,
+                "format": { "format": format },
+                "rotation": "size",
+                "max_size": max_size_mb.saturating_mul(1024 * 1024),
+                "max_files": max_files,
+            }),
+            AuditSinkConfig::Webhook { url, headers, bearer_token, api_key_header, api_key_value } => {
+                let auth = match (bearer_token, api_key_header, api_key_value) {
+                    (Some(token), _, _) => serde_json::json!({ "type": "bearer", "token": token }),
+                    (None, Some(header), Some(value)) => {
+                        serde_json::json!({ "type": "api_key", "header": header, "value": value })
+                    }
+                    _ => serde_json::Value::Null,
+                };
+                serde_json::json!({ "type": "webhook", "url": url, "headers": headers, "auth": auth })
+            }
+            other => serde_json::to_value(other)?,
+        };
+
+        serde_json::from_value(value)
+            .map_err(|e| AuditError::Configuration(format!("invalid audit sink: {}", e)))
+    }
+}
+
 /// Create a sink from configuration
 pub async fn create_sink(config: &SinkConfig) -> Result<Arc<dyn AuditSink>, AuditError> {
     match config {
@@ -536,7 +536,7 @@ impl ConnectionLogger for DatabaseConnectionLogger {
 
         let events: Vec<ConnectionEvent> = rows
             .iter()
-            .filter_map(|row| Self::parse_event_row(row))
+            .filter_map(Self::parse_event_row)
             .collect();
 
         Ok(Some(events))
@@ -552,7 +552,7 @@ impl ConnectionLogger for DatabaseConnectionLogger {
 
         let events: Vec<ConnectionEvent> = rows
             .iter()
-            .filter_map(|row| Self::parse_event_row(row))
+            .filter_map(Self::parse_event_row)
             .collect();
 
         Ok(Some(events))
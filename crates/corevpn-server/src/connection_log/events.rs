@@ -32,7 +32,7 @@ impl std::fmt::Display for ConnectionId {
 }
 
 /// Authentication method used
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum AuthMethod {
     /// Certificate-based authentication
@@ -46,17 +46,12 @@ pub enum AuthMethod {
     /// Pre-shared key
     Psk,
     /// Unknown/not yet determined
+    #[default]
     Unknown,
 }
 
-impl Default for AuthMethod {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 /// Reason for disconnection
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum DisconnectReason {
     /// Client initiated clean disconnect
@@ -80,15 +75,10 @@ pub enum DisconnectReason {
     /// Key renegotiation failure
     RenegotiationFailure,
     /// Unknown reason
+    #[default]
     Unknown,
 }
 
-impl Default for DisconnectReason {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 /// Authentication result
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
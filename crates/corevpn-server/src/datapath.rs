@@ -0,0 +1,286 @@
+//! Data Path
+//!
+//! Per-connection state needed to move tunnel packets, kept apart from the
+//! control channel so UDP workers and the TUN reader never wait on a TLS
+//! handshake. Connections live in a [`PeerMap`] sharded by peer address, and
+//! every established connection publishes a [`DataPath`] whose crypto state
+//! is shared through atomics instead of locks.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use corevpn_core::VpnAddress;
+use corevpn_protocol::{DataChannels, UNDEFINED_PEER_ID};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::connection_log::TransferStats;
+use crate::policy::{AccessPolicy, DeniedPackets};
+use crate::transport::Transport;
+
+/// Number of shards in the connection tables
+pub const PEER_SHARDS: usize = 64;
+
+/// Map keyed by peer address (or peer ID), split into independently locked shards
+pub struct PeerMap<V, K = SocketAddr> {
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<V, K: Hash + Eq> PeerMap<V, K> {
+    /// Create a map with the given number of shards
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, peer: &K) -> &RwLock<HashMap<K, V>> {
+        let index = self.hasher.hash_one(peer) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// Lock the shard holding a peer for reading
+    pub fn read(&self, peer: &K) -> RwLockReadGuard<'_, HashMap<K, V>> {
+        self.shard(peer).read()
+    }
+
+    /// Lock the shard holding a peer for writing
+    pub fn write(&self, peer: &K) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        self.shard(peer).write()
+    }
+
+    /// Insert an entry, returning the one it replaced
+    pub fn insert(&self, peer: K, value: V) -> Option<V> {
+        self.write(&peer).insert(peer, value)
+    }
+
+    /// Remove an entry
+    pub fn remove(&self, peer: &K) -> Option<V> {
+        self.write(peer).remove(peer)
+    }
+
+    /// Collect from all entries, locking one shard at a time
+    pub fn filter_map<T>(&self, mut f: impl FnMut(&K, &V) -> Option<T>) -> Vec<T> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read();
+                shard.iter().filter_map(|(peer, value)| f(peer, value)).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+impl<V: Clone, K: Hash + Eq> PeerMap<V, K> {
+    /// Get a copy of a peer's entry
+    pub fn get(&self, peer: &K) -> Option<V> {
+        self.read(peer).get(peer).cloned()
+    }
+}
+
+/// Allocator for the peer IDs clients put in front of P_DATA_V2 packets
+///
+/// IDs are handed out round-robin so a released ID is not reused while
+/// packets from its previous owner may still be in flight.
+pub struct PeerIdPool {
+    inner: Mutex<PeerIdPoolInner>,
+}
+
+struct PeerIdPoolInner {
+    next: u32,
+    in_use: HashSet<u32>,
+}
+
+impl PeerIdPool {
+    /// Create an empty pool
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(PeerIdPoolInner {
+                next: 0,
+                in_use: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Allocate an unused peer ID, or `None` if all are taken
+    pub fn allocate(&self) -> Option<u32> {
+        let mut inner = self.inner.lock();
+        if inner.in_use.len() >= UNDEFINED_PEER_ID as usize {
+            return None;
+        }
+
+        loop {
+            let id = inner.next;
+            inner.next = (id + 1) % UNDEFINED_PEER_ID;
+            if inner.in_use.insert(id) {
+                return Some(id);
+            }
+        }
+    }
+
+    /// Return a peer ID to the pool
+    pub fn release(&self, id: u32) {
+        self.inner.lock().in_use.remove(&id);
+    }
+}
+
+impl Default for PeerIdPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Transfer counters shared by a connection and its data path
+#[derive(Debug)]
+pub struct TrafficCounters {
+    bytes_rx: AtomicU64,
+    bytes_tx: AtomicU64,
+    packets_rx: AtomicU64,
+    packets_tx: AtomicU64,
+    /// Packets to the client dropped because its transport was backed up
+    dropped_tx: AtomicU64,
+    /// Milliseconds from `started` to the last packet received
+    last_rx: AtomicU64,
+    started: Instant,
+}
+
+impl TrafficCounters {
+    /// Create zeroed counters
+    pub fn new() -> Self {
+        Self {
+            bytes_rx: AtomicU64::new(0),
+            bytes_tx: AtomicU64::new(0),
+            packets_rx: AtomicU64::new(0),
+            packets_tx: AtomicU64::new(0),
+            dropped_tx: AtomicU64::new(0),
+            last_rx: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Count a packet received from the client
+    pub fn record_rx(&self, bytes: usize) {
+        self.bytes_rx.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_rx.fetch_add(1, Ordering::Relaxed);
+        self.last_rx.fetch_max(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Count a packet sent to the client
+    pub fn record_tx(&self, bytes: usize) {
+        self.bytes_tx.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_tx.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a packet to the client that was dropped, returning the total so far
+    pub fn record_tx_drop(&self) -> u64 {
+        self.dropped_tx.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Time since the last packet received from the client
+    pub fn idle_time(&self) -> Duration {
+        let last_rx = Duration::from_millis(self.last_rx.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_rx)
+    }
+
+    /// Current totals
+    pub fn snapshot(&self) -> TransferStats {
+        TransferStats {
+            bytes_rx: self.bytes_rx.load(Ordering::Relaxed),
+            bytes_tx: self.bytes_tx.load(Ordering::Relaxed),
+            packets_rx: self.packets_rx.load(Ordering::Relaxed),
+            packets_tx: self.packets_tx.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for TrafficCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything needed to move one established client's packets
+///
+/// Published once the client has an address and replaced as a whole when
+/// anything in it changes, so readers never see a half-updated connection.
+pub struct DataPath {
+    /// Peer address packets are sent to
+    pub peer_addr: SocketAddr,
+    /// Peer ID the client sends P_DATA_V2 packets with
+    pub peer_id: u32,
+    /// Outbound path to the peer
+    pub transport: Transport,
+    /// Data channel keys
+    pub channels: DataChannels,
+    /// Addresses the client may send from
+    pub vpn_address: VpnAddress,
+    /// Access granted by the policy engine (if enabled)
+    pub access: Option<AccessPolicy>,
+    /// Username (if authenticated)
+    pub username: Option<String>,
+    /// Transfer counters shared with the connection
+    pub counters: Arc<TrafficCounters>,
+    /// Packets dropped by the access policy
+    pub denied_packets: Mutex<DeniedPackets>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_map() {
+        let map = PeerMap::new(4);
+        let peers: Vec<SocketAddr> = (1..=32).map(|port| SocketAddr::from(([192, 0, 2, 1], port))).collect();
+        for (index, peer) in peers.iter().enumerate() {
+            assert!(map.insert(*peer, index).is_none());
+        }
+
+        assert_eq!(map.get(&peers[7]), Some(7));
+        *map.write(&peers[7]).get_mut(&peers[7]).unwrap() = 70;
+        assert_eq!(map.remove(&peers[7]), Some(70));
+        assert_eq!(map.get(&peers[7]), None);
+
+        let mut odd = map.filter_map(|_, index| (index % 2 == 1).then_some(*index));
+        odd.sort();
+        assert_eq!(odd.len(), 15);
+        assert_eq!(odd[0], 1);
+    }
+
+    #[test]
+    fn test_peer_id_pool() {
+        let pool = PeerIdPool::new();
+        assert_eq!(pool.allocate(), Some(0));
+        assert_eq!(pool.allocate(), Some(1));
+
+        // Released IDs are only reused once the counter wraps around
+        pool.release(0);
+        assert_eq!(pool.allocate(), Some(2));
+
+        pool.inner.lock().next = UNDEFINED_PEER_ID - 1;
+        assert_eq!(pool.allocate(), Some(UNDEFINED_PEER_ID - 1));
+        assert_eq!(pool.allocate(), Some(0));
+        assert_eq!(pool.allocate(), Some(3));
+    }
+
+    #[test]
+    fn test_traffic_counters() {
+        let counters = TrafficCounters::new();
+        counters.record_rx(100);
+        counters.record_rx(50);
+        counters.record_tx(1400);
+        assert_eq!(counters.record_tx_drop(), 1);
+        assert_eq!(counters.record_tx_drop(), 2);
+
+        let stats = counters.snapshot();
+        assert_eq!((stats.bytes_rx, stats.packets_rx), (150, 2));
+        assert_eq!((stats.bytes_tx, stats.packets_tx), (1400, 1));
+        assert!(counters.idle_time() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,18 @@
+//! CoreVPN Server
+//!
+//! The VPN server, its data path and the admin web UI, shared by the
+//! `corevpn-server` binary and the benchmarks.
+
+pub mod audit;
+pub mod connection_log;
+pub mod datapath;
+pub mod oauth;
+pub mod policy;
+pub mod pool;
+pub mod saml;
+pub mod server;
+pub mod setup;
+pub mod transport;
+pub mod tunnel;
+pub mod udp;
+pub mod webui;
@@ -9,13 +9,8 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use tracing::{info, warn, error};
 
-mod setup;
-mod server;
-mod webui;
-mod connection_log;
-pub mod audit;
-
 use corevpn_config::ServerConfig;
+use corevpn_server::{server, setup, webui};
 
 #[derive(Parser)]
 #[command(name = "corevpn-server")]
@@ -67,6 +62,62 @@ enum Commands {
         output: Option<PathBuf>,
     },
 
+    /// Track client certificates issued before revocation support, so they can be revoked
+    ImportCerts {
+        /// Configuration file path
+        #[arg(short, long, default_value = "/etc/corevpn/config.toml")]
+        config: PathBuf,
+
+        /// Client .ovpn profiles or PEM certificates
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Set or reset a local user's password
+    Passwd {
+        /// Configuration file path
+        #[arg(short, long, default_value = "/etc/corevpn/config.toml")]
+        config: PathBuf,
+
+        /// Username/email of the account (created if it does not exist)
+        #[arg(short, long)]
+        user: String,
+
+        /// Read the password from stdin instead of prompting
+        #[arg(long)]
+        stdin: bool,
+    },
+
+    /// Enroll a local user in TOTP, printing the otpauth:// URI for authenticator apps
+    Totp {
+        /// Configuration file path
+        #[arg(short, long, default_value = "/etc/corevpn/config.toml")]
+        config: PathBuf,
+
+        /// Username/email of the account
+        #[arg(short, long)]
+        user: String,
+
+        /// Remove the user's second factor instead
+        #[arg(long)]
+        disable: bool,
+    },
+
+    /// Reserve a static VPN address for a user (omit --ip to remove the reservation)
+    StaticIp {
+        /// Configuration file path
+        #[arg(short, long, default_value = "/etc/corevpn/config.toml")]
+        config: PathBuf,
+
+        /// Username/email or certificate common name (account created if it does not exist)
+        #[arg(short, long)]
+        user: String,
+
+        /// Address within the VPN subnet
+        #[arg(long)]
+        ip: Option<std::net::Ipv4Addr>,
+    },
+
     /// Show server status
     Status {
         /// Configuration file path
@@ -129,6 +180,30 @@ async fn main() -> Result<()> {
 
             generate_client_config(&server_config, &user, output.as_deref())?;
         }
+        Commands::ImportCerts { config, files } => {
+            let server_config = ServerConfig::load(&config)
+                .with_context(|| format!("Failed to load config from {:?}", config))?;
+
+            import_certificates(&server_config, &files)?;
+        }
+        Commands::Passwd { config, user, stdin } => {
+            let server_config = ServerConfig::load(&config)
+                .with_context(|| format!("Failed to load config from {:?}", config))?;
+
+            set_user_password(&server_config, &user, stdin).await?;
+        }
+        Commands::Totp { config, user, disable } => {
+            let server_config = ServerConfig::load(&config)
+                .with_context(|| format!("Failed to load config from {:?}", config))?;
+
+            enroll_totp(&server_config, &user, disable).await?;
+        }
+        Commands::StaticIp { config, user, ip } => {
+            let server_config = ServerConfig::load(&config)
+                .with_context(|| format!("Failed to load config from {:?}", config))?;
+
+            set_static_ip(&server_config, &user, ip).await?;
+        }
         Commands::Status { config } => {
             let server_config = ServerConfig::load(&config)
                 .with_context(|| format!("Failed to load config from {:?}", config))?;
@@ -210,6 +285,129 @@ fn generate_client_config(
     Ok(())
 }
 
+fn import_certificates(config: &ServerConfig, files: &[PathBuf]) -> Result<()> {
+    use corevpn_config::generator::{client_certificates, ConfigGenerator};
+    use corevpn_crypto::CertificateAuthority;
+
+    let ca_cert = std::fs::read_to_string(config.ca_cert_path())
+        .context("Failed to read CA certificate")?;
+    let ca_key = std::fs::read_to_string(config.ca_key_path())
+        .context("Failed to read CA key")?;
+    let ca = CertificateAuthority::from_pem(&ca_cert, &ca_key)
+        .context("Failed to load CA")?;
+    let generator = ConfigGenerator::new(config.clone(), ca, None);
+
+    let mut imported = 0;
+    for file in files {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {:?}", file))?;
+        let certs = client_certificates(&content);
+        if certs.is_empty() {
+            println!("{:?}: no client certificate found", file);
+        }
+        for cert in certs {
+            match generator.import_certificate(cert) {
+                Ok(Some(entry)) => {
+                    println!("{:?}: imported {} (serial {})", file, entry.common_name, entry.serial);
+                    imported += 1;
+                }
+                Ok(None) => println!("{:?}: already tracked", file),
+                Err(e) => println!("{:?}: skipped: {}", file, e),
+            }
+        }
+    }
+
+    println!("\nImported {} certificate(s) into {:?}", imported, config.cert_index_path());
+    Ok(())
+}
+
+async fn set_user_password(config: &ServerConfig, username: &str, stdin: bool) -> Result<()> {
+    use corevpn_core::SqliteUserStore;
+    use dialoguer::{theme::ColorfulTheme, Password};
+
+    let password = if stdin {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).context("Failed to read password")?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    } else {
+        Password::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("New password for {}", username))
+            .with_confirmation("Confirm password", "Passwords do not match")
+            .interact()?
+    };
+
+    let store = SqliteUserStore::open_in(config.data_dir())
+        .await
+        .context("Failed to open user database")?;
+    let user = corevpn_auth::set_password(&store, username, &password).await?;
+
+    println!("Password updated for: {}", user.id);
+    if !config.security.password_auth {
+        println!("\nNote: set security.password_auth = true to require passwords at login");
+    }
+
+    Ok(())
+}
+
+async fn enroll_totp(config: &ServerConfig, username: &str, disable: bool) -> Result<()> {
+    use corevpn_auth::TotpKey;
+    use corevpn_core::SqliteUserStore;
+
+    let store = SqliteUserStore::open_in(config.data_dir())
+        .await
+        .context("Failed to open user database")?;
+
+    if disable {
+        let user = corevpn_auth::totp::disable(&store, username).await?;
+        println!("TOTP disabled for: {}", user.id);
+        return Ok(());
+    }
+
+    let key = TotpKey::load_or_generate(&config.totp_key_path())
+        .context("Failed to load TOTP key")?;
+    let uri = corevpn_auth::totp::enroll(&store, &key, username, &config.server.public_host).await?;
+
+    println!("TOTP enrolled for: {}", username);
+    println!("\nAdd this URI to an authenticator app (or render it as a QR code):");
+    println!("  {}", uri);
+    if config.security.totp == corevpn_config::TotpMode::Disabled {
+        println!("\nNote: set security.totp = \"optional\" or \"required\" to ask for codes at login");
+    }
+
+    Ok(())
+}
+
+async fn set_static_ip(config: &ServerConfig, username: &str, ip: Option<std::net::Ipv4Addr>) -> Result<()> {
+    use corevpn_core::{SqliteUserStore, User, UserId, UserStore, VpnAddress};
+
+    if let Some(ip) = ip {
+        let subnet: ipnet::Ipv4Net = config.network.subnet.parse().context("Invalid subnet")?;
+        if !subnet.contains(&ip) {
+            anyhow::bail!("{} is not in the VPN subnet {}", ip, subnet);
+        }
+    }
+
+    let store = SqliteUserStore::open_in(config.data_dir())
+        .await
+        .context("Failed to open user database")?;
+    let existing = match store.get_user(&UserId::new(username)).await {
+        Some(user) => Some(user),
+        None => store.get_user_by_email(username).await,
+    };
+    let mut user = existing.unwrap_or_else(|| User::new(UserId::new(username)));
+
+    user.static_ip = ip.map(VpnAddress::v4);
+    store.upsert_user(&user).await?;
+
+    match ip {
+        Some(ip) => println!("Reserved {} for: {}", ip, user.id),
+        None => println!("Removed static address of: {}", user.id),
+    }
+    println!("\nA running server applies the change within a minute");
+
+    Ok(())
+}
+
 async fn show_status(config: &ServerConfig) -> Result<()> {
     println!("CoreVPN Server Status");
     println!("=====================");
@@ -229,7 +427,11 @@ async fn show_status(config: &ServerConfig) -> Result<()> {
     println!("  Cipher: {}", config.security.cipher);
     println!("  TLS Version: {}", config.security.tls_min_version);
     println!("  TLS Auth: {}", config.security.tls_auth);
+    println!("  TLS Crypt: {}", config.security.tls_crypt);
+    println!("  TLS Crypt v2: {}", config.security.tls_crypt_v2);
     println!("  PFS: {}", config.security.pfs);
+    println!("  Password Auth: {}", config.security.password_auth);
+    println!("  TOTP: {:?}", config.security.totp);
     println!();
 
     if let Some(oauth) = &config.oauth {
@@ -346,6 +548,9 @@ async fn run_doctor(_config_path: &std::path::Path, config_result: Result<Server
         print!("Checking TLS auth key... ");
         if config.ta_key_path().exists() {
             println!("{} Found", CHECK);
+        } else if config.security.tls_crypt {
+            println!("{} Not found (tls_crypt enabled)", WARN);
+            issues.push("TLS auth key missing but tls_crypt is enabled".to_string());
         } else if config.security.tls_auth {
             println!("{} Not found (tls_auth enabled)", WARN);
             issues.push("TLS auth key missing but tls_auth is enabled".to_string());
@@ -353,6 +558,16 @@ async fn run_doctor(_config_path: &std::path::Path, config_result: Result<Server
             println!("{} Not required", CHECK);
         }
 
+        if config.security.tls_crypt_v2 {
+            print!("Checking tls-crypt-v2 server key... ");
+            if config.tls_crypt_v2_key_path().exists() {
+                println!("{} Found", CHECK);
+            } else {
+                println!("{} Not found (tls_crypt_v2 enabled)", WARN);
+                issues.push("tls-crypt-v2 server key missing but tls_crypt_v2 is enabled".to_string());
+            }
+        }
+
         // Check network
         print!("Checking network configuration... ");
         if config.network.subnet.parse::<ipnet::Ipv4Net>().is_ok() {
@@ -0,0 +1,532 @@
+//! OAuth2 VPN Logins
+//!
+//! Binds VPN connections to an OAuth2/OIDC login using the device
+//! authorization flow, so no callback endpoint is needed. Clients that
+//! advertise `IV_SSO` are held with AUTH_PENDING and sent the verification
+//! URL; other clients get a CRV1 dynamic challenge and answer it on reconnect
+//! once the login is done.
+//!
+//! Logins bound to a tunnel are revalidated periodically by refreshing their
+//! tokens (and optionally introspecting them), so a user who is deactivated
+//! or removed from the required groups loses VPN access.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+use tracing::{debug, info, warn};
+
+use corevpn_auth::flow::DeviceAuthResponse;
+use corevpn_auth::{
+    AuthError, AuthSession, AuthSessionManager, DeviceAuthFlow, OAuthProvider, ProviderConfig,
+    TokenSet, TokenValidator, UserInfo,
+};
+use corevpn_config::server::OAuthSettings;
+
+use crate::connection_log::AuthResult;
+
+/// Session metadata key holding the auth-user-pass username
+const USERNAME_KEY: &str = "vpn_username";
+
+/// How long a login stays valid without a successful revalidation
+const LOGIN_LIFETIME: Duration = Duration::from_secs(24 * 3600);
+
+/// Outcome of a login
+#[derive(Debug, Clone)]
+pub enum LoginStatus {
+    /// Waiting for the user to finish logging in
+    Pending,
+    /// Login completed and the user passed the domain/group checks
+    Complete(UserInfo),
+    /// Login failed
+    Failed(LoginFailure),
+}
+
+/// Why a login failed
+#[derive(Debug, Clone)]
+pub struct LoginFailure {
+    /// Result recorded in the connection log
+    pub result: AuthResult,
+    /// Reason sent to the client with AUTH_FAILED
+    pub reason: String,
+}
+
+impl From<&AuthError> for LoginFailure {
+    fn from(err: &AuthError) -> Self {
+        let result = match err {
+            AuthError::UnauthorizedDomain(_)
+            | AuthError::NotInRequiredGroup
+            | AuthError::UserDisabled => AuthResult::NotAuthorized,
+            AuthError::TokenExpired | AuthError::InvalidGrant(_) | AuthError::TokenInactive => {
+                AuthResult::Expired
+            }
+            AuthError::DeviceAuthExpired | AuthError::SessionExpired => AuthResult::Timeout,
+            AuthError::TokenValidationFailed(_)
+            | AuthError::SamlValidationFailed(_)
+            | AuthError::SamlStatus(_)
+            | AuthError::InvalidNonce
+            | AuthError::InvalidState
+            | AuthError::SessionNotFound => AuthResult::InvalidCredentials,
+            _ => AuthResult::ProviderError,
+        };
+        Self { result, reason: err.to_string() }
+    }
+}
+
+/// Result of revalidating a login bound to a tunnel
+#[derive(Debug, Clone)]
+pub enum SessionCheck {
+    /// Login is still valid, or the provider could not be reached
+    Valid,
+    /// Login was refreshed; carries the user's current details, groups included
+    Refreshed(UserInfo),
+    /// Access was revoked and the tunnel must be torn down
+    Revoked(LoginFailure),
+}
+
+/// OAuth2 logins for VPN connections
+pub struct OAuthLogin {
+    /// Device authorization flow against the configured provider
+    flow: DeviceAuthFlow,
+    /// ID token claim validator
+    validator: TokenValidator,
+    /// Logins by state ID
+    sessions: AuthSessionManager,
+    /// Failed logins by state ID
+    failures: RwLock<HashMap<String, LoginFailure>>,
+    /// How often active logins are revalidated
+    check_interval: Duration,
+    /// Check tokens with the introspection endpoint when revalidating
+    introspect: bool,
+}
+
+impl OAuthLogin {
+    /// Set up the provider from the server's OAuth settings
+    pub async fn new(settings: &OAuthSettings) -> Result<Self> {
+        let mut provider = OAuthProvider::new(provider_config(settings)?);
+        if provider.device_authorization_endpoint().is_err() {
+            provider.discover().await.context("OIDC discovery failed")?;
+        }
+        provider.device_authorization_endpoint()
+            .context("OAuth provider does not support device authorization")?;
+
+        if settings.token_introspection && provider.introspection_endpoint().is_err() {
+            anyhow::bail!("OAuth provider has no token introspection endpoint");
+        }
+
+        Self::with_provider(provider, settings)
+    }
+
+    fn with_provider(provider: OAuthProvider, settings: &OAuthSettings) -> Result<Self> {
+        let validator = provider.token_validator()?;
+
+        Ok(Self {
+            flow: DeviceAuthFlow::new(provider),
+            validator,
+            sessions: AuthSessionManager::new(LOGIN_LIFETIME, 5),
+            failures: RwLock::new(HashMap::new()),
+            check_interval: Duration::from_secs(settings.session_check_interval_secs.max(1)),
+            introspect: settings.token_introspection,
+        })
+    }
+
+    /// How often active logins should be revalidated
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+
+    /// Start a device login, returning its state ID and the device authorization
+    pub async fn start(&self, username: &str) -> Result<(String, DeviceAuthResponse)> {
+        let device = self.flow.start().await?;
+        Ok((self.create_login(username)?, device))
+    }
+
+    /// Poll the provider until the login completes, fails or expires
+    pub async fn run(&self, state_id: &str, device: &DeviceAuthResponse) {
+        let interval = Duration::from_secs(device.interval.max(1));
+        let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+
+        let result = loop {
+            tokio::time::sleep(interval).await;
+            if Instant::now() > deadline {
+                break Err(AuthError::DeviceAuthExpired);
+            }
+            match self.flow.poll(&device.device_code).await {
+                Ok(tokens) => break self.complete(state_id, tokens).await,
+                Err(AuthError::AuthorizationPending) => continue,
+                Err(e) => break Err(e),
+            }
+        };
+
+        if let Err(e) = result {
+            self.fail(state_id, &e);
+        }
+    }
+
+    /// Check if a CRV1 response refers to an unused login started for this username
+    pub fn resume(&self, state_id: &str, username: &str) -> bool {
+        self.sessions.get_session(state_id).is_some_and(|session| {
+            session.vpn_session_id.is_none()
+                && session.metadata.get(USERNAME_KEY).map(String::as_str) == Some(username)
+        })
+    }
+
+    /// Get the current status of a login
+    pub fn status(&self, state_id: &str) -> LoginStatus {
+        if let Some(failure) = self.failures.read().get(state_id) {
+            return LoginStatus::Failed(failure.clone());
+        }
+
+        match self.sessions.get_session(state_id) {
+            Some(session) if session.vpn_session_id.is_some() => {
+                LoginStatus::Failed(LoginFailure::from(&AuthError::InvalidState))
+            }
+            Some(AuthSession { tokens: Some(_), user_info: Some(user), .. }) => {
+                LoginStatus::Complete(user)
+            }
+            Some(_) => LoginStatus::Pending,
+            None => LoginStatus::Failed(LoginFailure::from(&AuthError::SessionNotFound)),
+        }
+    }
+
+    /// Bind a completed login to the VPN connection that used it
+    pub fn associate(&self, state_id: &str, vpn_session_id: &str) {
+        if let Some(mut session) = self.sessions.get_session(state_id) {
+            session.associate_vpn_session(vpn_session_id);
+            let _ = self.sessions.update_session(&session);
+        }
+    }
+
+    /// Drop expired logins
+    pub fn cleanup(&self) {
+        let removed = self.sessions.cleanup_expired();
+        if removed > 0 {
+            self.failures.write().retain(|id, _| self.sessions.get_session(id).is_some());
+            debug!("Removed {} expired OAuth logins", removed);
+        }
+    }
+
+    /// Revalidate a login bound to a tunnel with the provider
+    ///
+    /// Revoked logins are removed. Transient provider errors keep the login,
+    /// so an IdP outage does not disconnect every client.
+    pub async fn revalidate(&self, state_id: &str) -> SessionCheck {
+        match self.refresh(state_id).await {
+            Ok(Some(user)) => SessionCheck::Refreshed(user),
+            Ok(None) => SessionCheck::Valid,
+            Err(e) if is_revocation(&e) => {
+                info!("OAuth login {} revoked: {}", state_id, e);
+                self.sessions.remove_session(state_id);
+                SessionCheck::Revoked(LoginFailure::from(&e))
+            }
+            Err(e) => {
+                warn!("Could not revalidate OAuth login {}: {}", state_id, e);
+                SessionCheck::Valid
+            }
+        }
+    }
+
+    /// Refresh a login's tokens and re-apply the domain/group restrictions
+    ///
+    /// Logins without a refresh token can only be introspected; they end
+    /// when the login lifetime runs out. Returns the user after a refresh.
+    async fn refresh(&self, state_id: &str) -> corevpn_auth::Result<Option<UserInfo>> {
+        let mut session = self.sessions.get_session(state_id)
+            .filter(|session| !session.is_expired())
+            .ok_or(AuthError::SessionExpired)?;
+        let (Some(tokens), Some(user)) = (session.tokens.clone(), session.user_info.clone()) else {
+            return Err(AuthError::SessionNotFound);
+        };
+        let provider = self.flow.provider();
+
+        if self.introspect {
+            let (token, hint) = match tokens.refresh_token.as_deref() {
+                Some(refresh_token) => (refresh_token, "refresh_token"),
+                None => (tokens.access_token.as_str(), "access_token"),
+            };
+            if !provider.introspect(token, hint).await?.active {
+                return Err(AuthError::TokenInactive);
+            }
+        }
+
+        let Some(refresh_token) = tokens.refresh_token else {
+            if self.introspect {
+                session.extend(LOGIN_LIFETIME);
+                self.sessions.update_session(&session)?;
+            }
+            return Ok(None);
+        };
+
+        let mut refreshed = provider.refresh_token(&refresh_token).await?;
+        // Providers without refresh token rotation keep the old one valid
+        refreshed.refresh_token.get_or_insert(refresh_token);
+
+        let user = match refreshed.id_token.as_deref() {
+            Some(id_token) => {
+                let claims = self.validator.validate(id_token, None).await?;
+                if claims.sub != user.sub {
+                    return Err(AuthError::TokenValidationFailed("subject changed on refresh".into()));
+                }
+                UserInfo::from_claims(&claims, &session.provider)
+            }
+            None => user,
+        };
+        provider.authorize_user(&user)?;
+
+        session.update_tokens(refreshed);
+        session.update_user_info(user.clone());
+        session.extend(LOGIN_LIFETIME);
+        self.sessions.update_session(&session)?;
+        Ok(Some(user))
+    }
+
+    fn fail(&self, state_id: &str, err: &AuthError) {
+        debug!("OAuth login {} failed: {}", state_id, err);
+        self.failures.write().insert(state_id.to_string(), LoginFailure::from(err));
+    }
+
+    fn create_login(&self, username: &str) -> Result<String> {
+        let provider = self.flow.provider().config().provider_type.to_string();
+        let mut session = self.sessions.create_session(&provider);
+        session.metadata.insert(USERNAME_KEY.to_string(), username.to_string());
+        self.sessions.update_session(&session)?;
+        Ok(session.id)
+    }
+
+    /// Verify the ID token against the provider's JWKS and accept the login
+    async fn complete(&self, state_id: &str, tokens: TokenSet) -> corevpn_auth::Result<()> {
+        let id_token = tokens.id_token.as_deref()
+            .ok_or_else(|| AuthError::TokenValidationFailed("no ID token in response".into()))?;
+        let claims = self.validator.validate(id_token, None).await?;
+
+        let provider = self.flow.provider().config().provider_type.to_string();
+        self.accept(state_id, tokens, UserInfo::from_claims(&claims, &provider))
+    }
+
+    /// Apply the domain/group restrictions and record the completed login
+    fn accept(&self, state_id: &str, tokens: TokenSet, user: UserInfo) -> corevpn_auth::Result<()> {
+        self.flow.provider().authorize_user(&user)?;
+
+        let mut session = self.sessions.get_session(state_id).ok_or(AuthError::SessionNotFound)?;
+        info!("OAuth login completed for {}", user.email.as_deref().unwrap_or(&user.sub));
+        session.complete_auth(tokens, user);
+        self.sessions.update_session(&session)
+    }
+}
+
+/// Check if an error means the user's access was revoked, rather than the
+/// provider being temporarily unavailable
+fn is_revocation(err: &AuthError) -> bool {
+    matches!(
+        err,
+        AuthError::InvalidGrant(_)
+            | AuthError::TokenInactive
+            | AuthError::TokenExpired
+            | AuthError::TokenValidationFailed(_)
+            | AuthError::UnauthorizedDomain(_)
+            | AuthError::NotInRequiredGroup
+            | AuthError::UserDisabled
+            | AuthError::SessionNotFound
+            | AuthError::SessionExpired
+    )
+}
+
+/// Build the provider configuration for the configured provider type
+fn provider_config(settings: &OAuthSettings) -> Result<ProviderConfig> {
+    let (id, secret) = (settings.client_id.as_str(), settings.client_secret.as_str());
+    let mut config = match settings.provider.as_str() {
+        "google" => ProviderConfig::google(id, secret, None),
+        "microsoft" => {
+            let tenant = settings.tenant_id.as_deref()
+                .context("oauth.tenant_id is required for Microsoft")?;
+            ProviderConfig::microsoft(id, secret, tenant)
+        }
+        "okta" => {
+            let domain = settings.domain.as_deref().context("oauth.domain is required for Okta")?;
+            ProviderConfig::okta(id, secret, domain, None)
+        }
+        "generic" => {
+            let issuer = settings.issuer_url.as_deref()
+                .context("oauth.issuer_url is required for generic OIDC")?;
+            ProviderConfig::generic(id, secret, issuer)
+        }
+        other => anyhow::bail!("Unknown OAuth provider: {}", other),
+    };
+
+    config.allowed_domains = settings.allowed_domains.clone();
+    config.required_groups = settings.required_groups.clone();
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    fn settings(provider: &str) -> OAuthSettings {
+        OAuthSettings {
+            enabled: true,
+            provider: provider.to_string(),
+            client_id: "client-id".to_string(),
+            client_secret: "client-secret".to_string(),
+            issuer_url: None,
+            tenant_id: None,
+            domain: None,
+            allowed_domains: vec!["example.com".to_string()],
+            required_groups: vec![],
+            session_check_interval_secs: 300,
+            token_introspection: false,
+        }
+    }
+
+    fn tokens(header: &str) -> TokenSet {
+        let now = chrono::Utc::now().timestamp();
+        let claims = serde_json::json!({
+            "iss": "https://accounts.google.com",
+            "sub": "user123",
+            "aud": "client-id",
+            "exp": now + 3600,
+            "iat": now,
+            "email": "alice@example.com",
+        });
+        let encode = |value: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value);
+        TokenSet {
+            access_token: "access".to_string(),
+            refresh_token: None,
+            id_token: Some(format!("{}.{}.", encode(header.as_bytes()), encode(claims.to_string().as_bytes()))),
+            expires_at: chrono::Utc::now() + chrono::Duration::hours(1),
+            token_type: "Bearer".to_string(),
+            scopes: vec![],
+        }
+    }
+
+    fn user(email: &str) -> UserInfo {
+        UserInfo {
+            sub: "user123".to_string(),
+            email: Some(email.to_string()),
+            email_verified: true,
+            name: None,
+            given_name: None,
+            family_name: None,
+            picture: None,
+            groups: vec![],
+            provider: "google".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_provider_config() {
+        let config = provider_config(&settings("google")).unwrap();
+        assert_eq!(config.allowed_domains, vec!["example.com"]);
+
+        assert!(provider_config(&settings("microsoft")).is_err());
+        let mut microsoft = settings("microsoft");
+        microsoft.tenant_id = Some("common".to_string());
+        assert!(provider_config(&microsoft).is_err());
+        microsoft.tenant_id = Some("3f2c9a1e-0000-4000-8000-000000000000".to_string());
+        assert!(provider_config(&microsoft).is_ok());
+
+        assert!(provider_config(&settings("okta")).is_err());
+        assert!(provider_config(&settings("generic")).is_err());
+        assert!(provider_config(&settings("bogus")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_login_lifecycle() {
+        let oauth = OAuthLogin::new(&settings("google")).await.unwrap();
+        let state_id = oauth.create_login("alice").unwrap();
+        assert!(matches!(oauth.status(&state_id), LoginStatus::Pending));
+        assert!(oauth.resume(&state_id, "alice"));
+        assert!(!oauth.resume(&state_id, "mallory"));
+
+        // Unsigned ID tokens are rejected before any key lookup
+        assert!(oauth.complete(&state_id, tokens(r#"{"alg":"none"}"#)).await.is_err());
+
+        oauth.accept(&state_id, tokens(r#"{"alg":"RS256"}"#), user("alice@example.com")).unwrap();
+        match oauth.status(&state_id) {
+            LoginStatus::Complete(user) => assert_eq!(user.email.as_deref(), Some("alice@example.com")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // A login can only be used by one connection
+        oauth.associate(&state_id, "conn-1");
+        assert!(!oauth.resume(&state_id, "alice"));
+        assert!(matches!(oauth.status(&state_id), LoginStatus::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_login_rejects_other_domain() {
+        let oauth = OAuthLogin::new(&settings("google")).await.unwrap();
+        let state_id = oauth.create_login("bob").unwrap();
+
+        let err = oauth
+            .accept(&state_id, tokens(r#"{"alg":"RS256"}"#), user("bob@other.com"))
+            .unwrap_err();
+        oauth.fail(&state_id, &err);
+        match oauth.status(&state_id) {
+            LoginStatus::Failed(failure) => assert_eq!(failure.result, AuthResult::NotAuthorized),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(matches!(oauth.status("unknown"), LoginStatus::Failed(_)));
+    }
+
+    /// Stand-in token endpoint answering every request with the given response
+    async fn token_endpoint(status: &'static str, body: &'static str) -> String {
+        format!("{}/token", corevpn_auth::testing::serve_response(status, body).await)
+    }
+
+    async fn login_with_token_endpoint(endpoint: String) -> (OAuthLogin, String) {
+        let mut config = provider_config(&settings("google")).unwrap();
+        config.token_endpoint = Some(endpoint);
+        let oauth = OAuthLogin::with_provider(OAuthProvider::new(config), &settings("google")).unwrap();
+
+        let state_id = oauth.create_login("alice").unwrap();
+        let mut tokens = tokens(r#"{"alg":"RS256"}"#);
+        tokens.refresh_token = Some("refresh".to_string());
+        oauth.accept(&state_id, tokens, user("alice@example.com")).unwrap();
+        oauth.associate(&state_id, "conn-1");
+        (oauth, state_id)
+    }
+
+    #[tokio::test]
+    async fn test_revalidate() {
+        let oauth = OAuthLogin::new(&settings("google")).await.unwrap();
+        assert!(matches!(oauth.revalidate("unknown").await, SessionCheck::Revoked(_)));
+
+        // Nothing to refresh: the login stays until its lifetime runs out
+        let state_id = oauth.create_login("alice").unwrap();
+        oauth.accept(&state_id, tokens(r#"{"alg":"RS256"}"#), user("alice@example.com")).unwrap();
+        assert!(matches!(oauth.revalidate(&state_id).await, SessionCheck::Valid));
+
+        // Provider outages keep the tunnel up
+        let endpoint = token_endpoint("503 Service Unavailable", "").await;
+        let (oauth, state_id) = login_with_token_endpoint(endpoint).await;
+        assert!(matches!(oauth.revalidate(&state_id).await, SessionCheck::Valid));
+
+        // A revoked refresh token ends the login
+        let endpoint = token_endpoint(
+            "400 Bad Request",
+            r#"{"error":"invalid_grant","error_description":"user deactivated"}"#,
+        ).await;
+        let (oauth, state_id) = login_with_token_endpoint(endpoint).await;
+        match oauth.revalidate(&state_id).await {
+            SessionCheck::Revoked(failure) => {
+                assert_eq!(failure.result, AuthResult::Expired);
+                assert!(failure.reason.contains("user deactivated"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(oauth.revalidate(&state_id).await, SessionCheck::Revoked(_)));
+
+        // A successful refresh hands back the user so their groups can be re-applied
+        let endpoint = token_endpoint("200 OK", r#"{"access_token":"access-2","token_type":"Bearer"}"#).await;
+        let (oauth, state_id) = login_with_token_endpoint(endpoint).await;
+        match oauth.revalidate(&state_id).await {
+            SessionCheck::Refreshed(user) => assert_eq!(user.email.as_deref(), Some("alice@example.com")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Access Policy Engine
+//!
+//! Maps a client's identity (username, groups, and the role and routes of its
+//! local account) to the destinations it may reach through the tunnel. The resulting [`AccessPolicy`] decides which
+//! routes are pushed to the client and filters every decrypted packet.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use corevpn_config::{PolicyAction, PolicyProtocol, PolicyRule, PolicySettings};
+use corevpn_core::{Route, UserRole};
+use ipnet::IpNet;
+
+/// Minimum time between audit events for packets dropped on one connection
+pub const DENIED_AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Compiled access policy rules
+pub struct PolicyEngine {
+    settings: PolicySettings,
+    rules: Vec<Rule>,
+}
+
+/// Access rule with parsed grants
+struct Rule {
+    config: PolicyRule,
+    grants: Vec<Grant>,
+}
+
+impl Rule {
+    fn applies_to(&self, identity: &Identity<'_>, role: &UserRole) -> bool {
+        let config = &self.config;
+        let everyone = config.groups.is_empty() && config.users.is_empty() && config.roles.is_empty();
+        everyone || identity.matches(&config.users, &config.groups) || config.roles.contains(role)
+    }
+}
+
+/// Allowed destination
+#[derive(Debug, Clone)]
+struct Grant {
+    net: IpNet,
+    protocol: PolicyProtocol,
+    ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Grant {
+    fn allows(&self, packet: &PacketInfo) -> bool {
+        if !self.net.contains(&packet.destination) || !self.protocol.matches(packet.protocol) {
+            return false;
+        }
+        if self.ports.is_empty() {
+            return true;
+        }
+        match packet.port {
+            Some(port) => self.ports.iter().any(|range| range.contains(&port)),
+            // Later fragments carry no ports; the first fragment was checked
+            None => packet.fragment,
+        }
+    }
+}
+
+/// Identity a policy is evaluated for
+#[derive(Debug, Clone, Copy)]
+pub struct Identity<'a> {
+    /// Username or email address
+    pub username: Option<&'a str>,
+    /// Groups from the identity provider
+    pub groups: &'a [String],
+    /// Role stored with the user's local account
+    pub role: Option<&'a UserRole>,
+    /// Routes stored with the user's local account
+    pub routes: &'a [Route],
+}
+
+impl Identity<'_> {
+    fn matches(&self, users: &[String], groups: &[String]) -> bool {
+        self.username.is_some_and(|name| users.iter().any(|u| u.eq_ignore_ascii_case(name)))
+            || self.groups.iter().any(|group| groups.contains(group))
+    }
+}
+
+impl PolicyEngine {
+    /// Compile the policy settings
+    pub fn new(settings: &PolicySettings) -> Result<Self> {
+        let rules = settings.rules
+            .iter()
+            .map(|rule| {
+                let grants = rule.allow
+                    .iter()
+                    .map(|grant| {
+                        Ok(Grant {
+                            net: grant.cidr.trunc(),
+                            protocol: grant.protocol,
+                            ports: grant.port_ranges()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Rule { config: rule.clone(), grants })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { settings: settings.clone(), rules })
+    }
+
+    /// Resolve the role of an identity
+    ///
+    /// A role assignment in the policy settings wins over the role stored
+    /// with the user's account.
+    pub fn role(&self, identity: &Identity<'_>) -> UserRole {
+        self.settings.roles
+            .iter()
+            .find(|assignment| identity.matches(&assignment.users, &assignment.groups))
+            .map(|assignment| assignment.role.clone())
+            .or_else(|| identity.role.cloned())
+            .unwrap_or_default()
+    }
+
+    /// Evaluate the policy for an identity
+    ///
+    /// The account's routes are granted on top of the matching rules, and
+    /// pushed to unrestricted clients with the network-wide routes.
+    pub fn evaluate(&self, identity: &Identity<'_>) -> AccessPolicy {
+        let role = self.role(identity);
+        let account_routes: Vec<IpNet> = identity.routes.iter().map(|route| route.network.trunc()).collect();
+
+        match role {
+            UserRole::Admin => return AccessPolicy::unrestricted(role).with_account_routes(account_routes),
+            UserRole::ReadOnly => return AccessPolicy::restricted(role, Vec::new(), Vec::new()),
+            _ => {}
+        }
+
+        let mut names = Vec::new();
+        let mut grants = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.applies_to(identity, &role)) {
+            names.push(rule.config.name.clone());
+            grants.extend(rule.grants.iter().cloned());
+        }
+
+        if names.is_empty() && role != UserRole::Limited && self.settings.default_action == PolicyAction::Allow {
+            return AccessPolicy::unrestricted(role).with_account_routes(account_routes);
+        }
+        grants.extend(account_routes.into_iter().map(|net| Grant {
+            net,
+            protocol: PolicyProtocol::Any,
+            ports: Vec::new(),
+        }));
+        AccessPolicy::restricted(role, names, grants)
+    }
+}
+
+/// Access granted to one client
+#[derive(Debug, Clone)]
+pub struct AccessPolicy {
+    role: UserRole,
+    rules: Vec<String>,
+    /// Allowed destinations, `None` for unrestricted access
+    grants: Option<Vec<Grant>>,
+    /// Routes from the user's account pushed alongside unrestricted access
+    account_routes: Vec<IpNet>,
+}
+
+impl AccessPolicy {
+    /// Access to everything the network settings route
+    pub fn unrestricted(role: UserRole) -> Self {
+        Self { role, rules: Vec::new(), grants: None, account_routes: Vec::new() }
+    }
+
+    fn restricted(role: UserRole, rules: Vec<String>, grants: Vec<Grant>) -> Self {
+        Self { role, rules, grants: Some(grants), account_routes: Vec::new() }
+    }
+
+    fn with_account_routes(mut self, routes: Vec<IpNet>) -> Self {
+        self.account_routes = routes;
+        self
+    }
+
+    /// Role the policy was evaluated for
+    pub fn role(&self) -> &UserRole {
+        &self.role
+    }
+
+    /// Names of the rules that granted access
+    pub fn rules(&self) -> &[String] {
+        &self.rules
+    }
+
+    /// Check if the client may not use the tunnel at all
+    pub fn is_denied(&self) -> bool {
+        self.grants.as_ref().is_some_and(|grants| grants.is_empty())
+    }
+
+    /// Networks to push as routes, `None` to push the network-wide routes
+    pub fn routes(&self) -> Option<Vec<IpNet>> {
+        let grants = self.grants.as_ref()?;
+        let networks = grants.iter().map(|grant| grant.net).collect();
+        Some(IpNet::aggregate(&networks))
+    }
+
+    /// Account routes to push along with the network-wide routes
+    pub fn account_routes(&self) -> &[IpNet] {
+        &self.account_routes
+    }
+
+    /// Check if a decrypted IPv4 or IPv6 packet from the client may be forwarded
+    pub fn allows(&self, packet: &[u8]) -> bool {
+        let Some(ref grants) = self.grants else {
+            return true;
+        };
+        PacketInfo::parse(packet).is_some_and(|info| grants.iter().any(|grant| grant.allows(&info)))
+    }
+}
+
+/// Header fields of an IP packet matched by policy grants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// Destination address
+    pub destination: IpAddr,
+    /// IP protocol number (the upper-layer header for IPv6)
+    pub protocol: u8,
+    /// TCP or UDP destination port (first fragment only)
+    pub port: Option<u16>,
+    /// Whether this is a later fragment without a transport header
+    pub fragment: bool,
+}
+
+impl PacketInfo {
+    /// Parse the IPv4 or IPv6 header
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        match packet.first()? >> 4 {
+            4 => Self::parse_v4(packet),
+            6 => Self::parse_v6(packet),
+            _ => None,
+        }
+    }
+
+    fn parse_v4(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 20 {
+            return None;
+        }
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        if header_len < 20 || packet.len() < header_len {
+            return None;
+        }
+
+        let protocol = packet[9];
+        let fragment = u16::from_be_bytes([packet[6] & 0x1f, packet[7]]) != 0;
+        Some(Self {
+            destination: IpAddr::V4(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19])),
+            protocol,
+            port: Self::port(packet, protocol, header_len, fragment),
+            fragment,
+        })
+    }
+
+    /// Follow the extension headers to the upper-layer protocol
+    fn parse_v6(packet: &[u8]) -> Option<Self> {
+        const HEADER_LEN: usize = 40;
+        const MAX_EXTENSION_HEADERS: usize = 8;
+
+        let destination: [u8; 16] = packet.get(24..HEADER_LEN)?.try_into().ok()?;
+        let mut protocol = packet[6];
+        let mut offset = HEADER_LEN;
+        let mut fragment = false;
+        for _ in 0..MAX_EXTENSION_HEADERS {
+            let header_len = match protocol {
+                // Hop-by-hop, routing and destination options
+                0 | 43 | 60 => (usize::from(*packet.get(offset + 1)?) + 1) * 8,
+                // Fragment: later fragments carry no upper-layer header
+                44 => {
+                    let fragment_offset = packet.get(offset + 2..offset + 4)?;
+                    fragment = u16::from_be_bytes([fragment_offset[0], fragment_offset[1]]) >> 3 != 0;
+                    8
+                }
+                // Authentication header
+                51 => (usize::from(*packet.get(offset + 1)?) + 2) * 4,
+                _ => break,
+            };
+            protocol = *packet.get(offset)?;
+            offset += header_len;
+            if fragment {
+                break;
+            }
+        }
+        if matches!(protocol, 0 | 43 | 44 | 51 | 60) && !fragment {
+            return None; // Too many extension headers to find the protocol
+        }
+
+        Some(Self {
+            destination: IpAddr::V6(Ipv6Addr::from(destination)),
+            protocol,
+            port: Self::port(packet, protocol, offset, fragment),
+            fragment,
+        })
+    }
+
+    fn port(packet: &[u8], protocol: u8, header_len: usize, fragment: bool) -> Option<u16> {
+        match protocol {
+            6 | 17 if !fragment => packet
+                .get(header_len + 2..header_len + 4)
+                .map(|port| u16::from_be_bytes([port[0], port[1]])),
+            _ => None,
+        }
+    }
+}
+
+/// Rate limit for audit events about dropped packets
+#[derive(Debug, Default)]
+pub struct DeniedPackets {
+    /// Drops since the last audit event
+    pending: u64,
+    last_logged: Option<Instant>,
+}
+
+impl DeniedPackets {
+    /// Record a dropped packet, returning the drop count to audit if one is due
+    pub fn record(&mut self) -> Option<u64> {
+        self.pending += 1;
+        if self.last_logged.is_some_and(|at| at.elapsed() < DENIED_AUDIT_INTERVAL) {
+            return None;
+        }
+        self.last_logged = Some(Instant::now());
+        Some(std::mem::take(&mut self.pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> PolicySettings {
+        serde_json::from_value(serde_json::json!({
+            "enabled": true,
+            "roles": [
+                { "role": "Admin", "users": ["root@example.com"] },
+                { "role": "Limited", "groups": ["contractors"] },
+            ],
+            "rules": [
+                {
+                    "name": "contractors-web",
+                    "groups": ["contractors"],
+                    "allow": [{ "cidr": "10.20.0.0/16", "protocol": "tcp", "ports": ["443", "8000-8100"] }],
+                },
+                { "name": "engineering", "groups": ["engineering"], "allow": [{ "cidr": "10.0.0.0/8" }] },
+                {
+                    "name": "dns",
+                    "roles": ["Limited"],
+                    "allow": [{ "cidr": "10.20.0.53/32", "protocol": "udp", "ports": ["53"] }],
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn identity<'a>(username: Option<&'a str>, groups: &'a [String]) -> Identity<'a> {
+        Identity { username, groups, role: None, routes: &[] }
+    }
+
+    fn packet(dst: [u8; 4], protocol: u8, port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        packet[16..20].copy_from_slice(&dst);
+        packet[22..24].copy_from_slice(&port.to_be_bytes());
+        packet
+    }
+
+    fn packet_v6(dst: &str, extensions: &[u8], protocol: u8, port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[6] = extensions.first().copied().unwrap_or(protocol);
+        packet[8..24].copy_from_slice(&"fd00:8::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&dst.parse::<Ipv6Addr>().unwrap().octets());
+        // Empty 8-byte extension headers, chained in order
+        for (i, _) in extensions.iter().enumerate() {
+            let next = extensions.get(i + 1).copied().unwrap_or(protocol);
+            packet.extend_from_slice(&[next, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        packet
+    }
+
+    #[test]
+    fn test_roles_and_routes() {
+        let engine = PolicyEngine::new(&settings()).unwrap();
+        let groups = vec!["contractors".to_string()];
+
+        let admin = engine.evaluate(&identity(Some("Root@example.com"), &groups));
+        assert_eq!(admin.role(), &UserRole::Admin);
+        assert!(admin.routes().is_none());
+
+        let contractor = engine.evaluate(&identity(Some("bob"), &groups));
+        assert_eq!(contractor.role(), &UserRole::Limited);
+        assert_eq!(contractor.rules(), ["contractors-web", "dns"]);
+        assert_eq!(contractor.routes().unwrap(), vec!["10.20.0.0/16".parse::<IpNet>().unwrap()]);
+
+        // No matching rule: default deny, unless allowed for regular users
+        let nobody = identity(Some("eve"), &[]);
+        assert!(engine.evaluate(&nobody).is_denied());
+        let mut open = settings();
+        open.default_action = PolicyAction::Allow;
+        let engine = PolicyEngine::new(&open).unwrap();
+        assert!(engine.evaluate(&nobody).routes().is_none());
+        assert!(!engine.evaluate(&identity(None, &groups)).allows(&packet([1, 1, 1, 1], 6, 443)));
+    }
+
+    #[test]
+    fn test_account_role_and_routes() {
+        let engine = PolicyEngine::new(&settings()).unwrap();
+        let contractors = vec!["contractors".to_string()];
+        let routes = vec![
+            Route::new("10.99.0.0/24".parse().unwrap()),
+            Route::new("fd00:99::/64".parse().unwrap()),
+        ];
+
+        // The account's role applies unless the policy assigns one
+        let admin = UserRole::Admin;
+        let account = Identity { username: Some("carol"), groups: &[], role: Some(&admin), routes: &routes };
+        let policy = engine.evaluate(&account);
+        assert_eq!(policy.role(), &UserRole::Admin);
+        assert!(policy.routes().is_none());
+        assert_eq!(
+            policy.account_routes(),
+            ["10.99.0.0/24".parse::<IpNet>().unwrap(), "fd00:99::/64".parse().unwrap()],
+        );
+        let assigned = Identity { groups: &contractors, ..account };
+        assert_eq!(engine.evaluate(&assigned).role(), &UserRole::Limited);
+
+        // Account routes are granted on top of the rules
+        let limited = UserRole::Limited;
+        let policy = engine.evaluate(&Identity { role: Some(&limited), ..account });
+        assert_eq!(policy.rules(), ["dns"]);
+        assert!(policy.allows(&packet([10, 99, 0, 7], 6, 22)));
+        assert!(!policy.allows(&packet([10, 98, 0, 7], 6, 22)));
+        assert_eq!(
+            policy.routes().unwrap(),
+            vec![
+                "10.20.0.53/32".parse::<IpNet>().unwrap(),
+                "10.99.0.0/24".parse().unwrap(),
+                "fd00:99::/64".parse().unwrap(),
+            ],
+        );
+        assert!(policy.allows(&packet_v6("fd00:99::7", &[], 6, 22)));
+
+        // Without any matching rule the account routes alone are granted
+        let nobody = Identity { username: Some("eve"), groups: &[], role: None, routes: &routes };
+        assert!(!engine.evaluate(&nobody).is_denied());
+    }
+
+    #[test]
+    fn test_packet_filter() {
+        let engine = PolicyEngine::new(&settings()).unwrap();
+        let groups = vec!["contractors".to_string()];
+        let policy = engine.evaluate(&identity(Some("bob"), &groups));
+
+        assert!(policy.allows(&packet([10, 20, 1, 1], 6, 443)));
+        assert!(policy.allows(&packet([10, 20, 1, 1], 6, 8080)));
+        assert!(policy.allows(&packet([10, 20, 0, 53], 17, 53)));
+        assert!(!policy.allows(&packet([10, 20, 1, 1], 6, 22)));
+        assert!(!policy.allows(&packet([10, 20, 1, 1], 17, 443)));
+        assert!(!policy.allows(&packet([10, 30, 1, 1], 6, 443)));
+        assert!(!policy.allows(&[0x45, 0, 0]));
+
+        // Truncated first fragment has no port to check
+        assert!(!policy.allows(&packet([10, 20, 1, 1], 6, 443)[..21]));
+        let mut later = packet([10, 20, 1, 1], 6, 0);
+        later[7] = 0x10;
+        assert!(policy.allows(&later));
+
+        let unrestricted = engine.evaluate(&identity(Some("root@example.com"), &[]));
+        assert!(unrestricted.allows(&packet([8, 8, 8, 8], 17, 53)));
+    }
+
+    #[test]
+    fn test_dual_stack_packet_filter() {
+        let mut settings = settings();
+        settings.rules[0].allow.push(serde_json::from_value(serde_json::json!(
+            { "cidr": "fd00:20::/48", "protocol": "tcp", "ports": ["443"] }
+        )).unwrap());
+        settings.rules[2].allow.push(serde_json::from_value(serde_json::json!(
+            { "cidr": "fd00:20::53/128", "protocol": "icmp" }
+        )).unwrap());
+        let engine = PolicyEngine::new(&settings).unwrap();
+        let groups = vec!["contractors".to_string()];
+        let policy = engine.evaluate(&identity(Some("bob"), &groups));
+
+        assert_eq!(
+            policy.routes().unwrap(),
+            vec!["10.20.0.0/16".parse::<IpNet>().unwrap(), "fd00:20::/48".parse().unwrap()],
+        );
+        assert!(policy.allows(&packet([10, 20, 1, 1], 6, 443)));
+        assert!(policy.allows(&packet_v6("fd00:20::1", &[], 6, 443)));
+        assert!(!policy.allows(&packet_v6("fd00:20::1", &[], 6, 22)));
+        assert!(!policy.allows(&packet_v6("fd00:30::1", &[], 6, 443)));
+        assert!(policy.allows(&packet_v6("fd00:20::53", &[], 58, 0)));
+        assert!(!policy.allows(&packet_v6("fd00:20::53", &[], 17, 53)));
+
+        // Ports are found behind extension headers
+        assert!(policy.allows(&packet_v6("fd00:20::1", &[0, 60], 6, 443)));
+        assert!(!policy.allows(&packet_v6("fd00:20::1", &[0, 60], 6, 22)));
+        let info = PacketInfo::parse(&packet_v6("fd00:20::1", &[44], 6, 443)).unwrap();
+        assert_eq!((info.protocol, info.port, info.fragment), (6, Some(443), false));
+
+        // Later fragments carry no ports; truncated headers are dropped
+        let mut later = packet_v6("fd00:20::1", &[44], 6, 0);
+        later[43] = 0x08;
+        let info = PacketInfo::parse(&later).unwrap();
+        assert_eq!((info.port, info.fragment), (None, true));
+        assert!(policy.allows(&later));
+        assert!(!policy.allows(&packet_v6("fd00:20::1", &[], 6, 443)[..39]));
+        assert!(!policy.allows(&packet_v6("fd00:20::1", &[60], 6, 443)[..44]));
+        assert!(PacketInfo::parse(&packet_v6("fd00:20::1", &[60; 9], 6, 443)).is_none());
+    }
+
+    #[test]
+    fn test_denied_packet_rate_limit() {
+        let mut denied = DeniedPackets::default();
+        assert_eq!(denied.record(), Some(1));
+        assert_eq!(denied.record(), None);
+        assert_eq!(denied.record(), None);
+
+        denied.last_logged = Some(Instant::now() - DENIED_AUDIT_INTERVAL);
+        assert_eq!(denied.record(), Some(3));
+    }
+}
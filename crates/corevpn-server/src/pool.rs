@@ -0,0 +1,108 @@
+//! Packet Buffers
+//!
+//! Every packet in flight gets its own buffer from a [`PacketPool`], so a
+//! packet waiting in a queue only holds on to its own memory. Buffers are
+//! handed out round-robin from a ring of slots, and a slot is reused in
+//! place once the packet last taken from it has been dropped. Slots whose
+//! packets are still in flight are skipped.
+
+use bytes::{BufMut, BytesMut};
+use corevpn_crypto::PACKET_HEADROOM;
+
+/// Busy slots skipped before a packet gets a new buffer
+const MAX_PROBES: usize = 8;
+
+/// Ring of reusable per-packet buffers
+pub struct PacketPool {
+    slots: Box<[BytesMut]>,
+    next: usize,
+    packet_size: usize,
+}
+
+impl PacketPool {
+    /// Create a pool of `slots` buffers holding `packet_size` bytes each
+    ///
+    /// Size the ring to cover every queue a packet may wait in: when every
+    /// slot tried is still in flight, the last one gets a new buffer.
+    pub fn new(slots: usize, packet_size: usize) -> Self {
+        Self {
+            slots: (0..slots.max(1)).map(|_| BytesMut::with_capacity(packet_size)).collect(),
+            next: 0,
+            packet_size,
+        }
+    }
+
+    /// Take an empty buffer for one packet
+    pub fn take(&mut self) -> BytesMut {
+        let mut index = self.next;
+        for _ in 0..self.slots.len().min(MAX_PROBES) {
+            index = self.next;
+            self.next = (index + 1) % self.slots.len();
+            if self.slots[index].try_reclaim(self.packet_size) {
+                return self.slots[index].split_off(0);
+            }
+        }
+
+        let slot = &mut self.slots[index];
+        slot.reserve(self.packet_size);
+        slot.split_off(0)
+    }
+
+    /// Take a buffer to read a plaintext packet into
+    ///
+    /// The buffer starts with [`PACKET_HEADROOM`] reserved bytes for the
+    /// packet to be appended after, so it can be encrypted in place.
+    pub fn plaintext(&mut self) -> BytesMut {
+        let mut buf = self.take();
+        buf.put_bytes(0, PACKET_HEADROOM);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_pool_reuse() {
+        let mut pool = PacketPool::new(2, 1500);
+
+        let first = pool.take();
+        let second = pool.take();
+        assert_eq!(first.capacity(), 1500);
+        assert_ne!(first.as_ptr(), second.as_ptr());
+
+        // A slot is reused once its packet has been dropped
+        let first_ptr = first.as_ptr();
+        let mut packet = first;
+        packet.extend_from_slice(&[1u8; 1400]);
+        drop(packet.freeze());
+        let third = pool.take();
+        assert_eq!(third.as_ptr(), first_ptr);
+
+        // With every slot in flight the packet gets a buffer of its own
+        let second_ptr = second.as_ptr();
+        let mut held = second;
+        held.extend_from_slice(&[2u8; 1400]);
+        let held = held.freeze();
+        let fourth = pool.take();
+        assert!(fourth.as_ptr() != first_ptr && fourth.as_ptr() != second_ptr);
+        assert_eq!(fourth.capacity(), 1500);
+        drop(third);
+
+        // Slots still in flight are skipped, and their packets stay intact
+        let fourth_ptr = fourth.as_ptr();
+        drop(fourth);
+        assert_eq!(pool.take().as_ptr(), fourth_ptr);
+        assert!(held.iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_plaintext_headroom() {
+        let mut pool = PacketPool::new(1, 1500);
+        let buf = pool.plaintext();
+        assert_eq!(buf.len(), PACKET_HEADROOM);
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(buf.capacity(), 1500);
+    }
+}
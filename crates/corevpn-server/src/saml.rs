@@ -0,0 +1,347 @@
+//! SAML VPN Logins
+//!
+//! Binds VPN connections to a SAML login with the configured IdP. Clients
+//! that advertise `IV_SSO` are held with AUTH_PENDING and sent the login URL;
+//! other clients get a CRV1 dynamic challenge naming it and answer it on
+//! reconnect once the login is done.
+//!
+//! The login URL points at the service provider endpoints this server
+//! answers (see [`crate::webui::saml`]): they send the browser on to the IdP
+//! with an AuthnRequest and take the signed response back at the assertion
+//! consumer service. SAML has no tokens to refresh, so a login is not
+//! revalidated once its tunnel is up.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use base64::Engine;
+use parking_lot::RwLock;
+use tracing::{debug, info};
+
+use corevpn_auth::saml::SamlAttributeMap;
+use corevpn_auth::{AuthError, SamlConfig, SamlServiceProvider, UserInfo};
+use corevpn_config::server::SamlSettings;
+
+use crate::oauth::{LoginFailure, LoginStatus};
+
+/// A login started for a VPN connection
+struct PendingLogin {
+    /// Username the client sent, which a CRV1 response must repeat
+    username: String,
+    /// ID of the latest AuthnRequest sent to the IdP for this login
+    request_id: Option<String>,
+    /// Outcome so far
+    status: LoginStatus,
+    /// Whether a connection has taken the completed login
+    used: bool,
+    /// When the login can no longer be completed or taken
+    expires_at: Instant,
+}
+
+impl PendingLogin {
+    fn is_open(&self) -> bool {
+        !self.used && self.expires_at > Instant::now()
+    }
+}
+
+/// SAML logins for VPN connections
+pub struct SamlLogin {
+    /// Service provider validating the IdP's responses
+    sp: SamlServiceProvider,
+    /// Public URL of the service provider endpoints
+    base_url: String,
+    /// Address the service provider endpoints listen on
+    listen_addr: SocketAddr,
+    /// Logins by state ID
+    logins: RwLock<HashMap<String, PendingLogin>>,
+    /// How long a user has to log in, and then to take the login
+    login_timeout: Duration,
+}
+
+impl SamlLogin {
+    /// Set up the service provider from the server's SAML settings
+    pub fn new(settings: &SamlSettings) -> Result<Self> {
+        let base_url = format!("{}/saml", settings.public_url.trim_end_matches('/'));
+
+        let mut config = SamlConfig::new(
+            settings.entity_id.clone().unwrap_or_else(|| format!("{}/metadata", base_url)),
+            format!("{}/acs", base_url),
+            &settings.idp_entity_id,
+            &settings.idp_sso_url,
+            settings.idp_certificates.clone(),
+        );
+        config.attributes = attribute_map(settings);
+        config.allowed_domains = settings.allowed_domains.clone();
+        config.required_groups = settings.required_groups.clone();
+
+        Ok(Self {
+            sp: SamlServiceProvider::new(config)?,
+            base_url,
+            listen_addr: settings.listen_addr,
+            logins: RwLock::new(HashMap::new()),
+            login_timeout: Duration::from_secs(settings.login_timeout_secs.max(1)),
+        })
+    }
+
+    /// Address the service provider endpoints listen on
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// How long a user has to finish logging in
+    pub fn login_timeout(&self) -> Duration {
+        self.login_timeout
+    }
+
+    /// SP metadata for registering the server with the IdP
+    pub fn metadata(&self) -> String {
+        self.sp.metadata()
+    }
+
+    /// URL of the SP metadata
+    pub fn metadata_url(&self) -> String {
+        format!("{}/metadata", self.base_url)
+    }
+
+    /// Start a login, returning its state ID and the URL the user logs in at
+    pub fn start(&self, username: &str) -> (String, String) {
+        let state_id = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(corevpn_crypto::random_bytes::<24>());
+        self.logins.write().insert(state_id.clone(), PendingLogin {
+            username: username.to_string(),
+            request_id: None,
+            status: LoginStatus::Pending,
+            used: false,
+            expires_at: Instant::now() + self.login_timeout,
+        });
+
+        let url = format!("{}/login?state={}", self.base_url, state_id);
+        (state_id, url)
+    }
+
+    /// URL sending the user's browser to the IdP with an AuthnRequest for a pending login
+    ///
+    /// Only the response to the latest request completes the login.
+    pub fn authn_redirect(&self, state_id: &str) -> Option<String> {
+        let mut logins = self.logins.write();
+        let login = logins.get_mut(state_id)
+            .filter(|login| login.is_open() && matches!(login.status, LoginStatus::Pending))?;
+
+        let request = self.sp.authn_request();
+        login.request_id = Some(request.id.clone());
+        Some(request.redirect_url(Some(state_id)))
+    }
+
+    /// Validate a response posted to the assertion consumer service and complete
+    /// the login named by its relay state
+    pub fn complete(&self, saml_response: &str, relay_state: Option<&str>) -> Result<UserInfo, LoginFailure> {
+        let state_id = relay_state.ok_or_else(|| LoginFailure::from(&AuthError::InvalidState))?;
+        let request_id = self.logins.read()
+            .get(state_id)
+            .filter(|login| login.is_open() && matches!(login.status, LoginStatus::Pending))
+            .and_then(|login| login.request_id.clone())
+            .ok_or_else(|| LoginFailure::from(&AuthError::SessionNotFound))?;
+
+        let result = self.sp.validate_response(saml_response, Some(&request_id))
+            .and_then(|assertion| {
+                self.sp.authorize_user(&assertion.user_info)?;
+                Ok(assertion.user_info)
+            });
+        self.finish(state_id, &request_id, result)
+    }
+
+    /// Record the outcome of a login's response, if it answered the latest request
+    fn finish(
+        &self,
+        state_id: &str,
+        request_id: &str,
+        result: corevpn_auth::Result<UserInfo>,
+    ) -> Result<UserInfo, LoginFailure> {
+        let mut logins = self.logins.write();
+        let login = logins.get_mut(state_id)
+            .filter(|login| login.request_id.as_deref() == Some(request_id))
+            .ok_or_else(|| LoginFailure::from(&AuthError::InvalidState))?;
+
+        match result {
+            Ok(user) => {
+                info!("SAML login completed for {}", user.email.as_deref().unwrap_or(&user.sub));
+                // CRV1 clients still have to reconnect to take the login
+                login.expires_at = Instant::now() + self.login_timeout;
+                login.status = LoginStatus::Complete(user.clone());
+                Ok(user)
+            }
+            Err(e) => {
+                debug!("SAML login {} failed: {}", state_id, e);
+                let failure = LoginFailure::from(&e);
+                login.status = LoginStatus::Failed(failure.clone());
+                Err(failure)
+            }
+        }
+    }
+
+    /// Check if a CRV1 response refers to an unused login started for this username
+    pub fn resume(&self, state_id: &str, username: &str) -> bool {
+        self.logins.read()
+            .get(state_id)
+            .is_some_and(|login| login.is_open() && login.username == username)
+    }
+
+    /// Get the current status of a login
+    pub fn status(&self, state_id: &str) -> LoginStatus {
+        match self.logins.read().get(state_id) {
+            Some(login) if login.used => LoginStatus::Failed(LoginFailure::from(&AuthError::InvalidState)),
+            Some(login) if !login.is_open() => {
+                LoginStatus::Failed(LoginFailure::from(&AuthError::SessionExpired))
+            }
+            Some(login) => login.status.clone(),
+            None => LoginStatus::Failed(LoginFailure::from(&AuthError::SessionNotFound)),
+        }
+    }
+
+    /// Mark a completed login as taken, so no other connection can use it
+    pub fn associate(&self, state_id: &str) {
+        if let Some(login) = self.logins.write().get_mut(state_id) {
+            login.used = true;
+        }
+    }
+
+    /// Drop expired logins
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        let mut logins = self.logins.write();
+        let before = logins.len();
+        logins.retain(|_, login| login.expires_at > now);
+        if logins.len() < before {
+            debug!("Removed {} expired SAML logins", before - logins.len());
+        }
+    }
+}
+
+/// Standard attribute names, after the configured ones
+fn attribute_map(settings: &SamlSettings) -> SamlAttributeMap {
+    let mut attributes = SamlAttributeMap::default();
+    if let Some(ref name) = settings.email_attribute {
+        attributes.email.insert(0, name.clone());
+    }
+    if let Some(ref name) = settings.groups_attribute {
+        attributes.groups.insert(0, name.clone());
+    }
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_log::AuthResult;
+
+    /// Certificate of the ADFS-shaped response fixture in corevpn-auth
+    const IDP_CERTIFICATE: &str = include_str!("../../corevpn-auth/src/saml/testdata/adfs_signing.crt");
+
+    fn settings() -> SamlSettings {
+        SamlSettings {
+            enabled: true,
+            listen_addr: "127.0.0.1:8081".parse().unwrap(),
+            public_url: "https://vpn.example.com/".to_string(),
+            entity_id: None,
+            idp_entity_id: "http://adfs.example.com/adfs/services/trust".to_string(),
+            idp_sso_url: "https://adfs.example.com/adfs/ls/".to_string(),
+            idp_certificates: vec![IDP_CERTIFICATE.to_string()],
+            email_attribute: None,
+            groups_attribute: Some("roles".to_string()),
+            allowed_domains: vec!["example.com".to_string()],
+            required_groups: vec![],
+            login_timeout_secs: 300,
+        }
+    }
+
+    fn user(email: &str) -> UserInfo {
+        UserInfo {
+            sub: email.to_string(),
+            email: Some(email.to_string()),
+            email_verified: true,
+            name: None,
+            given_name: None,
+            family_name: None,
+            picture: None,
+            groups: vec![],
+            provider: "saml".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_service_provider_urls() {
+        let saml = SamlLogin::new(&settings()).unwrap();
+        assert_eq!(saml.metadata_url(), "https://vpn.example.com/saml/metadata");
+        assert!(saml.metadata().contains(r#"entityID="https://vpn.example.com/saml/metadata""#));
+        assert!(saml.metadata().contains(r#"Location="https://vpn.example.com/saml/acs""#));
+        assert_eq!(attribute_map(&settings()).groups[0], "roles");
+
+        let (state_id, url) = saml.start("alice");
+        assert_eq!(url, format!("https://vpn.example.com/saml/login?state={}", state_id));
+
+        let redirect = saml.authn_redirect(&state_id).unwrap();
+        assert!(redirect.starts_with("https://adfs.example.com/adfs/ls/?SAMLRequest="));
+        assert!(redirect.ends_with(&format!("&RelayState={}", state_id)));
+        assert!(saml.authn_redirect("unknown").is_none());
+    }
+
+    #[test]
+    fn test_login_lifecycle() {
+        let saml = SamlLogin::new(&settings()).unwrap();
+        let (state_id, _) = saml.start("alice");
+        assert!(matches!(saml.status(&state_id), LoginStatus::Pending));
+        assert!(saml.resume(&state_id, "alice"));
+        assert!(!saml.resume(&state_id, "mallory"));
+
+        // Nothing is accepted before the user was sent to the IdP, or without the relay state
+        assert!(saml.complete("PHNhbWxwOlJlc3BvbnNlLz4=", Some(&state_id)).is_err());
+        assert!(matches!(saml.status(&state_id), LoginStatus::Pending));
+        saml.authn_redirect(&state_id).unwrap();
+        assert!(saml.complete("PHNhbWxwOlJlc3BvbnNlLz4=", None).is_err());
+
+        // Only the latest request's response counts
+        let request_id = saml.logins.read()[&state_id].request_id.clone().unwrap();
+        assert!(saml.finish(&state_id, "_other", Ok(user("alice@example.com"))).is_err());
+        saml.finish(&state_id, &request_id, Ok(user("alice@example.com"))).unwrap();
+        match saml.status(&state_id) {
+            LoginStatus::Complete(user) => assert_eq!(user.email.as_deref(), Some("alice@example.com")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // A login can only be used by one connection
+        saml.associate(&state_id);
+        assert!(!saml.resume(&state_id, "alice"));
+        assert!(matches!(saml.status(&state_id), LoginStatus::Failed(_)));
+        assert!(matches!(saml.status("unknown"), LoginStatus::Failed(_)));
+    }
+
+    #[test]
+    fn test_invalid_response_fails_login() {
+        let saml = SamlLogin::new(&settings()).unwrap();
+        let (state_id, _) = saml.start("alice");
+        saml.authn_redirect(&state_id).unwrap();
+
+        // The fixture answers another request
+        let response = base64::engine::general_purpose::STANDARD
+            .encode(include_str!("../../corevpn-auth/src/saml/testdata/adfs_response.xml"));
+        let failure = saml.complete(&response, Some(&state_id)).unwrap_err();
+        assert_eq!(failure.result, AuthResult::InvalidCredentials);
+        match saml.status(&state_id) {
+            LoginStatus::Failed(failure) => assert_eq!(failure.result, AuthResult::InvalidCredentials),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Logins run out when the user does not finish them
+        let mut short = settings();
+        short.login_timeout_secs = 1;
+        let saml = SamlLogin::new(&short).unwrap();
+        let (state_id, _) = saml.start("alice");
+        saml.logins.write().get_mut(&state_id).unwrap().expires_at = Instant::now();
+        assert!(saml.authn_redirect(&state_id).is_none());
+        assert!(matches!(saml.status(&state_id), LoginStatus::Failed(_)));
+        saml.cleanup();
+        assert!(saml.logins.read().is_empty());
+    }
+}
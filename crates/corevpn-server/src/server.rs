@@ -1,30 +1,81 @@
 //! VPN Server Implementation
 //!
-//! Handles OpenVPN-compatible connections with TLS and OAuth2 authentication.
+//! Handles OpenVPN-compatible connections with TLS, OAuth2 and SAML authentication.
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-
-use anyhow::Result;
-use bytes::Bytes;
-use parking_lot::RwLock;
-use tokio::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime};
+use std::num::NonZeroUsize;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use ipnet::{IpNet, Ipv6Net};
+use parking_lot::{Mutex, RwLock};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use futures::StreamExt;
+use tokio::sync::mpsc;
 use tracing::{info, warn, error, debug, trace};
 
-use corevpn_config::{ConnectionLogMode, ServerConfig};
-use corevpn_core::{SessionManager, AddressPool};
-use corevpn_crypto::CipherSuite;
+use corevpn_auth::flow::{generate_crv1_challenge, generate_vpn_auth_challenge, parse_vpn_auth_response};
+use corevpn_auth::{AuthError, Login, TotpKey, TotpVerifier, UserInfo};
+use corevpn_config::{ConnectionLogMode, SecuritySettings, ServerConfig, TotpMode};
+use corevpn_core::{SessionManager, AddressPool, LeaseManager, SqliteUserStore, User, UserId, UserStore, VpnAddress};
+use corevpn_crypto::{
+    CertificateIdentity, CipherSuite, ClientKeyMetadata, KeyMaterial, KeyStatus, TlsCryptV2ServerKey,
+    PACKET_HEADROOM, PACKET_TAILROOM,
+};
+use corevpn_crypto::kdf::{EKM_LABEL, KEY_BLOCK_SIZE};
 use corevpn_protocol::{
-    OpCode, ProtocolSession, ProtocolState, ProcessedPacket,
-    TlsHandler, create_server_config, load_certs_from_pem, load_key_from_pem,
+    DataPacket, OpCode, ProtocolError, ProtocolSession, ProtocolState, ProcessedPacket, KeyMethodV2,
+    ControlMessage, TlsHandler, TlsWrapKey, RevocableClientVerifier, create_server_config,
+    load_certs_from_pem, load_crls_from_pem, load_key_from_pem,
 };
+use corevpn_protocol::control::{AuthMessage, PushReply, PushRoute, IV_PROTO_DATA_V2, IV_PROTO_TLS_KEY_EXPORT};
 
 use crate::connection_log::{
     ConnectionLogger, ConnectionEvent, ConnectionEventBuilder, ConnectionId,
-    AuthMethod, DisconnectReason, TransferStats, Anonymizer, create_logger,
+    AuthMethod, AuthResult, DisconnectReason, TransferStats, Anonymizer, create_logger,
 };
+use crate::audit::{AuditConfig, AuditEventBuilder, AuditLogger};
+use crate::datapath::{DataPath, PeerIdPool, PeerMap, TrafficCounters, PEER_SHARDS};
+use crate::oauth::{LoginFailure, LoginStatus, OAuthLogin, SessionCheck};
+use crate::policy::{AccessPolicy, DeniedPackets, Identity, PacketInfo, PolicyEngine};
+use crate::saml::SamlLogin;
+use crate::transport::{self, Transport, TCP_QUEUE_DEPTH};
+use crate::tunnel::{self, TunReader, TunWriter};
+use crate::pool::PacketPool;
+use crate::udp::{BatchSocket, RecvBatch, SendBatch, BATCH_SIZE};
+
+/// Depth of the queue feeding the TUN writer task
+pub const TUN_QUEUE_DEPTH: usize = 1024;
+
+/// Depth of each egress worker's queue of packets from the TUN device
+const EGRESS_QUEUE_DEPTH: usize = 1024;
+
+/// Packet buffers per UDP worker, enough for every packet queued at once
+const PACKET_POOL_SLOTS: usize = TUN_QUEUE_DEPTH + 2 * BATCH_SIZE;
+
+/// Depth of the queue feeding the control task from the UDP workers
+const CONTROL_QUEUE_DEPTH: usize = 1024;
+
+/// How often the CRL file is checked for changes
+const CRL_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often key lifetimes and renegotiations in progress are checked
+const RENEGOTIATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// First pause after a TUN read error, doubled while the errors persist
+const TUN_RETRY_MIN: Duration = Duration::from_millis(10);
+
+/// Longest pause between TUN reads that keep failing
+const TUN_RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// OAuth2 logins revalidated with the provider at once
+const MAX_CONCURRENT_REVALIDATIONS: usize = 16;
 
 /// Active connection state
 struct Connection {
@@ -38,31 +89,106 @@ struct Connection {
     connected_at: Instant,
     /// Peer address
     peer_addr: SocketAddr,
-    /// Assigned VPN IP (if authenticated)
-    vpn_ip: Option<std::net::Ipv4Addr>,
+    /// Peer ID assigned for P_DATA_V2
+    peer_id: u32,
+    /// Outbound path to the peer
+    transport: Transport,
+    /// Assigned VPN addresses (if authenticated)
+    vpn_address: Option<VpnAddress>,
     /// Connection ID for logging
     connection_id: ConnectionId,
     /// Username (if authenticated)
     username: Option<String>,
+    /// Groups from the identity provider
+    groups: Vec<String>,
+    /// Access granted by the policy engine (if enabled)
+    access: Option<AccessPolicy>,
+    /// Identity of the verified client certificate
+    certificate: Option<CertificateIdentity>,
     /// Authentication method used
     auth_method: AuthMethod,
-    /// Transfer statistics
-    stats: TransferStats,
+    /// Local account whose password was verified
+    password_user: Option<User>,
+    /// State ID of the OAuth2 login this connection is waiting on
+    oauth_state: Option<String>,
+    /// State ID of the SAML login this connection is waiting on
+    saml_state: Option<String>,
+    /// Token pushed to a client without a certificate, required to renegotiate
+    auth_token: Option<String>,
+    /// Username the client sent with its credentials
+    login: Option<String>,
+    /// Whether the client was refused, ending the connection once that is sent
+    refused: bool,
+    /// Transfer statistics, updated by the data path
+    counters: Arc<TrafficCounters>,
+    /// Buffered control channel plaintext
+    control_buf: Vec<u8>,
+    /// How the data channel keys were derived
+    key_derivation: KeyDerivation,
+    /// When the current data channel keys were installed
+    key_installed_at: Instant,
+    /// Transfer totals when the current data channel keys were installed
+    key_traffic: TransferStats,
+}
+
+/// Login an auth token lets a client resume on a new session
+struct AuthTokenGrant {
+    /// Username the client sends the token with
+    login: String,
+    username: Option<String>,
+    groups: Vec<String>,
+    auth_method: AuthMethod,
+    password_user: Option<User>,
+    oauth_state: Option<String>,
+    expires_at: Instant,
+}
+
+/// Data channel key derivation method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyDerivation {
+    /// OpenVPN PRF over the key method v2 randoms
+    Prf,
+    /// TLS keying material exporter
+    TlsEkm,
 }
 
 impl Connection {
-    fn new(peer_addr: SocketAddr, cipher_suite: CipherSuite, connection_id: ConnectionId) -> Self {
+    fn new(
+        peer_addr: SocketAddr,
+        peer_id: u32,
+        transport: Transport,
+        cipher_suite: CipherSuite,
+        connection_id: ConnectionId,
+    ) -> Self {
+        let mut protocol = ProtocolSession::new_server(cipher_suite);
+        protocol.set_stream_transport(transport.is_stream());
+
         Self {
-            protocol: ProtocolSession::new_server(cipher_suite),
+            protocol,
             tls: None,
             last_activity: Instant::now(),
             connected_at: Instant::now(),
             peer_addr,
-            vpn_ip: None,
+            peer_id,
+            transport,
+            vpn_address: None,
             connection_id,
             username: None,
+            groups: Vec::new(),
+            access: None,
+            certificate: None,
             auth_method: AuthMethod::Unknown,
-            stats: TransferStats::default(),
+            password_user: None,
+            oauth_state: None,
+            saml_state: None,
+            auth_token: None,
+            login: None,
+            refused: false,
+            counters: Arc::new(TrafficCounters::new()),
+            control_buf: Vec::new(),
+            key_derivation: KeyDerivation::Prf,
+            key_installed_at: Instant::now(),
+            key_traffic: TransferStats::default(),
         }
     }
 
@@ -71,34 +197,94 @@ impl Connection {
     }
 
     fn is_stale(&self, timeout: Duration) -> bool {
-        self.last_activity.elapsed() > timeout
+        self.last_activity.elapsed().min(self.counters.idle_time()) > timeout
+    }
+
+    /// Whether a client certificate, password, OAuth2 or SAML login vouches for the client
+    fn is_authenticated(&self) -> bool {
+        self.certificate.is_some()
+            || self.password_user.is_some()
+            || matches!(self.auth_method, AuthMethod::OAuth2 | AuthMethod::Saml)
     }
 
     fn duration(&self) -> Duration {
         self.connected_at.elapsed()
     }
 
-    fn add_bytes_rx(&mut self, bytes: u64) {
-        self.stats.bytes_rx += bytes;
-        self.stats.packets_rx += 1;
+    /// Start the renegotiation limits over for newly installed keys
+    fn keys_installed(&mut self) {
+        self.key_installed_at = Instant::now();
+        self.key_traffic = self.counters.snapshot();
     }
 
-    fn add_bytes_tx(&mut self, bytes: u64) {
-        self.stats.bytes_tx += bytes;
-        self.stats.packets_tx += 1;
+    /// Check if the current keys reached the reneg-sec, reneg-bytes or reneg-pkts
+    /// limit, or are close to their AEAD usage limits
+    fn renegotiation_due(&self, security: &SecuritySettings) -> bool {
+        if self.vpn_address.is_none()
+            || self.protocol.state() != ProtocolState::Established
+            || self.protocol.is_renegotiating()
+        {
+            return false;
+        }
+
+        let stats = self.counters.snapshot();
+        let bytes = (stats.bytes_rx + stats.bytes_tx) - (self.key_traffic.bytes_rx + self.key_traffic.bytes_tx);
+        let packets = (stats.packets_rx + stats.packets_tx) - (self.key_traffic.packets_rx + self.key_traffic.packets_tx);
+        let reached = |limit: u64, used: u64| limit > 0 && used >= limit;
+
+        reached(security.reneg_sec.into(), self.key_installed_at.elapsed().as_secs())
+            || reached(security.reneg_bytes, bytes)
+            || reached(security.reneg_pkts, packets)
+            || self.protocol.key_status() != KeyStatus::Usable
     }
 }
 
-/// Connection map type
-type ConnectionMap = Arc<RwLock<HashMap<SocketAddr, Connection>>>;
+/// VPN IP to data path index, used to route packets read from the TUN device
+type VpnRouteMap = RwLock<HashMap<IpAddr, Arc<DataPath>>>;
 
 /// Server state
 pub struct VpnServer {
     config: ServerConfig,
     session_manager: SessionManager,
-    address_pool: AddressPool,
-    connections: ConnectionMap,
+    /// VPN address leases
+    leases: LeaseManager,
+    /// Local accounts by lowercase username or email
+    accounts: RwLock<HashMap<String, Arc<User>>>,
+    /// Logins a new session can resume with a pushed auth token, by token
+    auth_tokens: RwLock<HashMap<String, AuthTokenGrant>>,
+    /// Connections (control channel state) by peer address
+    connections: PeerMap<Connection>,
+    /// Data paths of established connections by peer address
+    data_paths: PeerMap<Arc<DataPath>>,
+    /// Data paths of established connections by peer ID
+    peers: PeerMap<Arc<DataPath>, u32>,
+    /// Peer IDs of live connections
+    peer_ids: PeerIdPool,
+    /// Data path lookup by assigned VPN IP
+    vpn_routes: VpnRouteMap,
     tls_config: Option<Arc<rustls::ServerConfig>>,
+    /// Client certificate verifier (if a CA is configured)
+    client_verifier: Option<Arc<RevocableClientVerifier>>,
+    /// tls-auth / tls-crypt key for the control channel (if enabled)
+    tls_wrap: Option<TlsWrapKey>,
+    /// tls-crypt-v2 server key for unwrapping per-client keys (if enabled)
+    tls_crypt_v2: Option<Arc<TlsCryptV2ServerKey>>,
+    /// Serials listed in the current CRL
+    revoked_serials: RwLock<HashSet<String>>,
+    /// OAuth2 logins required before a client gets its PUSH_REPLY (if enabled)
+    oauth: Option<Arc<OAuthLogin>>,
+    /// SAML logins required before a client gets its PUSH_REPLY (if enabled)
+    saml: Option<Arc<SamlLogin>>,
+    /// Local user accounts
+    users: Arc<dyn UserStore>,
+    /// TOTP second factor for local password logins (if enabled)
+    totp: Option<Arc<TotpVerifier>>,
+    /// Group-based access policy (if enabled)
+    policy: Option<PolicyEngine>,
+    /// Audit logger for policy decisions
+    audit: AuditLogger,
+    /// Queue of decrypted packets for the TUN device
+    tun_tx: Option<mpsc::Sender<Bytes>>,
     /// Connection logger
     connection_logger: Arc<dyn ConnectionLogger>,
     /// Event anonymizer (if configured)
@@ -115,11 +301,69 @@ impl VpnServer {
 
         let subnet = config.network.subnet.parse()
             .map_err(|e| anyhow::anyhow!("Invalid subnet: {}", e))?;
-
-        let address_pool = AddressPool::new(Some(subnet), None);
+        let subnet_v6 = config.network.subnet_v6
+            .as_deref()
+            .map(str::parse::<Ipv6Net>)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid IPv6 subnet: {}", e))?;
+
+        let leases = LeaseManager::load(
+            &config.leases_path(),
+            AddressPool::new(Some(subnet), subnet_v6),
+            chrono::Duration::hours(i64::from(config.network.lease_hours)),
+        )?;
 
         // Load TLS configuration
-        let tls_config = Self::load_tls_config(&config)?;
+        let client_verifier = Self::load_client_verifier(&config)?;
+        let tls_config = Self::load_tls_config(&config, client_verifier.clone())?;
+        let tls_wrap = Self::load_tls_wrap(&config)?;
+        let tls_crypt_v2 = Self::load_tls_crypt_v2(&config)?;
+        let revoked_serials = Self::load_revoked_serials(&config)?;
+
+        let oauth = match config.oauth.as_ref().filter(|oauth| oauth.enabled) {
+            Some(settings) => {
+                info!("OAuth2 login required ({})", settings.provider);
+                Some(Arc::new(OAuthLogin::new(settings).await?))
+            }
+            None => None,
+        };
+
+        let saml = match config.saml.as_ref().filter(|saml| saml.enabled) {
+            Some(settings) => {
+                info!("SAML login required ({})", settings.idp_entity_id);
+                Some(Arc::new(SamlLogin::new(settings)?))
+            }
+            None => None,
+        };
+
+        // Local accounts hold passwords, TOTP secrets and static addresses
+        let users: Arc<dyn UserStore> = Arc::new(SqliteUserStore::open_in(config.data_dir()).await?);
+        if config.security.password_auth {
+            info!("Local password authentication required");
+        }
+
+        let totp = if config.security.password_auth && config.security.totp != TotpMode::Disabled {
+            info!("TOTP second factor: {:?}", config.security.totp);
+            let key = TotpKey::load_or_generate(&config.totp_key_path())?;
+            Some(Arc::new(TotpVerifier::new(key).with_required(config.security.totp == TotpMode::Required)))
+        } else {
+            None
+        };
+
+        let policy = if config.policy.enabled {
+            info!("Access policy enforced ({} rules)", config.policy.rules.len());
+            Some(PolicyEngine::new(&config.policy)?)
+        } else {
+            None
+        };
+
+        let audit = if config.audit.enabled {
+            let audit_config = AuditConfig::from_settings(&config.audit)?;
+            info!("Audit logging: {} sinks", audit_config.sinks.len());
+            AuditLogger::new(audit_config).await?
+        } else {
+            AuditLogger::null()
+        };
 
         // Initialize connection logger
         let connection_logger = create_logger(&config.logging).await?;
@@ -158,15 +402,119 @@ impl VpnServer {
             None
         };
 
-        Ok(Self {
+        let server = Self {
             config,
             session_manager,
-            address_pool,
-            connections: Arc::new(RwLock::new(HashMap::new())),
+            leases,
+            accounts: RwLock::new(HashMap::new()),
+            auth_tokens: RwLock::new(HashMap::new()),
+            connections: PeerMap::new(PEER_SHARDS),
+            data_paths: PeerMap::new(PEER_SHARDS),
+            peers: PeerMap::new(PEER_SHARDS),
+            peer_ids: PeerIdPool::new(),
+            vpn_routes: RwLock::new(HashMap::new()),
             tls_config,
+            client_verifier,
+            tls_wrap,
+            tls_crypt_v2,
+            revoked_serials: RwLock::new(revoked_serials),
+            oauth,
+            saml,
+            users,
+            totp,
+            policy,
+            audit,
+            tun_tx: None,
             connection_logger,
             anonymizer,
-        })
+        };
+
+        // Static addresses must be reserved before the first client connects
+        server.sync_accounts().await;
+        Ok(server)
+    }
+
+    /// Attach the TUN writer queue
+    pub fn with_tunnel(mut self, tun_tx: mpsc::Sender<Bytes>) -> Self {
+        self.tun_tx = Some(tun_tx);
+        self
+    }
+
+    /// Make a data path visible to the UDP workers and the TUN reader
+    ///
+    /// Replaces any path already published for the same peer address,
+    /// peer ID or VPN addresses.
+    pub fn install_data_path(&self, path: DataPath) {
+        let path = Arc::new(path);
+        {
+            let mut routes = self.vpn_routes.write();
+            for ip in path.vpn_address.addresses() {
+                routes.insert(ip, path.clone());
+            }
+        }
+        self.peers.insert(path.peer_id, path.clone());
+        self.data_paths.insert(path.peer_addr, path);
+    }
+
+    /// Local account of a username or email
+    fn account(&self, username: Option<&str>) -> Option<Arc<User>> {
+        self.accounts.read().get(&username?.to_lowercase()).cloned()
+    }
+
+    /// Lease key for a username: the account ID of a local user, otherwise the name itself
+    fn lease_key(&self, username: Option<&str>) -> Option<String> {
+        let username = username?;
+        Some(self.account(Some(username)).map_or_else(|| username.to_string(), |user| user.id.to_string()))
+    }
+
+    /// Reload local accounts and static address reservations from the user store
+    async fn sync_accounts(&self) {
+        let users = self.users.list_users().await;
+
+        // Auth tokens stop resuming logins of accounts that were disabled or deleted
+        let enabled: HashSet<&UserId> = users.iter().filter(|user| user.enabled).map(|user| &user.id).collect();
+        self.auth_tokens.write().retain(|_, grant| {
+            grant.password_user.as_ref().is_none_or(|user| enabled.contains(&user.id))
+        });
+
+        let mut accounts = HashMap::new();
+        let mut reservations = HashMap::new();
+        for user in users {
+            let user = Arc::new(user);
+            if let Some(ref email) = user.email {
+                accounts.insert(email.to_lowercase(), user.clone());
+            }
+            accounts.insert(user.id.as_str().to_lowercase(), user.clone());
+            if let Some(address) = user.static_ip {
+                reservations.insert(user.id.to_string(), address);
+            }
+        }
+
+        *self.accounts.write() = accounts;
+        self.leases.set_reservations(&reservations);
+    }
+
+    /// Let a new session resume a connection's login with the auth token pushed to it
+    fn grant_auth_token(&self, conn: &Connection, token: &str) {
+        let lifetime = self.config.security.auth_token_lifetime;
+        let Some(login) = conn.login.clone().filter(|_| lifetime > 0) else {
+            return;
+        };
+        self.auth_tokens.write().insert(token.to_string(), AuthTokenGrant {
+            login,
+            username: conn.username.clone(),
+            groups: conn.groups.clone(),
+            auth_method: conn.auth_method.clone(),
+            password_user: conn.password_user.clone(),
+            oauth_state: conn.oauth_state.clone(),
+            expires_at: Instant::now() + Duration::from_secs(lifetime.into()),
+        });
+    }
+
+    /// Drop auth tokens that can no longer resume a login
+    fn expire_auth_tokens(&self) {
+        let now = Instant::now();
+        self.auth_tokens.write().retain(|_, grant| grant.expires_at > now);
     }
 
     /// Log a connection event, applying anonymization if configured
@@ -182,7 +530,169 @@ impl VpnServer {
         }
     }
 
-    fn load_tls_config(config: &ServerConfig) -> Result<Option<Arc<rustls::ServerConfig>>> {
+    fn load_client_verifier(config: &ServerConfig) -> Result<Option<Arc<RevocableClientVerifier>>> {
+        let ca_path = config.ca_cert_path();
+        if !ca_path.exists() {
+            let oauth = config.oauth.as_ref().is_some_and(|oauth| oauth.enabled);
+            let saml = config.saml.as_ref().is_some_and(|saml| saml.enabled);
+            if !oauth && !saml && !config.security.password_auth {
+                return Err(anyhow::anyhow!(
+                    "CA certificate {} not found: clients could not be authenticated \
+                     (initialize the PKI, or enable password_auth, OAuth2 or SAML)",
+                    ca_path.display()
+                ));
+            }
+            warn!("CA certificate not found, clients must log in without a certificate");
+            return Ok(None);
+        }
+
+        let ca_pem = std::fs::read_to_string(&ca_path)
+            .map_err(|e| anyhow::anyhow!("Failed to read CA cert: {}", e))?;
+        let ca_certs = load_certs_from_pem(&ca_pem)
+            .map_err(|e| anyhow::anyhow!("Failed to parse CA cert: {}", e))?;
+
+        let crl_pem = match Self::read_crl(config)? {
+            Some(pem) => pem,
+            None => {
+                warn!("CRL not found, certificate revocation will not be checked");
+                String::new()
+            }
+        };
+        let crls = load_crls_from_pem(&crl_pem)
+            .map_err(|e| anyhow::anyhow!("Failed to parse CRL: {}", e))?;
+
+        let verifier = RevocableClientVerifier::new(ca_certs, crls)
+            .map_err(|e| anyhow::anyhow!("Failed to create client verifier: {}", e))?;
+
+        Ok(Some(Arc::new(verifier)))
+    }
+
+    fn load_tls_wrap(config: &ServerConfig) -> Result<Option<TlsWrapKey>> {
+        let security = &config.security;
+        if security.tls_crypt_v2 {
+            return Ok(None);
+        }
+        if !security.tls_auth && !security.tls_crypt {
+            warn!("tls-auth and tls-crypt disabled, control channel is unauthenticated");
+            return Ok(None);
+        }
+
+        // Fail closed: an enabled mode without its key is a configuration error
+        let ta_path = config.ta_key_path();
+        let ta_pem = std::fs::read_to_string(&ta_path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", ta_path.display(), e))?;
+        let ta_key = corevpn_crypto::cert::parse_static_key(&ta_pem)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", ta_path.display(), e))?;
+
+        let key = if security.tls_crypt {
+            TlsWrapKey::tls_crypt(&ta_key, true)
+        } else {
+            TlsWrapKey::tls_auth(&ta_key, Some(0))
+        };
+        info!("Control channel protection: {}", key.mode());
+
+        Ok(Some(key))
+    }
+
+    fn load_tls_crypt_v2(config: &ServerConfig) -> Result<Option<Arc<TlsCryptV2ServerKey>>> {
+        if !config.security.tls_crypt_v2 {
+            return Ok(None);
+        }
+
+        let key_path = config.tls_crypt_v2_key_path();
+        let key = corevpn_config::generator::load_tls_crypt_v2_key(&key_path)
+            .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", key_path.display(), e))?;
+        info!("Control channel protection: tls-crypt-v2");
+
+        Ok(Some(Arc::new(key)))
+    }
+
+    fn load_revoked_serials(config: &ServerConfig) -> Result<HashSet<String>> {
+        match Self::read_crl(config)? {
+            Some(pem) => corevpn_crypto::crl::crl_revoked_serials(&pem)
+                .map(|serials| serials.into_iter().collect())
+                .map_err(|e| anyhow::anyhow!("Failed to parse CRL: {}", e)),
+            None => Ok(HashSet::new()),
+        }
+    }
+
+    /// Read the CRL, or `None` if the CA has never published one
+    ///
+    /// Once certificates are tracked in the index, a missing CRL would
+    /// silently un-revoke them, so it fails startup instead.
+    fn read_crl(config: &ServerConfig) -> Result<Option<String>> {
+        let crl_path = config.crl_path();
+        match std::fs::read_to_string(&crl_path) {
+            Ok(pem) => Ok(Some(pem)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if config.cert_index_path().exists() {
+                    return Err(anyhow::anyhow!(
+                        "CRL {} not found but certificates are tracked in {}",
+                        crl_path.display(),
+                        config.cert_index_path().display()
+                    ));
+                }
+                Ok(None)
+            }
+            Err(e) => Err(anyhow::anyhow!("Failed to read CRL: {}", e)),
+        }
+    }
+
+    /// Check a hard reset against the configured control channel protection
+    ///
+    /// Returns the session's wrapping key and the packet without any
+    /// tls-crypt-v2 wrapped client key, or `None` if the reset must be dropped.
+    fn authenticate_hard_reset<'a>(
+        &self,
+        peer_addr: SocketAddr,
+        data: &'a [u8],
+    ) -> Option<(Option<TlsWrapKey>, &'a [u8])> {
+        let Some(ref server_key) = self.tls_crypt_v2 else {
+            return match self.tls_wrap {
+                Some(ref key) => match key.unwrap(data) {
+                    Ok(_) => Some((Some(key.clone()), data)),
+                    Err(e) => {
+                        debug!("Dropping hard reset from {} failing {}: {}", peer_addr, key.mode(), e);
+                        None
+                    }
+                },
+                None => Some((None, data)),
+            };
+        };
+
+        if OpCode::from_byte(data[0]).ok() != Some(OpCode::HardResetClientV3) {
+            debug!("Dropping hard reset from {} without a tls-crypt-v2 client key", peer_addr);
+            return None;
+        }
+
+        let (key, metadata, packet) = match TlsWrapKey::from_wrapped_client_key(server_key, data) {
+            Ok(unwrapped) => unwrapped,
+            Err(e) => {
+                debug!("Dropping hard reset from {} with invalid tls-crypt-v2 key: {}", peer_addr, e);
+                return None;
+            }
+        };
+        if let Err(e) = key.unwrap(packet) {
+            debug!("Dropping hard reset from {} failing tls-crypt-v2: {}", peer_addr, e);
+            return None;
+        }
+
+        // Keys issued by the generator carry the client certificate's serial
+        if let ClientKeyMetadata::User(serial) = metadata {
+            let serial = String::from_utf8_lossy(&serial).to_lowercase();
+            if self.revoked_serials.read().contains(&serial) {
+                info!("Rejected tls-crypt-v2 key of revoked certificate {} from {}", serial, peer_addr);
+                return None;
+            }
+        }
+
+        Some((Some(key), packet))
+    }
+
+    fn load_tls_config(
+        config: &ServerConfig,
+        client_verifier: Option<Arc<RevocableClientVerifier>>,
+    ) -> Result<Option<Arc<rustls::ServerConfig>>> {
         // Check if certificates exist
         let cert_path = config.server_cert_path();
         let key_path = config.server_key_path();
@@ -202,12 +712,98 @@ impl VpnServer {
         let key = load_key_from_pem(&key_pem)
             .map_err(|e| anyhow::anyhow!("Failed to parse server key: {}", e))?;
 
-        let tls_config = create_server_config(certs, key, None)
+        let client_verifier = client_verifier
+            .map(|v| v as Arc<dyn rustls::server::danger::ClientCertVerifier>);
+        let tls_config = create_server_config(certs, key, client_verifier)
             .map_err(|e| anyhow::anyhow!("Failed to create TLS config: {}", e))?;
 
         Ok(Some(tls_config))
     }
 
+    /// Build the PUSH_REPLY for a client from the network settings and its access policy
+    fn build_push_reply(
+        &self,
+        vpn_address: VpnAddress,
+        peer_id: Option<u32>,
+        key_derivation: KeyDerivation,
+        access: Option<&AccessPolicy>,
+    ) -> PushReply {
+        let network = &self.config.network;
+        let pool = self.leases.pool();
+        let mut reply = PushReply::default();
+
+        if let (Some(ip), Some(net)) = (vpn_address.ipv4, pool.ipv4_net()) {
+            reply.ifconfig = Some((ip.to_string(), net.netmask().to_string()));
+        }
+        reply.route_gateway = pool.gateway_v4().map(|gw| gw.to_string());
+        if let (Some(ip), Some(net), Some(gw)) = (vpn_address.ipv6, pool.ipv6_net(), pool.gateway_v6()) {
+            reply.ifconfig_ipv6 = Some(format!("{}/{} {}", ip, net.prefix_len(), gw));
+        }
+
+        // Restricted clients only get routes to the networks they were granted
+        let routes = match access.and_then(AccessPolicy::routes) {
+            Some(granted) => granted,
+            None => {
+                reply.redirect_gateway = network.redirect_gateway;
+                reply.redirect_gateway_ipv6 = network.redirect_gateway && reply.ifconfig_ipv6.is_some();
+                network.push_routes
+                    .iter()
+                    .filter_map(|route| {
+                        let net = route.parse::<IpNet>().ok();
+                        if net.is_none() {
+                            warn!("Ignoring invalid push route: {}", route);
+                        }
+                        net
+                    })
+                    .chain(access.iter().flat_map(|access| access.account_routes()).copied())
+                    .collect::<Vec<_>>()
+            }
+        };
+        for net in routes {
+            match net {
+                IpNet::V4(net) => {
+                    reply.routes.push(PushRoute::new(&net.network().to_string(), &net.netmask().to_string()));
+                }
+                IpNet::V6(net) if reply.ifconfig_ipv6.is_some() => {
+                    reply.routes_ipv6.push(net.trunc().to_string());
+                }
+                IpNet::V6(net) => warn!("Ignoring IPv6 push route {} without network.subnet_v6", net),
+            }
+        }
+
+        reply.dns = network.dns.clone();
+        reply.dns_search = network.dns_search.clone();
+        reply.peer_id = peer_id;
+
+        reply.options.push(format!("tun-mtu {}", network.mtu));
+        reply.options.push(format!("cipher {}", self.get_cipher_suite().openvpn_name()));
+        if key_derivation == KeyDerivation::TlsEkm {
+            reply.options.push("key-derivation tls-ekm".to_string());
+        }
+
+        reply
+    }
+
+    /// Start a server TLS session for a new key, if TLS is configured
+    fn new_tls_session(&self) -> Result<Option<TlsHandler>> {
+        let Some(ref tls_config) = self.tls_config else {
+            return Ok(None);
+        };
+        let tls = TlsHandler::new(tls_config.clone())
+            .map_err(|e| anyhow::anyhow!("TLS init failed: {}", e))?;
+        Ok(Some(tls))
+    }
+
+    /// Options string sent in the key method v2 reply
+    fn options_string(&self, transport: &Transport) -> String {
+        format!(
+            "V4,dev-type tun,tun-mtu {},proto {},cipher {},auth [null-digest],keysize 256,key-method 2,tls-server",
+            self.config.network.mtu,
+            transport.proto_name(),
+            self.get_cipher_suite().openvpn_name(),
+        )
+    }
+
     /// Get cipher suite from config
     fn get_cipher_suite(&self) -> CipherSuite {
         if self.config.security.cipher.contains("chacha") {
@@ -225,11 +821,39 @@ pub async fn run_server(config: ServerConfig) -> Result<()> {
     info!("Public host: {}", config.server.public_host);
     info!("VPN subnet: {}", config.network.subnet);
 
-    let server = Arc::new(VpnServer::new(config.clone()).await?);
+    let server = VpnServer::new(config.clone()).await?;
 
-    // Bind UDP socket
-    let socket = UdpSocket::bind(&config.server.listen_addr).await?;
-    let socket = Arc::new(socket);
+    // Clients could connect but never pass traffic without the TUN device
+    let (tun_reader, tun_writer) = tunnel::create_tun(&config.network)
+        .context("Cannot route tunnel traffic (the server needs CAP_NET_ADMIN)")?;
+    let (tun_tx, tun_rx) = mpsc::channel(TUN_QUEUE_DEPTH);
+    tokio::spawn(run_tun_writer(tun_writer, tun_rx));
+    let server = Arc::new(server.with_tunnel(tun_tx));
+
+    // Bind one UDP socket per worker, all sharing the listen port
+    let workers = match config.server.workers {
+        0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        workers => workers,
+    };
+    let sockets = transport::bind_udp(config.server.listen_addr, workers)?;
+    info!("UDP data path: {} workers", sockets.len());
+
+    // Bind TCP listener
+    if let Some(tcp_addr) = config.server.tcp_listen_addr {
+        let listener = TcpListener::bind(tcp_addr).await?;
+        info!("Listening on: {} (TCP)", tcp_addr);
+        tokio::spawn(run_tcp_listener(server.clone(), listener));
+    }
+
+    // Bind the SAML service provider endpoints
+    if let Some(ref saml) = server.saml {
+        let listener = TcpListener::bind(saml.listen_addr()).await
+            .with_context(|| format!("Cannot bind SAML endpoints on {}", saml.listen_addr()))?;
+        tokio::spawn(crate::webui::saml::serve(listener, saml.clone()));
+    }
+
+    // Spawn TUN reader task, handing packets to one egress worker per UDP socket
+    tokio::spawn(run_tun_reader(server.clone(), tun_reader, EgressQueues::spawn(&sockets)));
 
     info!("Server ready, waiting for connections...");
 
@@ -240,9 +864,34 @@ pub async fn run_server(config: ServerConfig) -> Result<()> {
         loop {
             interval.tick().await;
             cleanup_stale_connections(&server_cleanup, Duration::from_secs(300)).await;
+            server_cleanup.sync_accounts().await;
+            server_cleanup.leases.expire();
+            server_cleanup.expire_auth_tokens();
+            if let Some(ref oauth) = server_cleanup.oauth {
+                oauth.cleanup();
+            }
+            if let Some(ref saml) = server_cleanup.saml {
+                saml.cleanup();
+            }
+            if let Err(e) = server_cleanup.audit.flush().await {
+                warn!("Audit flush failed: {}", e);
+            }
         }
     });
 
+    // Spawn CRL reload task
+    if server.client_verifier.is_some() {
+        tokio::spawn(run_crl_watcher(server.clone()));
+    }
+
+    // Spawn key renegotiation task
+    tokio::spawn(run_renegotiation_timer(server.clone()));
+
+    // Spawn OAuth session revalidation task
+    if server.oauth.is_some() {
+        tokio::spawn(run_session_watchdog(server.clone()));
+    }
+
     // Spawn log cleanup task
     let logger = server.connection_logger.clone();
     tokio::spawn(async move {
@@ -255,293 +904,1796 @@ pub async fn run_server(config: ServerConfig) -> Result<()> {
         }
     });
 
-    // Main receive loop
-    let mut buf = vec![0u8; 65535];
+    // Workers decrypt data packets themselves and queue everything else
+    let (control_tx, control_rx) = mpsc::channel(CONTROL_QUEUE_DEPTH);
+    for socket in sockets {
+        tokio::spawn(run_udp_worker(server.clone(), socket, control_tx.clone()));
+    }
+    drop(control_tx);
 
-    loop {
-        let (len, peer_addr) = match socket.recv_from(&mut buf).await {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Receive error: {}", e);
-                continue;
-            }
-        };
+    run_control_loop(server, control_rx).await;
+    Ok(())
+}
+
+/// Control channel packet handed from a UDP worker to the control task
+pub struct ControlInput {
+    transport: Transport,
+    peer_addr: SocketAddr,
+    data: Bytes,
+}
 
-        let packet_data = Bytes::copy_from_slice(&buf[..len]);
-        let socket_clone = socket.clone();
-        let server_clone = server.clone();
+/// Receive on one UDP socket, forwarding tunnel data and queueing control packets
+pub async fn run_udp_worker(server: Arc<VpnServer>, socket: Arc<UdpSocket>, control_tx: mpsc::Sender<ControlInput>) {
+    let socket = BatchSocket::new(socket);
+    let udp = Transport::Udp(socket.socket().clone());
+    let mut batch = RecvBatch::new();
+    let mut pool = PacketPool::new(PACKET_POOL_SLOTS, packet_size(&server));
+
+    loop {
+        if let Err(e) = socket.recv(&mut batch, &mut pool).await {
+            error!("Receive error: {}", e);
+            continue;
+        }
 
-        // Handle packet - directly without spawning to avoid Send issues
-        // In production, you'd use a message passing channel instead
-        if let Err(e) = handle_packet(&server_clone, &socket_clone, peer_addr, packet_data).await {
-            debug!("Packet handling error from {}: {}", peer_addr, e);
+        for (peer_addr, data) in batch.datagrams() {
+            dispatch_datagram(&server, &udp, &control_tx, peer_addr, data);
         }
     }
 }
 
-/// Cleanup stale connections
-async fn cleanup_stale_connections(server: &VpnServer, timeout: Duration) {
-    let stale_connections: Vec<_> = {
-        let map = server.connections.read();
-        map.iter()
-            .filter(|(_, conn)| conn.is_stale(timeout))
-            .map(|(addr, conn)| (*addr, conn.connection_id, conn.username.clone(), conn.duration(), conn.stats.clone()))
-            .collect()
-    };
-
-    if stale_connections.is_empty() {
-        return;
+/// Decrypt a received data packet in place, or queue anything else for the control task
+fn dispatch_datagram(
+    server: &Arc<VpnServer>,
+    udp: &Transport,
+    control_tx: &mpsc::Sender<ControlInput>,
+    peer_addr: SocketAddr,
+    data: BytesMut,
+) {
+    match data.first().map(|&byte| OpCode::from_byte(byte)) {
+        Some(Ok(OpCode::DataV1 | OpCode::DataV2)) => {
+            if let Err(e) = handle_data_packet(server, peer_addr, data) {
+                debug!("Data packet error from {}: {}", peer_addr, e);
+            }
+        }
+        Some(Ok(_)) => {
+            let input = ControlInput {
+                transport: udp.clone(),
+                peer_addr,
+                data: data.freeze(),
+            };
+            // Dropped control packets are retransmitted by the peer
+            if control_tx.try_send(input).is_err() {
+                debug!("Control queue full, dropping packet from {}", peer_addr);
+            }
+        }
+        Some(Err(e)) => trace!("Dropping packet from {}: {}", peer_addr, e),
+        None => {}
     }
+}
 
-    // Log disconnections for stale connections
-    for (addr, connection_id, username, duration, stats) in &stale_connections {
-        let event = ConnectionEventBuilder::with_id(*connection_id).disconnected(
-            *addr,
-            username.clone(),
-            DisconnectReason::IdleTimeout,
-            *duration,
-            Some(stats.clone()),
-        );
-        server.log_event(event).await;
-    }
+/// Size of the pooled packet buffers: a full tunnel packet with room to encrypt it in place
+fn packet_size(server: &VpnServer) -> usize {
+    PACKET_HEADROOM + server.config.network.mtu as usize + PACKET_TAILROOM
+}
 
-    // Remove stale connections
-    let mut map = server.connections.write();
-    for (addr, _, _, _, _) in &stale_connections {
-        map.remove(addr);
+/// Process queued control packets for all UDP workers in order
+async fn run_control_loop(server: Arc<VpnServer>, mut control_rx: mpsc::Receiver<ControlInput>) {
+    while let Some(input) = control_rx.recv().await {
+        if let Err(e) = handle_packet(&server, &input.transport, input.peer_addr, input.data).await {
+            debug!("Packet handling error from {}: {}", input.peer_addr, e);
+        }
     }
-
-    info!("Cleaned up {} stale connections", stale_connections.len());
 }
 
-async fn handle_packet(
-    server: &VpnServer,
-    socket: &UdpSocket,
-    peer_addr: SocketAddr,
-    data: Bytes,
-) -> Result<()> {
-    if data.is_empty() {
-        return Ok(());
+/// Write decrypted packets from clients to the TUN device
+async fn run_tun_writer(mut writer: TunWriter, mut rx: mpsc::Receiver<Bytes>) {
+    while let Some(packet) = rx.recv().await {
+        if let Err(e) = writer.write_all(&packet).await {
+            warn!("TUN write failed: {}", e);
+        }
     }
+}
 
-    // Parse packet opcode
-    let opcode = OpCode::from_byte(data[0])?;
-    trace!("Received {} from {}", opcode, peer_addr);
+/// Read packets from the TUN device and hand each to its client's egress worker
+///
+/// Packets are read behind reserved headroom so the egress workers can
+/// encrypt them where they landed; this task only routes them.
+async fn run_tun_reader(server: Arc<VpnServer>, mut reader: TunReader, egress: EgressQueues) {
+    let mut pool = PacketPool::new(egress.pool_slots(), packet_size(&server));
+    let mut retry = Duration::ZERO;
 
-    match opcode {
-        OpCode::HardResetClientV2 | OpCode::HardResetClientV3 => {
-            handle_hard_reset(server, socket, peer_addr, &data).await?;
-        }
-        OpCode::ControlV1 | OpCode::AckV1 | OpCode::SoftResetV1 => {
-            handle_control_packet(server, socket, peer_addr, &data).await?;
-        }
-        OpCode::DataV1 | OpCode::DataV2 => {
-            handle_data_packet(server, socket, peer_addr, &data).await?;
-        }
-        _ => {
-            debug!("Unhandled opcode: {}", opcode);
+    loop {
+        let mut packet = pool.plaintext();
+        match reader.read_buf(&mut packet).await {
+            Ok(0) => {
+                error!("TUN device closed");
+                return;
+            }
+            Ok(_) => {
+                retry = Duration::ZERO;
+                egress.route(&server, packet);
+            }
+            // Back off instead of spinning on a device that keeps failing
+            Err(e) => {
+                retry = (retry * 2).clamp(TUN_RETRY_MIN, TUN_RETRY_MAX);
+                error!("TUN read error: {}, retrying in {:?}", e, retry);
+                tokio::time::sleep(retry).await;
+            }
         }
     }
+}
 
-    Ok(())
+/// Plaintext packet from the TUN device, queued for its client's egress worker
+struct EgressPacket {
+    path: Arc<DataPath>,
+    packet: BytesMut,
 }
 
-async fn handle_hard_reset(
-    server: &VpnServer,
-    socket: &UdpSocket,
-    peer_addr: SocketAddr,
-    data: &[u8],
-) -> Result<()> {
-    info!("New connection from {}", peer_addr);
+/// Queues feeding the egress workers, one worker per UDP socket
+///
+/// Each client's packets always go to the same worker, chosen by peer ID,
+/// so they stay in order while encryption spreads across the workers.
+#[derive(Clone)]
+pub struct EgressQueues {
+    queues: Arc<[mpsc::Sender<EgressPacket>]>,
+}
 
-    // Create connection ID for logging
-    let event_builder = ConnectionEventBuilder::new();
-    let connection_id = event_builder.connection_id();
+impl EgressQueues {
+    /// Spawn an egress worker sending through each of the UDP sockets
+    pub fn spawn(sockets: &[Arc<UdpSocket>]) -> Self {
+        let queues = sockets.iter()
+            .enumerate()
+            .map(|(worker, socket)| {
+                let socket = BatchSocket::new(socket.clone());
+                if worker == 0 {
+                    info!("UDP GSO: {}", if socket.gso_enabled() { "enabled" } else { "unavailable" });
+                }
+                let (tx, rx) = mpsc::channel(EGRESS_QUEUE_DEPTH);
+                tokio::spawn(run_egress_worker(socket, rx));
+                tx
+            })
+            .collect();
+        Self { queues }
+    }
 
-    // Log connection attempt if configured
-    if server.config.logging.connection_events.attempts {
-        let event = event_builder.attempt(peer_addr);
-        server.log_event(event).await;
+    /// Packet buffers for the TUN reader, enough for every queue and batch to be full at once
+    pub fn pool_slots(&self) -> usize {
+        self.queues.len() * (EGRESS_QUEUE_DEPTH + BATCH_SIZE) + 1
     }
 
-    let cipher_suite = server.get_cipher_suite();
-    let mut conn = Connection::new(peer_addr, cipher_suite, connection_id);
+    /// Queue a packet read behind its headroom for the egress worker of its client
+    ///
+    /// A worker that falls behind loses packets instead of stalling the
+    /// TUN reader and every other client.
+    pub fn route(&self, server: &VpnServer, packet: BytesMut) {
+        let Some(dest) = tunnel::destination(&packet[PACKET_HEADROOM..]) else {
+            trace!("Dropping non-IP packet from TUN");
+            return;
+        };
 
-    // Process hard reset
-    let _result = conn.protocol.process_packet(data)?;
+        let Some(path) = server.vpn_routes.read().get(&dest).cloned() else {
+            trace!("No client for {}", dest);
+            return;
+        };
 
-    // Initialize TLS handler if we have TLS config
-    if let Some(ref tls_config) = server.tls_config {
-        let tls = TlsHandler::new(tls_config.clone())
-            .map_err(|e| anyhow::anyhow!("TLS init failed: {}", e))?;
-        conn.tls = Some(tls);
+        let queue = &self.queues[path.peer_id as usize % self.queues.len()];
+        if let Err(e) = queue.try_send(EgressPacket { path, packet }) {
+            let path = match e {
+                mpsc::error::TrySendError::Full(queued) | mpsc::error::TrySendError::Closed(queued) => queued.path,
+            };
+            let dropped = path.counters.record_tx_drop();
+            debug!("Egress queue full, dropping packet to {} ({} dropped)", path.peer_addr, dropped);
+        }
     }
+}
 
-    // Send hard reset response
-    let response = conn.protocol.create_hard_reset_response()?;
-    socket.send_to(&response, peer_addr).await?;
-
-    debug!("Sent hard reset response to {}", peer_addr);
-
-    // Store connection
-    server.connections.write().insert(peer_addr, conn);
+/// Encrypt queued TUN packets and send them to their clients
+///
+/// Whatever else is already queued is taken without waiting, so UDP
+/// packets go out in batches through this worker's socket, which shares
+/// the listen port with the others.
+async fn run_egress_worker(socket: BatchSocket, mut rx: mpsc::Receiver<EgressPacket>) {
+    let mut batch = SendBatch::new();
+
+    while let Some(first) = rx.recv().await {
+        encrypt_tun_packet(first, &mut batch);
+        for _ in 1..BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(next) => encrypt_tun_packet(next, &mut batch),
+                Err(_) => break,
+            }
+        }
 
-    Ok(())
+        if !batch.is_empty() {
+            if let Err(e) = socket.send(&mut batch).await {
+                debug!("Batched send failed: {}", e);
+            }
+        }
+    }
 }
 
-async fn handle_control_packet(
-    server: &VpnServer,
-    socket: &UdpSocket,
-    peer_addr: SocketAddr,
-    data: &[u8],
+/// Encrypt a packet read behind its headroom for its client, queueing it if it goes out over UDP
+fn encrypt_tun_packet(EgressPacket { path, mut packet }: EgressPacket, batch: &mut SendBatch) {
+    if let Err(e) = path.channels.encrypt_in_place(&mut packet) {
+        debug!("Encrypt failed for {}: {}", path.peer_addr, e);
+        return;
+    }
+    let encrypted = packet.freeze();
+    path.counters.record_tx(encrypted.len());
+
+    match path.transport {
+        Transport::Udp(_) => batch.push(path.peer_addr, encrypted),
+        // A TCP client that falls behind loses packets instead of stalling everyone else
+        ref transport => {
+            if let Err(e) = transport.try_send(&encrypted, path.peer_addr) {
+                let dropped = path.counters.record_tx_drop();
+                debug!("Dropping packet to {} ({} dropped): {}", path.peer_addr, dropped, e);
+            }
+        }
+    }
+}
+
+/// Accept TCP connections
+async fn run_tcp_listener(server: Arc<VpnServer>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer_addr)) => {
+                tokio::spawn(run_tcp_connection(server.clone(), stream, peer_addr));
+            }
+            Err(e) => {
+                error!("TCP accept error: {}", e);
+            }
+        }
+    }
+}
+
+/// Drive a single TCP connection until the peer closes it
+async fn run_tcp_connection(server: Arc<VpnServer>, stream: TcpStream, peer_addr: SocketAddr) {
+    debug!("TCP connection from {}", peer_addr);
+    let _ = stream.set_nodelay(true);
+
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<Bytes>(TCP_QUEUE_DEPTH);
+    let tcp = Transport::Tcp(tx);
+
+    // Writer task drains the outbound queue
+    tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Session started over this stream, if any
+    let mut connection_id = None;
+    let reason = loop {
+        match transport::read_frame(&mut reader).await {
+            Ok(Some(packet)) => match handle_packet(&server, &tcp, peer_addr, packet).await {
+                Ok(Some(id)) => connection_id = Some(id),
+                Ok(None) => {}
+                Err(e) => debug!("Packet handling error from {}: {}", peer_addr, e),
+            },
+            Ok(None) => break DisconnectReason::ClientDisconnect,
+            Err(e) => {
+                debug!("TCP read error from {}: {}", peer_addr, e);
+                break DisconnectReason::ConnectionReset;
+            }
+        }
+    };
+
+    // Closing the stream ends its own session, but not one that has since
+    // replaced it or floated onto the same address
+    if let Some(connection_id) = connection_id {
+        remove_connection(&server, peer_addr, connection_id, reason).await;
+    }
+}
+
+/// Remove a connection, release its VPN address and log the disconnect
+///
+/// Does nothing if the connection has gone away or another session now holds its address.
+async fn remove_connection(
+    server: &VpnServer,
+    peer_addr: SocketAddr,
+    connection_id: ConnectionId,
+    reason: DisconnectReason,
+) {
+    let conn = {
+        let mut connections = server.connections.write(&peer_addr);
+        if !connections.get(&peer_addr).is_some_and(|c| c.connection_id == connection_id) {
+            return;
+        }
+        connections.remove(&peer_addr)
+    };
+    let Some(conn) = conn else {
+        return;
+    };
+    if let Some(event) = retire_connection(server, &conn, reason) {
+        server.log_event(event).await;
+    }
+}
+
+/// Release a removed connection, returning its disconnect event if those are logged
+fn retire_connection(server: &VpnServer, conn: &Connection, reason: DisconnectReason) -> Option<ConnectionEvent> {
+    release_connection(server, conn);
+
+    // A client thrown out must log in again rather than resume with its token
+    if matches!(reason, DisconnectReason::AuthFailure | DisconnectReason::AdminTerminated) {
+        if let Some(ref token) = conn.auth_token {
+            server.auth_tokens.write().remove(token);
+        }
+    }
+
+    server.config.logging.connection_events.disconnects.then(|| {
+        ConnectionEventBuilder::with_id(conn.connection_id).disconnected(
+            conn.peer_addr,
+            conn.username.clone(),
+            reason,
+            conn.duration(),
+            Some(conn.counters.snapshot()),
+        )
+    })
+}
+
+/// Withdraw a connection's data path and release its VPN address
+fn release_connection(server: &VpnServer, conn: &Connection) {
+    server.data_paths.remove(&conn.peer_addr);
+    server.peers.remove(&conn.peer_id);
+    server.peer_ids.release(conn.peer_id);
+    if let Some(vpn_address) = conn.vpn_address {
+        let mut routes = server.vpn_routes.write();
+        for ip in vpn_address.addresses() {
+            routes.remove(&ip);
+        }
+        server.leases.release(&vpn_address);
+    }
+}
+
+/// Publish a connection's current keys, addresses and access to the data path
+///
+/// Called with the connection's shard locked, so the published state
+/// always matches the connection.
+fn publish_data_path(server: &VpnServer, conn: &Connection) {
+    let Some(vpn_address) = conn.vpn_address else {
+        return;
+    };
+
+    server.install_data_path(DataPath {
+        peer_addr: conn.peer_addr,
+        peer_id: conn.peer_id,
+        transport: conn.transport.clone(),
+        channels: conn.protocol.data_channels().clone(),
+        vpn_address,
+        access: conn.access.clone(),
+        username: conn.username.clone(),
+        counters: conn.counters.clone(),
+        denied_packets: Mutex::new(DeniedPackets::default()),
+    });
+}
+
+/// Move an established UDP connection to the address it now sends from
+///
+/// Called once a packet from `new_addr` has authenticated against the
+/// connection's data channel keys, so the client's NAT mapping or network
+/// changed rather than someone else taking over its session.
+fn float_connection(server: &Arc<VpnServer>, path: &DataPath, new_addr: SocketAddr) {
+    let old_addr = path.peer_addr;
+    let mut conn = {
+        let mut connections = server.connections.write(&old_addr);
+        match connections.remove(&old_addr) {
+            Some(conn) if conn.peer_id == path.peer_id => conn,
+            // Another packet already moved it
+            Some(other) => {
+                connections.insert(old_addr, other);
+                return;
+            }
+            None => return,
+        }
+    };
+    server.data_paths.remove(&old_addr);
+
+    conn.peer_addr = new_addr;
+    conn.touch();
+    let connection_id = conn.connection_id;
+    let username = conn.username.clone();
+    let mut log_events = Vec::new();
+
+    {
+        let mut connections = server.connections.write(&new_addr);
+        if let Some(stale) = connections.remove(&new_addr) {
+            debug!("Retiring stale session at {} for floating peer {}", new_addr, conn.peer_id);
+            log_events.extend(retire_connection(server, &stale, DisconnectReason::ServerDisconnect));
+        }
+        publish_data_path(server, &conn);
+        connections.insert(new_addr, conn);
+    }
+
+    info!("Peer {} ({:?}) floated from {} to {}", path.peer_id, username, old_addr, new_addr);
+
+    if server.config.logging.connection_events.ip_changes {
+        log_events.push(ConnectionEventBuilder::with_id(connection_id).ip_change(old_addr, new_addr, username));
+    }
+    if !log_events.is_empty() {
+        let server = server.clone();
+        tokio::spawn(async move {
+            for event in log_events {
+                server.log_event(event).await;
+            }
+        });
+    }
+}
+
+/// Reload the CRL when it changes and disconnect clients whose certificate was revoked
+async fn run_crl_watcher(server: Arc<VpnServer>) {
+    let Some(verifier) = server.client_verifier.clone() else {
+        return;
+    };
+    let crl_path = server.config.crl_path();
+    let modified = |path: &std::path::Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let mut last_modified: Option<SystemTime> = modified(&crl_path);
+    let mut interval = tokio::time::interval(CRL_RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let current = modified(&crl_path);
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        let crl_pem = match std::fs::read_to_string(&crl_path) {
+            Ok(pem) => pem,
+            Err(e) => {
+                warn!("Failed to read CRL: {}", e);
+                continue;
+            }
+        };
+        let reloaded = load_crls_from_pem(&crl_pem)
+            .map_err(|e| e.to_string())
+            .and_then(|crls| verifier.reload_crls(crls).map_err(|e| e.to_string()));
+        if let Err(e) = reloaded {
+            warn!("Failed to reload CRL: {}", e);
+            continue;
+        }
+
+        let revoked = match corevpn_crypto::crl::crl_revoked_serials(&crl_pem) {
+            Ok(serials) => serials,
+            Err(e) => {
+                warn!("Failed to parse CRL: {}", e);
+                continue;
+            }
+        };
+        info!("CRL reloaded ({} revoked certificates)", revoked.len());
+        *server.revoked_serials.write() = revoked.iter().cloned().collect();
+
+        let revoked_peers: Vec<(SocketAddr, ConnectionId)> = server.connections.filter_map(|addr, conn| {
+            conn.certificate
+                .as_ref()
+                .is_some_and(|certificate| revoked.contains(&certificate.serial))
+                .then_some((*addr, conn.connection_id))
+        });
+
+        for (addr, connection_id) in revoked_peers {
+            info!("Disconnecting {}: client certificate revoked", addr);
+            remove_connection(&server, addr, connection_id, DisconnectReason::AdminTerminated).await;
+        }
+    }
+}
+
+/// Revalidate OAuth2 logins of connected clients and disconnect those whose access was revoked
+async fn run_session_watchdog(server: Arc<VpnServer>) {
+    let Some(oauth) = server.oauth.clone() else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(oauth.check_interval());
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        let sessions: Vec<(SocketAddr, ConnectionId, Option<String>, String)> =
+            server.connections.filter_map(|addr, conn| {
+                if conn.auth_method != AuthMethod::OAuth2 {
+                    return None;
+                }
+                let state_id = conn.oauth_state.clone()?;
+                Some((*addr, conn.connection_id, conn.username.clone(), state_id))
+            });
+
+        // One slow provider response must not hold up every other check
+        futures::stream::iter(sessions)
+            .for_each_concurrent(MAX_CONCURRENT_REVALIDATIONS, |(addr, connection_id, username, state_id)| {
+                let (server, oauth) = (&server, &oauth);
+                async move {
+                    check_oauth_session(server, oauth, addr, connection_id, username, &state_id).await
+                }
+            })
+            .await;
+    }
+}
+
+/// Revalidate one OAuth2 login, applying refreshed groups or disconnecting the client
+async fn check_oauth_session(
+    server: &VpnServer,
+    oauth: &OAuthLogin,
+    addr: SocketAddr,
+    connection_id: ConnectionId,
+    username: Option<String>,
+    state_id: &str,
+) {
+    let failure = match oauth.revalidate(state_id).await {
+        SessionCheck::Valid => return,
+        SessionCheck::Refreshed(user) => {
+            match apply_refreshed_groups(server, addr, connection_id, user.groups) {
+                Some(failure) => failure,
+                None => return,
+            }
+        }
+        SessionCheck::Revoked(failure) => failure,
+    };
+
+    let reason = failure.reason.clone();
+    let notified = send_to_connection(server, addr, connection_id, |_| {
+        vec![ControlMessage::AuthFailed(Some(reason))]
+    })
+    .await;
+    match notified {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => debug!("Failed to send AUTH_FAILED to {}: {}", addr, e),
+    }
+
+    info!("Disconnecting {}: OAuth login no longer authorized ({})", addr, failure.reason);
+    if server.config.logging.connection_events.auth_events {
+        let event = ConnectionEventBuilder::with_id(connection_id).authentication(
+            addr,
+            username,
+            AuthMethod::OAuth2,
+            failure.result,
+        );
+        server.log_event(event).await;
+    }
+    remove_connection(server, addr, connection_id, DisconnectReason::AuthFailure).await;
+}
+
+/// Give a connection the groups from a refreshed login and re-evaluate its access
+///
+/// Returns the failure to disconnect with when the new groups are denied by policy.
+fn apply_refreshed_groups(
+    server: &VpnServer,
+    addr: SocketAddr,
+    connection_id: ConnectionId,
+    groups: Vec<String>,
+) -> Option<LoginFailure> {
+    let mut connections = server.connections.write(&addr);
+    let conn = connections.get_mut(&addr).filter(|c| c.connection_id == connection_id)?;
+    if conn.groups == groups {
+        return None;
+    }
+    info!("Groups of {:?} changed to {:?}", conn.username, groups);
+    conn.groups = groups;
+
+    let engine = server.policy.as_ref()?;
+    let access = evaluate_access(server, engine, conn);
+    audit_policy_decision(server, conn, &access);
+    let denied = access.is_denied();
+    conn.access = Some(access);
+    if denied {
+        return Some(LoginFailure {
+            result: AuthResult::NotAuthorized,
+            reason: "access denied by policy".into(),
+        });
+    }
+    // Filter the tunnel with the new policy from the next packet on
+    publish_data_path(server, conn);
+    None
+}
+
+/// Renegotiate keys that reached a limit, retire lame duck keys and fail stalled renegotiations
+async fn run_renegotiation_timer(server: Arc<VpnServer>) {
+    let security = &server.config.security;
+    let hand_window = Duration::from_secs(security.hand_window.into());
+    let transition_window = Duration::from_secs(security.transition_window.into());
+
+    let mut interval = tokio::time::interval(RENEGOTIATION_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let peers: Vec<SocketAddr> = server.connections.filter_map(|addr, conn| {
+            let active = conn.protocol.is_renegotiating() || conn.protocol.lame_duck().is_some();
+            (active || conn.renegotiation_due(security)).then_some(*addr)
+        });
+
+        for peer_addr in peers {
+            let (connection_id, transport, outcome) = {
+                let mut connections = server.connections.write(&peer_addr);
+                let Some(conn) = connections.get_mut(&peer_addr) else {
+                    continue;
+                };
+                let outcome = check_keys(&server, conn, hand_window, transition_window);
+                (conn.connection_id, conn.transport.clone(), outcome)
+            };
+
+            match outcome {
+                Ok(packets) => {
+                    for packet in packets {
+                        if let Err(e) = transport.send(&packet, peer_addr).await {
+                            debug!("Send to {} failed: {}", peer_addr, e);
+                        }
+                    }
+                }
+                Err(e) => renegotiation_failed(&server, peer_addr, connection_id, &e).await,
+            }
+        }
+    }
+}
+
+/// Advance a connection's key lifecycle, returning control packets to send
+///
+/// Fails if a renegotiation in progress outlived the handshake window.
+fn check_keys(
+    server: &VpnServer,
+    conn: &mut Connection,
+    hand_window: Duration,
+    transition_window: Duration,
+) -> Result<Vec<Bytes>> {
+    if conn.protocol.expire_lame_duck(transition_window) {
+        debug!("Retired lame duck key of {}", conn.peer_addr);
+        publish_data_path(server, conn);
+    }
+
+    if let Some(elapsed) = conn.protocol.renegotiation_time() {
+        if elapsed > hand_window {
+            return Err(anyhow::anyhow!("not completed within {}s", hand_window.as_secs()));
+        }
+        // Nothing else retransmits a soft reset the client never answered
+        return Ok(conn.protocol.get_retransmits());
+    }
+
+    if !conn.renegotiation_due(&server.config.security) {
+        return Ok(Vec::new());
+    }
+
+    info!("Renegotiating keys with {}", conn.peer_addr);
+    let soft_reset = conn.protocol.start_renegotiation()?;
+    conn.tls = server.new_tls_session()?;
+    conn.control_buf.clear();
+    Ok(vec![soft_reset])
+}
+
+/// Disconnect a client whose key renegotiation failed
+async fn renegotiation_failed(
+    server: &VpnServer,
+    peer_addr: SocketAddr,
+    connection_id: ConnectionId,
+    error: &anyhow::Error,
+) {
+    warn!("Key renegotiation with {} failed: {}", peer_addr, error);
+    if server.config.logging.connection_events.renegotiations {
+        let event = ConnectionEventBuilder::with_id(connection_id).renegotiation(peer_addr, false);
+        server.log_event(event).await;
+    }
+    remove_connection(server, peer_addr, connection_id, DisconnectReason::RenegotiationFailure).await;
+}
+
+/// Cleanup stale connections
+async fn cleanup_stale_connections(server: &VpnServer, timeout: Duration) {
+    let stale_connections: Vec<(SocketAddr, ConnectionId)> = server.connections
+        .filter_map(|addr, conn| conn.is_stale(timeout).then_some((*addr, conn.connection_id)));
+
+    if stale_connections.is_empty() {
+        return;
+    }
+
+    for &(addr, connection_id) in &stale_connections {
+        remove_connection(server, addr, connection_id, DisconnectReason::IdleTimeout).await;
+    }
+
+    info!("Cleaned up {} stale connections", stale_connections.len());
+}
+
+/// Handle a packet from a peer
+///
+/// Returns the ID of the connection a hard reset started.
+async fn handle_packet(
+    server: &Arc<VpnServer>,
+    transport: &Transport,
+    peer_addr: SocketAddr,
+    data: Bytes,
+) -> Result<Option<ConnectionId>> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    // Parse packet opcode
+    let opcode = OpCode::from_byte(data[0])?;
+    trace!("Received {} from {}", opcode, peer_addr);
+
+    match opcode {
+        OpCode::HardResetClientV2 | OpCode::HardResetClientV3 => {
+            return handle_hard_reset(server, transport, peer_addr, &data).await;
+        }
+        OpCode::ControlV1 | OpCode::AckV1 | OpCode::SoftResetV1 => {
+            handle_control_packet(server, transport, peer_addr, &data).await?;
+        }
+        OpCode::DataV1 | OpCode::DataV2 => {
+            handle_data_packet(server, peer_addr, BytesMut::from(&data[..]))?;
+        }
+        _ => {
+            debug!("Unhandled opcode: {}", opcode);
+        }
+    }
+
+    Ok(None)
+}
+
+async fn handle_hard_reset(
+    server: &VpnServer,
+    transport: &Transport,
+    peer_addr: SocketAddr,
+    data: &[u8],
+) -> Result<Option<ConnectionId>> {
+    // Drop unauthenticated resets before allocating any state
+    let Some((tls_wrap, data)) = server.authenticate_hard_reset(peer_addr, data) else {
+        return Ok(None);
+    };
+
+    info!("New connection from {}", peer_addr);
+
+    // Create connection ID for logging
+    let event_builder = ConnectionEventBuilder::new();
+    let connection_id = event_builder.connection_id();
+
+    // Log connection attempt if configured
+    if server.config.logging.connection_events.attempts {
+        let event = event_builder.attempt(peer_addr);
+        server.log_event(event).await;
+    }
+
+    let Some(peer_id) = server.peer_ids.allocate() else {
+        warn!("Peer IDs exhausted, rejecting {}", peer_addr);
+        return Ok(None);
+    };
+
+    let cipher_suite = server.get_cipher_suite();
+    let mut conn = Connection::new(peer_addr, peer_id, transport.clone(), cipher_suite, connection_id);
+    if let Some(key) = tls_wrap {
+        conn.protocol.set_tls_wrap(key);
+    }
+
+    // The peer ID goes back to the pool if the handshake can't start
+    let handshake: Result<()> = async {
+        // Process hard reset
+        let _result = conn.protocol.process_packet(data)?;
+
+        // Initialize TLS handler if we have TLS config
+        conn.tls = server.new_tls_session()?;
+
+        // Send hard reset response
+        let response = conn.protocol.create_hard_reset_response()?;
+        transport.send(&response, peer_addr).await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = handshake {
+        server.peer_ids.release(peer_id);
+        return Err(e);
+    }
+
+    debug!("Sent hard reset response to {}", peer_addr);
+
+    // Store connection, retiring any earlier session from the same peer
+    if let Some(old) = server.connections.insert(peer_addr, conn) {
+        if let Some(event) = retire_connection(server, &old, DisconnectReason::ConnectionReset) {
+            server.log_event(event).await;
+        }
+    }
+
+    Ok(Some(connection_id))
+}
+
+async fn handle_control_packet(
+    server: &Arc<VpnServer>,
+    transport: &Transport,
+    peer_addr: SocketAddr,
+    data: &[u8],
+) -> Result<()> {
+    // Collect data we need to send while holding the lock
+    let mut log_events: Vec<ConnectionEvent> = Vec::new();
+    let connection_id;
+    let refused;
+
+    let outcome = {
+        // Scope for the write lock - release before any awaits
+        let mut connections = server.connections.write(&peer_addr);
+        let conn = match connections.get_mut(&peer_addr) {
+            Some(c) => c,
+            None => {
+                debug!("No session for {}", peer_addr);
+                return Ok(());
+            }
+        };
+
+        conn.touch();
+        connection_id = conn.connection_id;
+
+        // Process control packet
+        let result = conn.protocol.process_packet(data)?;
+
+        let outcome = match process_control(server, conn, peer_addr, result, &mut log_events) {
+            Ok(packets) => Ok(packets),
+            // A failed renegotiation ends the session
+            Err(e) if conn.protocol.is_renegotiating() => Err(e),
+            Err(e) => return Err(e),
+        };
+        refused = conn.refused;
+        outcome
+    }; // Lock released here
+
+    // Log any events
+    for event in log_events {
+        server.log_event(event).await;
+    }
+
+    let packets_to_send = match outcome {
+        Ok(packets) => packets,
+        Err(e) => {
+            renegotiation_failed(server, peer_addr, connection_id, &e).await;
+            return Ok(());
+        }
+    };
+
+    // Now send all collected packets without holding the lock
+    for packet in packets_to_send {
+        transport.send(&packet, peer_addr).await?;
+    }
+
+    if refused {
+        remove_connection(server, peer_addr, connection_id, DisconnectReason::AuthFailure).await;
+    }
+
+    Ok(())
+}
+
+/// Act on a processed control packet, returning the packets to send back
+fn process_control(
+    server: &Arc<VpnServer>,
+    conn: &mut Connection,
+    peer_addr: SocketAddr,
+    result: ProcessedPacket,
+    log_events: &mut Vec<ConnectionEvent>,
+) -> Result<Vec<Bytes>> {
+    let mut pending_packets = Vec::new();
+
+    match result {
+        ProcessedPacket::TlsData(records) => {
+            // Pass TLS records to TLS handler
+            if let Some(ref mut tls) = conn.tls {
+                tls.process_tls_records(records)
+                    .map_err(|e| anyhow::anyhow!("TLS processing failed: {}", e))?;
+
+                // Check if handshake is complete
+                let handshaking = matches!(conn.protocol.state(), ProtocolState::TlsHandshake | ProtocolState::Rekeying);
+                if tls.is_handshake_complete() && handshaking {
+                    info!("TLS handshake complete with {}", peer_addr);
+                    conn.protocol.set_state(ProtocolState::KeyExchange);
+
+                    // The verifier has already checked the chain, EKU, validity and CRL
+                    let identity = tls.peer_certificates()
+                        .and_then(|certs| certs.into_iter().next())
+                        .and_then(|cert| CertificateIdentity::from_der(&cert).ok());
+
+                    if conn.protocol.is_renegotiating() {
+                        // The session keeps its identity, so the certificate must not change
+                        if identity.as_ref().map(|i| &i.serial) != conn.certificate.as_ref().map(|c| &c.serial) {
+                            return Err(anyhow::anyhow!("Client certificate changed during renegotiation"));
+                        }
+                    } else if let Some(identity) = identity {
+                        conn.auth_method = AuthMethod::Certificate;
+                        conn.username = identity.username().map(String::from);
+                        conn.certificate = Some(identity);
+                        info!("Client certificate for {:?} verified from {}", conn.username, peer_addr);
+
+                        // Log successful authentication if configured
+                        if server.config.logging.connection_events.auth_events {
+                            log_events.push(ConnectionEventBuilder::with_id(conn.connection_id)
+                                .authentication(
+                                    peer_addr,
+                                    conn.username.clone(),
+                                    conn.auth_method.clone(),
+                                    AuthResult::Success,
+                                ));
+                        }
+                    } else {
+                        debug!("No client certificate from {}", peer_addr);
+                    }
+                }
+
+                // Read any application data (key method v2, control messages)
+                if tls.is_handshake_complete() {
+                    let mut buf = [0u8; 4096];
+                    loop {
+                        let n = tls.read_plaintext(&mut buf)
+                            .map_err(|e| anyhow::anyhow!("TLS read failed: {}", e))?;
+                        if n == 0 {
+                            break;
+                        }
+                        conn.control_buf.extend_from_slice(&buf[..n]);
+                    }
+                }
+            }
+
+            handle_control_plaintext(server, conn, peer_addr, log_events)?;
+            pending_packets.extend(flush_control(conn)?);
+        }
+        ProcessedPacket::HardReset { session_id: _ } => {
+            debug!("Late hard reset from {}", peer_addr);
+        }
+        ProcessedPacket::SoftReset { key_id } => {
+            info!("Key renegotiation from {} (key ID {})", peer_addr, key_id.0);
+
+            // The new key gets its own TLS session, started by the client
+            conn.tls = server.new_tls_session()?;
+            conn.control_buf.clear();
+            pending_packets.push(conn.protocol.create_soft_reset()?);
+        }
+        ProcessedPacket::None => {
+            // ACK or no action needed
+        }
+        _ => {}
+    }
+
+    // Collect ACKs
+    if conn.protocol.should_send_ack() {
+        if let Some(ack) = conn.protocol.create_ack_packet() {
+            pending_packets.push(ack);
+        }
+    }
+
+    // Collect retransmits
+    for retransmit in conn.protocol.get_retransmits() {
+        pending_packets.push(retransmit);
+    }
+
+    Ok(pending_packets)
+}
+
+/// Handle decrypted control channel data
+fn handle_control_plaintext(
+    server: &Arc<VpnServer>,
+    conn: &mut Connection,
+    peer_addr: SocketAddr,
+    log_events: &mut Vec<ConnectionEvent>,
+) -> Result<()> {
+    if conn.control_buf.is_empty() {
+        return Ok(());
+    }
+
+    if conn.protocol.state() == ProtocolState::KeyExchange {
+        let (client_km, consumed) = match KeyMethodV2::parse(&conn.control_buf, true) {
+            Ok(parsed) => parsed,
+            // Wait for the rest of the message
+            Err(ProtocolError::PacketTooShort { .. }) => return Ok(()),
+            Err(e) => return Err(anyhow::anyhow!("Invalid key method from {}: {}", peer_addr, e)),
+        };
+        // Keep any control message that arrived in the same record
+        conn.control_buf.drain(..consumed);
+
+        debug!(
+            "Key method v2 from {} (peer info: {})",
+            peer_addr,
+            client_km.peer_info_value("IV_VER").unwrap_or("unknown"),
+        );
+
+        // Without a certificate vouching for the client, new keys need the session's auth token
+        if conn.protocol.is_renegotiating() && conn.certificate.is_none() && !has_auth_token(conn, &client_km) {
+            return Err(anyhow::anyhow!("Renegotiation from {} without the session's auth token", peer_addr));
+        }
+
+        let server_km = KeyMethodV2::new_server(server.options_string(&conn.transport));
+        let tls = conn.tls.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("No TLS session for {}", peer_addr))?;
+        tls.write_plaintext(&server_km.encode())
+            .map_err(|e| anyhow::anyhow!("TLS write failed: {}", e))?;
+
+        // Installing the keys completes a renegotiation
+        let renegotiating = conn.protocol.is_renegotiating();
+
+        // Clients that support P_DATA_V2 get their peer ID with the keys
+        if client_km.iv_proto() & IV_PROTO_DATA_V2 != 0 {
+            conn.protocol.set_peer_id(conn.peer_id);
+        }
+
+        // Prefer exporting keys from TLS when the client supports it
+        if client_km.iv_proto() & IV_PROTO_TLS_KEY_EXPORT != 0 {
+            let block = tls.export_keying_material(EKM_LABEL, KEY_BLOCK_SIZE)
+                .map_err(|e| anyhow::anyhow!("Key export failed: {}", e))?;
+            let key_material = KeyMaterial::from_key_block(&block)?;
+            conn.protocol.install_keys(&key_material, true);
+            conn.key_derivation = KeyDerivation::TlsEkm;
+        } else {
+            conn.protocol.install_exchanged_keys(&client_km, &server_km, true)?;
+            conn.key_derivation = KeyDerivation::Prf;
+        }
+
+        conn.protocol.set_state(ProtocolState::Established);
+        conn.keys_installed();
+        info!(
+            "Data channel keys installed for {} (key ID {}, {:?})",
+            peer_addr,
+            conn.protocol.key_id().0,
+            conn.key_derivation,
+        );
+
+        if renegotiating {
+            // The session is already authenticated, so only the data path changes
+            publish_data_path(server, conn);
+            if server.config.logging.connection_events.renegotiations {
+                log_events.push(ConnectionEventBuilder::with_id(conn.connection_id)
+                    .renegotiation(peer_addr, true));
+            }
+        } else if conn.certificate.is_none()
+            && client_km.auth_message().is_some_and(|auth| resume_login(server, conn, &auth))
+        {
+            info!("Login for {:?} from {} resumed with its auth token", conn.username, peer_addr);
+            if server.config.logging.connection_events.auth_events {
+                log_events.push(ConnectionEventBuilder::with_id(conn.connection_id).authentication(
+                    peer_addr,
+                    conn.username.clone(),
+                    conn.auth_method.clone(),
+                    AuthResult::Success,
+                ));
+            }
+        } else {
+            conn.login = client_km.auth_message().map(|auth| auth.username);
+            if let Some(ref oauth) = server.oauth {
+                begin_oauth_login(server, oauth, conn, &client_km)?;
+            }
+            if let Some(ref saml) = server.saml {
+                begin_saml_login(saml, conn, &client_km)?;
+            }
+            if server.config.security.password_auth {
+                tokio::spawn(check_password_login(
+                    server.clone(),
+                    server.users.clone(),
+                    conn.peer_addr,
+                    conn.connection_id,
+                    conn.certificate.clone(),
+                    client_km.auth_message(),
+                ));
+            }
+        }
+    }
+
+    // Remaining data is NUL-terminated text messages
+    while conn.protocol.is_established() {
+        let Some(end) = conn.control_buf.iter().position(|&b| b == 0) else {
+            break;
+        };
+        let message: Vec<u8> = conn.control_buf.drain(..=end).collect();
+
+        match ControlMessage::parse(&message) {
+            Ok(ControlMessage::PushRequest) => {
+                // The password comes first so the OAuth2 login can be checked against it
+                if server.config.security.password_auth && conn.password_user.is_none() {
+                    trace!("Password check for {} still pending", peer_addr);
+                    continue;
+                }
+                if server.oauth.is_some() && conn.auth_method != AuthMethod::OAuth2 {
+                    check_oauth_login(server, conn, peer_addr, log_events)?;
+                    if conn.auth_method != AuthMethod::OAuth2 {
+                        continue;
+                    }
+                }
+                if server.saml.is_some() && conn.auth_method != AuthMethod::Saml {
+                    check_saml_login(server, conn, peer_addr, log_events)?;
+                    if conn.auth_method != AuthMethod::Saml {
+                        continue;
+                    }
+                }
+                if !conn.is_authenticated() {
+                    warn!("Rejecting {}: no verified certificate or login", peer_addr);
+                    send_control_message(
+                        conn,
+                        &ControlMessage::AuthFailed(Some("authentication required".into())),
+                    )?;
+                    continue;
+                }
+                handle_push_request(server, conn, peer_addr, log_events)?;
+            }
+            Ok(other) => {
+                debug!("Ignoring control message from {}: {:?}", peer_addr, other);
+            }
+            Err(e) => {
+                debug!("Invalid control message from {}: {}", peer_addr, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Start the OAuth2 login for a new connection, or resume one answered with a CRV1 response
+fn begin_oauth_login(
+    server: &Arc<VpnServer>,
+    oauth: &Arc<OAuthLogin>,
+    conn: &mut Connection,
+    client_km: &KeyMethodV2,
 ) -> Result<()> {
-    // Collect data we need to send while holding the lock
-    let packets_to_send: Vec<Bytes>;
-    let mut log_events: Vec<ConnectionEvent> = Vec::new();
+    let auth = client_km.auth_message();
+    let username = auth.as_ref().map(|auth| auth.username.clone()).unwrap_or_default();
+
+    if let Some((state_id, _)) = auth.as_ref().and_then(|auth| parse_vpn_auth_response(&auth.password)) {
+        if !oauth.resume(state_id, &username) {
+            warn!("Unknown or reused OAuth login from {}", conn.peer_addr);
+            return send_control_message(
+                conn,
+                &ControlMessage::AuthFailed(Some("OAuth login expired, please reconnect".into())),
+            );
+        }
+        conn.oauth_state = Some(state_id.to_string());
+        return Ok(());
+    }
 
-    {
-        // Scope for the write lock - release before any awaits
-        let mut connections = server.connections.write();
-        let conn = match connections.get_mut(&peer_addr) {
-            Some(c) => c,
-            None => {
-                debug!("No session for {}", peer_addr);
-                return Ok(());
-            }
+    tokio::spawn(start_oauth_login(
+        server.clone(),
+        oauth.clone(),
+        conn.peer_addr,
+        conn.connection_id,
+        username,
+        LoginPrompt::for_client(client_km),
+    ));
+    Ok(())
+}
+
+/// How a client is told to complete its OAuth2 or SAML login
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LoginPrompt {
+    /// AUTH_PENDING and a WEB_AUTH URL opened in an embedded browser
+    WebAuth,
+    /// AUTH_PENDING and an OPEN_URL URL opened in the system browser
+    OpenUrl,
+    /// CRV1 dynamic challenge, answered on reconnect
+    Challenge,
+}
+
+impl LoginPrompt {
+    /// Best prompt among the SSO methods the client advertises in IV_SSO
+    fn for_client(client_km: &KeyMethodV2) -> Self {
+        if client_km.supports_sso("webauth") {
+            Self::WebAuth
+        } else if client_km.supports_sso("openurl") {
+            Self::OpenUrl
+        } else {
+            Self::Challenge
+        }
+    }
+
+    /// AUTH_PENDING messages sending the client to a login URL, or `None` for a CRV1 challenge
+    fn pending(self, url: &str, timeout_secs: u64) -> Option<Vec<ControlMessage>> {
+        let info = match self {
+            Self::WebAuth => format!("INFO_PRE,WEB_AUTH::{}", url),
+            Self::OpenUrl => format!("INFO_PRE,OPEN_URL:{}", url),
+            Self::Challenge => return None,
         };
+        Some(vec![
+            ControlMessage::AuthPending(Some(format!("timeout {}", timeout_secs))),
+            ControlMessage::Info(info),
+        ])
+    }
+}
 
-        conn.touch();
+/// Start a device login for a connection, prompt the client and poll until it completes
+async fn start_oauth_login(
+    server: Arc<VpnServer>,
+    oauth: Arc<OAuthLogin>,
+    peer_addr: SocketAddr,
+    connection_id: ConnectionId,
+    username: String,
+    prompt: LoginPrompt,
+) {
+    let (state_id, device) = match oauth.start(&username).await {
+        Ok(login) => login,
+        Err(e) => {
+            warn!("Failed to start OAuth login for {}: {}", peer_addr, e);
+            let message = ControlMessage::AuthFailed(Some("OAuth login unavailable".into()));
+            let _ = send_to_connection(&server, peer_addr, connection_id, |_| vec![message]).await;
+            return;
+        }
+    };
 
-        // Process control packet
-        let result = conn.protocol.process_packet(data)?;
+    // WEB_AUTH/OPEN_URL need a URL that already carries the user code
+    let pending = device.verification_uri_complete.as_deref()
+        .and_then(|url| prompt.pending(url, device.expires_in));
+    let (pending, messages) = match pending {
+        Some(messages) => (true, messages),
+        None => (false, vec![ControlMessage::AuthFailed(Some(
+            generate_vpn_auth_challenge(&state_id, &username, &device),
+        ))]),
+    };
 
-        let mut pending_packets = Vec::new();
-
-        match result {
-            ProcessedPacket::TlsData(records) => {
-                // Pass TLS records to TLS handler
-                if let Some(ref mut tls) = conn.tls {
-                    tls.process_tls_records(records)
-                        .map_err(|e| anyhow::anyhow!("TLS processing failed: {}", e))?;
-
-                    // If TLS handler wants to write, get the data
-                    while tls.wants_write() {
-                        if let Some(tls_out) = tls.get_outgoing()
-                            .map_err(|e| anyhow::anyhow!("TLS outgoing failed: {}", e))?
-                        {
-                            // Wrap TLS data in control packet
-                            let ctrl_packet = conn.protocol.create_control_packet(tls_out)?;
-                            pending_packets.push(ctrl_packet);
-                        } else {
-                            break;
-                        }
-                    }
+    let sent = send_to_connection(&server, peer_addr, connection_id, |conn| {
+        if pending {
+            conn.oauth_state = Some(state_id.clone());
+        }
+        messages
+    })
+    .await;
+    match sent {
+        Ok(true) => debug!("OAuth login {} started for {}", state_id, peer_addr),
+        Ok(false) => return,
+        Err(e) => {
+            debug!("Failed to send OAuth prompt to {}: {}", peer_addr, e);
+            return;
+        }
+    }
 
-                    // Check if handshake is complete
-                    if tls.is_handshake_complete() && conn.protocol.state() == ProtocolState::TlsHandshake {
-                        info!("TLS handshake complete with {}", peer_addr);
-                        conn.protocol.set_state(ProtocolState::KeyExchange);
-                        conn.auth_method = AuthMethod::Certificate;
+    oauth.run(&state_id, &device).await;
+}
 
-                        // Read any application data (key method v2)
-                        let mut buf = vec![0u8; 4096];
-                        if let Ok(n) = tls.read_plaintext(&mut buf) {
-                            if n > 0 {
-                                debug!("Received {} bytes of post-handshake data", n);
-                            }
-                        }
+/// Accept the connection once its OAuth2 login has completed, or reject it if the login failed
+fn check_oauth_login(
+    server: &VpnServer,
+    conn: &mut Connection,
+    peer_addr: SocketAddr,
+    log_events: &mut Vec<ConnectionEvent>,
+) -> Result<()> {
+    let (Some(oauth), Some(state_id)) = (&server.oauth, conn.oauth_state.clone()) else {
+        trace!("OAuth login for {} not started yet", peer_addr);
+        return Ok(());
+    };
 
-                        // Log successful authentication if configured
-                        if server.config.logging.connection_events.auth_events {
-                            log_events.push(ConnectionEventBuilder::with_id(conn.connection_id)
-                                .authentication(
-                                    peer_addr,
-                                    conn.username.clone(),
-                                    conn.auth_method.clone(),
-                                    crate::connection_log::AuthResult::Success,
-                                ));
-                        }
-                    }
+    let result = match oauth.status(&state_id) {
+        LoginStatus::Pending => {
+            trace!("OAuth login for {} still pending", peer_addr);
+            return Ok(());
+        }
+        LoginStatus::Complete(user) => {
+            // Used up whether or not it matches the certificate
+            oauth.associate(&state_id, &conn.connection_id.to_string());
+
+            let name = user.email.clone().unwrap_or_else(|| user.sub.clone());
+            match accept_sso_login(conn, user, AuthMethod::OAuth2) {
+                Ok(()) => {
+                    info!("OAuth login for {:?} accepted from {}", conn.username, peer_addr);
+                    AuthResult::Success
+                }
+                Err(other) => {
+                    warn!(
+                        "OAuth login for {} from {} does not match the {} of {:?}",
+                        name, peer_addr, other, conn.username
+                    );
+                    conn.oauth_state = None;
+                    send_control_message(
+                        conn,
+                        &ControlMessage::AuthFailed(Some(format!("login does not match the {}", other))),
+                    )?;
+                    AuthResult::NotAuthorized
                 }
             }
-            ProcessedPacket::HardReset { session_id: _ } => {
-                debug!("Late hard reset from {}", peer_addr);
-            }
-            ProcessedPacket::SoftReset => {
-                info!("Key renegotiation from {}", peer_addr);
+        }
+        LoginStatus::Failed(failure) => {
+            warn!("OAuth login from {} rejected: {}", peer_addr, failure.reason);
+            conn.oauth_state = None;
+            send_control_message(conn, &ControlMessage::AuthFailed(Some(failure.reason)))?;
+            failure.result
+        }
+    };
+
+    if server.config.logging.connection_events.auth_events {
+        log_events.push(ConnectionEventBuilder::with_id(conn.connection_id).authentication(
+            peer_addr,
+            conn.username.clone(),
+            AuthMethod::OAuth2,
+            result,
+        ));
+    }
+    Ok(())
+}
+
+/// Start the SAML login for a new connection, or resume one answered with a CRV1 response
+///
+/// Nothing has to be fetched from the IdP to start a login, so unlike
+/// OAuth2 the client is prompted right away.
+fn begin_saml_login(saml: &SamlLogin, conn: &mut Connection, client_km: &KeyMethodV2) -> Result<()> {
+    let auth = client_km.auth_message();
+    let username = auth.as_ref().map(|auth| auth.username.clone()).unwrap_or_default();
+
+    if let Some((state_id, _)) = auth.as_ref().and_then(|auth| parse_vpn_auth_response(&auth.password)) {
+        if !saml.resume(state_id, &username) {
+            warn!("Unknown or reused SAML login from {}", conn.peer_addr);
+            return send_control_message(
+                conn,
+                &ControlMessage::AuthFailed(Some("SAML login expired, please reconnect".into())),
+            );
+        }
+        conn.saml_state = Some(state_id.to_string());
+        return Ok(());
+    }
+
+    let (state_id, url) = saml.start(&username);
+    let messages = match LoginPrompt::for_client(client_km).pending(&url, saml.login_timeout().as_secs()) {
+        Some(messages) => {
+            conn.saml_state = Some(state_id.clone());
+            messages
+        }
+        None => vec![ControlMessage::AuthFailed(Some(generate_crv1_challenge(
+            &state_id,
+            &username,
+            &format!("Log in at {} and then continue", url),
+        )))],
+    };
+    for message in &messages {
+        send_control_message(conn, message)?;
+    }
+    debug!("SAML login {} started for {}", state_id, conn.peer_addr);
+    Ok(())
+}
 
-                // Log renegotiation if configured
-                if server.config.logging.connection_events.renegotiations {
-                    log_events.push(ConnectionEventBuilder::with_id(conn.connection_id)
-                        .renegotiation(peer_addr, true));
+/// Accept the connection once its SAML login has completed, or reject it if the login failed
+fn check_saml_login(
+    server: &VpnServer,
+    conn: &mut Connection,
+    peer_addr: SocketAddr,
+    log_events: &mut Vec<ConnectionEvent>,
+) -> Result<()> {
+    let (Some(saml), Some(state_id)) = (&server.saml, conn.saml_state.clone()) else {
+        trace!("SAML login for {} not started yet", peer_addr);
+        return Ok(());
+    };
+
+    let result = match saml.status(&state_id) {
+        LoginStatus::Pending => {
+            trace!("SAML login for {} still pending", peer_addr);
+            return Ok(());
+        }
+        LoginStatus::Complete(user) => {
+            // Used up whether or not it matches the certificate
+            saml.associate(&state_id);
+
+            let name = user.email.clone().unwrap_or_else(|| user.sub.clone());
+            match accept_sso_login(conn, user, AuthMethod::Saml) {
+                Ok(()) => {
+                    info!("SAML login for {:?} accepted from {}", conn.username, peer_addr);
+                    AuthResult::Success
+                }
+                Err(other) => {
+                    warn!(
+                        "SAML login for {} from {} does not match the {} of {:?}",
+                        name, peer_addr, other, conn.username
+                    );
+                    conn.saml_state = None;
+                    send_control_message(
+                        conn,
+                        &ControlMessage::AuthFailed(Some(format!("login does not match the {}", other))),
+                    )?;
+                    AuthResult::NotAuthorized
                 }
             }
-            ProcessedPacket::None => {
-                // ACK or no action needed
-            }
-            _ => {}
         }
+        LoginStatus::Failed(failure) => {
+            warn!("SAML login from {} rejected: {}", peer_addr, failure.reason);
+            conn.saml_state = None;
+            send_control_message(conn, &ControlMessage::AuthFailed(Some(failure.reason)))?;
+            failure.result
+        }
+    };
 
-        // Collect ACKs
-        if conn.protocol.should_send_ack() {
-            if let Some(ack) = conn.protocol.create_ack_packet() {
-                pending_packets.push(ack);
-            }
+    if server.config.logging.connection_events.auth_events {
+        log_events.push(ConnectionEventBuilder::with_id(conn.connection_id).authentication(
+            peer_addr,
+            conn.username.clone(),
+            AuthMethod::Saml,
+            result,
+        ));
+    }
+    Ok(())
+}
+
+/// Record a completed OAuth2 or SAML login on its connection
+///
+/// The login must be for the user of the connection's client certificate and
+/// password login, if any; otherwise the one it does not match is returned.
+fn accept_sso_login(conn: &mut Connection, user: UserInfo, method: AuthMethod) -> Result<(), &'static str> {
+    let email = user.email.as_deref().filter(|_| user.email_verified);
+    let names = [email, Some(user.sub.as_str())];
+    if !matches_certificate(conn.certificate.as_ref(), &names) {
+        return Err("client certificate");
+    }
+    if conn.password_user.as_ref().is_some_and(|local| !same_user(&local_names(local), &names)) {
+        return Err("password login");
+    }
+
+    // Only a verified email may name a local account (and with it a role)
+    conn.username = Some(email.map_or_else(|| user.sub.clone(), String::from));
+    conn.auth_method = method;
+    conn.groups = user.groups;
+    Ok(())
+}
+
+/// Whether a login is for the user a client certificate was issued to
+///
+/// Compares the certificate's common name and email address with the
+/// login's names, ignoring case. Without a certificate any login matches.
+fn matches_certificate(certificate: Option<&CertificateIdentity>, names: &[Option<&str>]) -> bool {
+    let Some(certificate) = certificate else {
+        return true;
+    };
+    same_user(&[certificate.common_name.as_deref(), certificate.email.as_deref()], names)
+}
+
+/// Whether two logins share a name, ignoring case
+fn same_user(names: &[Option<&str>], other: &[Option<&str>]) -> bool {
+    names
+        .iter()
+        .flatten()
+        .any(|name| other.iter().flatten().any(|other| other.eq_ignore_ascii_case(name)))
+}
+
+/// Names a local account logs in with
+fn local_names(user: &User) -> [Option<&str>; 2] {
+    [Some(user.id.as_str()), user.email.as_deref()]
+}
+
+/// Record a verified local password login on its connection
+///
+/// With OAuth2 enabled the password is a second factor: the OAuth2 login
+/// still decides the username, groups and method, and must be for the same
+/// account. Otherwise the account's groups apply, and the method stays
+/// `Certificate` if a client certificate was verified.
+fn accept_password_login(conn: &mut Connection, user: User, oauth_enabled: bool) {
+    if !oauth_enabled {
+        conn.groups = user.groups.clone();
+        conn.username = Some(user.email.clone().unwrap_or_else(|| user.id.to_string()));
+        if conn.certificate.is_none() {
+            conn.auth_method = AuthMethod::UsernamePassword;
+        }
+    }
+    conn.password_user = Some(user);
+}
+
+/// Verify a connection's auth-user-pass credentials (and TOTP code) against the local user accounts
+///
+/// A client certificate, if presented, must belong to the same user.
+async fn check_password_login(
+    server: Arc<VpnServer>,
+    users: Arc<dyn UserStore>,
+    peer_addr: SocketAddr,
+    connection_id: ConnectionId,
+    certificate: Option<CertificateIdentity>,
+    auth: Option<AuthMessage>,
+) {
+    let result = match auth {
+        Some(auth) => {
+            corevpn_auth::totp::login(users.as_ref(), server.totp.as_deref(), &auth.username, &auth.password)
+                .await
         }
+        None => Err(AuthError::InvalidCredentials),
+    };
 
-        // Collect retransmits
-        for retransmit in conn.protocol.get_retransmits() {
-            pending_packets.push(retransmit);
+    let outcome = match result {
+        Ok(Login::Challenge(challenge)) => {
+            debug!("Asking {} for a TOTP code", peer_addr);
+            let message = ControlMessage::AuthFailed(Some(challenge));
+            if let Err(e) = send_to_connection(&server, peer_addr, connection_id, |_| vec![message]).await {
+                debug!("Failed to send TOTP challenge to {}: {}", peer_addr, e);
+            }
+            return;
+        }
+        Ok(Login::Accepted(user)) => {
+            if matches_certificate(certificate.as_ref(), &local_names(&user)) {
+                info!("Password login for {} accepted from {}", user.id, peer_addr);
+                Ok(*user)
+            } else {
+                warn!(
+                    "Password login for {} from {} does not match certificate {:?}",
+                    user.id, peer_addr, certificate.as_ref().and_then(CertificateIdentity::username)
+                );
+                Err(AuthResult::NotAuthorized)
+            }
+        }
+        Err(e) => {
+            warn!("Password login from {} rejected: {}", peer_addr, e);
+            Err(match e {
+                AuthError::InvalidCredentials | AuthError::InvalidOtp => AuthResult::InvalidCredentials,
+                AuthError::InvalidState => AuthResult::Expired,
+                AuthError::UserDisabled | AuthError::MfaRequired => AuthResult::NotAuthorized,
+                _ => AuthResult::Unknown,
+            })
         }
+    };
 
-        packets_to_send = pending_packets;
-    } // Lock released here
+    let (username, auth_result) = match &outcome {
+        Ok(user) => (Some(user.id.to_string()), AuthResult::Success),
+        Err(result) => (certificate.as_ref().and_then(|c| c.username()).map(String::from), result.clone()),
+    };
 
-    // Log any events
-    for event in log_events {
+    let oauth_enabled = server.oauth.is_some();
+    let sent = send_to_connection(&server, peer_addr, connection_id, |conn| match outcome {
+        Ok(user) => {
+            accept_password_login(conn, user, oauth_enabled);
+            Vec::new()
+        }
+        Err(_) => vec![ControlMessage::AuthFailed(None)],
+    })
+    .await;
+    match sent {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => debug!("Failed to answer password login from {}: {}", peer_addr, e),
+    }
+
+    if server.config.logging.connection_events.auth_events {
+        let event = ConnectionEventBuilder::with_id(connection_id).authentication(
+            peer_addr,
+            username,
+            AuthMethod::UsernamePassword,
+            auth_result,
+        );
         server.log_event(event).await;
     }
+}
 
-    // Now send all collected packets without holding the lock
-    for packet in packets_to_send {
-        socket.send_to(&packet, peer_addr).await?;
+/// Assign a VPN address if needed and answer with the client's PUSH_REPLY
+fn handle_push_request(
+    server: &VpnServer,
+    conn: &mut Connection,
+    peer_addr: SocketAddr,
+    log_events: &mut Vec<ConnectionEvent>,
+) -> Result<()> {
+    if let Some(ref engine) = server.policy {
+        if conn.access.is_none() {
+            let access = evaluate_access(server, engine, conn);
+            audit_policy_decision(server, conn, &access);
+            conn.access = Some(access);
+        }
+        if conn.access.as_ref().is_some_and(AccessPolicy::is_denied) {
+            warn!("Access policy denied {:?} from {}", conn.username, peer_addr);
+            conn.refused = true;
+            return send_control_message(
+                conn,
+                &ControlMessage::AuthFailed(Some("access denied by policy".into())),
+            );
+        }
+    }
+
+    let vpn_address = match conn.vpn_address {
+        Some(address) => address,
+        None => {
+            let key = server.lease_key(conn.username.as_deref());
+            let Some((address, primary)) = server.leases
+                .acquire(key.as_deref())
+                .ok()
+                .and_then(|address| Some((address, address.primary()?)))
+            else {
+                warn!("Address pool exhausted, rejecting {}", peer_addr);
+                return send_control_message(
+                    conn,
+                    &ControlMessage::AuthFailed(Some("address pool exhausted".into())),
+                );
+            };
+
+            conn.vpn_address = Some(address);
+            match address.ipv6 {
+                Some(ipv6) => info!("Assigned {} and {} to {}", primary, ipv6, peer_addr),
+                None => info!("Assigned {} to {}", primary, peer_addr),
+            }
+
+            if server.config.logging.connection_events.connects {
+                log_events.push(ConnectionEventBuilder::with_id(conn.connection_id).connected(
+                    peer_addr,
+                    primary,
+                    conn.username.clone(),
+                    conn.auth_method.clone(),
+                ));
+            }
+            address
+        }
+    };
+
+    publish_data_path(server, conn);
+
+    let mut reply = server.build_push_reply(
+        vpn_address,
+        conn.protocol.peer_id(),
+        conn.key_derivation,
+        conn.access.as_ref(),
+    );
+    debug!("PUSH_REPLY to {}: {}", peer_addr, reply.encode());
+
+    // Clients without a certificate send the token back in place of their password
+    // when renegotiating or reconnecting
+    if conn.certificate.is_none() {
+        let token = match conn.auth_token.clone() {
+            Some(token) => token,
+            None => {
+                let token = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .encode(corevpn_crypto::random_bytes::<32>());
+                server.grant_auth_token(conn, &token);
+                conn.auth_token = Some(token.clone());
+                token
+            }
+        };
+        reply.options.push(format!("auth-token {}", token));
+    }
+    send_control_message(conn, &ControlMessage::PushReply(reply))
+}
+
+/// Whether a renegotiation's key method message carries the token pushed to the session
+fn has_auth_token(conn: &Connection, client_km: &KeyMethodV2) -> bool {
+    match (&conn.auth_token, client_km.auth_message()) {
+        (Some(token), Some(auth)) => bool::from(token.as_bytes().ct_eq(auth.password.as_bytes())),
+        _ => false,
+    }
+}
+
+/// Give a new session the login of an earlier one whose auth token the client sent as its password
+///
+/// The token must be unexpired and sent with the username it was granted
+/// to. The session keeps the token, so it expires as first granted.
+fn resume_login(server: &VpnServer, conn: &mut Connection, auth: &AuthMessage) -> bool {
+    let tokens = server.auth_tokens.read();
+    let Some(grant) = tokens.get(&auth.password) else {
+        return false;
+    };
+    if grant.expires_at <= Instant::now() || grant.login != auth.username {
+        return false;
     }
 
+    conn.login = Some(grant.login.clone());
+    conn.username = grant.username.clone();
+    conn.groups = grant.groups.clone();
+    conn.auth_method = grant.auth_method.clone();
+    conn.password_user = grant.password_user.clone();
+    conn.oauth_state = grant.oauth_state.clone();
+    conn.auth_token = Some(auth.password.clone());
+    true
+}
+
+/// Evaluate the access policy for a connection, with the role and routes of its local account
+fn evaluate_access(server: &VpnServer, engine: &PolicyEngine, conn: &Connection) -> AccessPolicy {
+    let stored = server.account(conn.username.as_deref());
+    let account = conn.password_user.as_ref().or(stored.as_deref());
+    engine.evaluate(&Identity {
+        username: conn.username.as_deref(),
+        groups: &conn.groups,
+        role: account.map(|user| &user.role),
+        routes: account.map_or(&[], |user| &user.custom_routes),
+    })
+}
+
+/// Record the access policy evaluated for a connection in the audit log
+fn audit_policy_decision(server: &VpnServer, conn: &Connection, access: &AccessPolicy) {
+    let username = conn.username.as_deref().unwrap_or("unknown");
+    let source_ip = conn.peer_addr.ip().to_string();
+    let role = format!("{:?}", access.role());
+
+    let event = if access.is_denied() {
+        AuditEventBuilder::policy_denied(username, &source_ip, &role)
+    } else {
+        let routes: Vec<String> = match access.routes() {
+            Some(routes) => routes.iter().map(|net| net.to_string()).collect(),
+            None => vec!["*".to_string()],
+        };
+        AuditEventBuilder::policy_granted(username, &source_ip, &role, access.rules(), &routes)
+    };
+    server.audit.try_log(event.build());
+}
+
+/// Write a control message to the connection's TLS session
+fn send_control_message(conn: &mut Connection, message: &ControlMessage) -> Result<()> {
+    let tls = conn.tls.as_mut()
+        .ok_or_else(|| anyhow::anyhow!("No TLS session for {}", conn.peer_addr))?;
+    tls.write_plaintext(&message.encode())
+        .map_err(|e| anyhow::anyhow!("TLS write failed: {}", e))?;
     Ok(())
 }
 
-async fn handle_data_packet(
+/// Wrap pending TLS output in control packets
+fn flush_control(conn: &mut Connection) -> Result<Vec<Bytes>> {
+    let mut packets = Vec::new();
+    if let Some(ref mut tls) = conn.tls {
+        while tls.wants_write() {
+            let Some(tls_out) = tls.get_outgoing()
+                .map_err(|e| anyhow::anyhow!("TLS outgoing failed: {}", e))?
+            else {
+                break;
+            };
+            packets.push(conn.protocol.create_control_packet(tls_out)?);
+        }
+    }
+    Ok(packets)
+}
+
+/// Send control messages to a connection from outside the packet path
+///
+/// Returns `false` if the connection has gone away or was replaced.
+async fn send_to_connection(
     server: &VpnServer,
-    _socket: &UdpSocket,
     peer_addr: SocketAddr,
-    data: &[u8],
-) -> Result<()> {
-    // Get existing connection
-    let mut connections = server.connections.write();
-    let conn = match connections.get_mut(&peer_addr) {
-        Some(c) => c,
-        None => {
-            debug!("No session for data packet from {}", peer_addr);
-            return Ok(());
+    connection_id: ConnectionId,
+    messages: impl FnOnce(&mut Connection) -> Vec<ControlMessage>,
+) -> Result<bool> {
+    let (packets, transport) = {
+        let mut connections = server.connections.write(&peer_addr);
+        let Some(conn) = connections.get_mut(&peer_addr).filter(|c| c.connection_id == connection_id)
+        else {
+            return Ok(false);
+        };
+        for message in messages(conn) {
+            send_control_message(conn, &message)?;
         }
+        (flush_control(conn)?, conn.transport.clone())
     };
 
-    conn.touch();
+    for packet in packets {
+        transport.send(&packet, peer_addr).await?;
+    }
+    Ok(true)
+}
 
-    // Only process data if established
-    if conn.protocol.state() != ProtocolState::Established {
-        debug!("Data packet before established from {}", peer_addr);
+/// Decrypt a tunnel packet from an established client in place and queue it for the TUN device
+///
+/// P_DATA_V2 packets are matched to their connection by peer ID, so a UDP
+/// client whose address changed floats to the new one once its packet
+/// decrypts.
+fn handle_data_packet(server: &Arc<VpnServer>, peer_addr: SocketAddr, mut packet: BytesMut) -> Result<()> {
+    let path = match DataPacket::peek_peer_id(&packet) {
+        Some(peer_id) => server.peers.get(&peer_id),
+        None => server.data_paths.get(&peer_addr),
+    };
+    let Some(path) = path else {
+        debug!("No data path for packet from {}", peer_addr);
         return Ok(());
+    };
+
+    let received = packet.len();
+    path.channels.decrypt_in_place(&mut packet)?;
+
+    // Only authenticated packets count as activity
+    path.counters.record_rx(received);
+
+    if path.peer_addr != peer_addr {
+        if path.transport.is_stream() {
+            debug!("Dropping packet for peer {} from another connection {}", path.peer_id, peer_addr);
+            return Ok(());
+        }
+        float_connection(server, &path, peer_addr);
     }
+    let ip_packet = packet.freeze();
+    trace!("Received {} bytes of tunnel data from {}", ip_packet.len(), peer_addr);
 
-    // Track incoming bytes
-    conn.add_bytes_rx(data.len() as u64);
+    // Only forward packets sourced from the client's own VPN addresses
+    if !tunnel::source(&ip_packet).is_some_and(|ip| path.vpn_address.contains(ip)) {
+        trace!("Dropping spoofed or unroutable packet from {}", peer_addr);
+        return Ok(());
+    }
 
-    // Process data packet
-    let result = conn.protocol.process_packet(data)?;
+    if path.access.as_ref().is_some_and(|access| !access.allows(&ip_packet)) {
+        trace!("Access policy dropped packet from {}", peer_addr);
+        let dropped = path.denied_packets.lock().record();
+        if let (Some(dropped), Some(info)) = (dropped, PacketInfo::parse(&ip_packet)) {
+            let destination = match info.port {
+                Some(port) => SocketAddr::new(info.destination, port).to_string(),
+                None => info.destination.to_string(),
+            };
+            let event = AuditEventBuilder::policy_packet_denied(
+                path.username.as_deref().unwrap_or("unknown"),
+                &peer_addr.ip().to_string(),
+                &destination,
+                info.protocol,
+                dropped,
+            );
+            server.audit.try_log(event.build());
+        }
+        return Ok(());
+    }
 
-    if let ProcessedPacket::Data(ip_packet) = result {
-        trace!("Received {} bytes of tunnel data from {}", ip_packet.len(), peer_addr);
+    if let Some(ref tun_tx) = server.tun_tx {
+        if tun_tx.try_send(ip_packet).is_err() {
+            debug!("TUN queue full, dropping packet from {}", peer_addr);
+        }
     }
 
     Ok(())
@@ -567,15 +2719,481 @@ pub struct ServerStats {
 impl ServerStats {
     /// Get current stats from server
     pub fn from_server(server: &VpnServer) -> Self {
-        let connections = server.connections.read();
-        let active = connections.values()
-            .filter(|c| c.protocol.is_established())
-            .count();
+        let connections = server.connections
+            .filter_map(|_, conn| Some((conn.protocol.is_established(), conn.counters.snapshot())));
 
-        Self {
+        let mut stats = Self {
             connections: connections.len() as u64,
-            active_sessions: active as u64,
             ..Default::default()
+        };
+        for (established, transfer) in connections {
+            stats.active_sessions += u64::from(established);
+            stats.bytes_rx += transfer.bytes_rx;
+            stats.bytes_tx += transfer.bytes_tx;
+            stats.packets_rx += transfer.packets_rx;
+            stats.packets_tx += transfer.packets_tx;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    async fn test_server(data_dir: &std::path::Path) -> Arc<VpnServer> {
+        let mut config = ServerConfig::default_config("127.0.0.1");
+        config.server.data_dir = data_dir.to_path_buf();
+        config.security.tls_auth = false;
+        config.security.password_auth = true;
+        config.logging.connection_events.disconnects = true;
+        config.logging.connection_events.ip_changes = true;
+        Arc::new(VpnServer::new(config).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_startup_requires_client_authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default_config("127.0.0.1");
+        config.server.data_dir = dir.path().to_path_buf();
+        config.security.tls_auth = false;
+
+        // Without a CA nothing would authenticate clients
+        config.security.password_auth = false;
+        assert!(VpnServer::new(config.clone()).await.is_err());
+        config.security.password_auth = true;
+        assert!(VpnServer::new(config.clone()).await.is_ok());
+
+        // Tracked certificates can't lose their revocations
+        std::fs::write(config.cert_index_path(), "{\"crl_number\":1,\"certificates\":[]}").unwrap();
+        assert!(VpnServer::new(config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_float_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = test_server(dir.path()).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let old_addr: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let new_addr: SocketAddr = "198.51.100.20:50000".parse().unwrap();
+        let client_ip = Ipv4Addr::new(10, 8, 0, 2);
+        let key_material = KeyMaterial::from_key_block(&[7u8; KEY_BLOCK_SIZE]).unwrap();
+        let cipher_suite = server.get_cipher_suite();
+
+        // An authenticated session at the old address
+        let peer_id = server.peer_ids.allocate().unwrap();
+        let connection_id = ConnectionId::new();
+        let mut conn = Connection::new(
+            old_addr,
+            peer_id,
+            Transport::Udp(socket.clone()),
+            cipher_suite,
+            connection_id,
+        );
+        conn.protocol.set_peer_id(peer_id);
+        conn.protocol.install_keys(&key_material, true);
+        conn.vpn_address = Some(VpnAddress::v4(client_ip));
+        conn.username = Some("alice".to_string());
+        publish_data_path(&server, &conn);
+        server.connections.insert(old_addr, conn);
+
+        // A half-open session already using the new address
+        let stale_id = ConnectionId::new();
+        let stale_peer_id = server.peer_ids.allocate().unwrap();
+        let stale = Connection::new(new_addr, stale_peer_id, Transport::Udp(socket), cipher_suite, stale_id);
+        server.connections.insert(new_addr, stale);
+
+        let mut client = ProtocolSession::new_client(cipher_suite);
+        client.set_peer_id(peer_id);
+        client.install_keys(&key_material, false);
+        let mut ip_packet = vec![0u8; 20];
+        ip_packet[0] = 0x45;
+        ip_packet[12..16].copy_from_slice(&client_ip.octets());
+        let packet = client.encrypt_data(&ip_packet).unwrap();
+
+        // Packets that fail to decrypt neither count as traffic nor move the session
+        let mut forged = BytesMut::from(&packet[..]);
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(handle_data_packet(&server, new_addr, forged).is_err());
+        assert_eq!(server.peers.get(&peer_id).unwrap().counters.snapshot().packets_rx, 0);
+        assert!(server.data_paths.get(&old_addr).is_some());
+
+        handle_data_packet(&server, new_addr, BytesMut::from(&packet[..])).unwrap();
+        assert_eq!(server.peers.get(&peer_id).unwrap().counters.snapshot().packets_rx, 1);
+
+        assert!(!server.connections.read(&old_addr).contains_key(&old_addr));
+        {
+            let connections = server.connections.read(&new_addr);
+            let conn = connections.get(&new_addr).unwrap();
+            assert_eq!(conn.peer_id, peer_id);
+            assert_eq!(conn.connection_id, connection_id);
+        }
+        assert!(server.data_paths.get(&old_addr).is_none());
+        assert_eq!(server.data_paths.get(&new_addr).unwrap().peer_id, peer_id);
+        assert_eq!(server.peers.get(&peer_id).unwrap().peer_addr, new_addr);
+        assert!(server.peers.get(&stale_peer_id).is_none());
+        assert_eq!(server.vpn_routes.read()[&IpAddr::V4(client_ip)].peer_addr, new_addr);
+
+        // The move and the displaced session are both logged
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+            events = server.connection_logger.query_recent(10).await.unwrap().unwrap();
+            if events.len() >= 2 {
+                break;
+            }
+        }
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ConnectionEvent::IpChange { connection_id: id, old_addr: from, new_addr: to, .. }
+                if *id == connection_id && *from == old_addr && *to == new_addr
+        )));
+        assert!(events.iter().any(|event| event.connection_id() == stale_id && event.event_type() == "disconnected"));
+
+        // Removing the displaced session leaves the one now at its address alone
+        remove_connection(&server, new_addr, stale_id, DisconnectReason::IdleTimeout).await;
+        assert!(server.connections.read(&new_addr).contains_key(&new_addr));
+        remove_connection(&server, new_addr, connection_id, DisconnectReason::IdleTimeout).await;
+        assert!(!server.connections.read(&new_addr).contains_key(&new_addr));
+        assert!(server.peers.get(&peer_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_close_removes_only_its_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = test_server(dir.path()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        // P_CONTROL_HARD_RESET_CLIENT_V2, session ID, no ACKs, packet ID 0
+        let mut hard_reset = vec![0x38];
+        hard_reset.extend_from_slice(&[1; 8]);
+        hard_reset.extend_from_slice(&[0; 5]);
+
+        // A UDP session at the same address as a stream that never started one
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let udp_id = ConnectionId::new();
+        let udp = Connection::new(peer_addr, 0, Transport::Udp(socket.clone()), server.get_cipher_suite(), udp_id);
+        server.connections.insert(peer_addr, udp);
+        drop(client);
+        run_tcp_connection(server.clone(), stream, peer_addr).await;
+        assert!(server.connections.read(&peer_addr).contains_key(&peer_addr));
+
+        // A stream's own session ends with it
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let task = tokio::spawn(run_tcp_connection(server.clone(), stream, peer_addr));
+        client.write_all(&transport::encode_frame(&hard_reset).unwrap()).await.unwrap();
+        let mut response = [0u8; 2];
+        tokio::io::AsyncReadExt::read_exact(&mut client, &mut response).await.unwrap();
+        assert!(server.connections.read(&peer_addr).contains_key(&peer_addr));
+        drop(client);
+        task.await.unwrap();
+        assert!(!server.connections.read(&peer_addr).contains_key(&peer_addr));
+    }
+
+    #[tokio::test]
+    async fn test_egress_workers() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = test_server(dir.path()).await;
+        let sockets = transport::bind_udp("127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        let egress = EgressQueues::spawn(&sockets);
+        let key_material = KeyMaterial::from_key_block(&[7u8; KEY_BLOCK_SIZE]).unwrap();
+        let cipher_suite = server.get_cipher_suite();
+        let mut pool = PacketPool::new(8, packet_size(&server));
+
+        // Two clients, each handled by a different egress worker
+        let mut clients = Vec::new();
+        for peer_id in 0..2u32 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer_addr = socket.local_addr().unwrap();
+            let client_ip = Ipv4Addr::new(10, 8, 0, 2 + peer_id as u8);
+            let mut conn = Connection::new(
+                peer_addr,
+                peer_id,
+                Transport::Udp(sockets[0].clone()),
+                cipher_suite,
+                ConnectionId::new(),
+            );
+            conn.protocol.set_peer_id(peer_id);
+            conn.protocol.install_keys(&key_material, true);
+            conn.vpn_address = Some(VpnAddress::v4(client_ip));
+            publish_data_path(&server, &conn);
+
+            let mut client = ProtocolSession::new_client(cipher_suite);
+            client.set_peer_id(peer_id);
+            client.install_keys(&key_material, false);
+            clients.push((socket, client, client_ip));
+        }
+
+        for (_, _, client_ip) in &clients {
+            for seq in 0..3u8 {
+                let mut packet = pool.plaintext();
+                packet.resize(PACKET_HEADROOM + 20, 0);
+                packet[PACKET_HEADROOM] = 0x45;
+                packet[PACKET_HEADROOM + 1] = seq;
+                packet[PACKET_HEADROOM + 16..PACKET_HEADROOM + 20].copy_from_slice(&client_ip.octets());
+                egress.route(&server, packet);
+            }
+        }
+
+        // Every client gets its own packets, encrypted, in order
+        for (socket, client, _) in &clients {
+            let mut buf = [0u8; 2048];
+            for seq in 0..3u8 {
+                let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+                let plaintext = client.data_channels().decrypt(&buf[..len]).unwrap();
+                assert_eq!(plaintext[1], seq);
+            }
         }
+        for peer_id in 0..2 {
+            assert_eq!(server.peers.get(&peer_id).unwrap().counters.snapshot().packets_tx, 3);
+        }
+    }
+
+    #[test]
+    fn test_matches_certificate() {
+        let certificate = CertificateIdentity {
+            serial: "01".to_string(),
+            common_name: Some("alice".to_string()),
+            email: Some("alice@example.com".to_string()),
+        };
+
+        assert!(matches_certificate(None, &[Some("bob@example.com")]));
+        assert!(matches_certificate(Some(&certificate), &[None, Some("ALICE")]));
+        assert!(matches_certificate(Some(&certificate), &[Some("Alice@Example.com"), Some("00u1a2b3")]));
+
+        // alice's certificate with bob's login
+        assert!(!matches_certificate(Some(&certificate), &[Some("bob@example.com"), Some("bob")]));
+        assert!(!matches_certificate(Some(&certificate), &[None]));
+    }
+
+    #[tokio::test]
+    async fn test_password_login_with_oauth() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let connection = || Connection::new(
+            addr,
+            0,
+            Transport::Udp(socket.clone()),
+            CipherSuite::Aes256Gcm,
+            ConnectionId::new(),
+        );
+        let mut user = User::new(UserId::new("alice")).with_email("alice@example.com");
+        user.groups = vec!["local".to_string()];
+
+        // Password only: the local account decides
+        let mut conn = connection();
+        accept_password_login(&mut conn, user.clone(), false);
+        assert_eq!(conn.auth_method, AuthMethod::UsernamePassword);
+        assert_eq!(conn.groups, vec!["local".to_string()]);
+        assert_eq!(conn.username.as_deref(), Some("alice@example.com"));
+
+        // After an OAuth2 login the provider's identity stays
+        let mut conn = connection();
+        conn.auth_method = AuthMethod::OAuth2;
+        conn.groups = vec!["engineering".to_string()];
+        conn.username = Some("alice@example.com".to_string());
+        accept_password_login(&mut conn, user, true);
+        assert_eq!(conn.auth_method, AuthMethod::OAuth2);
+        assert_eq!(conn.groups, vec!["engineering".to_string()]);
+        assert!(conn.is_authenticated());
+
+        let local = conn.password_user.as_ref().unwrap();
+        assert!(same_user(&local_names(local), &[Some("ALICE@example.com"), Some("00u1a2b3")]));
+        assert!(!same_user(&local_names(local), &[Some("bob@example.com"), Some("bob")]));
+    }
+
+    #[tokio::test]
+    async fn test_accept_saml_login() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let connection = || Connection::new(
+            "192.0.2.10:40000".parse().unwrap(),
+            0,
+            Transport::Udp(socket.clone()),
+            CipherSuite::Aes256Gcm,
+            ConnectionId::new(),
+        );
+        let user = UserInfo {
+            sub: "alice@example.com".to_string(),
+            email: Some("alice@example.com".to_string()),
+            email_verified: true,
+            name: None,
+            given_name: None,
+            family_name: None,
+            picture: None,
+            groups: vec!["engineering".to_string()],
+            provider: "saml".to_string(),
+        };
+
+        let mut conn = connection();
+        assert!(!conn.is_authenticated());
+        accept_sso_login(&mut conn, user.clone(), AuthMethod::Saml).unwrap();
+        assert_eq!(conn.auth_method, AuthMethod::Saml);
+        assert_eq!(conn.username.as_deref(), Some("alice@example.com"));
+        assert_eq!(conn.groups, vec!["engineering".to_string()]);
+        assert!(conn.is_authenticated());
+
+        // bob's certificate with alice's login
+        let mut conn = connection();
+        conn.certificate = Some(CertificateIdentity {
+            serial: "02".to_string(),
+            common_name: Some("bob".to_string()),
+            email: Some("bob@example.com".to_string()),
+        });
+        assert_eq!(accept_sso_login(&mut conn, user, AuthMethod::Saml), Err("client certificate"));
+        assert_eq!(conn.auth_method, AuthMethod::Unknown);
+        assert!(conn.groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_policy_uses_account_role_and_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = test_server(dir.path()).await;
+        let engine = PolicyEngine::new(&corevpn_config::PolicySettings::default()).unwrap();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut conn = Connection::new(
+            "192.0.2.10:40000".parse().unwrap(),
+            0,
+            Transport::Udp(socket),
+            CipherSuite::Aes256Gcm,
+            ConnectionId::new(),
+        );
+        conn.username = Some("Carol@example.com".to_string());
+
+        // Default deny without an account
+        assert!(evaluate_access(&server, &engine, &conn).is_denied());
+
+        let mut user = User::new(UserId::new("carol")).with_email("carol@example.com");
+        user.custom_routes = vec![corevpn_core::Route::new("10.99.0.0/24".parse().unwrap())];
+        server.users.upsert_user(&user).await.unwrap();
+        server.sync_accounts().await;
+        let access = evaluate_access(&server, &engine, &conn);
+        assert_eq!(access.routes().unwrap(), vec!["10.99.0.0/24".parse::<IpNet>().unwrap()]);
+
+        user.role = corevpn_core::UserRole::ReadOnly;
+        server.users.upsert_user(&user).await.unwrap();
+        server.sync_accounts().await;
+        let access = evaluate_access(&server, &engine, &conn);
+        assert_eq!(access.role(), &corevpn_core::UserRole::ReadOnly);
+        assert!(access.is_denied());
+    }
+
+    #[tokio::test]
+    async fn test_restricted_dual_stack_push_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig::default_config("127.0.0.1");
+        config.server.data_dir = dir.path().to_path_buf();
+        config.security.tls_auth = false;
+        config.security.password_auth = true;
+        config.network.subnet_v6 = Some("fd00:8::/64".to_string());
+        let server = VpnServer::new(config).await.unwrap();
+
+        let settings: corevpn_config::PolicySettings = serde_json::from_value(serde_json::json!({
+            "enabled": true,
+            "rules": [{
+                "name": "internal",
+                "groups": ["engineering"],
+                "allow": [{ "cidr": "10.20.0.0/16" }, { "cidr": "fd00:20::/48" }],
+            }],
+        }))
+        .unwrap();
+        let engine = PolicyEngine::new(&settings).unwrap();
+        let groups = vec!["engineering".to_string()];
+        let routes = vec![corevpn_core::Route::new("fd00:99::/64".parse().unwrap())];
+        let access = engine.evaluate(&Identity { username: Some("dave"), groups: &groups, role: None, routes: &routes });
+
+        let address = VpnAddress { ipv4: Some("10.8.0.2".parse().unwrap()), ipv6: Some("fd00:8::2".parse().unwrap()) };
+        let reply = server.build_push_reply(address, None, KeyDerivation::Prf, Some(&access));
+        assert!(reply.ifconfig_ipv6.is_some());
+        assert!(!reply.redirect_gateway && !reply.redirect_gateway_ipv6);
+        assert_eq!(reply.routes.len(), 1);
+        assert_eq!((reply.routes[0].network.as_str(), reply.routes[0].netmask.as_str()), ("10.20.0.0", "255.255.0.0"));
+        assert_eq!(reply.routes_ipv6, ["fd00:20::/48", "fd00:99::/64"]);
+
+        // Granted IPv6 destinations pass the packet filter
+        let mut packet = vec![0u8; 44];
+        packet[0] = 0x60;
+        packet[6] = 17;
+        packet[24..40].copy_from_slice(&"fd00:20::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        assert!(access.allows(&packet));
+        packet[24..40].copy_from_slice(&"fd00:30::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        assert!(!access.allows(&packet));
+    }
+
+    #[tokio::test]
+    async fn test_resume_login_with_auth_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = test_server(dir.path()).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let connection = || Connection::new(
+            addr,
+            0,
+            Transport::Udp(socket.clone()),
+            CipherSuite::Aes256Gcm,
+            ConnectionId::new(),
+        );
+        let auth = |username: &str, password: &str| AuthMessage {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+
+        let mut first = connection();
+        first.login = Some("alice".to_string());
+        accept_password_login(&mut first, User::new(UserId::new("alice")), false);
+        server.grant_auth_token(&first, "token");
+
+        // Only with the username it was granted to
+        let mut conn = connection();
+        assert!(!resume_login(&server, &mut conn, &auth("bob", "token")));
+        assert!(!resume_login(&server, &mut conn, &auth("alice", "secret")));
+        assert!(!conn.is_authenticated());
+
+        assert!(resume_login(&server, &mut conn, &auth("alice", "token")));
+        assert!(conn.is_authenticated());
+        assert_eq!(conn.username.as_deref(), Some("alice"));
+        assert_eq!(conn.auth_method, AuthMethod::UsernamePassword);
+        assert_eq!(conn.auth_token.as_deref(), Some("token"));
+
+        // Accounts that are gone or disabled can't resume
+        server.sync_accounts().await;
+        assert!(!resume_login(&server, &mut connection(), &auth("alice", "token")));
+
+        // Nor can expired tokens
+        server.grant_auth_token(&first, "token");
+        server.auth_tokens.write().get_mut("token").unwrap().expires_at = Instant::now();
+        assert!(!resume_login(&server, &mut connection(), &auth("alice", "token")));
+        server.expire_auth_tokens();
+        assert!(server.auth_tokens.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_renegotiation_auth_token() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let mut conn = Connection::new(
+            addr,
+            0,
+            Transport::Udp(socket),
+            CipherSuite::Aes256Gcm,
+            ConnectionId::new(),
+        );
+        let key_method = |password: Option<&str>| KeyMethodV2 {
+            username: Some("alice".to_string()),
+            password: password.map(String::from),
+            ..KeyMethodV2::new_server(String::new())
+        };
+
+        // No token has been pushed yet
+        assert!(!has_auth_token(&conn, &key_method(Some(""))));
+
+        conn.auth_token = Some("token".to_string());
+        assert!(has_auth_token(&conn, &key_method(Some("token"))));
+        assert!(!has_auth_token(&conn, &key_method(Some("secret"))));
+        assert!(!has_auth_token(&conn, &key_method(None)));
+        assert!(!has_auth_token(&conn, &KeyMethodV2::new_server(String::new())));
     }
 }
@@ -425,6 +425,8 @@ fn setup_google_oauth(theme: &ColorfulTheme) -> Result<corevpn_config::server::O
         domain: None,
         allowed_domains: if domain.is_empty() { vec![] } else { vec![domain] },
         required_groups: vec![],
+        session_check_interval_secs: 300,
+        token_introspection: false,
     })
 }
 
@@ -447,8 +449,7 @@ fn setup_microsoft_oauth(theme: &ColorfulTheme) -> Result<corevpn_config::server
         .interact_text()?;
 
     let tenant_id: String = Input::with_theme(theme)
-        .with_prompt("  Tenant ID (or 'common' for multi-tenant)")
-        .default("common".to_string())
+        .with_prompt("  Directory (tenant) ID")
         .interact_text()?;
 
     Ok(corevpn_config::server::OAuthSettings {
@@ -461,6 +462,8 @@ fn setup_microsoft_oauth(theme: &ColorfulTheme) -> Result<corevpn_config::server
         domain: None,
         allowed_domains: vec![],
         required_groups: vec![],
+        session_check_interval_secs: 300,
+        token_introspection: false,
     })
 }
 
@@ -495,5 +498,7 @@ fn setup_okta_oauth(theme: &ColorfulTheme) -> Result<corevpn_config::server::OAu
         domain: Some(domain),
         allowed_domains: vec![],
         required_groups: vec![],
+        session_check_interval_secs: 300,
+        token_introspection: false,
     })
 }
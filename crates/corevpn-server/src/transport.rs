@@ -0,0 +1,185 @@
+//! Packet Transports
+//!
+//! UDP datagrams and OpenVPN's TCP stream framing, where every packet is
+//! prefixed with its length as a 2-byte big-endian integer.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Size of the TCP frame length prefix
+pub const FRAME_HEADER_SIZE: usize = 2;
+
+/// Depth of a TCP connection's outbound queue
+pub const TCP_QUEUE_DEPTH: usize = 256;
+
+/// Outbound path to a peer
+#[derive(Clone)]
+pub enum Transport {
+    /// Shared UDP socket
+    Udp(Arc<UdpSocket>),
+    /// Queue feeding the TCP connection's writer task
+    Tcp(mpsc::Sender<Bytes>),
+}
+
+impl Transport {
+    /// Whether this is a stream transport (no retransmits needed)
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Transport::Tcp(_))
+    }
+
+    /// OpenVPN protocol name for the options string
+    pub fn proto_name(&self) -> &'static str {
+        match self {
+            Transport::Udp(_) => "UDPv4",
+            Transport::Tcp(_) => "TCPv4_SERVER",
+        }
+    }
+
+    /// Send a packet to the peer
+    pub async fn send(&self, data: &[u8], peer_addr: SocketAddr) -> Result<()> {
+        match self {
+            Transport::Udp(socket) => {
+                socket.send_to(data, peer_addr).await?;
+            }
+            Transport::Tcp(tx) => {
+                tx.send(encode_frame(data)?).await
+                    .map_err(|_| anyhow::anyhow!("TCP connection to {} closed", peer_addr))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a packet to the peer without waiting
+    ///
+    /// Fails instead of blocking when the socket buffer or the TCP
+    /// connection's outbound queue is full, so a slow peer never holds up
+    /// the caller.
+    pub fn try_send(&self, data: &[u8], peer_addr: SocketAddr) -> Result<()> {
+        match self {
+            Transport::Udp(socket) => {
+                socket.try_send_to(data, peer_addr)?;
+            }
+            Transport::Tcp(tx) => {
+                tx.try_send(encode_frame(data)?).map_err(|e| match e {
+                    mpsc::error::TrySendError::Full(_) => anyhow::anyhow!("TCP queue to {} full", peer_addr),
+                    mpsc::error::TrySendError::Closed(_) => anyhow::anyhow!("TCP connection to {} closed", peer_addr),
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bind UDP sockets sharing one address
+///
+/// On Unix every socket sets `SO_REUSEPORT`, so the kernel spreads peers
+/// across them by their address. Elsewhere a single socket is bound.
+pub fn bind_udp(addr: SocketAddr, count: usize) -> Result<Vec<Arc<UdpSocket>>> {
+    let count = if cfg!(unix) { count.max(1) } else { 1 };
+    (0..count)
+        .map(|_| {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            Ok(Arc::new(UdpSocket::from_std(socket.into())?))
+        })
+        .collect()
+}
+
+/// Prefix a packet with its 2-byte length
+pub fn encode_frame(data: &[u8]) -> Result<Bytes> {
+    let len = u16::try_from(data.len())
+        .map_err(|_| anyhow::anyhow!("Packet too large for TCP framing: {} bytes", data.len()))?;
+
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + data.len());
+    buf.put_u16(len);
+    buf.put_slice(data);
+    Ok(buf.freeze())
+}
+
+/// Read one length-prefixed packet from a stream
+///
+/// Returns `None` on a clean end of stream.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Bytes>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u16::from_be_bytes(header) as usize;
+    if len == 0 {
+        return Err(anyhow::anyhow!("Empty TCP frame"));
+    }
+
+    let mut packet = vec![0u8; len];
+    reader.read_exact(&mut packet).await?;
+    Ok(Some(Bytes::from(packet)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&encode_frame(&[0x38, 1, 2, 3]).unwrap());
+        stream.extend_from_slice(&encode_frame(&[0x20; 300]).unwrap());
+        assert_eq!(&stream[..2], &[0, 4]);
+
+        let mut reader = &stream[..];
+        assert_eq!(&read_frame(&mut reader).await.unwrap().unwrap()[..], &[0x38, 1, 2, 3]);
+        assert_eq!(read_frame(&mut reader).await.unwrap().unwrap().len(), 300);
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame() {
+        let stream = [0u8, 10, 1, 2, 3];
+        let mut reader = &stream[..];
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_shared_port() {
+        let sockets = bind_udp("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        let addr = sockets[0].local_addr().unwrap();
+
+        // Further sockets can join the bound port
+        let count = if cfg!(unix) { 2 } else { 1 };
+        let sockets = bind_udp(addr, count).unwrap();
+        assert_eq!(sockets.len(), count);
+        assert!(sockets.iter().all(|socket| socket.local_addr().unwrap() == addr));
+    }
+
+    #[tokio::test]
+    async fn test_try_send_full_queue() {
+        let peer_addr = "127.0.0.1:1194".parse().unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        let tcp = Transport::Tcp(tx);
+
+        tcp.try_send(&[1, 2, 3], peer_addr).unwrap();
+        assert!(tcp.try_send(&[4, 5, 6], peer_addr).is_err());
+        assert_eq!(&rx.recv().await.unwrap()[..], &[0, 3, 1, 2, 3]);
+        tcp.try_send(&[7], peer_addr).unwrap();
+
+        drop(rx);
+        assert!(tcp.try_send(&[8], peer_addr).is_err());
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        assert!(encode_frame(&vec![0u8; 70000]).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! TUN Device
+//!
+//! Creates the server-side TUN interface and provides helpers for
+//! inspecting the IP packets that flow through it.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::Result;
+use ipnet::{Ipv4Net, Ipv6Net};
+use tokio::io::{ReadHalf, WriteHalf};
+use tracing::{info, warn};
+
+use corevpn_config::server::NetworkSettings;
+
+/// Default TUN interface name
+pub const TUN_NAME: &str = "corevpn0";
+
+/// Read half of the TUN device
+pub type TunReader = ReadHalf<tun::AsyncDevice>;
+
+/// Write half of the TUN device
+pub type TunWriter = WriteHalf<tun::AsyncDevice>;
+
+/// Create and bring up the TUN device described by the network settings
+///
+/// The interface takes the gateway address (first host of the subnet)
+/// and the subnet's netmask, so the kernel routes the whole VPN subnet
+/// through it. The IPv6 subnet, if any, is added the same way.
+pub fn create_tun(network: &NetworkSettings) -> Result<(TunReader, TunWriter)> {
+    let subnet: Ipv4Net = network.subnet.parse()
+        .map_err(|e| anyhow::anyhow!("Invalid subnet: {}", e))?;
+    let gateway = Ipv4Addr::from(u32::from(subnet.network()) + 1);
+
+    let mut tun_config = tun::Configuration::default();
+    tun_config
+        .name(TUN_NAME)
+        .address(gateway)
+        .netmask(subnet.netmask())
+        .mtu(network.mtu as i32)
+        .up();
+
+    #[cfg(target_os = "linux")]
+    tun_config.platform(|platform| {
+        platform.packet_information(false);
+    });
+
+    let device = tun::create_as_async(&tun_config)
+        .map_err(|e| anyhow::anyhow!("Failed to create TUN device: {}", e))?;
+
+    info!("TUN device {} up: {}/{} mtu {}", TUN_NAME, gateway, subnet.prefix_len(), network.mtu);
+
+    if let Some(ref subnet_v6) = network.subnet_v6 {
+        let subnet: Ipv6Net = subnet_v6.parse()
+            .map_err(|e| anyhow::anyhow!("Invalid IPv6 subnet: {}", e))?;
+        let gateway = Ipv6Addr::from(u128::from(subnet.network()) + 1);
+        match add_ipv6_address(gateway, subnet.prefix_len()) {
+            Ok(()) => info!("TUN device {} IPv6: {}/{}", TUN_NAME, gateway, subnet.prefix_len()),
+            Err(e) => warn!("{}, IPv6 tunnel traffic will not be routed", e),
+        }
+    }
+
+    Ok(tokio::io::split(device))
+}
+
+/// Assign an IPv6 address to the TUN device (the tun crate only sets IPv4)
+#[cfg(target_os = "linux")]
+fn add_ipv6_address(address: Ipv6Addr, prefix_len: u8) -> Result<()> {
+    let status = std::process::Command::new("ip")
+        .args(["-6", "addr", "replace", &format!("{}/{}", address, prefix_len), "dev", TUN_NAME])
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to run ip: {}", e))?;
+    if !status.success() {
+        anyhow::bail!("Failed to add IPv6 address to {}: ip exited with {}", TUN_NAME, status);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn add_ipv6_address(_address: Ipv6Addr, _prefix_len: u8) -> Result<()> {
+    anyhow::bail!("IPv6 TUN addresses are only supported on Linux")
+}
+
+/// Get the source address of an IPv4 or IPv6 packet
+pub fn source(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 12))),
+        6 if packet.len() >= 40 => Some(IpAddr::V6(ipv6_at(packet, 8))),
+        _ => None,
+    }
+}
+
+/// Get the destination address of an IPv4 or IPv6 packet
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 16))),
+        6 if packet.len() >= 40 => Some(IpAddr::V6(ipv6_at(packet, 24))),
+        _ => None,
+    }
+}
+
+fn ipv4_at(packet: &[u8], offset: usize) -> Ipv4Addr {
+    let octets: [u8; 4] = packet[offset..offset + 4].try_into().unwrap_or_default();
+    Ipv4Addr::from(octets)
+}
+
+fn ipv6_at(packet: &[u8], offset: usize) -> Ipv6Addr {
+    let octets: [u8; 16] = packet[offset..offset + 16].try_into().unwrap_or_default();
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dst);
+        packet
+    }
+
+    #[test]
+    fn test_ipv4_addresses() {
+        let packet = ipv4_packet([10, 8, 0, 2], [1, 1, 1, 1]);
+        assert_eq!(source(&packet), Some(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2))));
+        assert_eq!(destination(&packet), Some(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))));
+    }
+
+    #[test]
+    fn test_ipv6_addresses() {
+        let src: Ipv6Addr = "fd00:8::2".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[8..24].copy_from_slice(&src.octets());
+        packet[24..40].copy_from_slice(&dst.octets());
+
+        assert_eq!(source(&packet), Some(IpAddr::V6(src)));
+        assert_eq!(destination(&packet), Some(IpAddr::V6(dst)));
+        assert_eq!(destination(&packet[..39]), None);
+    }
+
+    #[test]
+    fn test_malformed_rejected() {
+        // IPv6 version nibble on an IPv4-sized packet
+        let mut packet = ipv4_packet([10, 8, 0, 2], [1, 1, 1, 1]);
+        packet[0] = 0x60;
+        assert_eq!(source(&packet), None);
+        assert_eq!(destination(&packet[..10]), None);
+        assert_eq!(destination(&[]), None);
+    }
+}
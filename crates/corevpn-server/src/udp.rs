@@ -0,0 +1,671 @@
+//! Batched UDP I/O
+//!
+//! [`BatchSocket`] moves many datagrams per system call. On Linux it
+//! receives with `recvmmsg` and sends with `sendmmsg`, turns on UDP GRO so
+//! the kernel can hand over several datagrams of one flow in a single
+//! buffer, and coalesces runs of equal-sized packets to the same peer into
+//! one UDP GSO send. Other platforms fall back to one datagram per call.
+//!
+//! Datagrams are received straight into buffers from a [`PacketPool`], so
+//! each one travels on in its own buffer. Only the tail of a datagram too
+//! large for a pooled buffer, and the later datagrams of a GRO-merged run,
+//! are copied out of the shared overflow space.
+
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bytes::{Bytes, BytesMut};
+use tokio::net::UdpSocket;
+
+use crate::pool::PacketPool;
+
+/// Datagrams received or sent per system call
+pub const BATCH_SIZE: usize = 32;
+
+/// Overflow space per datagram, large enough for a GRO-merged run
+pub const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Most segments the kernel accepts in one GSO send (`UDP_MAX_SEGMENTS`)
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// Largest UDP payload, which bounds a whole GSO send
+const MAX_GSO_BYTES: usize = 65507;
+
+/// Datagram received into a [`RecvBatch`] slot
+#[derive(Debug, Clone, Copy)]
+struct RecvMeta {
+    /// Slot the datagram was received into
+    slot: usize,
+    /// Sender address
+    addr: SocketAddr,
+    /// Bytes received, continuing into the slot's overflow space
+    len: usize,
+    /// Size of each GRO-merged datagram (`len` when nothing was merged)
+    segment_size: usize,
+}
+
+/// Receive buffers for one batch of datagrams
+pub struct RecvBatch {
+    /// Pooled buffer each slot receives into first, taken for one receive
+    packets: Vec<BytesMut>,
+    /// Where datagrams longer than their pooled buffer continue
+    overflow: Vec<u8>,
+    meta: Vec<RecvMeta>,
+    /// Datagrams from the last receive
+    received: Vec<(SocketAddr, BytesMut)>,
+}
+
+impl RecvBatch {
+    /// Allocate buffers for [`BATCH_SIZE`] datagrams
+    pub fn new() -> Self {
+        Self {
+            packets: Vec::with_capacity(BATCH_SIZE),
+            overflow: vec![0u8; BATCH_SIZE * RECV_BUFFER_SIZE],
+            meta: Vec::with_capacity(BATCH_SIZE),
+            received: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Datagrams from the last receive, with GRO-merged runs split apart
+    pub fn datagrams(&mut self) -> impl Iterator<Item = (SocketAddr, BytesMut)> + '_ {
+        self.received.drain(..)
+    }
+
+    /// Give every slot an empty pooled buffer to receive into
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn fill(&mut self, pool: &mut PacketPool) {
+        self.meta.clear();
+        self.packets.clear();
+        self.packets.extend((0..BATCH_SIZE).map(|_| pool.take()));
+    }
+
+    /// Move the received datagrams out of their slots
+    ///
+    /// A datagram that fits its pooled buffer is handed on as is. A GRO run
+    /// keeps its first datagram in the slot's buffer and copies the others
+    /// into buffers of their own. Buffers left unused go straight back to
+    /// the pool rather than waiting here for the next receive.
+    fn split(&mut self, pool: &mut PacketPool) {
+        for meta in self.meta.drain(..) {
+            let mut packet = std::mem::take(&mut self.packets[meta.slot]);
+            let in_packet = packet.len();
+            let overflow = &self.overflow[meta.slot * RECV_BUFFER_SIZE..][..meta.len - in_packet];
+            let segment_size = meta.segment_size.max(1);
+            let first_len = segment_size.min(meta.len);
+
+            let first = self.received.len();
+            self.received.push((meta.addr, BytesMut::new()));
+            for start in (first_len..meta.len).step_by(segment_size) {
+                let end = (start + segment_size).min(meta.len);
+                let mut datagram = pool.take();
+                datagram.extend_from_slice(&packet[start.min(in_packet)..end.min(in_packet)]);
+                datagram.extend_from_slice(&overflow[start.saturating_sub(in_packet)..end.saturating_sub(in_packet)]);
+                self.received.push((meta.addr, datagram));
+            }
+
+            if first_len <= in_packet {
+                packet.truncate(first_len);
+            } else {
+                packet.extend_from_slice(&overflow[..first_len - in_packet]);
+            }
+            self.received[first].1 = packet;
+        }
+        self.packets.clear();
+    }
+}
+
+impl Default for RecvBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Outgoing datagrams queued for one batched send
+#[derive(Default)]
+pub struct SendBatch {
+    packets: Vec<(SocketAddr, Bytes)>,
+    /// Scratch space for grouping packets into sends
+    runs: Vec<Range<usize>>,
+}
+
+impl SendBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Self {
+            packets: Vec::with_capacity(BATCH_SIZE),
+            runs: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Queue a datagram
+    pub fn push(&mut self, addr: SocketAddr, packet: Bytes) {
+        self.packets.push((addr, packet));
+    }
+
+    /// Check if nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// UDP socket sending and receiving in batches
+pub struct BatchSocket {
+    socket: Arc<UdpSocket>,
+    /// Whether sends may be coalesced with UDP GSO
+    gso: AtomicBool,
+}
+
+impl BatchSocket {
+    /// Wrap a socket, enabling UDP GRO and GSO where the kernel supports them
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
+        #[cfg(target_os = "linux")]
+        let gso = {
+            if !linux::enable_gro(&socket) {
+                tracing::debug!("UDP GRO unavailable, receiving one datagram per buffer");
+            }
+            linux::gso_supported(&socket)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let gso = false;
+
+        Self { socket, gso: AtomicBool::new(gso) }
+    }
+
+    /// The wrapped socket
+    pub fn socket(&self) -> &Arc<UdpSocket> {
+        &self.socket
+    }
+
+    /// Whether sends are coalesced with UDP GSO
+    pub fn gso_enabled(&self) -> bool {
+        self.gso.load(Ordering::Relaxed)
+    }
+
+    /// Receive at least one datagram into buffers from `pool`, returning how many arrived
+    pub async fn recv(&self, batch: &mut RecvBatch, pool: &mut PacketPool) -> io::Result<usize> {
+        batch.received.clear();
+
+        #[cfg(target_os = "linux")]
+        loop {
+            self.socket.readable().await?;
+            batch.fill(pool);
+            let received = self.socket.try_io(tokio::io::Interest::READABLE, || linux::recv(&self.socket, batch));
+            batch.split(pool);
+            match received {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let (len, addr) = self.socket.recv_from(&mut batch.overflow[..RECV_BUFFER_SIZE]).await?;
+            batch.meta.clear();
+            batch.packets.clear();
+            batch.packets.push(pool.take());
+            batch.meta.push(RecvMeta { slot: 0, addr, len, segment_size: len });
+            batch.split(pool);
+        }
+
+        Ok(batch.received.len())
+    }
+
+    /// Send every queued datagram, leaving the batch empty
+    ///
+    /// A datagram the kernel rejects is dropped like a failed `send_to`, and
+    /// the last such error is returned once the rest have been sent.
+    pub async fn send(&self, batch: &mut SendBatch) -> io::Result<()> {
+        let packets = std::mem::take(&mut batch.packets);
+        let mut result = Ok(());
+
+        #[cfg(target_os = "linux")]
+        {
+            let runs = &mut batch.runs;
+            gso_runs(&packets, self.gso_enabled(), runs);
+            let mut next = 0;
+            while next < runs.len() {
+                self.socket.writable().await?;
+                let sent = self.socket.try_io(tokio::io::Interest::WRITABLE, || {
+                    linux::send(&self.socket, &packets, &runs[next..])
+                });
+                match sent {
+                    Ok(sent) => next += sent,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // Without checksum offload the device cannot segment
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) && self.gso_enabled() => {
+                        tracing::warn!("UDP GSO send failed, falling back to one datagram per packet");
+                        self.gso.store(false, Ordering::Relaxed);
+                        next = runs[next].start;
+                        gso_runs(&packets, false, runs);
+                    }
+                    Err(e) => {
+                        next += 1;
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        for (addr, packet) in &packets {
+            if let Err(e) = self.socket.send_to(packet, *addr).await {
+                result = Err(e);
+            }
+        }
+
+        batch.packets = packets;
+        batch.packets.clear();
+        result
+    }
+}
+
+/// Split queued packets into runs that each go out as one send
+///
+/// With GSO, consecutive packets to the same peer share a run while they
+/// have the first packet's size; only the last packet of a run may be
+/// shorter. Without GSO every packet is its own run. `runs` is cleared
+/// and refilled so its allocation carries over between batches.
+fn gso_runs(packets: &[(SocketAddr, Bytes)], gso: bool, runs: &mut Vec<Range<usize>>) {
+    runs.clear();
+    let mut run_bytes = 0;
+
+    for (index, (addr, packet)) in packets.iter().enumerate() {
+        if let Some(run) = runs.last_mut().filter(|_| gso) {
+            let (first_addr, first) = &packets[run.start];
+            let segment_size = first.len();
+            let joins = addr == first_addr
+                && segment_size > 0
+                && packet.len() <= segment_size
+                && packets[run.end - 1].1.len() == segment_size
+                && run.len() < MAX_GSO_SEGMENTS
+                && run_bytes + packet.len() <= MAX_GSO_BYTES;
+            if joins {
+                run.end = index + 1;
+                run_bytes += packet.len();
+                continue;
+            }
+        }
+        runs.push(index..index + 1);
+        run_bytes = packet.len();
+    }
+}
+
+/// `recvmmsg`/`sendmmsg` with UDP GRO and GSO control messages
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::ops::Range;
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    use bytes::Bytes;
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    use super::{BATCH_SIZE, MAX_GSO_SEGMENTS, RECV_BUFFER_SIZE, RecvBatch, RecvMeta};
+
+    /// Packets handed to one `sendmmsg`, enough for at least one full GSO run
+    const MAX_SEND_PACKETS: usize = 2 * MAX_GSO_SEGMENTS;
+
+    /// Control message buffer holding one integer option
+    type ControlBuf = [u64; 4];
+
+    fn set_option(socket: &UdpSocket, option: libc::c_int, value: libc::c_int) -> bool {
+        // SAFETY: the option value is a valid c_int for the call's duration
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                option,
+                ptr::from_ref(&value).cast(),
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        rc == 0
+    }
+
+    /// Ask the kernel to merge datagrams of one flow into a single receive
+    pub fn enable_gro(socket: &UdpSocket) -> bool {
+        set_option(socket, libc::UDP_GRO, 1)
+    }
+
+    /// Check if the kernel knows the UDP GSO socket option
+    pub fn gso_supported(socket: &UdpSocket) -> bool {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are valid for writes for the call's duration
+        let rc = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                ptr::from_mut(&mut value).cast(),
+                &mut len,
+            )
+        };
+        rc == 0
+    }
+
+    /// Receive up to a batch of datagrams without blocking
+    ///
+    /// Each slot receives into the spare capacity of its pooled buffer and
+    /// continues into its share of the overflow space.
+    pub fn recv(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<()> {
+        // SAFETY: all-zero is a valid value for these plain C structs
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls: [ControlBuf; BATCH_SIZE] = [[0; 4]; BATCH_SIZE];
+        // SAFETY: as above
+        let mut iovecs: [[libc::iovec; 2]; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let slots = batch.packets.iter_mut().zip(batch.overflow.chunks_exact_mut(RECV_BUFFER_SIZE));
+        for (iovec, (packet, overflow)) in iovecs.iter_mut().zip(slots) {
+            let spare = packet.spare_capacity_mut();
+            iovec[0] = libc::iovec { iov_base: spare.as_mut_ptr().cast(), iov_len: spare.len() };
+            iovec[1] = libc::iovec { iov_base: overflow.as_mut_ptr().cast(), iov_len: overflow.len() };
+        }
+        // SAFETY: as above
+        let mut messages: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (i, message) in messages.iter_mut().enumerate() {
+            let hdr = &mut message.msg_hdr;
+            hdr.msg_name = ptr::from_mut(&mut names[i]).cast();
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = iovecs[i].as_mut_ptr();
+            hdr.msg_iovlen = 2;
+            hdr.msg_control = controls[i].as_mut_ptr().cast();
+            hdr.msg_controllen = mem::size_of::<ControlBuf>() as _;
+        }
+
+        // SAFETY: every header points at buffers that outlive the call
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                BATCH_SIZE as libc::c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (slot, (message, name)) in messages.iter().zip(names).take(count as usize).enumerate() {
+            let len = message.msg_len as usize;
+            let packet = &mut batch.packets[slot];
+            // SAFETY: the kernel wrote len bytes, starting in the spare capacity
+            unsafe { packet.set_len(len.min(packet.capacity())) };
+
+            // SAFETY: the kernel wrote a socket address of msg_namelen bytes
+            let addr = unsafe { SockAddr::new(name, message.msg_hdr.msg_namelen) }.as_socket();
+            let Some(addr) = addr else {
+                continue;
+            };
+            let segment_size = gro_segment_size(&message.msg_hdr).unwrap_or(len);
+            batch.meta.push(RecvMeta { slot, addr, len, segment_size });
+        }
+        Ok(())
+    }
+
+    /// Segment size reported by a `UDP_GRO` control message
+    fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+        // SAFETY: the kernel filled msg_control with well-formed control messages
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>());
+                    return usize::try_from(size).ok().filter(|&size| size > 0);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    /// Send up to a batch of runs without blocking, returning how many were sent
+    ///
+    /// Runs of more than one packet carry a `UDP_SEGMENT` control message
+    /// with the first packet's size, and the kernel splits them back apart.
+    pub fn send(socket: &UdpSocket, packets: &[(std::net::SocketAddr, Bytes)], runs: &[Range<usize>]) -> io::Result<usize> {
+        let mut names: [Option<SockAddr>; BATCH_SIZE] = std::array::from_fn(|_| None);
+        let mut controls: [ControlBuf; BATCH_SIZE] = [[0; 4]; BATCH_SIZE];
+        // SAFETY: all-zero is a valid value for these plain C structs
+        let mut iovecs: [libc::iovec; MAX_SEND_PACKETS] = unsafe { mem::zeroed() };
+        // SAFETY: as above
+        let mut messages: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        let mut count = 0;
+        let mut offset = 0;
+        for run in runs.iter().take(BATCH_SIZE) {
+            if offset + run.len() > MAX_SEND_PACKETS {
+                break;
+            }
+            for (iovec, (_, packet)) in iovecs[offset..].iter_mut().zip(&packets[run.clone()]) {
+                *iovec = libc::iovec { iov_base: packet.as_ptr().cast_mut().cast(), iov_len: packet.len() };
+            }
+            let name = names[count].insert(SockAddr::from(packets[run.start].0));
+
+            let hdr = &mut messages[count].msg_hdr;
+            hdr.msg_name = name.as_ptr().cast_mut().cast();
+            hdr.msg_namelen = name.len();
+            hdr.msg_iov = iovecs[offset..].as_mut_ptr();
+            hdr.msg_iovlen = run.len() as _;
+            offset += run.len();
+
+            if run.len() > 1 {
+                let segment_size = packets[run.start].1.len() as u16;
+                hdr.msg_control = controls[count].as_mut_ptr().cast();
+                // SAFETY: the control buffer fits one u16 control message
+                unsafe {
+                    hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segment_size);
+                }
+            }
+            count += 1;
+        }
+
+        // SAFETY: every header points at buffers that outlive the call
+        let sent = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), count as libc::c_uint, 0)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn test_gso_runs() {
+        let packet = |len: usize| Bytes::from(vec![0u8; len]);
+        let packets = vec![
+            (addr(1), packet(1400)),
+            (addr(1), packet(1400)),
+            (addr(1), packet(600)), // Short packet ends the run
+            (addr(1), packet(1400)),
+            (addr(2), packet(1400)), // Other peer
+            (addr(2), packet(1500)), // Larger than the segment size
+        ];
+
+        let mut runs = Vec::new();
+        gso_runs(&packets, true, &mut runs);
+        assert_eq!(runs, vec![0..3, 3..4, 4..5, 5..6]);
+        gso_runs(&packets, false, &mut runs);
+        assert_eq!(runs.len(), packets.len());
+
+        // Runs stop at the kernel's segment limit
+        let many: Vec<_> = (0..MAX_GSO_SEGMENTS + 1).map(|_| (addr(1), packet(100))).collect();
+        gso_runs(&many, true, &mut runs);
+        assert_eq!(runs, vec![0..MAX_GSO_SEGMENTS, MAX_GSO_SEGMENTS..MAX_GSO_SEGMENTS + 1]);
+    }
+
+    #[test]
+    fn test_datagrams() {
+        let mut pool = PacketPool::new(2 * BATCH_SIZE, 1500);
+        let mut batch = RecvBatch::new();
+        batch.fill(&mut pool);
+
+        // GRO run of two full segments and a short one, spilling into the overflow
+        let run: Vec<u8> = (0..2500).map(|i| (i / 1000) as u8).collect();
+        batch.packets[0].extend_from_slice(&run[..1500]);
+        batch.overflow[..1000].copy_from_slice(&run[1500..]);
+        batch.packets[1].extend_from_slice(&[9u8; 300]);
+        let first_ptr = batch.packets[0].as_ptr();
+        batch.meta = vec![
+            RecvMeta { slot: 0, addr: addr(1), len: 2500, segment_size: 1000 },
+            RecvMeta { slot: 1, addr: addr(2), len: 300, segment_size: 300 },
+        ];
+        batch.split(&mut pool);
+
+        let datagrams: Vec<_> = batch.datagrams().collect();
+        assert_eq!(datagrams.len(), 4);
+        for (i, len) in [1000, 1000, 500].into_iter().enumerate() {
+            assert_eq!(datagrams[i].0, addr(1));
+            assert_eq!(datagrams[i].1.len(), len);
+            assert!(datagrams[i].1.iter().all(|&b| b == i as u8));
+        }
+        assert_eq!(datagrams[3].0, addr(2));
+        assert_eq!(&datagrams[3].1[..], &[9u8; 300][..]);
+
+        // The first datagram of each slot stays in its pooled buffer
+        assert_eq!(datagrams[0].1.as_ptr(), first_ptr);
+        assert_eq!(datagrams[0].1.capacity(), 1500);
+
+        // A datagram larger than a pooled buffer comes back whole
+        batch.fill(&mut pool);
+        batch.packets[2].extend_from_slice(&[7u8; 1500]);
+        batch.overflow[2 * RECV_BUFFER_SIZE..][..500].fill(7);
+        batch.meta = vec![RecvMeta { slot: 2, addr: addr(3), len: 2000, segment_size: 2000 }];
+        batch.split(&mut pool);
+        let datagrams: Vec<_> = batch.datagrams().collect();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(&datagrams[0].1[..], &[7u8; 2000][..]);
+
+        // Slots without a datagram are skipped, and their buffers go back to the pool
+        let mut pool = PacketPool::new(BATCH_SIZE, 1500);
+        batch.fill(&mut pool);
+        let buffers: Vec<_> = batch.packets.iter().map(|packet| packet.as_ptr()).collect();
+        batch.split(&mut pool);
+        assert_eq!(batch.datagrams().count(), 0);
+        batch.fill(&mut pool);
+        assert!(batch.packets.iter().map(|packet| packet.as_ptr()).eq(buffers));
+    }
+
+    #[tokio::test]
+    async fn test_batch_roundtrip() {
+        let receiver = BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let sender = BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let to = receiver.socket().local_addr().unwrap();
+        let from = sender.socket().local_addr().unwrap();
+
+        let mut batch = SendBatch::new();
+        for i in 0..10u8 {
+            let len = if i == 9 { 300 } else { 1200 };
+            batch.push(to, Bytes::from(vec![i; len]));
+        }
+        sender.send(&mut batch).await.unwrap();
+        assert!(batch.is_empty());
+
+        let received = recv_all(&receiver, 10).await;
+
+        // GRO may merge datagrams, but each comes back whole and in order
+        assert_eq!(received.len(), 10);
+        for (i, (peer, datagram)) in received.iter().enumerate() {
+            assert_eq!(*peer, from);
+            assert_eq!(datagram.len(), if i == 9 { 300 } else { 1200 });
+            assert!(datagram.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    /// Receive datagrams until `count` have arrived
+    async fn recv_all(socket: &BatchSocket, count: usize) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut received = Vec::new();
+        let mut pool = PacketPool::new(2 * BATCH_SIZE, 1500);
+        let mut buffers = RecvBatch::new();
+        while received.len() < count {
+            socket.recv(&mut buffers, &mut pool).await.unwrap();
+            received.extend(buffers.datagrams().map(|(peer, datagram)| (peer, datagram.to_vec())));
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_batch_many_peers() {
+        let sender = BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let from = sender.socket().local_addr().unwrap();
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            receivers.push(BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())));
+        }
+
+        // More runs than one sendmmsg takes, interleaved so no GSO run forms
+        let mut batch = SendBatch::new();
+        for i in 0..3 * BATCH_SIZE {
+            let to = receivers[i % 3].socket().local_addr().unwrap();
+            batch.push(to, Bytes::from(vec![(i / 3) as u8; 100 + i % 3]));
+        }
+        sender.send(&mut batch).await.unwrap();
+
+        for (r, receiver) in receivers.iter().enumerate() {
+            let received = recv_all(receiver, BATCH_SIZE).await;
+            for (i, (peer, datagram)) in received.iter().enumerate() {
+                assert_eq!(*peer, from);
+                assert_eq!(*datagram, vec![i as u8; 100 + r]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_send_error() {
+        let receiver = BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let sender = BatchSocket::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        let to = receiver.socket().local_addr().unwrap();
+
+        // The oversized datagram is rejected, the ones around it still go out
+        let mut batch = SendBatch::new();
+        batch.push(to, Bytes::from(vec![1u8; 1000]));
+        batch.push(to, Bytes::from(vec![2u8; MAX_GSO_BYTES + 1]));
+        batch.push(to, Bytes::from(vec![3u8; 1000]));
+        assert!(sender.send(&mut batch).await.is_err());
+        assert!(batch.is_empty());
+
+        let received = recv_all(&receiver, 2).await;
+        assert_eq!(received[0].1, vec![1u8; 1000]);
+        assert_eq!(received[1].1, vec![3u8; 1000]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_ipv6() {
+        // Hosts without IPv6 loopback have nothing to test
+        let Ok(socket) = UdpSocket::bind("[::1]:0").await else {
+            return;
+        };
+        let receiver = BatchSocket::new(Arc::new(socket));
+        let sender = BatchSocket::new(Arc::new(UdpSocket::bind("[::1]:0").await.unwrap()));
+        let to = receiver.socket().local_addr().unwrap();
+
+        let mut batch = SendBatch::new();
+        batch.push(to, Bytes::from_static(b"hello"));
+        sender.send(&mut batch).await.unwrap();
+
+        let received = recv_all(&receiver, 1).await;
+        assert_eq!(received, vec![(sender.socket().local_addr().unwrap(), b"hello".to_vec())]);
+    }
+}
@@ -15,6 +15,7 @@
 pub mod auth;
 pub mod templates;
 pub mod routes;
+pub mod saml;
 pub mod state;
 
 pub use auth::{ADMIN_PASSWORD_ENV, ADMIN_USERNAME, is_auth_configured};
@@ -37,6 +37,10 @@ pub fn create_router(state: WebUiState) -> Router {
         .route("/admin/clients/quick-generate", get(quick_generate_form))
         .route("/admin/clients/quick-generate", post(quick_generate_download))
 
+        // Local user accounts
+        .route("/admin/users/password", get(set_password_form))
+        .route("/admin/users/password", post(set_password))
+
         // Sessions
         .route("/admin/sessions", get(sessions_list))
         .route("/admin/sessions/", get(sessions_list))
@@ -84,10 +88,36 @@ async fn dashboard(State(state): State<WebUiState>) -> Html<String> {
     Html(html)
 }
 
-async fn clients_list(State(_state): State<WebUiState>) -> Html<String> {
-    // In a real implementation, this would fetch from the database
-    // For now, show empty list
-    let clients: Vec<templates::ClientInfo> = vec![];
+async fn clients_list(State(state): State<WebUiState>) -> Html<String> {
+    use corevpn_crypto::CertificateIndex;
+
+    // List every client holding an active certificate
+    let index = CertificateIndex::load(&state.config.cert_index_path()).unwrap_or_else(|e| {
+        tracing::warn!("Failed to load certificate index: {}", e);
+        CertificateIndex::new()
+    });
+
+    let server_cert_serial = std::fs::read_to_string(state.config.server_cert_path())
+        .ok()
+        .and_then(|pem| corevpn_protocol::load_certs_from_pem(&pem).ok())
+        .and_then(|certs| certs.first().and_then(|c| corevpn_crypto::crl::certificate_serial(c).ok()));
+
+    let mut clients: Vec<templates::ClientInfo> = Vec::new();
+    for entry in index.certificates().iter().filter(|entry| entry.is_active()) {
+        if Some(&entry.serial) == server_cert_serial.as_ref()
+            || clients.iter().any(|c| c.id == entry.common_name)
+        {
+            continue;
+        }
+        clients.push(templates::ClientInfo {
+            id: entry.common_name.clone(),
+            name: entry.common_name.clone(),
+            email: entry.email.clone().unwrap_or_default(),
+            connected: false,
+            vpn_ip: None,
+            last_seen: None,
+        });
+    }
 
     Html(templates::clients_list(&clients))
 }
@@ -150,6 +180,40 @@ async fn create_client(
     Html(html).into_response()
 }
 
+async fn set_password_form() -> Html<String> {
+    Html(templates::set_password(None))
+}
+
+#[derive(Deserialize)]
+struct SetPasswordForm {
+    username: String,
+    password: String,
+    confirm: String,
+}
+
+async fn set_password(
+    State(state): State<WebUiState>,
+    Form(form): Form<SetPasswordForm>,
+) -> Response {
+    use corevpn_auth::AuthError;
+    use corevpn_core::SqliteUserStore;
+
+    if form.password != form.confirm {
+        return error_response(400, "Passwords do not match");
+    }
+
+    let store = match SqliteUserStore::open_in(state.config.data_dir()).await {
+        Ok(store) => store,
+        Err(e) => return error_response(500, &format!("Failed to open user database: {}", e)),
+    };
+
+    match corevpn_auth::set_password(&store, form.username.trim(), &form.password).await {
+        Ok(user) => Html(templates::set_password(Some(user.id.as_str()))).into_response(),
+        Err(e @ AuthError::WeakPassword(_)) => error_response(400, &e.to_string()),
+        Err(e) => error_response(500, &format!("Failed to set password: {}", e)),
+    }
+}
+
 async fn download_client_config(
     State(state): State<WebUiState>,
     Path(id): Path<String>,
@@ -195,12 +259,38 @@ async fn download_client_config(
 }
 
 async fn revoke_client(
-    State(_state): State<WebUiState>,
-    Path(_id): Path<String>,
-) -> Redirect {
-    // In a real implementation, this would revoke the client certificate
-    // For now, just redirect back
-    Redirect::to("/admin/clients")
+    State(state): State<WebUiState>,
+    Path(id): Path<String>,
+) -> Response {
+    use corevpn_config::generator::ConfigGenerator;
+    use corevpn_crypto::{CertificateAuthority, RevocationReason};
+
+    // Load CA
+    let ca_cert = match std::fs::read_to_string(state.config.ca_cert_path()) {
+        Ok(c) => c,
+        Err(e) => return error_response(500, &format!("Failed to read CA: {}", e)),
+    };
+    let ca_key = match std::fs::read_to_string(state.config.ca_key_path()) {
+        Ok(k) => k,
+        Err(e) => return error_response(500, &format!("Failed to read CA key: {}", e)),
+    };
+
+    let ca = match CertificateAuthority::from_pem(&ca_cert, &ca_key) {
+        Ok(ca) => ca,
+        Err(e) => return error_response(500, &format!("Failed to load CA: {}", e)),
+    };
+
+    let config = (*state.config).clone();
+    let generator = ConfigGenerator::new(config, ca, None);
+
+    // Revoke every certificate held by the client and publish the new CRL
+    match generator.revoke_client(&id, RevocationReason::Unspecified) {
+        Ok(serials) => {
+            tracing::info!("Revoked {} certificate(s) for client {}", serials.len(), id);
+            Redirect::to("/admin/clients").into_response()
+        }
+        Err(e) => error_response(400, &format!("Failed to revoke client: {}", e)),
+    }
 }
 
 /// Download mobile-optimized client config
@@ -387,9 +477,11 @@ async fn disconnect_all_sessions(State(state): State<WebUiState>) -> Redirect {
 async fn settings_page(State(state): State<WebUiState>) -> Html<String> {
     let config = &state.config;
 
-    let (oauth_enabled, oauth_provider) = config.oauth.as_ref()
-        .map(|o| (o.enabled, Some(o.provider.as_str())))
-        .unwrap_or((false, None));
+    let (oauth_enabled, oauth_provider) = match (&config.saml, &config.oauth) {
+        (Some(saml), _) if saml.enabled => (true, Some("saml")),
+        (_, Some(oauth)) => (oauth.enabled, Some(oauth.provider.as_str())),
+        _ => (false, None),
+    };
 
     let html = templates::settings(
         &config.server.public_host,
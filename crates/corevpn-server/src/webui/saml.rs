@@ -0,0 +1,89 @@
+//! SAML Service Provider Routes
+//!
+//! Public endpoints for SAML VPN logins, served by the VPN server itself
+//! since they complete logins it holds. They are not behind the admin
+//! Basic auth: the IdP's signed response is what authenticates the user.
+
+use std::sync::Arc;
+
+use axum::{
+    Router,
+    routing::{get, post},
+    response::{Html, IntoResponse, Redirect, Response},
+    extract::{Form, Query, State},
+    http::{StatusCode, header},
+};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use super::templates;
+use crate::saml::SamlLogin;
+
+/// Create the router for the service provider endpoints
+pub fn create_router(saml: Arc<SamlLogin>) -> Router {
+    Router::new()
+        .route("/saml/metadata", get(metadata))
+        .route("/saml/login", get(login))
+        .route("/saml/acs", post(assertion_consumer_service))
+        .with_state(saml)
+}
+
+/// Serve the service provider endpoints until the listener fails
+pub async fn serve(listener: TcpListener, saml: Arc<SamlLogin>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("SAML service provider listening on {} (metadata at {})", addr, saml.metadata_url());
+    }
+    if let Err(e) = axum::serve(listener, create_router(saml)).await {
+        error!("SAML service provider stopped: {}", e);
+    }
+}
+
+async fn metadata(State(saml): State<Arc<SamlLogin>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/samlmetadata+xml")],
+        saml.metadata(),
+    ).into_response()
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    state: String,
+}
+
+async fn login(State(saml): State<Arc<SamlLogin>>, Query(query): Query<LoginQuery>) -> Response {
+    match saml.authn_redirect(&query.state) {
+        Some(url) => Redirect::to(&url).into_response(),
+        None => result_page(
+            StatusCode::NOT_FOUND,
+            false,
+            "This login has expired or was already used. Reconnect the VPN to start a new one.",
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct AcsForm {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+    #[serde(rename = "RelayState")]
+    relay_state: Option<String>,
+}
+
+async fn assertion_consumer_service(
+    State(saml): State<Arc<SamlLogin>>,
+    Form(form): Form<AcsForm>,
+) -> Response {
+    match saml.complete(&form.saml_response, form.relay_state.as_deref()) {
+        Ok(_) => result_page(
+            StatusCode::OK,
+            true,
+            "You are logged in. Return to your VPN client to finish connecting.",
+        ),
+        Err(failure) => result_page(StatusCode::FORBIDDEN, false, &failure.reason),
+    }
+}
+
+fn result_page(status: StatusCode, success: bool, message: &str) -> Response {
+    (status, Html(templates::login_result(success, message))).into_response()
+}
@@ -423,6 +423,12 @@ pub fn clients_list(clients: &[ClientInfo]) -> String {
                     <p class="text-void-400 mt-1">Manage VPN client configurations</p>
                 </div>
                 <div class="flex items-center gap-3">
+                    <a href="/admin/users/password" class="flex items-center gap-2 px-4 py-2 bg-void-800/50 text-void-300 rounded-lg font-medium hover:bg-void-700/50 hover:text-void-100 transition-colors">
+                        <svg class="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
+                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M12 15v2m-6 4h12a2 2 0 002-2v-6a2 2 0 00-2-2H6a2 2 0 00-2 2v6a2 2 0 002 2zm10-10V7a4 4 0 00-8 0v4h8z"/>
+                        </svg>
+                        Set Password
+                    </a>
                     <a href="/admin/clients/quick-generate" class="flex items-center gap-2 px-4 py-2 bg-neon-blue/10 text-neon-blue rounded-lg font-medium hover:bg-neon-blue/20 transition-colors">
                         <svg class="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
                             <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M4 16v1a3 3 0 003 3h10a3 3 0 003-3v-1m-4-4l-4 4m0 0l-4-4m4 4V4"/>
@@ -526,6 +532,73 @@ pub fn new_client() -> String {
     base("Add Client", &content)
 }
 
+/// Set or reset a local user's password
+pub fn set_password(updated: Option<&str>) -> String {
+    let notice = match updated {
+        Some(username) => format!(r#"
+                <div class="glass rounded-xl p-4 mb-6 text-neon-green">
+                    Password updated for <span class="font-mono">{}</span>
+                </div>
+            "#, html_escape(username)),
+        None => String::new(),
+    };
+
+    let content = format!(r##"
+        {nav}
+        <main class="ml-64 p-8">
+            <header class="mb-8">
+                <a href="/admin/clients" class="inline-flex items-center gap-2 text-void-400 hover:text-void-100 transition-colors mb-4">
+                    <svg class="w-4 h-4" fill="none" stroke="currentColor" viewBox="0 0 24 24">
+                        <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M15 19l-7-7 7-7"/>
+                    </svg>
+                    Back to Clients
+                </a>
+                <h1 class="text-3xl font-bold text-void-50">Set Password</h1>
+                <p class="text-void-400 mt-1">Set or reset the local password of a VPN user</p>
+            </header>
+
+            <div class="max-w-2xl">
+                {notice}
+                <form action="/admin/users/password" method="POST" class="glass rounded-xl p-8 space-y-6">
+                    <div>
+                        <label for="username" class="block text-sm font-medium text-void-300 mb-2">Username</label>
+                        <input type="text" id="username" name="username" required
+                            class="w-full px-4 py-3 bg-void-900/50 border border-void-700/50 rounded-lg text-void-100 placeholder-void-500 focus:outline-none focus:border-neon-green focus:ring-1 focus:ring-neon-green transition-colors"
+                            placeholder="user@example.com">
+                        <p class="text-xs text-void-500 mt-1">The account is created if it does not exist</p>
+                    </div>
+
+                    <div>
+                        <label for="password" class="block text-sm font-medium text-void-300 mb-2">New Password</label>
+                        <input type="password" id="password" name="password" required minlength="8" autocomplete="new-password"
+                            class="w-full px-4 py-3 bg-void-900/50 border border-void-700/50 rounded-lg text-void-100 placeholder-void-500 focus:outline-none focus:border-neon-green focus:ring-1 focus:ring-neon-green transition-colors">
+                    </div>
+
+                    <div>
+                        <label for="confirm" class="block text-sm font-medium text-void-300 mb-2">Confirm Password</label>
+                        <input type="password" id="confirm" name="confirm" required minlength="8" autocomplete="new-password"
+                            class="w-full px-4 py-3 bg-void-900/50 border border-void-700/50 rounded-lg text-void-100 placeholder-void-500 focus:outline-none focus:border-neon-green focus:ring-1 focus:ring-neon-green transition-colors">
+                    </div>
+
+                    <div class="pt-4 flex items-center gap-4">
+                        <button type="submit" class="flex-1 px-6 py-3 bg-neon-green text-void-950 rounded-lg font-semibold hover:bg-neon-green/90 transition-colors glow-green">
+                            Set Password
+                        </button>
+                        <a href="/admin/clients" class="px-6 py-3 bg-void-800/50 text-void-300 rounded-lg font-medium hover:bg-void-700/50 hover:text-void-100 transition-colors">
+                            Cancel
+                        </a>
+                    </div>
+                </form>
+            </div>
+        </main>
+    "##,
+        nav = nav("clients"),
+        notice = notice,
+    );
+
+    base("Set Password", &content)
+}
+
 /// Sessions list page
 pub fn sessions_list(sessions: &[SessionInfo]) -> String {
     let mut rows = String::new();
@@ -980,6 +1053,32 @@ pub fn error_page(status: u16, message: &str) -> String {
     base("Error", &content)
 }
 
+/// Result page shown after an SSO login for a VPN connection
+pub fn login_result(success: bool, message: &str) -> String {
+    let (emoji, title, color) = if success {
+        ("✅", "Login Complete", "text-neon-green")
+    } else {
+        ("🚫", "Login Failed", "text-neon-pink")
+    };
+
+    let content = format!(r##"
+        <div class="min-h-screen flex items-center justify-center p-8">
+            <div class="text-center">
+                <div class="text-6xl mb-6">{emoji}</div>
+                <h1 class="text-4xl font-bold {color} text-glow mb-4">{title}</h1>
+                <p class="text-void-400 max-w-md">{message}</p>
+            </div>
+        </div>
+    "##,
+        emoji = emoji,
+        color = color,
+        title = title,
+        message = html_escape(message),
+    );
+
+    base(title, &content)
+}
+
 // ============================================================================
 // Helper types and functions
 // ============================================================================